@@ -0,0 +1,26 @@
+fn main() {
+    // Commit the binary was built from, recorded in audit logs and exports
+    let commit = std::process::Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+    println!("cargo:rustc-env=ALLERGY_NET_GIT_COMMIT={}", commit.as_deref().unwrap_or("unknown"));
+    for path in [".git/HEAD", ".git/refs/heads", ".git/packed-refs"] {
+        if std::path::Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/analysis.proto");
+        // protox compiles the schema in pure Rust, so no protoc install is needed
+        let descriptors = protox::compile(["proto/analysis.proto"], ["proto"]).expect("compile proto/analysis.proto");
+        tonic_build::configure()
+            .build_client(false)
+            .compile_fds(descriptors)
+            .expect("generate gRPC service");
+    }
+}
@@ -0,0 +1,129 @@
+//! extendr bindings exposing the allergy graph to R.
+//!
+//! ```r
+//! g <- AllergyGraph$from_csv("records.csv", NULL, NULL)
+//! g$node_metrics()
+//! g$group_metrics(c("race", "race*payer"), 11L)
+//! g$prevalence(c("race"), 11L)
+//! ```
+//!
+//! Suppressed counts and estimates come back as `NA`.
+
+use extendr_api::prelude::*;
+use petgraph::graph::DiGraph;
+use project_name::stats::prevalence::{prevalence, Prevalence};
+use project_name::strata::{Grouping, DEFAULT_DIMENSIONS};
+use project_name::{
+    calculate_centrality, create_graph, read_csv, EdgeWeight, GraphOptions, GroupScore, NodeType, ReportOptions,
+};
+
+/// The individual-allergy graph built from one CSV.
+pub struct AllergyGraph {
+    graph: DiGraph<NodeType, EdgeWeight>,
+}
+
+fn to_error(error: impl ToString) -> Error {
+    Error::Other(error.to_string())
+}
+
+/// `stratify_by` as written on the command line; empty for the default
+/// demographics.
+fn groupings(stratify_by: &[String]) -> Result<Vec<Grouping>> {
+    if stratify_by.is_empty() {
+        DEFAULT_DIMENSIONS.split(',').map(str::parse).collect::<std::result::Result<Vec<Grouping>, _>>()
+    } else {
+        stratify_by.iter().map(|g| g.parse()).collect::<std::result::Result<Vec<Grouping>, _>>()
+    }
+    .map_err(to_error)
+}
+
+fn report_options(small_cell_threshold: i32) -> ReportOptions {
+    ReportOptions { small_cell_threshold: small_cell_threshold.max(0) as usize, ..Default::default() }
+}
+
+/// A count as an R double, `NA` when suppressed.
+fn count(value: Option<usize>) -> Option<f64> {
+    value.map(|value| value as f64)
+}
+
+#[extendr]
+impl AllergyGraph {
+    /// Reads `path` and builds the graph; pass `NULL` to skip either onset
+    /// bound.
+    fn from_csv(path: &str, onset_before: Nullable<f64>, onset_after: Nullable<f64>) -> Result<Self> {
+        let records = read_csv(path).map_err(to_error)?;
+        let options = GraphOptions {
+            onset_before: onset_before.into_option(),
+            onset_after: onset_after.into_option(),
+            ..Default::default()
+        };
+        Ok(AllergyGraph { graph: create_graph(records, &options) })
+    }
+
+    fn node_count(&self) -> i32 {
+        self.graph.node_count() as i32
+    }
+
+    fn edge_count(&self) -> i32 {
+        self.graph.edge_count() as i32
+    }
+
+    /// Degree of every individual as a data frame (`node`, `id`, `degree`).
+    fn node_metrics(&self) -> Result<Robj> {
+        let report = calculate_centrality(&self.graph, &[], &ReportOptions::default()).map_err(to_error)?;
+        let node: Vec<Option<i32>> = report.nodes.iter().map(|score| score.node.map(|node| node as i32)).collect();
+        let id: Vec<String> = report.nodes.iter().map(|score| score.id.clone()).collect();
+        let degree: Vec<f64> = report.nodes.iter().map(|score| score.value).collect();
+        Ok(data_frame!(node = node, id = id, degree = degree))
+    }
+
+    /// Mean degree per group as a data frame with the same columns as the
+    /// ndjson `group` objects; an empty `stratify_by` uses the default
+    /// demographics.
+    fn group_metrics(&self, stratify_by: Vec<String>, small_cell_threshold: i32) -> Result<Robj> {
+        let options = report_options(small_cell_threshold);
+        let report = calculate_centrality(&self.graph, &groupings(&stratify_by)?, &options).map_err(to_error)?;
+        let groups = &report.groups;
+        let text = |cell: fn(&GroupScore) -> &str| -> Vec<String> { groups.iter().map(|g| cell(g).into()).collect() };
+        let number = |cell: fn(&GroupScore) -> Option<f64>| -> Vec<Option<f64>> { groups.iter().map(cell).collect() };
+        let flag = |cell: fn(&GroupScore) -> bool| -> Vec<bool> { groups.iter().map(cell).collect() };
+        Ok(data_frame!(
+            grouping = text(|g| &g.grouping),
+            group = text(|g| &g.group),
+            mean = number(|g| g.mean),
+            total = number(|g| g.total),
+            n = number(|g| count(g.n)),
+            denominator = vec![report.denominator as f64; groups.len()],
+            small_cell = flag(|g| g.small_cell),
+            suppressed = flag(|g| g.suppressed)
+        ))
+    }
+
+    /// Prevalence of each allergy, overall and per group, as a data frame
+    /// with the columns of the `prevalence` table; an empty `stratify_by`
+    /// uses the default demographics.
+    fn prevalence(&self, stratify_by: Vec<String>, small_cell_threshold: i32) -> Result<Robj> {
+        let options = report_options(small_cell_threshold);
+        let rows = prevalence(&self.graph, &groupings(&stratify_by)?, &options).map_err(to_error)?;
+        let text = |cell: fn(&Prevalence) -> &str| -> Vec<String> { rows.iter().map(|r| cell(r).into()).collect() };
+        let number = |cell: fn(&Prevalence) -> Option<f64>| -> Vec<Option<f64>> { rows.iter().map(cell).collect() };
+        let flag = |cell: fn(&Prevalence) -> bool| -> Vec<bool> { rows.iter().map(cell).collect() };
+        Ok(data_frame!(
+            allergy = text(|r| &r.allergy),
+            grouping = text(|r| &r.grouping),
+            group = text(|r| &r.group),
+            cases = number(|r| count(r.cases)),
+            n = number(|r| count(r.n)),
+            proportion = number(|r| r.proportion),
+            ci_lower = number(|r| r.ci_lower),
+            ci_upper = number(|r| r.ci_upper),
+            small_cell = flag(|r| r.small_cell),
+            suppressed = flag(|r| r.suppressed)
+        ))
+    }
+}
+
+extendr_module! {
+    mod allergynet;
+    impl AllergyGraph;
+}
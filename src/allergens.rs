@@ -0,0 +1,237 @@
+//! Allergen column mapping (`--allergen`, `--allergen-map`,
+//! `--allergen-column`): the columns holding each allergen's onset and end
+//! ages, for inputs whose nut columns are named differently, and for
+//! allergens beyond the nine nuts, such as sesame, egg or milk.
+
+use std::error::Error;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::remote;
+use crate::{Record, ALLERGENS, FOOD_ALLERGENS, RECORD_COLUMNS};
+
+/// Onset and end columns of one allergen.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AllergenColumns {
+    pub start: String,
+    pub end: String,
+}
+
+/// Allergens and the columns they are read from:
+///
+/// ```yaml
+/// Peanut: {start: pn_onset, end: pn_resolved}
+/// Sesame: {start: sesame_onset, end: sesame_end}
+/// Egg:
+/// ```
+///
+/// One of the nine nuts (matched ignoring case) is read from its declared
+/// columns instead of the canonical ones. Any other name is an allergen of
+/// its own, added after the nuts in the order declared; `FOOD_ALLERGENS`
+/// are matched ignoring case too. An allergen without columns is read from
+/// its canonical ones.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AllergenMap {
+    allergens: Vec<(String, AllergenColumns)>,
+}
+
+impl AllergenMap {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let invalid = |e: &dyn std::fmt::Display| format!("invalid allergen map {}: {}", path.display(), e);
+        let declared: serde_yaml::Mapping = serde_yaml::from_slice(&remote::read(path)?).map_err(|e| invalid(&e))?;
+        let mut map = AllergenMap::default();
+        for (name, columns) in declared {
+            let name = name.as_str().ok_or_else(|| invalid(&"allergen names must be strings"))?.to_string();
+            let columns = match columns {
+                serde_yaml::Value::Null => AllergenColumns::canonical(&name),
+                columns => serde_yaml::from_value(columns).map_err(|e| invalid(&format!("{}: {}", name, e)))?,
+            };
+            map.insert(name, columns).map_err(|e| invalid(&e))?;
+        }
+        Ok(map)
+    }
+
+    /// Adds or replaces an allergen's columns. A nut or food allergen
+    /// takes its canonical name; a column may only feed one allergen.
+    pub fn insert(&mut self, name: String, columns: AllergenColumns) -> Result<(), String> {
+        let known = ALLERGENS.iter().chain(FOOD_ALLERGENS);
+        let name = match known.into_iter().find(|allergen| allergen.eq_ignore_ascii_case(&name)) {
+            Some(allergen) => allergen.to_string(),
+            None if name.trim().is_empty() => return Err("an allergen needs a name".to_string()),
+            None => name,
+        };
+        if columns.start == columns.end {
+            return Err(format!("{}: onset and end are both read from {}", name, columns.start));
+        }
+        self.allergens.retain(|(declared, _)| *declared != name);
+        for column in [&columns.start, &columns.end] {
+            if let Some((other, _)) = self.allergens.iter().find(|(_, declared)| declared.feeds(column)) {
+                return Err(format!("column {} is declared for both {} and {}", column, other, name));
+            }
+            if RECORD_COLUMNS[..9].contains(&column.as_str()) {
+                return Err(format!("{}: {} is not an allergy column", name, column));
+            }
+        }
+        self.allergens.push((name, columns));
+        Ok(())
+    }
+
+    /// Declared allergens that aren't one of the nine nuts, in order.
+    pub fn extra_allergens(&self) -> Vec<String> {
+        self.allergens
+            .iter()
+            .filter(|(name, _)| !ALLERGENS.contains(&name.as_str()))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Whether `column` is read as an allergen's onset or end.
+    pub fn declares(&self, column: &str) -> bool {
+        self.allergens.iter().any(|(_, columns)| columns.feeds(column))
+    }
+
+    /// Whether `column` is the canonical column of a nut read from other
+    /// columns instead, so its absence is expected.
+    pub fn replaces(&self, column: &str) -> bool {
+        self.allergens.iter().any(|(name, columns)| {
+            let Some(i) = ALLERGENS.iter().position(|allergen| allergen == name) else { return false };
+            let canonical = &RECORD_COLUMNS[9 + 2 * i..11 + 2 * i];
+            canonical.contains(&column) && !columns.feeds(column)
+        })
+    }
+
+    /// Moves the declared columns out of each record's `extra` into its
+    /// allergies, so they aren't also attributes of the individual. A
+    /// blank or `NA` value is no onset or end; anything else must be a
+    /// number. Records are numbered from 1 in errors.
+    pub fn apply(&self, records: &mut [Record]) -> Result<(), String> {
+        for (row, record) in records.iter_mut().enumerate() {
+            for (name, columns) in &self.allergens {
+                let mut take = |column: &str| -> Result<Option<f64>, String> {
+                    let Some(value) = record.extra.remove(column) else { return Ok(None) };
+                    let value = value.trim();
+                    if value.is_empty() || value.eq_ignore_ascii_case("na") {
+                        return Ok(None);
+                    }
+                    value.parse().map(Some).map_err(|_| {
+                        format!("row {} (subject {}): {} '{}' is not an age", row + 1, record.subject_id, column, value)
+                    })
+                };
+                let (start, end) = (take(&columns.start)?, take(&columns.end)?);
+                if let Some(start) = start {
+                    record.set_allergy_start(name, start);
+                }
+                if let Some(end) = end {
+                    record.set_allergy_end(name, end);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AllergenColumns {
+    /// A nut's own columns; otherwise `<name>_alg_start` and
+    /// `<name>_alg_end`, lowercased with spaces as underscores.
+    pub fn canonical(name: &str) -> Self {
+        if let Some(i) = ALLERGENS.iter().position(|allergen| allergen.eq_ignore_ascii_case(name.trim())) {
+            let (start, end) = (RECORD_COLUMNS[9 + 2 * i], RECORD_COLUMNS[10 + 2 * i]);
+            return AllergenColumns { start: start.to_string(), end: end.to_string() };
+        }
+        let name = name.trim().to_lowercase().split_whitespace().collect::<Vec<_>>().join("_");
+        AllergenColumns { start: format!("{}_alg_start", name), end: format!("{}_alg_end", name) }
+    }
+
+    fn feeds(&self, column: &str) -> bool {
+        self.start == column || self.end == column
+    }
+}
+
+/// The allergens `--allergen NAME` declares, each read from its canonical
+/// columns. `food` stands for all of `FOOD_ALLERGENS`.
+pub fn named_allergens(name: &str) -> Vec<(String, AllergenColumns)> {
+    let names = match name.trim() {
+        "food" => FOOD_ALLERGENS.to_vec(),
+        name => vec![name],
+    };
+    names.into_iter().map(|name| (name.to_string(), AllergenColumns::canonical(name))).collect()
+}
+
+/// Parses `--allergen-column NAME=START:END`.
+pub fn parse_allergen_column(value: &str) -> Result<(String, AllergenColumns), String> {
+    let invalid = || format!("expected NAME=START_COLUMN:END_COLUMN, got '{}'", value);
+    let (name, columns) = value.split_once('=').ok_or_else(invalid)?;
+    let (start, end) = columns.split_once(':').ok_or_else(invalid)?;
+    if [name, start, end].iter().any(|part| part.trim().is_empty()) {
+        return Err(invalid());
+    }
+    Ok((name.trim().to_string(), AllergenColumns { start: start.trim().to_string(), end: end.trim().to_string() }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_graph, read_csv_from_reader, GraphOptions, NodeType};
+    use petgraph::graph::NodeIndex;
+
+    #[test]
+    fn test_allergen_map() {
+        let csv = "subject_id,birth_year,gender_factor,race_factor,ethnicity_factor,payer_factor,\
+                   atopic_march_cohort,age_start_years,age_end_years,pn_onset,pn_end,sesame_start,sesame_end,site\n\
+                   1,2010,S0 - Male,R0 - White,E0 - Non-Hispanic,P0 - Non-Medicaid,false,0,8,1.5,,2,4,north\n\
+                   2,2011,S1 - Female,R1 - Black,E0 - Non-Hispanic,P1 - Medicaid,false,0,6,,,NA,,south\n";
+        let mut records = read_csv_from_reader(csv.as_bytes()).unwrap();
+        let mut map = AllergenMap::default();
+        let (name, columns) = parse_allergen_column("peanut=pn_onset:pn_end").unwrap();
+        map.insert(name, columns).unwrap();
+        let (name, columns) = parse_allergen_column("Sesame=sesame_start:sesame_end").unwrap();
+        map.insert(name, columns).unwrap();
+        assert_eq!(map.extra_allergens(), ["Sesame"]);
+        assert!(map.declares("sesame_end") && map.replaces("peanut_alg_start") && !map.replaces("walnut_alg_start"));
+
+        map.apply(&mut records).unwrap();
+        assert_eq!((records[0].get_allergy_start("Peanut"), records[0].get_allergy_end("Peanut")), (Some(1.5), None));
+        let sesame = (records[0].get_allergy_start("Sesame"), records[0].get_allergy_end("Sesame"));
+        assert_eq!(sesame, (Some(2.0), Some(4.0)));
+        assert_eq!(records[1].get_allergy_start("Sesame"), None);
+        assert_eq!(records[0].extra.keys().collect::<Vec<_>>(), ["site"]);
+        let options = GraphOptions { extra_allergens: map.extra_allergens(), ..Default::default() };
+        let graph = create_graph(records.clone(), &options);
+        let sesame = &graph[NodeIndex::new(ALLERGENS.len())];
+        assert!(matches!(sesame, NodeType::AllergenStatus(name) if name == "Sesame"));
+        assert_eq!(graph.edge_count(), 2);
+        let mut out = Vec::new();
+        crate::write_csv(&records, &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains(",cashew_alg_end,sesame_alg_start,sesame_alg_end,site\n"));
+
+        let mut bad = read_csv_from_reader(csv.replace(",2,4,north", ",soon,4,north").as_bytes()).unwrap();
+        assert_eq!(map.apply(&mut bad).unwrap_err(), "row 1 (subject 1): sesame_start 'soon' is not an age");
+        let clash = AllergenColumns { start: "pn_end".to_string(), end: "egg_end".to_string() };
+        assert!(map.insert("Egg".to_string(), clash).unwrap_err().contains("both Peanut and Egg"));
+        assert!(parse_allergen_column("Egg=egg_start").is_err());
+    }
+
+    #[test]
+    fn test_named_allergens() {
+        let mut map = AllergenMap::default();
+        let names = ["food", "milk", "Pistachio", "Lupin seed"];
+        for (name, columns) in names.iter().flat_map(|name| named_allergens(name)) {
+            map.insert(name, columns).unwrap();
+        }
+        let extra = map.extra_allergens();
+        assert_eq!(extra, ["Egg", "Soy", "Wheat", "Shellfish", "Sesame", "Milk", "Lupin seed"]);
+        assert!(map.declares("shellfish_alg_end") && map.declares("lupin_seed_alg_start"));
+        assert!(map.declares("pistach_alg_start") && !map.replaces("pistach_alg_start"));
+
+        let csv = "subject_id,birth_year,gender_factor,race_factor,ethnicity_factor,payer_factor,\
+                   atopic_march_cohort,age_start_years,age_end_years,egg_alg_start,egg_alg_end\n\
+                   1,2010,S0 - Male,R0 - White,E0 - Non-Hispanic,P0 - Non-Medicaid,false,0,8,0.5,3\n";
+        let mut records = read_csv_from_reader(csv.as_bytes()).unwrap();
+        map.apply(&mut records).unwrap();
+        let graph = create_graph(records, &GraphOptions { extra_allergens: extra, ..Default::default() });
+        assert_eq!((graph.node_count(), graph.edge_count()), (ALLERGENS.len() + 7 + 1, 1));
+    }
+}
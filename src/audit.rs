@@ -0,0 +1,256 @@
+//! Audit records: one JSON line per run with the inputs' hashes, every
+//! effective parameter, the filters applied and the outcome, so results
+//! can be traced back to exactly what produced them. The same details are
+//! embedded in exported reports and graphs as their provenance.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::{ArgMatches, Command};
+use log::warn;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::exit;
+use crate::remote;
+
+/// Commit the crate was built from, or `unknown` outside a git checkout.
+pub const GIT_COMMIT: &str = env!("ALLERGY_NET_GIT_COMMIT");
+
+/// A file or other source the run read from.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Input {
+    pub location: String,
+    /// Hex SHA-256 of the contents; absent for sources that aren't files
+    /// and files that could not be read.
+    pub sha256: Option<String>,
+    pub bytes: Option<usize>,
+    /// Why a file could not be read for its hash.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct AuditRecord {
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    pub started_at: String,
+    pub finished_at: String,
+    /// Subcommand name, absent for the default analysis.
+    pub command: Option<String>,
+    /// Every argument with a value, including defaults, as given on the
+    /// command line.
+    pub parameters: BTreeMap<String, Value>,
+    pub seed: Option<u64>,
+    pub inputs: Vec<Input>,
+    pub filters: Vec<String>,
+    pub outputs: Vec<String>,
+    pub exit_code: u8,
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    /// A record for a run starting now.
+    pub fn begin() -> Self {
+        AuditRecord {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: GIT_COMMIT,
+            started_at: timestamp(SystemTime::now()),
+            ..Default::default()
+        }
+    }
+
+    /// Records the parsed arguments of `command`, replacing the values of
+    /// the `redacted` argument ids.
+    pub fn parameters(&mut self, command: &Command, matches: &ArgMatches, redacted: &[&str]) {
+        let mut add = |command: &Command, matches: &ArgMatches| {
+            for arg in command.get_arguments() {
+                let id = arg.get_id().as_str();
+                let Ok(Some(raw)) = matches.try_get_raw(id) else { continue };
+                let mut values: Vec<Value> = raw.map(|v| v.to_string_lossy().into_owned().into()).collect();
+                let value = if redacted.contains(&id) {
+                    "<redacted>".into()
+                } else if values.len() == 1 {
+                    values.remove(0)
+                } else {
+                    values.into()
+                };
+                self.parameters.insert(id.to_string(), value);
+            }
+        };
+        add(command, matches);
+        if let Some((name, sub_matches)) = matches.subcommand() {
+            if let Some(subcommand) = command.find_subcommand(name) {
+                add(subcommand, sub_matches);
+            }
+            self.command = Some(name.to_string());
+        }
+    }
+
+    /// Records a file input with its hash, streaming the file through
+    /// SHA-256. A file that can't be read is recorded without one, with
+    /// the error; reading it for the analysis fails the run.
+    pub fn input(&mut self, path: &Path) {
+        let location = path.display().to_string();
+        let mut hasher = Sha256::new();
+        let input = match remote::copy(path, &mut hasher) {
+            Ok(bytes) => Input {
+                location,
+                sha256: Some(hex(&hasher.finalize())),
+                bytes: Some(bytes as usize),
+                error: None,
+            },
+            Err(error) => {
+                warn!("Could not hash {}: {}", location, error);
+                Input { location, sha256: None, bytes: None, error: Some(error.to_string()) }
+            }
+        };
+        self.inputs.push(input);
+    }
+
+    /// Records an input that isn't a file, such as a server URL or query.
+    pub fn source(&mut self, location: impl Into<String>) {
+        self.inputs.push(Input { location: location.into(), sha256: None, bytes: None, error: None });
+    }
+
+    pub fn output(&mut self, location: impl Into<String>) {
+        self.outputs.push(location.into());
+    }
+
+    /// What produced an output so far in the run, for embedding in it.
+    pub fn provenance(&self) -> Value {
+        json!({
+            "version": self.version,
+            "git_commit": self.git_commit,
+            "started_at": self.started_at,
+            "command": self.command,
+            "parameters": self.parameters,
+            "seed": self.seed,
+            "inputs": self.inputs,
+            "filters": self.filters,
+        })
+    }
+
+    /// Stamps the finish time and the run's exit code.
+    pub fn finish(&mut self, result: Result<(), &(dyn Error + 'static)>) {
+        self.finished_at = timestamp(SystemTime::now());
+        if let Err(error) = result {
+            self.exit_code = exit::status(error);
+            self.error = Some(error.to_string());
+        }
+    }
+
+    /// Appends the record to the JSON Lines file at `path`.
+    pub fn append(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut line = serde_json::to_vec(self)?;
+        line.push(b'\n');
+        file.write_all(&line)
+    }
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    hex(&Sha256::digest(bytes))
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().fold(String::new(), |mut hex, byte| {
+        write!(hex, "{:02x}", byte).unwrap();
+        hex
+    })
+}
+
+/// RFC 3339 UTC timestamp to the second, e.g. `2024-03-01T09:30:00Z`.
+pub fn timestamp(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) as i64;
+    let (days, second_of_day) = (seconds.div_euclid(86_400), seconds.rem_euclid(86_400));
+    // Days since 1970-01-01 to a proleptic Gregorian date
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        second_of_day / 3600,
+        second_of_day / 60 % 60,
+        second_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use clap::{Arg, ArgAction};
+
+    #[test]
+    fn test_audit_record() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_secs(1_709_285_400)), "2024-03-01T09:30:00Z");
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_secs(951_868_799)), "2000-02-29T23:59:59Z");
+
+        let command = Command::new("prog")
+            .arg(Arg::new("seed").long("seed"))
+            .arg(Arg::new("stratify_by").long("stratify-by").value_delimiter(',').default_value("race,payer"))
+            .arg(Arg::new("id_salt").long("id-salt"))
+            .arg(Arg::new("strict").long("strict").action(ArgAction::SetTrue))
+            .arg(Arg::new("exclude_ids").long("exclude-ids"))
+            .subcommand(Command::new("check").arg(Arg::new("file")));
+        let matches = command
+            .clone()
+            .try_get_matches_from(["prog", "--seed", "7", "--id-salt", "s3cret", "check", "records.csv"])
+            .unwrap();
+        let mut record = AuditRecord::begin();
+        record.parameters(&command, &matches, &["id_salt"]);
+        let parameters = serde_json::to_value(&record.parameters).unwrap();
+        assert_eq!(
+            parameters,
+            serde_json::json!({
+                "seed": "7",
+                "stratify_by": ["race", "payer"],
+                "id_salt": "<redacted>",
+                "strict": "false",
+                "file": "records.csv",
+            })
+        );
+        assert_eq!(record.command.as_deref(), Some("check"));
+
+        let fixture = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv"));
+        record.input(fixture);
+        record.input(Path::new("missing.csv"));
+        let contents = fs::read(fixture).unwrap();
+        assert_eq!(record.inputs[0].sha256, Some(sha256_hex(&contents)));
+        assert_eq!(record.inputs[0].bytes, Some(contents.len()));
+        assert_eq!(record.inputs[1].sha256, None);
+        assert!(record.inputs[1].error.is_some());
+        assert_eq!(sha256_hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+        let failure: Box<dyn Error> = Box::new(exit::Failure::EmptyCohort("cohort x".to_string()));
+        record.finish(Err(failure.as_ref()));
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["exit_code"], 4);
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        let provenance = record.provenance();
+        assert_eq!(provenance["inputs"][0]["sha256"], json["inputs"][0]["sha256"]);
+        assert_eq!((&provenance["parameters"]["seed"], &provenance["git_commit"]), (&"7".into(), &json["git_commit"]));
+        assert!(json["error"].as_str().unwrap().contains("cohort x"));
+    }
+}
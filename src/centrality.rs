@@ -0,0 +1,130 @@
+//! Shortest-path centralities of the graph's nodes. Paths ignore edge
+//! direction, since individual→allergy edges only ever point one way, and
+//! values are normalised as NetworkX's `betweenness_centrality` and
+//! `closeness_centrality` normalise them.
+
+use std::collections::VecDeque;
+
+use petgraph::graph::{DiGraph, NodeIndex};
+
+use crate::{progress, EdgeWeight, NodeType};
+
+/// Betweenness centrality of every node, indexed by node index (Brandes'
+/// algorithm): the share of shortest paths between other pairs of nodes
+/// that pass through it, in `[0, 1]`.
+pub fn betweenness(graph: &DiGraph<NodeType, EdgeWeight>) -> Vec<f64> {
+    let n = graph.node_count();
+    let mut centrality = vec![0.0; n];
+    let bar = progress::bar(n as u64, "betweenness");
+    for source in graph.node_indices() {
+        bar.inc(1);
+        let mut order = Vec::with_capacity(n);
+        let mut predecessors: Vec<Vec<NodeIndex>> = vec![Vec::new(); n];
+        let mut paths = vec![0.0; n];
+        let mut distance: Vec<Option<usize>> = vec![None; n];
+        paths[source.index()] = 1.0;
+        distance[source.index()] = Some(0);
+        let mut queue = VecDeque::from([source]);
+        while let Some(node) = queue.pop_front() {
+            order.push(node);
+            let next = distance[node.index()].map(|d| d + 1);
+            for neighbour in graph.neighbors_undirected(node) {
+                let i = neighbour.index();
+                if distance[i].is_none() {
+                    distance[i] = next;
+                    queue.push_back(neighbour);
+                }
+                if distance[i] == next {
+                    paths[i] += paths[node.index()];
+                    predecessors[i].push(node);
+                }
+            }
+        }
+        let mut dependency = vec![0.0; n];
+        while let Some(node) = order.pop() {
+            let i = node.index();
+            for predecessor in &predecessors[i] {
+                let p = predecessor.index();
+                dependency[p] += paths[p] / paths[i] * (1.0 + dependency[i]);
+            }
+            if node != source {
+                centrality[i] += dependency[i];
+            }
+        }
+    }
+    bar.finish_and_clear();
+    // Every pair was counted once from each end
+    if n > 2 {
+        let scale = 1.0 / ((n - 1) * (n - 2)) as f64;
+        centrality.iter_mut().for_each(|value| *value *= scale);
+    }
+    centrality
+}
+
+/// Closeness centrality of every node, indexed by node index: the inverse
+/// of its mean distance to the nodes it can reach, scaled by the share of
+/// the graph it can reach (Wasserman and Faust), so isolated nodes score 0.
+pub fn closeness(graph: &DiGraph<NodeType, EdgeWeight>) -> Vec<f64> {
+    let n = graph.node_count();
+    let bar = progress::bar(n as u64, "closeness");
+    let centrality = graph
+        .node_indices()
+        .map(|source| {
+            bar.inc(1);
+            let mut distance: Vec<Option<usize>> = vec![None; n];
+            distance[source.index()] = Some(0);
+            let mut queue = VecDeque::from([(source, 0)]);
+            let (mut reached, mut total) = (0, 0);
+            while let Some((node, d)) = queue.pop_front() {
+                for neighbour in graph.neighbors_undirected(node) {
+                    if distance[neighbour.index()].is_none() {
+                        distance[neighbour.index()] = Some(d + 1);
+                        reached += 1;
+                        total += d + 1;
+                        queue.push_back((neighbour, d + 1));
+                    }
+                }
+            }
+            if total == 0 {
+                return 0.0;
+            }
+            let reached = reached as f64;
+            reached / total as f64 * reached / (n - 1) as f64
+        })
+        .collect();
+    bar.finish_and_clear();
+    centrality
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::tests::get_mock_records;
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    #[test]
+    fn test_path_centralities() {
+        // Peanut - individual - Treenut, beside 7 isolated allergens
+        let mut records = get_mock_records();
+        records[0].treenut_alg_start = Some(3.0);
+        let graph = create_graph(records, &GraphOptions::default());
+        let (peanut, treenut, individual) = (0, 1, 9);
+        let between = betweenness(&graph);
+        assert!((between[individual] - 1.0 / 36.0).abs() < 1e-12);
+        assert_eq!((between[peanut], between[treenut]), (0.0, 0.0));
+        let close = closeness(&graph);
+        assert!((close[individual] - 2.0 / 9.0).abs() < 1e-12);
+        assert!((close[peanut] - 2.0 / 3.0 * 2.0 / 9.0).abs() < 1e-12);
+        assert_eq!(close[2], 0.0);
+
+        // Subject 205654 (node 13) joins the Treenut cluster to the others
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let between = betweenness(&graph);
+        let hub = (9..graph.node_count()).max_by(|&a, &b| between[a].total_cmp(&between[b]));
+        assert_eq!(hub, Some(13));
+        assert!(between.iter().all(|value| (0.0..=1.0).contains(value)));
+        // Subject 205653 has no allergies
+        assert_eq!((between[12], closeness(&graph)[12]), (0.0, 0.0));
+    }
+}
@@ -0,0 +1,115 @@
+//! Approved-input checks (`--checksums`): a manifest of expected SHA-256
+//! checksums, in `sha256sum` format, that every input file must match
+//! before the analysis runs.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::audit::{sha256_hex, Input};
+use crate::exit::Failure;
+use crate::remote;
+
+/// Expected checksums, keyed by path as listed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Checksums {
+    pub entries: BTreeMap<String, String>,
+}
+
+/// Where a listed or read path points, so `./a.csv` and `a.csv` match.
+fn resolve(location: &str) -> PathBuf {
+    fs::canonicalize(location).unwrap_or_else(|_| PathBuf::from(location))
+}
+
+impl Checksums {
+    /// Parses `sha256sum` output: `<hex>  <path>` (or `<hex> *<path>`) per
+    /// line. Blank lines and `#` comments are ignored; relative paths are
+    /// taken from the working directory, as by `sha256sum -c`.
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut entries = BTreeMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim_end();
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (hash, path) = line
+                .split_once(' ')
+                .map(|(hash, path)| (hash, path.strip_prefix([' ', '*']).unwrap_or(path)))
+                .filter(|(hash, path)| hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) && !path.is_empty())
+                .ok_or_else(|| format!("line {}: expected '<sha256>  <path>'", i + 1))?;
+            entries.insert(path.to_string(), hash.to_ascii_lowercase());
+        }
+        Ok(Checksums { entries })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let contents = remote::read(path)?;
+        Checksums::parse(&String::from_utf8_lossy(&contents))
+            .map_err(|e| format!("invalid checksum manifest {}: {}", path.display(), e).into())
+    }
+
+    /// Hashes every listed file, failing on the first that is missing or
+    /// doesn't match.
+    pub fn verify(&self) -> Result<(), Failure> {
+        for (location, expected) in &self.entries {
+            let actual = remote::read(Path::new(location))
+                .map(|contents| sha256_hex(&contents))
+                .map_err(|e| Failure::Checksum(format!("{}: {}", location, e)))?;
+            if &actual != expected {
+                return Err(Failure::Checksum(format!("{}: expected {}, got {}", location, expected, actual)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that every file the run read is listed with the checksum it
+    /// had when read. Inputs that aren't files (a FHIR server, a DuckDB
+    /// query) can't be checked and are refused.
+    pub fn approve(&self, inputs: &[Input]) -> Result<(), Failure> {
+        let listed: BTreeMap<PathBuf, &String> =
+            self.entries.iter().map(|(location, hash)| (resolve(location), hash)).collect();
+        for input in inputs {
+            let Some(actual) = &input.sha256 else {
+                return Err(Failure::Checksum(format!("{}: cannot be checksummed", input.location)));
+            };
+            match listed.get(&resolve(&input.location)) {
+                None => return Err(Failure::Checksum(format!("{}: not in the checksum manifest", input.location))),
+                Some(&expected) if expected != actual => {
+                    return Err(Failure::Checksum(format!("{}: expected {}, got {}", input.location, expected, actual)))
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditRecord;
+
+    #[test]
+    fn test_checksum_manifest() {
+        let fixture = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let hash = sha256_hex(&fs::read(fixture).unwrap());
+        let checksums = Checksums::parse(&format!("# approved extract\n{}  {}\n", hash.to_uppercase(), fixture)).unwrap();
+        assert_eq!(checksums.entries[fixture], hash);
+        assert!(checksums.verify().is_ok());
+        assert!(Checksums::parse("abc  records.csv").unwrap_err().starts_with("line 1:"));
+
+        let mut audit = AuditRecord::begin();
+        audit.input(Path::new(fixture));
+        assert!(checksums.approve(&audit.inputs).is_ok());
+        audit.input(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml")));
+        let failure = checksums.approve(&audit.inputs).unwrap_err();
+        assert_eq!(failure.code(), 7);
+        assert!(failure.to_string().ends_with("Cargo.toml: not in the checksum manifest"));
+
+        let tampered = Checksums::parse(&format!("{}  {}", "0".repeat(64), fixture)).unwrap();
+        assert!(tampered.verify().unwrap_err().to_string().contains(&format!("got {}", hash)));
+        assert!(tampered.approve(&audit.inputs[..1]).is_err());
+    }
+}
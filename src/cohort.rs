@@ -0,0 +1,307 @@
+//! Named cohort definitions (`--cohort-def`, `--cohort`): demographic and
+//! age criteria that select the records a run analyses.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::strata::{AgeBins, Dimension};
+use crate::Individual;
+
+/// A YAML file of named cohorts, e.g.
+///
+/// ```yaml
+/// cohorts:
+///   young_medicaid:
+///     label: Medicaid patients under 5
+///     include:
+///       payer: ["P1 - Medicaid"]
+///     max_age: 5
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CohortFile {
+    pub cohorts: BTreeMap<String, CohortDef>,
+}
+
+/// Inclusion criteria for one cohort. Dimension keys accept the same names
+/// as `--stratify-by`, extra columns included, so they can only be checked
+/// against the input (`check_keys`). An individual must match every
+/// `include` entry and no `exclude` entry. Ages refer to the mid-observation
+/// age.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CohortDef {
+    pub label: Option<String>,
+    #[serde(default)]
+    pub include: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub exclude: BTreeMap<String, Vec<String>>,
+    pub min_age: Option<f64>,
+    pub max_age: Option<f64>,
+}
+
+impl CohortFile {
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(path).map_err(|e| format!("cannot open cohort file {}: {}", path, e))?;
+        let parsed = serde_yaml::from_reader(file)
+            .map_err(|e| format!("invalid cohort file {}: {}", path, e))?;
+        Ok(parsed)
+    }
+
+    pub fn get(&self, name: &str) -> Result<CohortDef, String> {
+        self.cohorts.get(name).cloned().ok_or_else(|| {
+            let known: Vec<&str> = self.cohorts.keys().map(String::as_str).collect();
+            format!("unknown cohort '{}' (defined: {})", name, known.join(", "))
+        })
+    }
+}
+
+impl CohortDef {
+    /// One-line summary of the criteria, e.g. for `--explain` output.
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        for (key, values) in &self.include {
+            parts.push(format!("{} in [{}]", key, values.join(", ")));
+        }
+        for (key, values) in &self.exclude {
+            parts.push(format!("{} not in [{}]", key, values.join(", ")));
+        }
+        if let Some(min) = self.min_age {
+            parts.push(format!("age >= {}", min));
+        }
+        if let Some(max) = self.max_age {
+            parts.push(format!("age < {}", max));
+        }
+        if parts.is_empty() {
+            "all individuals".to_string()
+        } else {
+            parts.join(" and ")
+        }
+    }
+
+    /// Checks that every `include` and `exclude` key is a dimension or one
+    /// of the input's extra `columns`; otherwise a misspelt key would
+    /// silently match nobody, or exclude nobody.
+    pub fn check_keys(&self, columns: &[String]) -> Result<(), String> {
+        for (side, criteria) in [("include", &self.include), ("exclude", &self.exclude)] {
+            for key in criteria.keys() {
+                if let Dimension::Column(name) = key.parse()? {
+                    if !columns.contains(&name) {
+                        return Err(format!(
+                            "unknown {} key '{}': not gender, race, ethnicity, payer, cohort, age or an input column",
+                            side, key
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, individual: &Individual, age_bins: &AgeBins) -> bool {
+        if self.min_age.is_some_and(|min| individual.age < min)
+            || self.max_age.is_some_and(|max| individual.age >= max)
+        {
+            return false;
+        }
+        let value = |key: &str| {
+            let dimension: Dimension = key.parse().ok()?;
+            dimension.with_age_bins(age_bins).value_of(individual)
+        };
+        let included = self.include.iter().all(|(key, allowed)| {
+            value(key).is_some_and(|v| allowed.contains(&v))
+        });
+        let excluded = self.exclude.iter().any(|(key, denied)| {
+            value(key).is_some_and(|v| denied.contains(&v))
+        });
+        included && !excluded
+    }
+}
+
+/// One test of an [`IndividualFilter`]: `dimension=value`, or
+/// `dimension!=value`, where `value` may list alternatives separated by
+/// `|`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    /// The dimension as written, e.g. `gender`.
+    pub key: String,
+    pub dimension: Dimension,
+    pub values: Vec<String>,
+    pub negated: bool,
+}
+
+impl Condition {
+    /// Whether the individual's value is one of `values`, or for `!=` none
+    /// of them. An individual without the column only passes `!=`.
+    pub fn matches(&self, individual: &Individual) -> bool {
+        let Some(value) = self.dimension.value_of(individual) else { return self.negated };
+        self.values.iter().any(|wanted| value_matches(&value, wanted)) != self.negated
+    }
+}
+
+/// Whether `wanted` names `value`, ignoring case: the whole value, its code
+/// before ` - ` (`R2`), its label after it, or the label's leading words
+/// (`Asian` for `R2 - Asian or Pacific Islander`).
+fn value_matches(value: &str, wanted: &str) -> bool {
+    let (code, label) = value.split_once(" - ").unwrap_or((value, value));
+    let label = label.to_lowercase();
+    let wanted = wanted.to_lowercase();
+    value.eq_ignore_ascii_case(&wanted)
+        || code.eq_ignore_ascii_case(&wanted)
+        || label == wanted
+        || label.strip_prefix(&wanted).is_some_and(|rest| rest.starts_with(' '))
+}
+
+/// Individuals selected by conditions joined with `AND`, as given to the
+/// `filter` subcommand, e.g. `gender=Female AND race=Asian AND cohort=true`.
+/// Dimension names are those of `--stratify-by`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndividualFilter {
+    pub conditions: Vec<Condition>,
+}
+
+impl IndividualFilter {
+    /// Points `age` conditions at the configured bins.
+    pub fn with_age_bins(mut self, bins: &AgeBins) -> Self {
+        for condition in &mut self.conditions {
+            condition.dimension = condition.dimension.clone().with_age_bins(bins);
+        }
+        self
+    }
+
+    pub fn matches(&self, individual: &Individual) -> bool {
+        self.conditions.iter().all(|condition| condition.matches(individual))
+    }
+}
+
+impl FromStr for IndividualFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // ASCII lowercasing keeps the byte offsets of the original
+        let lower = s.to_ascii_lowercase();
+        let mut parts = Vec::new();
+        let mut start = 0;
+        for (at, _) in lower.match_indices(" and ") {
+            parts.push(&s[start..at]);
+            start = at + " and ".len();
+        }
+        parts.push(&s[start..]);
+        let conditions = parts
+            .into_iter()
+            .map(|part| {
+                let part = part.trim();
+                let (key, values, negated) = match part.split_once("!=") {
+                    Some((key, values)) => (key, values, true),
+                    None => {
+                        let (key, values) = part
+                            .split_once('=')
+                            .ok_or_else(|| format!("expected dimension=value, got '{}'", part))?;
+                        (key, values, false)
+                    }
+                };
+                let values: Vec<String> = values.split('|').map(|value| value.trim().to_string()).collect();
+                if values.iter().any(String::is_empty) {
+                    return Err(format!("no value in '{}'", part));
+                }
+                Ok(Condition { key: key.trim().to_string(), dimension: key.parse()?, values, negated })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(IndividualFilter { conditions })
+    }
+}
+
+impl fmt::Display for IndividualFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, condition) in self.conditions.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" AND ")?;
+            }
+            let op = if condition.negated { "!=" } else { "=" };
+            write!(f, "{}{}{}", condition.key, op, condition.values.join("|"))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn individual(payer: &str, age: f64) -> Individual {
+        Individual {
+            id: "1".to_string(),
+            gender: "S1 - Female".to_string(),
+            race: "R0 - White".to_string(),
+            ethnicity: "E0 - Non-Hispanic".to_string(),
+            payer_factor: payer.to_string(),
+            atopic_march_cohort: Some(true),
+            age,
+            attributes: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_cohort_matching() {
+        let file: CohortFile = serde_yaml::from_str(
+            "cohorts:\n  young_medicaid:\n    label: Young Medicaid\n    include:\n      payer: [\"P1 - Medicaid\"]\n    exclude:\n      race: [\"R1 - Black\"]\n    max_age: 5\n",
+        )
+        .unwrap();
+        let cohort = file.get("young_medicaid").unwrap();
+        let bins = AgeBins::default();
+        assert!(cohort.matches(&individual("P1 - Medicaid", 3.0), &bins));
+        assert!(!cohort.matches(&individual("P1 - Medicaid", 5.0), &bins));
+        assert!(!cohort.matches(&individual("P0 - Non-Medicaid", 3.0), &bins));
+        assert!(file.get("teens").is_err());
+        assert_eq!(
+            cohort.describe(),
+            "payer in [P1 - Medicaid] and race not in [R1 - Black] and age < 5"
+        );
+    }
+
+    #[test]
+    fn test_cohort_keys_are_checked() {
+        let file: CohortFile = serde_yaml::from_str(
+            concat!(
+                "cohorts:\n  north:\n    include:\n      site: [north]\n      gender_factor: [\"S1 - Female\"]\n",
+                "    exclude:\n      payr: [\"P1 - Medicaid\"]\n",
+            ),
+        )
+        .unwrap();
+        let cohort = file.get("north").unwrap();
+        let columns = vec!["site".to_string()];
+        assert_eq!(
+            cohort.check_keys(&columns).unwrap_err(),
+            "unknown exclude key 'payr': not gender, race, ethnicity, payer, cohort, age or an input column"
+        );
+        let fixed = CohortDef { exclude: BTreeMap::new(), ..cohort };
+        assert!(fixed.check_keys(&columns).is_ok());
+        assert!(fixed.check_keys(&[]).unwrap_err().contains("include key 'site'"));
+    }
+
+    #[test]
+    fn test_individual_filter() {
+        let filter: IndividualFilter = "gender=female and payer=P1|P2 AND race != R1 - Black".parse().unwrap();
+        assert_eq!(filter.to_string(), "gender=female AND payer=P1|P2 AND race!=R1 - Black");
+        let mut asian = individual("P1 - Medicaid", 3.0);
+        assert!(filter.matches(&asian));
+        asian.race = "R1 - Black".to_string();
+        assert!(!filter.matches(&asian));
+
+        let bins = AgeBins::default();
+        let leading: IndividualFilter = "race=Asian AND cohort=true".parse().unwrap();
+        let mut individual = individual("P1 - Medicaid", 3.0);
+        individual.race = "R2 - Asian or Pacific Islander".to_string();
+        assert!(leading.with_age_bins(&bins).matches(&individual));
+        assert!(!"race=Asia".parse::<IndividualFilter>().unwrap().matches(&individual));
+        assert!(!"site=north".parse::<IndividualFilter>().unwrap().matches(&individual));
+        assert!("site!=north".parse::<IndividualFilter>().unwrap().matches(&individual));
+        assert!("gender".parse::<IndividualFilter>().is_err());
+        assert!("gender=".parse::<IndividualFilter>().is_err());
+    }
+}
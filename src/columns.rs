@@ -0,0 +1,175 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use csv::ReaderBuilder;
+
+use crate::error::AllergyNetError;
+use crate::headers::HeaderAliases;
+use crate::io::decompress;
+use crate::{Show, RECORD_COLUMNS};
+
+/// Whether a CSV value is missing: empty or `NA`.
+pub fn is_missing(value: &str) -> bool {
+    let value = value.trim();
+    value.is_empty() || value.eq_ignore_ascii_case("na")
+}
+
+/// Narrowest type every non-missing value in a column parses as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Empty,
+    Boolean,
+    Integer,
+    Float,
+    Text,
+}
+
+impl ColumnType {
+    fn of_value(value: &str) -> Option<ColumnType> {
+        let value = value.trim();
+        if is_missing(value) {
+            None
+        } else if value.eq_ignore_ascii_case("true") || value.eq_ignore_ascii_case("false") {
+            Some(ColumnType::Boolean)
+        } else if value.parse::<i64>().is_ok() {
+            Some(ColumnType::Integer)
+        } else if value.parse::<f64>().is_ok() {
+            Some(ColumnType::Float)
+        } else {
+            Some(ColumnType::Text)
+        }
+    }
+
+    fn widen(self, other: ColumnType) -> ColumnType {
+        use ColumnType::*;
+        match (self, other) {
+            (Empty, t) | (t, Empty) => t,
+            (a, b) if a == b => a,
+            (Integer, Float) | (Float, Integer) => Float,
+            _ => Text,
+        }
+    }
+}
+
+impl fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ColumnType::Empty => "empty",
+            ColumnType::Boolean => "boolean",
+            ColumnType::Integer => "integer",
+            ColumnType::Float => "float",
+            ColumnType::Text => "string",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ColumnInfo {
+    pub header: String,
+    pub inferred: ColumnType,
+    pub missing: usize,
+    /// Canonical `Record` field the column feeds, if any.
+    pub maps_to: Option<&'static str>,
+}
+
+#[derive(Debug)]
+pub struct ColumnReport {
+    pub rows: usize,
+    pub columns: Vec<ColumnInfo>,
+    /// Canonical fields with no matching header.
+    pub missing_fields: Vec<&'static str>,
+}
+
+/// Scans the CSV at `path`, matching its headers through `aliases` as
+/// records are read.
+pub fn inspect(path: impl AsRef<Path>, aliases: &HeaderAliases) -> Result<ColumnReport, AllergyNetError> {
+    let path = path.as_ref();
+    let mut rdr = ReaderBuilder::new().from_reader(decompress(path, io::BufReader::new(File::open(path)?))?);
+    let headers = rdr.headers()?.clone();
+    let resolved = aliases.resolve(&headers)?;
+    let mut types = vec![ColumnType::Empty; headers.len()];
+    let mut missing = vec![0; headers.len()];
+    let mut rows = 0;
+    for row in rdr.records() {
+        let row = row?;
+        rows += 1;
+        for (i, value) in row.iter().enumerate() {
+            match ColumnType::of_value(value) {
+                Some(t) => types[i] = types[i].widen(t),
+                None => missing[i] += 1,
+            }
+        }
+    }
+    let columns = headers
+        .iter()
+        .enumerate()
+        .map(|(i, header)| ColumnInfo {
+            header: header.to_string(),
+            inferred: types[i],
+            missing: missing[i],
+            maps_to: resolved[i].map(|(field, _)| field),
+        })
+        .collect();
+    let missing_fields = RECORD_COLUMNS
+        .iter()
+        .copied()
+        .filter(|field| !resolved.iter().flatten().any(|(matched, _)| matched == field))
+        .collect();
+    Ok(ColumnReport { rows, columns, missing_fields })
+}
+
+impl ColumnReport {
+    pub fn write(&self, show: Show, out: &mut dyn Write) -> io::Result<()> {
+        let width = self.columns.iter().map(|c| c.header.len()).max().unwrap_or(0).max(6);
+        let missing: Vec<String> = self.columns.iter().map(|c| show.format(c.missing, self.rows)).collect();
+        let missing_width = missing.iter().map(String::len).max().unwrap_or(0).max(7);
+        writeln!(out, "{:width$}  {:8}  {:>missing_width$}  maps to", "column", "type", "missing")?;
+        for (column, missing) in self.columns.iter().zip(&missing) {
+            writeln!(
+                out,
+                "{:width$}  {:8}  {:>missing_width$}  {}",
+                column.header,
+                column.inferred.to_string(),
+                missing,
+                column.maps_to.unwrap_or("(unmapped)"),
+            )?;
+        }
+        writeln!(out, "{} rows scanned", self.rows)?;
+        let unmapped: Vec<&str> = self
+            .columns
+            .iter()
+            .filter(|c| c.maps_to.is_none())
+            .map(|c| c.header.as_str())
+            .collect();
+        if !unmapped.is_empty() {
+            writeln!(out, "Unmapped columns: {}", unmapped.join(", "))?;
+        }
+        if !self.missing_fields.is_empty() {
+            writeln!(out, "Missing canonical fields: {}", self.missing_fields.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inspect_fixture() {
+        let fixture = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let report = inspect(fixture, &HeaderAliases::default()).unwrap();
+        assert_eq!(report.rows, 5);
+        assert!(report.missing_fields.is_empty());
+        let find = |name: &str| report.columns.iter().find(|c| c.header == name).unwrap();
+        assert_eq!(find("subject_id").inferred, ColumnType::Integer);
+        assert_eq!(find("atopic_march_cohort").inferred, ColumnType::Boolean);
+        assert_eq!(find("peanut_alg_start").inferred, ColumnType::Float);
+        assert_eq!(find("peanut_alg_start").missing, 2);
+        assert_eq!(find("almond_alg_start").inferred, ColumnType::Empty);
+        assert_eq!(find("site").maps_to, None);
+        assert_eq!(find("site").inferred, ColumnType::Text);
+    }
+}
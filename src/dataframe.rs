@@ -0,0 +1,105 @@
+//! Polars interop: build records from a `DataFrame` and get results back
+//! as `DataFrame`s, for use inside Polars-based ETL pipelines.
+
+use std::error::Error;
+
+use csv::StringRecord;
+use petgraph::graph::DiGraph;
+use polars::prelude::*;
+
+use crate::strata::Grouping;
+use crate::{calculate_centrality, centrality_results, record_from_row, EdgeWeight, NodeType, Record, ReportOptions};
+
+/// Converts a frame with the canonical record columns into records; other
+/// columns are carried through as extra metadata, and nulls read as empty.
+pub fn records_from_frame(frame: &DataFrame) -> Result<Vec<Record>, Box<dyn Error>> {
+    let headers: StringRecord = frame.get_column_names().iter().map(|name| name.as_str()).collect();
+    let columns = frame
+        .get_columns()
+        .iter()
+        .map(|column| column.cast(&DataType::String))
+        .collect::<PolarsResult<Vec<_>>>()?;
+    let columns = columns.iter().map(|column| column.str()).collect::<PolarsResult<Vec<_>>>()?;
+    let mut records = Vec::with_capacity(frame.height());
+    for i in 0..frame.height() {
+        let row: StringRecord = columns.iter().map(|column| column.get(i).unwrap_or("")).collect();
+        records.push(record_from_row(&headers, &row)?);
+    }
+    Ok(records)
+}
+
+/// Degree of every individual: columns `node`, `id`, `degree`.
+pub fn node_metrics_frame(graph: &DiGraph<NodeType, EdgeWeight>) -> Result<DataFrame, Box<dyn Error>> {
+    let nodes = calculate_centrality(graph, &[], &ReportOptions::default())?.nodes;
+    let frame = DataFrame::new(vec![
+        Column::new("node".into(), nodes.iter().map(|n| n.node.map(|node| node as u32)).collect::<Vec<_>>()),
+        Column::new("id".into(), nodes.iter().map(|n| n.id.as_str()).collect::<Vec<_>>()),
+        Column::new("degree".into(), nodes.iter().map(|n| n.value).collect::<Vec<_>>()),
+    ])?;
+    Ok(frame)
+}
+
+/// Mean degree per group, with the same columns as the ndjson `group`
+/// objects.
+pub fn group_metrics_frame(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    groupings: &[Grouping],
+    options: &ReportOptions,
+) -> Result<DataFrame, Box<dyn Error>> {
+    let results = centrality_results(graph, groupings, options)?;
+    let rows: Vec<&serde_json::Value> = results.iter().filter(|r| r["type"] == "group").collect();
+    let text = |key: &str| Column::new(key.into(), rows.iter().map(|r| r[key].as_str().unwrap_or_default()).collect::<Vec<_>>());
+    // Suppressed groups have null mean, total and n
+    let number = |key: &str| Column::new(key.into(), rows.iter().map(|r| r[key].as_f64()).collect::<Vec<_>>());
+    let count = |key: &str| Column::new(key.into(), rows.iter().map(|r| r[key].as_u64()).collect::<Vec<_>>());
+    let flag = |key: &str| Column::new(key.into(), rows.iter().map(|r| r[key].as_bool().unwrap_or_default()).collect::<Vec<_>>());
+    let frame = DataFrame::new(vec![
+        text("grouping"),
+        text("group"),
+        number("mean"),
+        number("total"),
+        count("n"),
+        count("denominator"),
+        flag("small_cell"),
+        flag("suppressed"),
+        text("unit"),
+    ])?;
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    #[test]
+    fn test_frame_round_trip() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let expected = read_csv(path).unwrap();
+        // Rebuild the fixture as a typed frame, as an ETL step would hold it
+        let frame = DataFrame::new(vec![
+            Column::new("subject_id".into(), expected.iter().map(|r| r.subject_id.as_str()).collect::<Vec<_>>()),
+            Column::new("birth_year".into(), expected.iter().map(|r| r.birth_year).collect::<Vec<_>>()),
+            Column::new("gender_factor".into(), expected.iter().map(|r| r.gender_factor.as_str()).collect::<Vec<_>>()),
+            Column::new("race_factor".into(), expected.iter().map(|r| r.race_factor.as_str()).collect::<Vec<_>>()),
+            Column::new("ethnicity_factor".into(), expected.iter().map(|r| r.ethnicity_factor.as_str()).collect::<Vec<_>>()),
+            Column::new("payer_factor".into(), expected.iter().map(|r| r.payer_factor.as_str()).collect::<Vec<_>>()),
+            Column::new("atopic_march_cohort".into(), expected.iter().map(|r| r.atopic_march_cohort).collect::<Vec<_>>()),
+            Column::new("age_start_years".into(), expected.iter().map(|r| r.age_start_years).collect::<Vec<_>>()),
+            Column::new("age_end_years".into(), expected.iter().map(|r| r.age_end_years).collect::<Vec<_>>()),
+            Column::new("peanut_alg_start".into(), expected.iter().map(|r| r.peanut_alg_start).collect::<Vec<_>>()),
+            Column::new("site".into(), expected.iter().map(|r| r.extra["site"].as_str()).collect::<Vec<_>>()),
+        ])
+        .unwrap();
+        let records = records_from_frame(&frame).unwrap();
+        assert_eq!(records.len(), expected.len());
+        assert_eq!(records[0].peanut_alg_start, expected[0].peanut_alg_start);
+        assert_eq!(records[1].extra["site"], "south");
+
+        let graph = create_graph(expected, &GraphOptions::default());
+        assert_eq!(node_metrics_frame(&graph).unwrap().height(), 5);
+        let groups = group_metrics_frame(&graph, &["payer".parse().unwrap()], &ReportOptions::default()).unwrap();
+        assert_eq!(groups.height(), 2);
+        assert_eq!(groups.width(), 9);
+    }
+}
@@ -0,0 +1,249 @@
+//! Disclosure control for stratified tables: groups below the small-cell
+//! threshold are flagged, masked or merged before they are reported, and
+//! released statistics can carry differential privacy noise.
+
+use std::fmt;
+
+use clap::ValueEnum;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+
+/// How groups below the small-cell threshold are reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Suppression {
+    /// Report them as computed, marked as small cells.
+    Flag,
+    /// Hide their values. When only one group of a grouping would be
+    /// hidden, the next smallest is hidden too, so the masked count can't
+    /// be recovered from the denominator.
+    #[default]
+    Mask,
+    /// Pool them into one `other` group per grouping, which is masked if
+    /// it is still below the threshold.
+    Merge,
+}
+
+/// One group of a stratified table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cell {
+    pub group: String,
+    /// Sum of the members' values.
+    pub total: f64,
+    pub count: usize,
+    /// Whether the values must not be reported.
+    pub suppressed: bool,
+}
+
+impl Cell {
+    pub fn new(group: String, total: f64, count: usize) -> Self {
+        Cell { group, total, count, suppressed: false }
+    }
+}
+
+/// Applies `mode` to the groups of one grouping.
+pub fn suppress(mut cells: Vec<Cell>, threshold: usize, mode: Suppression) -> Vec<Cell> {
+    let small = |cell: &Cell| cell.count < threshold;
+    match mode {
+        Suppression::Flag => return cells,
+        Suppression::Mask => {
+            for cell in cells.iter_mut().filter(|cell| small(cell)) {
+                cell.suppressed = true;
+            }
+        }
+        Suppression::Merge => {
+            let (merged, mut kept): (Vec<Cell>, Vec<Cell>) = cells.into_iter().partition(small);
+            if !merged.is_empty() {
+                let names: Vec<&str> = merged.iter().map(|cell| cell.group.as_str()).collect();
+                let mut other = Cell::new(
+                    format!("other ({})", names.join(", ")),
+                    merged.iter().map(|cell| cell.total).sum(),
+                    merged.iter().map(|cell| cell.count).sum(),
+                );
+                other.suppressed = small(&other);
+                kept.push(other);
+            }
+            cells = kept;
+        }
+    }
+    // Complementary suppression
+    if cells.iter().filter(|cell| cell.suppressed).count() == 1 {
+        if let Some(next) = cells.iter_mut().filter(|cell| !cell.suppressed).min_by_key(|cell| cell.count) {
+            next.suppressed = true;
+        }
+    }
+    cells
+}
+
+/// Noise distribution for differential privacy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Mechanism {
+    /// Laplace noise, giving pure epsilon-DP.
+    #[default]
+    Laplace,
+    /// Gaussian noise, giving (epsilon, delta)-DP for epsilon < 1.
+    Gaussian,
+}
+
+impl fmt::Display for Mechanism {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.to_possible_value().expect("no skipped variants").get_name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoiseOptions {
+    /// Total privacy budget for the report.
+    pub epsilon: f64,
+    pub mechanism: Mechanism,
+    /// Only used by the Gaussian mechanism.
+    pub delta: f64,
+}
+
+/// A report's privacy budget, split evenly over the queries it releases.
+/// Statistics over disjoint groups (the groups of one grouping) compose in
+/// parallel and are charged as one query.
+///
+/// Noise is drawn from OS entropy, never from the report's seed, which is
+/// printed in the provenance: anyone holding the report could otherwise
+/// regenerate the noise and subtract it.
+pub struct Budget {
+    options: NoiseOptions,
+    per_query: f64,
+    charged: usize,
+    rng: StdRng,
+}
+
+impl Budget {
+    /// Errors where the Gaussian mechanism's calibration doesn't hold:
+    /// it needs a delta between 0 and 1 and an epsilon below 1 per query.
+    pub fn new(options: &NoiseOptions, queries: usize) -> Result<Self, String> {
+        let per_query = options.epsilon / queries.max(1) as f64;
+        if options.mechanism == Mechanism::Gaussian {
+            if !(options.delta > 0.0 && options.delta < 1.0) {
+                return Err(format!("Gaussian noise needs a delta between 0 and 1, got {}", options.delta));
+            }
+            if per_query >= 1.0 {
+                return Err(format!(
+                    "Gaussian noise needs an epsilon below 1 per query, got {} ({} over {} queries)",
+                    per_query, options.epsilon, queries
+                ));
+            }
+        }
+        Ok(Budget { options: options.clone(), per_query, charged: 0, rng: StdRng::from_entropy() })
+    }
+
+    /// `value` plus noise calibrated to `sensitivity` at one query's share
+    /// of the budget.
+    pub fn noise(&mut self, value: f64, sensitivity: f64) -> f64 {
+        let epsilon = self.per_query;
+        // Uniform on the open interval (-0.5, 0.5)
+        let mut uniform = || self.rng.gen_range(f64::EPSILON..1.0) - 0.5;
+        value
+            + match self.options.mechanism {
+                Mechanism::Laplace => {
+                    let u = uniform();
+                    -(sensitivity / epsilon) * u.signum() * (1.0 - 2.0 * u.abs()).ln()
+                }
+                Mechanism::Gaussian => {
+                    let sigma = sensitivity * (2.0 * (1.25 / self.options.delta).ln()).sqrt() / epsilon;
+                    let (u1, u2) = (uniform() + 0.5, uniform() + 0.5);
+                    sigma * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+                }
+            }
+    }
+
+    /// A count (sensitivity 1) with noise, rounded and clamped at zero.
+    pub fn noisy_count(&mut self, count: usize) -> usize {
+        self.noise(count as f64, 1.0).round().max(0.0) as usize
+    }
+
+    /// Records that one query's share has been released.
+    pub fn charge(&mut self) {
+        self.charged += 1;
+    }
+
+    pub fn spent(&self) -> f64 {
+        self.charged as f64 * self.per_query
+    }
+
+    pub fn per_query(&self) -> f64 {
+        self.per_query
+    }
+
+    pub fn options(&self) -> &NoiseOptions {
+        &self.options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cells(counts: &[(&str, usize)]) -> Vec<Cell> {
+        counts.iter().map(|&(group, count)| Cell::new(group.to_string(), count as f64, count)).collect()
+    }
+
+    #[test]
+    fn test_suppression_modes() {
+        let table = cells(&[("a", 40), ("b", 3), ("c", 25), ("d", 5)]);
+        assert_eq!(suppress(table.clone(), 11, Suppression::Flag), table);
+
+        let masked: Vec<bool> = suppress(table.clone(), 11, Suppression::Mask).iter().map(|c| c.suppressed).collect();
+        assert_eq!(masked, vec![false, true, false, true]);
+        // A lone small cell takes the next smallest group with it
+        let masked: Vec<bool> = suppress(cells(&[("a", 40), ("b", 3), ("c", 25)]), 11, Suppression::Mask)
+            .iter()
+            .map(|c| c.suppressed)
+            .collect();
+        assert_eq!(masked, vec![false, true, true]);
+
+        let merged = suppress(table, 11, Suppression::Merge);
+        let groups: Vec<(&str, usize, bool)> = merged.iter().map(|c| (c.group.as_str(), c.count, c.suppressed)).collect();
+        // The pooled group is still small, so it takes `c` with it
+        assert_eq!(groups, vec![("a", 40, false), ("c", 25, true), ("other (b, d)", 8, true)]);
+        let merged = suppress(cells(&[("a", 40), ("b", 6), ("c", 7)]), 11, Suppression::Merge);
+        assert_eq!((merged[1].group.as_str(), merged[1].count, merged[1].suppressed), ("other (b, c)", 13, false));
+    }
+
+    #[test]
+    fn test_noise_is_calibrated() {
+        for mechanism in [Mechanism::Laplace, Mechanism::Gaussian] {
+            let options = NoiseOptions { epsilon: 0.5, mechanism, delta: 1e-5 };
+            let mut budget = Budget::new(&options, 5).unwrap();
+            assert_eq!(budget.per_query(), 0.1);
+            let samples: Vec<f64> = (0..20_000).map(|_| budget.noise(100.0, 1.0)).collect();
+            let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+            let sd = (samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / samples.len() as f64).sqrt();
+            // Laplace(b = 10) has sd 10 * sqrt(2); the Gaussian's sigma is
+            // sqrt(2 ln(1.25e5)) / 0.1
+            let expected = match mechanism {
+                Mechanism::Laplace => 10.0 * 2f64.sqrt(),
+                Mechanism::Gaussian => (2.0 * 1.25e5f64.ln()).sqrt() / 0.1,
+            };
+            assert!((mean - 100.0).abs() < 0.05 * expected, "{:?} mean {}", mechanism, mean);
+            assert!((sd / expected - 1.0).abs() < 0.05, "{:?} sd {} vs {}", mechanism, sd, expected);
+            // Symmetric about the value: about half the draws fall each side
+            let above = samples.iter().filter(|&&x| x > 100.0).count() as f64 / samples.len() as f64;
+            assert!((above - 0.5).abs() < 0.02, "{:?} {} above", mechanism, above);
+            if mechanism == Mechanism::Laplace {
+                // Laplace(b = 10): P(|noise| > b) = 1/e
+                let beyond = samples.iter().filter(|&&x| (x - 100.0).abs() > 10.0).count() as f64;
+                assert!((beyond / samples.len() as f64 - (-1f64).exp()).abs() < 0.02);
+            }
+        }
+        let laplace = NoiseOptions { epsilon: 1.0, mechanism: Mechanism::Laplace, delta: 0.0 };
+        let mut budget = Budget::new(&laplace, 4).unwrap();
+        budget.charge();
+        budget.charge();
+        assert_eq!(budget.spent(), 0.5);
+
+        let gaussian = |epsilon: f64, delta: f64| NoiseOptions { epsilon, mechanism: Mechanism::Gaussian, delta };
+        assert!(Budget::new(&gaussian(0.5, 0.0), 1).is_err());
+        assert!(Budget::new(&gaussian(0.5, 2.0), 1).is_err());
+        let error = Budget::new(&gaussian(3.0, 1e-6), 3).err().unwrap();
+        assert_eq!(error, "Gaussian noise needs an epsilon below 1 per query, got 1 (3 over 3 queries)");
+        assert!(Budget::new(&gaussian(3.0, 1e-6), 4).is_ok());
+    }
+}
@@ -0,0 +1,98 @@
+//! DuckDB input and output (`--duckdb`, `--from-duckdb`, `--duckdb-results`).
+
+use std::error::Error;
+use std::path::Path;
+
+use csv::StringRecord;
+use duckdb::{params, Connection};
+
+use crate::headers::HeaderAliases;
+use crate::{record_from_row, Record};
+
+/// Runs `query` against the database at `path` and reads each result row
+/// as a record; columns are matched as CSV headers are.
+pub fn read_records(path: impl AsRef<Path>, query: &str) -> Result<Vec<Record>, Box<dyn Error>> {
+    let conn = Connection::open(path)?;
+    // Casting every column to text lets the CSV deserializer parse the
+    // values exactly as it would a file
+    let mut stmt = conn.prepare(&format!("SELECT COLUMNS(*)::VARCHAR FROM ({})", query))?;
+    let mut rows = stmt.query([])?;
+    let headers: StringRecord = match rows.as_ref() {
+        Some(stmt) => HeaderAliases::default().rename(&stmt.column_names().into_iter().collect())?,
+        None => return Ok(Vec::new()),
+    };
+    let mut records = Vec::new();
+    while let Some(row) = rows.next()? {
+        let values = (0..headers.len())
+            .map(|i| row.get::<_, Option<String>>(i).map(Option::unwrap_or_default))
+            .collect::<Result<Vec<String>, _>>()?;
+        records.push(record_from_row(&headers, &StringRecord::from(values))?);
+    }
+    Ok(records)
+}
+
+/// Replaces `table` in the database at `path` with the group rows of
+/// `results` (the objects produced by `centrality_results`).
+pub fn write_group_results(
+    path: impl AsRef<Path>,
+    table: &str,
+    results: &[serde_json::Value],
+) -> Result<usize, Box<dyn Error>> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(&format!(
+        "CREATE OR REPLACE TABLE \"{}\" (metric VARCHAR, grouping VARCHAR, \"group\" VARCHAR, \
+         mean DOUBLE, total DOUBLE, n BIGINT, denominator BIGINT, small_cell BOOLEAN, suppressed BOOLEAN, \
+         unit VARCHAR)",
+        table.replace('"', "\"\"")
+    ))?;
+    let mut appender = conn.appender(table)?;
+    let mut written = 0;
+    for result in results.iter().filter(|r| r["type"] == "group") {
+        appender.append_row(params![
+            result["metric"].as_str(),
+            result["grouping"].as_str(),
+            result["group"].as_str(),
+            result["mean"].as_f64(),
+            result["total"].as_f64(),
+            result["n"].as_i64(),
+            result["denominator"].as_i64(),
+            result["small_cell"].as_bool(),
+            result["suppressed"].as_bool(),
+            result["unit"].as_str(),
+        ])?;
+        written += 1;
+    }
+    appender.flush()?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{centrality_results, create_graph, GraphOptions, ReportOptions};
+
+    #[test]
+    fn test_read_query_and_write_results() {
+        let fixture = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let path = std::env::temp_dir().join(format!("allergy-net-{}.duckdb", std::process::id()));
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(&format!("CREATE OR REPLACE TABLE cohort AS SELECT * FROM read_csv('{}')", fixture))
+            .unwrap();
+        drop(conn);
+
+        let records = read_records(&path, "SELECT * FROM cohort WHERE site = 'south'").unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].extra["site"], "south");
+
+        let graph = create_graph(read_records(&path, "SELECT * FROM cohort").unwrap(), &GraphOptions::default());
+        assert_eq!(graph.edge_count(), 10);
+        let results = centrality_results(&graph, &["payer".parse().unwrap()], &ReportOptions::default()).unwrap();
+        assert_eq!(write_group_results(&path, "payer results", &results).unwrap(), 2);
+        let count: i64 = Connection::open(&path)
+            .unwrap()
+            .query_row("SELECT count(*) FROM \"payer results\"", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
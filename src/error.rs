@@ -0,0 +1,139 @@
+//! The crate's error type, for reading records, building graphs and
+//! exporting them. CSV errors carry the file, line and column, so they can
+//! be reported as is.
+
+use std::fmt::Write;
+use std::io;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AllergyNetError {
+    #[error("{}{source}", location(path, &None, &None))]
+    Io {
+        path: Option<PathBuf>,
+        #[source]
+        source: io::Error,
+    },
+    /// A row that couldn't be read or deserialized into a `Record`.
+    #[error("{}{message}", location(path, line, column))]
+    Csv {
+        path: Option<PathBuf>,
+        /// 1-based line of the file, counting the header.
+        line: Option<u64>,
+        column: Option<String>,
+        message: String,
+    },
+    /// The input lacks a column the analysis needs.
+    #[error("schema mismatch: {0}")]
+    Schema(String),
+    /// A saved graph that can't be loaded.
+    #[error("invalid graph: {0}")]
+    Graph(String),
+    #[error("export failed: {0}")]
+    Export(String),
+    #[error("{}invalid Parquet: {message}", location(path, &None, &None))]
+    Parquet { path: Option<PathBuf>, message: String },
+    #[error("{}invalid Arrow IPC file: {message}", location(path, &None, &None))]
+    Arrow { path: Option<PathBuf>, message: String },
+}
+
+/// `path: line 3, column peanut_alg_start: ` for whichever are known.
+fn location(path: &Option<PathBuf>, line: &Option<u64>, column: &Option<String>) -> String {
+    let mut location = String::new();
+    if let Some(path) = path {
+        let _ = write!(location, "{}: ", path.display());
+    }
+    match (line, column) {
+        (Some(line), Some(column)) => {
+            let _ = write!(location, "line {}, column {}: ", line, column);
+        }
+        (Some(line), None) => {
+            let _ = write!(location, "line {}: ", line);
+        }
+        (None, Some(column)) => {
+            let _ = write!(location, "column {}: ", column);
+        }
+        (None, None) => {}
+    }
+    location
+}
+
+impl AllergyNetError {
+    /// The error with the file it came from, for errors that read one.
+    pub fn in_file(mut self, file: impl Into<PathBuf>) -> Self {
+        if let AllergyNetError::Io { path, .. }
+        | AllergyNetError::Csv { path, .. }
+        | AllergyNetError::Parquet { path, .. }
+        | AllergyNetError::Arrow { path, .. } = &mut self
+        {
+            *path = Some(file.into());
+        }
+        self
+    }
+
+    /// Line of the file a CSV error is on, if known.
+    pub fn line(&self) -> Option<u64> {
+        match self {
+            AllergyNetError::Csv { line, .. } => *line,
+            _ => None,
+        }
+    }
+
+    /// Whether the input itself is at fault, rather than the environment.
+    pub fn is_invalid_input(&self) -> bool {
+        matches!(
+            self,
+            AllergyNetError::Csv { .. }
+                | AllergyNetError::Schema(_)
+                | AllergyNetError::Parquet { .. }
+                | AllergyNetError::Arrow { .. }
+        )
+    }
+}
+
+impl From<csv::Error> for AllergyNetError {
+    fn from(error: csv::Error) -> Self {
+        let line = error.position().map(csv::Position::line);
+        let message = match error.kind() {
+            // `record_from_row` names the column, knowing the headers
+            csv::ErrorKind::Deserialize { err, .. } => err.kind().to_string(),
+            csv::ErrorKind::UnequalLengths { expected_len, len, .. } => {
+                format!("found {} fields, but the header has {}", len, expected_len)
+            }
+            csv::ErrorKind::Utf8 { err, .. } => format!("invalid UTF-8 in field {}", err.field() + 1),
+            // csv hands back the wrapped io::Error
+            csv::ErrorKind::Io(_) => return AllergyNetError::Io { path: None, source: error.into() },
+            _ => error.to_string(),
+        };
+        AllergyNetError::Csv { path: None, line, column: None, message }
+    }
+}
+
+impl From<io::Error> for AllergyNetError {
+    fn from(source: io::Error) -> Self {
+        AllergyNetError::Io { path: None, source }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_csv_from_reader;
+
+    #[test]
+    fn test_csv_errors_name_line_and_column() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let csv = std::fs::read_to_string(path).unwrap();
+        let bad = csv.replacen("0.5,10.0,1.0,4.5", "0.5,10.0,soon,4.5", 1);
+        let error = read_csv_from_reader(bad.as_bytes()).unwrap_err().in_file("records.csv");
+        assert!(matches!(&error, AllergyNetError::Csv { line: Some(2), .. }));
+        assert!(error.to_string().starts_with("records.csv: line 2, column peanut_alg_start: "), "{}", error);
+        assert!(error.is_invalid_input());
+
+        let short = csv.replacen("205651,2004,", "205651,", 1);
+        let error = read_csv_from_reader(short.as_bytes()).unwrap_err();
+        assert_eq!(error.to_string(), "line 3: found 27 fields, but the header has 28");
+    }
+}
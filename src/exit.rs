@@ -0,0 +1,85 @@
+use std::error::Error;
+use std::fmt;
+use std::process::ExitCode;
+
+use crate::error::AllergyNetError;
+
+/// Failures orchestration needs to tell apart, each with a fixed process
+/// exit code (documented in the README). Anything else exits with 1 and
+/// command-line usage errors with 2.
+#[derive(Debug)]
+pub enum Failure {
+    /// Input rows failed to parse or validate.
+    Validation(String),
+    /// A filter or cohort left no individuals to analyse.
+    EmptyCohort(String),
+    /// Results were written but some groups fell below the small-cell
+    /// threshold.
+    SmallCells(usize),
+    /// Recomputed results differ from the `verify` baseline.
+    Drift(usize),
+    /// An input file is missing from, or doesn't match, the checksum
+    /// manifest.
+    Checksum(String),
+}
+
+impl Failure {
+    pub fn code(&self) -> u8 {
+        match self {
+            Failure::Validation(_) => 3,
+            Failure::EmptyCohort(_) => 4,
+            Failure::SmallCells(_) => 5,
+            Failure::Drift(_) => 6,
+            Failure::Checksum(_) => 7,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Validation(message) => write!(f, "validation failed: {}", message),
+            Failure::EmptyCohort(message) => write!(f, "no individuals left after filtering: {}", message),
+            Failure::SmallCells(count) => {
+                write!(f, "{} group(s) below the small-cell threshold", count)
+            }
+            Failure::Drift(count) => write!(f, "{} difference(s) from the baseline", count),
+            Failure::Checksum(message) => write!(f, "input not approved: {}", message),
+        }
+    }
+}
+
+impl Error for Failure {}
+
+/// Numeric exit status for an error returned from `run`. Unreadable or
+/// malformed input exits as a validation failure.
+pub fn status(error: &(dyn Error + 'static)) -> u8 {
+    if let Some(error) = error.downcast_ref::<AllergyNetError>() {
+        return if error.is_invalid_input() { 3 } else { 1 };
+    }
+    error.downcast_ref::<Failure>().map_or(1, Failure::code)
+}
+
+/// Exit code for an error returned from `run`.
+pub fn exit_code(error: &(dyn Error + 'static)) -> ExitCode {
+    ExitCode::from(status(error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes_are_distinct() {
+        let boxed: Box<dyn Error> = Box::new(Failure::EmptyCohort("cohort x".to_string()));
+        assert_eq!(exit_code(boxed.as_ref()), ExitCode::from(4));
+        let other: Box<dyn Error> = "boom".into();
+        assert_eq!(exit_code(other.as_ref()), ExitCode::FAILURE);
+        assert_eq!(Failure::Validation(String::new()).code(), 3);
+        assert_eq!(Failure::SmallCells(2).code(), 5);
+        assert_eq!(Failure::Drift(1).code(), 6);
+        assert_eq!(Failure::Checksum(String::new()).code(), 7);
+        let schema: Box<dyn Error> = Box::new(AllergyNetError::Schema("no column 'site'".to_string()));
+        assert_eq!(status(schema.as_ref()), 3);
+    }
+}
@@ -0,0 +1,243 @@
+//! FHIR R4 adapter: turns `Patient` and `AllergyIntolerance` resources into
+//! records, and (with the `fhir` feature) drives a Bulk Data `$export` to
+//! fetch them from a FHIR server.
+//!
+//! Race and ethnicity come from the US Core extensions and are mapped onto
+//! the coded values of the canonical schema. Allergies are matched to an
+//! allergen by the words in `code.text` or a coding's `display`, and their
+//! onset is taken from `onsetAge` (in years) or from `onsetDateTime`
+//! relative to the patient's birth date. FHIR has no observation window or
+//! payer on these resources, so the window spans birth to the latest
+//! allergy onset and the payer is `Unknown`.
+
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use crate::Record;
+
+const US_CORE_RACE: &str = "http://hl7.org/fhir/us/core/StructureDefinition/us-core-race";
+const US_CORE_ETHNICITY: &str = "http://hl7.org/fhir/us/core/StructureDefinition/us-core-ethnicity";
+
+/// Parses newline-delimited JSON resources, as written by `$export`.
+pub fn parse_ndjson(text: &str) -> Result<Vec<Value>, String> {
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(i, line)| serde_json::from_str(line).map_err(|e| format!("ndjson line {}: {}", i + 1, e)))
+        .collect()
+}
+
+/// One record per `Patient`, with the allergies that reference them.
+/// Resources of other types are ignored.
+pub fn records_from_resources(resources: &[Value]) -> Result<Vec<Record>, String> {
+    let mut records = BTreeMap::new();
+    let mut birth_dates = BTreeMap::new();
+    for patient in resources.iter().filter(|r| r["resourceType"] == "Patient") {
+        let id = patient["id"].as_str().ok_or("Patient resource without an id")?.to_string();
+        let birth_date = patient["birthDate"].as_str().and_then(parse_date);
+        let record = Record {
+            subject_id: id.clone(),
+            birth_year: birth_date.map_or(0, |(year, _, _)| year),
+            gender_factor: match patient["gender"].as_str() {
+                Some("male") => "S0 - Male".to_string(),
+                Some("female") => "S1 - Female".to_string(),
+                other => other.unwrap_or("unknown").to_string(),
+            },
+            race_factor: race(patient),
+            ethnicity_factor: ethnicity(patient),
+            payer_factor: "Unknown".to_string(),
+            ..Default::default()
+        };
+        birth_dates.insert(id.clone(), birth_date);
+        records.insert(id, record);
+    }
+    for allergy in resources.iter().filter(|r| r["resourceType"] == "AllergyIntolerance") {
+        let Some(id) = allergy["patient"]["reference"].as_str().and_then(|r| r.strip_prefix("Patient/")) else {
+            continue;
+        };
+        let (Some(record), Some(allergen)) = (records.get_mut(id), allergen(allergy)) else {
+            continue;
+        };
+        let onset = allergy["onsetAge"]["value"].as_f64().or_else(|| {
+            let onset = allergy["onsetDateTime"].as_str().and_then(parse_date)?;
+            Some(years_between(birth_dates[id]?, onset))
+        });
+        if let Some(onset) = onset {
+            if record.get_allergy_start(allergen).is_none_or(|earlier| onset < earlier) {
+                record.set_allergy_start(allergen, onset);
+            }
+            record.age_end_years = record.age_end_years.max(onset);
+        }
+    }
+    Ok(records.into_values().collect())
+}
+
+/// Words identifying each allergen, most specific first so that "walnut"
+/// isn't read as a generic tree nut.
+const ALLERGEN_TERMS: &[(&str, &[&str])] = &[
+    ("Peanut", &["peanut"]),
+    ("Walnut", &["walnut"]),
+    ("Pecan", &["pecan"]),
+    ("Pistachio", &["pistachio"]),
+    ("Almond", &["almond"]),
+    ("Brazil", &["brazil nut"]),
+    ("Hazelnut", &["hazelnut", "filbert"]),
+    ("Cashew", &["cashew"]),
+    ("Treenut", &["tree nut", "treenut"]),
+];
+
+/// The allergen named by the resource's code, if it is one we track.
+fn allergen(allergy: &Value) -> Option<&'static str> {
+    let code = &allergy["code"];
+    let codings = code["coding"].as_array().into_iter().flatten();
+    let texts: Vec<String> = code["text"]
+        .as_str()
+        .into_iter()
+        .chain(codings.filter_map(|c| c["display"].as_str()))
+        .map(str::to_lowercase)
+        .collect();
+    ALLERGEN_TERMS
+        .iter()
+        .find(|(_, terms)| terms.iter().any(|term| texts.iter().any(|text| text.contains(term))))
+        .map(|&(allergen, _)| allergen)
+}
+
+/// The first OMB category code of a US Core race or ethnicity extension.
+fn us_core_category<'a>(patient: &'a Value, url: &str) -> Option<&'a str> {
+    let extension = patient["extension"].as_array()?.iter().find(|e| e["url"] == url)?;
+    extension["extension"]
+        .as_array()?
+        .iter()
+        .find(|part| part["url"] == "ombCategory")?["valueCoding"]["code"]
+        .as_str()
+}
+
+fn race(patient: &Value) -> String {
+    match us_core_category(patient, US_CORE_RACE) {
+        Some("2106-3") => "R0 - White".to_string(),
+        Some("2054-5") => "R1 - Black".to_string(),
+        Some("2028-9" | "2076-8") => "R2 - Asian or Pacific Islander".to_string(),
+        Some("2131-1" | "1002-5") => "R3 - Other".to_string(),
+        _ => "R4 - Unknown".to_string(),
+    }
+}
+
+fn ethnicity(patient: &Value) -> String {
+    match us_core_category(patient, US_CORE_ETHNICITY) {
+        Some("2186-5") => "E0 - Non-Hispanic".to_string(),
+        Some("2135-2") => "E1 - Hispanic".to_string(),
+        _ => "E2 - Unknown".to_string(),
+    }
+}
+
+/// Year, month and day of a FHIR `date` or `dateTime`.
+fn parse_date(text: &str) -> Option<(i32, u32, u32)> {
+    let mut parts = text.get(..10)?.split('-');
+    let year = parts.next()?.parse().ok()?;
+    let month = parts.next()?.parse().ok()?;
+    let day = parts.next()?.parse().ok()?;
+    Some((year, month, day))
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil((year, month, day): (i32, u32, u32)) -> i64 {
+    let year = i64::from(year) - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn years_between(from: (i32, u32, u32), to: (i32, u32, u32)) -> f64 {
+    (days_from_civil(to) - days_from_civil(from)) as f64 / 365.25
+}
+
+#[cfg(feature = "fhir")]
+pub use self::bulk::bulk_export;
+
+#[cfg(feature = "fhir")]
+mod bulk {
+    use std::error::Error;
+    use std::io::Read;
+    use std::thread;
+    use std::time::Duration;
+
+    use log::info;
+    use serde_json::Value;
+
+    use super::parse_ndjson;
+
+    fn get(url: &str, accept: &str) -> ureq::Request {
+        let request = ureq::get(url).set("Accept", accept);
+        match std::env::var("FHIR_TOKEN") {
+            Ok(token) => request.set("Authorization", &format!("Bearer {}", token)),
+            Err(_) => request,
+        }
+    }
+
+    /// Runs a patient-level `$export` of `Patient` and
+    /// `AllergyIntolerance` against `base_url`, waits for it to complete and
+    /// returns every exported resource. A bearer token is sent when
+    /// `FHIR_TOKEN` is set.
+    pub fn bulk_export(base_url: &str) -> Result<Vec<Value>, Box<dyn Error>> {
+        let kickoff = format!("{}/Patient/$export?_type=Patient,AllergyIntolerance", base_url.trim_end_matches('/'));
+        let response = get(&kickoff, "application/fhir+json").set("Prefer", "respond-async").call()?;
+        let status_url = response
+            .header("Content-Location")
+            .ok_or("$export response has no Content-Location")?
+            .to_string();
+        info!("Bulk export started: {}", status_url);
+
+        let manifest: Value = loop {
+            let response = get(&status_url, "application/json").call()?;
+            if response.status() == 200 {
+                break response.into_json()?;
+            }
+            let wait = response.header("Retry-After").and_then(|s| s.parse().ok()).unwrap_or(5);
+            info!("Bulk export in progress ({}), checking again in {}s", response.header("X-Progress").unwrap_or("?"), wait);
+            thread::sleep(Duration::from_secs(wait));
+        };
+
+        let mut resources = Vec::new();
+        for output in manifest["output"].as_array().into_iter().flatten() {
+            let url = output["url"].as_str().ok_or("$export output without a url")?;
+            let mut text = String::new();
+            get(url, "application/fhir+ndjson").call()?.into_reader().read_to_string(&mut text)?;
+            let parsed = parse_ndjson(&text)?;
+            info!("Downloaded {} {} resources", parsed.len(), output["type"].as_str().unwrap_or("?"));
+            resources.extend(parsed);
+        }
+        Ok(resources)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_from_patient_and_allergies() {
+        let ndjson = r#"
+{"resourceType":"Patient","id":"p1","gender":"female","birthDate":"2010-03-01","extension":[{"url":"http://hl7.org/fhir/us/core/StructureDefinition/us-core-race","extension":[{"url":"ombCategory","valueCoding":{"code":"2054-5"}},{"url":"text","valueString":"Black"}]},{"url":"http://hl7.org/fhir/us/core/StructureDefinition/us-core-ethnicity","extension":[{"url":"ombCategory","valueCoding":{"code":"2135-2"}}]}]}
+{"resourceType":"AllergyIntolerance","patient":{"reference":"Patient/p1"},"code":{"text":"Peanut allergy"},"onsetDateTime":"2012-03-01"}
+{"resourceType":"AllergyIntolerance","patient":{"reference":"Patient/p1"},"code":{"coding":[{"display":"Allergy to walnut"}]},"onsetAge":{"value":3.5,"unit":"a"}}
+{"resourceType":"AllergyIntolerance","patient":{"reference":"Patient/p1"},"code":{"text":"Tree nut allergy"},"onsetAge":{"value":4}}
+{"resourceType":"AllergyIntolerance","patient":{"reference":"Patient/p1"},"code":{"text":"Penicillin"},"onsetAge":{"value":1}}
+{"resourceType":"Observation","id":"o1"}
+"#;
+        let records = records_from_resources(&parse_ndjson(ndjson).unwrap()).unwrap();
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!(record.birth_year, 2010);
+        assert_eq!(record.gender_factor, "S1 - Female");
+        assert_eq!((record.race_factor.as_str(), record.ethnicity_factor.as_str()), ("R1 - Black", "E1 - Hispanic"));
+        assert!((record.peanut_alg_start.unwrap() - 2.0).abs() < 0.01);
+        assert_eq!(record.walnut_alg_start, Some(3.5));
+        assert_eq!(record.treenut_alg_start, Some(4.0));
+        assert_eq!(record.age_end_years, 4.0);
+        assert!(parse_ndjson("{not json").is_err());
+    }
+}
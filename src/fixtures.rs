@@ -0,0 +1,205 @@
+//! Deterministic synthetic cohorts with known expected metrics, for testing
+//! new metrics and report changes (`fixtures` subcommand).
+
+use std::collections::BTreeMap;
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+
+use crate::quality;
+use crate::schema::AllowedValues;
+use crate::{Record, ALLERGENS};
+
+/// Extra column every generated record carries, to exercise
+/// `Dimension::Column`.
+pub const SITES: &[&str] = &["north", "south", "east"];
+
+/// The metrics a fixture must produce, worked out from the records
+/// directly rather than through the graph.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Expected {
+    pub seed: u64,
+    pub denominator: usize,
+    /// Degree of each subject: the number of allergies with an onset.
+    pub degrees: BTreeMap<String, usize>,
+    /// Grouping label (as in reports) -> group value -> sizes and sums.
+    pub groups: BTreeMap<String, BTreeMap<String, ExpectedGroup>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExpectedGroup {
+    pub n: usize,
+    pub total: usize,
+    pub mean: f64,
+}
+
+/// `subjects` records, one per subject, drawn from `seed`. Categorical
+/// values are the canonical codes, so the cohort passes `--strict`, every
+/// onset falls inside the subject's observation window, and the Treenut
+/// interval is derived from the specific tree nuts.
+pub fn cohort(subjects: usize, seed: u64) -> Vec<Record> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let codes = AllowedValues::default().columns;
+    let pick = |rng: &mut StdRng, column: &str| -> String {
+        let values: Vec<&String> = codes[column].iter().collect();
+        values.choose(rng).map(|value| value.to_string()).unwrap_or_default()
+    };
+    (0..subjects)
+        .map(|i| {
+            // Quarter-year ages keep every value exact in CSV and JSON
+            let age_start = f64::from(rng.gen_range(0..8)) / 4.0;
+            let age_end = age_start + f64::from(rng.gen_range(4..60)) / 4.0;
+            let mut record = Record {
+                subject_id: format!("F{:05}", i + 1),
+                birth_year: rng.gen_range(2000..2020),
+                gender_factor: pick(&mut rng, "gender_factor"),
+                race_factor: pick(&mut rng, "race_factor"),
+                ethnicity_factor: pick(&mut rng, "ethnicity_factor"),
+                payer_factor: pick(&mut rng, "payer_factor"),
+                atopic_march_cohort: rng.gen_bool(0.5),
+                age_start_years: age_start,
+                age_end_years: age_end,
+                ..Default::default()
+            };
+            for &allergy in ALLERGENS.iter().filter(|&&allergy| allergy != "Treenut") {
+                if rng.gen_bool(0.3) {
+                    let onset = age_start + f64::from(rng.gen_range(0..=((age_end - age_start) * 4.0) as u32)) / 4.0;
+                    record.set_allergy_start(allergy, onset);
+                    if rng.gen_bool(0.5) {
+                        record.set_allergy_end(allergy, age_end);
+                    }
+                }
+            }
+            quality::derive_treenut(std::slice::from_mut(&mut record));
+            record.extra.insert("site".to_string(), SITES.choose(&mut rng).unwrap().to_string());
+            record
+        })
+        .collect()
+}
+
+/// What the default groupings (and `site`) must report for `records`,
+/// with no onset filters, suppression or noise.
+pub fn expected(records: &[Record], seed: u64) -> Expected {
+    let degrees: BTreeMap<String, usize> = records
+        .iter()
+        .map(|record| {
+            let degree = ALLERGENS.iter().filter(|&&allergy| record.get_allergy_start(allergy).is_some()).count();
+            (record.subject_id.clone(), degree)
+        })
+        .collect();
+    let mut groups: BTreeMap<String, BTreeMap<String, ExpectedGroup>> = BTreeMap::new();
+    for record in records {
+        let values = [
+            ("gender", record.gender_factor.clone()),
+            ("race", record.race_factor.clone()),
+            ("ethnicity", record.ethnicity_factor.clone()),
+            ("payer factor", record.payer_factor.clone()),
+            ("atopic march cohort", record.atopic_march_cohort.to_string()),
+            ("site", record.extra["site"].clone()),
+        ];
+        for (label, value) in values {
+            let table = groups.entry(label.to_string()).or_default();
+            let group = table.entry(value).or_insert(ExpectedGroup { n: 0, total: 0, mean: 0.0 });
+            group.n += 1;
+            group.total += degrees[&record.subject_id];
+            group.mean = group.total as f64 / group.n as f64;
+        }
+    }
+    Expected { seed, denominator: records.len(), degrees, groups }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::privacy::{node_link_export, Deidentify, ExportIds};
+    use crate::strata::{Grouping, DEFAULT_DIMENSIONS};
+    use crate::{
+        centrality_results, create_graph, graph_from_node_link, node_link_json, read_csv_from_reader, write_csv,
+        GraphOptions, ReportOptions, Unit,
+    };
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_fixture_metrics_match_report() {
+        let records = cohort(60, 11);
+        assert_eq!(records, cohort(60, 11));
+        assert_ne!(records, cohort(60, 12));
+        let expected = expected(&records, 11);
+        assert!(quality::check_consistency(&records).violations.is_empty());
+
+        let mut groupings: Vec<Grouping> = DEFAULT_DIMENSIONS.split(',').map(|d| d.parse().unwrap()).collect();
+        groupings.push("site".parse().unwrap());
+        let graph = create_graph(records, &GraphOptions::default());
+        let results = centrality_results(&graph, &groupings, &ReportOptions::default()).unwrap();
+        for result in &results {
+            match result["type"].as_str() {
+                Some("node") => assert_eq!(result["value"].as_f64(), Some(expected.degrees[result["id"].as_str().unwrap()] as f64)),
+                Some("group") => {
+                    let group = &expected.groups[result["grouping"].as_str().unwrap()][result["group"].as_str().unwrap()];
+                    assert_eq!((result["n"].as_u64(), result["total"].as_f64()), (Some(group.n as u64), Some(group.total as f64)));
+                    assert_eq!(result["denominator"].as_u64(), Some(expected.denominator as u64));
+                }
+                _ => {}
+            }
+        }
+        let reported = results.iter().filter(|r| r["type"] == "group").count();
+        assert_eq!(reported, expected.groups.values().map(BTreeMap::len).sum::<usize>());
+    }
+
+    fn arbitrary_record() -> impl Strategy<Value = Record> {
+        let text = "[A-Za-z0-9 ,\"'-]{0,12}";
+        let age = (0u32..480).prop_map(|quarters| f64::from(quarters) / 4.0);
+        let onset = proptest::option::of(-10.0..120.0f64);
+        (
+            ("[0-9]{1,8}", 1900..2030i32, text, text, text, text, any::<bool>(), age.clone(), age),
+            proptest::collection::vec((onset.clone(), onset), ALLERGENS.len()),
+            text,
+        )
+            .prop_map(|((id, birth_year, gender, race, ethnicity, payer, cohort, start, end), allergies, site)| {
+                let mut record = Record {
+                    subject_id: id,
+                    birth_year,
+                    gender_factor: gender,
+                    race_factor: race,
+                    ethnicity_factor: ethnicity,
+                    payer_factor: payer,
+                    atopic_march_cohort: cohort,
+                    age_start_years: start,
+                    age_end_years: end,
+                    ..Default::default()
+                };
+                for (&allergy, (start, end)) in ALLERGENS.iter().zip(allergies) {
+                    if let Some(start) = start {
+                        record.set_allergy_start(allergy, start);
+                    }
+                    if let Some(end) = end {
+                        record.set_allergy_end(allergy, end);
+                    }
+                }
+                record.extra.insert("site".to_string(), site);
+                record
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn test_csv_round_trip(records in proptest::collection::vec(arbitrary_record(), 1..20)) {
+            let mut csv = Vec::new();
+            write_csv(&records, &mut csv).unwrap();
+            prop_assert_eq!(read_csv_from_reader(csv.as_slice()).unwrap(), records);
+        }
+
+        #[test]
+        fn test_graph_export_round_trip(records in proptest::collection::vec(arbitrary_record(), 1..20)) {
+            let graph = create_graph(records, &GraphOptions::default());
+            let keep = Deidentify { ids: ExportIds::Keep, ..Default::default() };
+            let exported = node_link_export(&graph, Unit::Record, &keep).unwrap();
+            let reloaded = graph_from_node_link(&exported).unwrap();
+            let (before, after) = (node_link_json(&graph), node_link_json(&reloaded));
+            prop_assert_eq!(&before["nodes"], &after["nodes"]);
+            prop_assert_eq!(&before["links"], &after["links"]);
+        }
+    }
+}
@@ -0,0 +1,1369 @@
+//! Building the individual-allergy graph, converting it to and from
+//! NetworkX node-link JSON or a compact binary file, and writing it as
+//! GraphML, GEXF or DOT.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::io::{self, Write};
+
+use clap::ValueEnum;
+use petgraph::graph::{DiGraph, NodeIndex};
+use petgraph::visit::EdgeRef;
+use petgraph::Direction;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::cohort::IndividualFilter;
+use crate::error::AllergyNetError;
+use crate::io::CHUNK_ROWS;
+use crate::progress;
+use crate::strata::Dimension;
+use crate::taxonomy::{Taxonomy, TaxonomyLevel};
+use crate::{quality, Individual, NodeType, Record, Unit, ALLERGENS};
+
+/// Options controlling which individuals and allergy edges are added to
+/// the graph.
+#[derive(Debug, Clone, Default)]
+pub struct GraphOptions {
+    /// Only keep allergies with onset strictly before this age.
+    pub onset_before: Option<f64>,
+    /// Only keep allergies with onset at or after this age.
+    pub onset_after: Option<f64>,
+    /// Build the graph as it was at this age: only individuals observed at
+    /// it, and only their allergies that had started and not yet resolved.
+    pub snapshot_age: Option<f64>,
+    pub unit: Unit,
+    pub mode: GraphMode,
+    /// Allergens beyond the nine nuts, declared in an allergen map
+    /// (`allergens::AllergenMap`), whose nodes follow the nuts'.
+    pub extra_allergens: Vec<String>,
+    pub taxonomy: Taxonomy,
+    /// Allergens below this level of `taxonomy` are merged into their
+    /// ancestor's node.
+    pub taxonomy_level: TaxonomyLevel,
+    /// Only keep allergies that hadn't resolved by the end of observation.
+    pub unresolved_only: bool,
+    /// Only these allergen nodes, if any are named; names are those of
+    /// `allergens` at `taxonomy_level`.
+    pub only_allergens: Vec<String>,
+}
+
+/// Whether demographics are only attributes of individuals, or also nodes
+/// of their own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum GraphMode {
+    /// Individuals and allergies.
+    #[default]
+    Bipartite,
+    /// Individuals, allergies, and a node per value of each of
+    /// `DEMOGRAPHICS` with an edge to each individual who has it, for
+    /// paths such as race → individual → allergy.
+    Tripartite,
+}
+
+impl fmt::Display for GraphMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.to_possible_value().expect("no skipped variants").get_name())
+    }
+}
+
+/// Dimensions that become nodes in the tripartite graph, as named in
+/// `NodeType::Demographic`.
+pub const DEMOGRAPHICS: &[(&str, Dimension)] = &[
+    ("gender", Dimension::Gender),
+    ("race", Dimension::Race),
+    ("ethnicity", Dimension::Ethnicity),
+    ("payer", Dimension::Payer),
+    ("cohort", Dimension::Cohort),
+];
+
+impl GraphOptions {
+    /// Allergens read from the records, in order: `ALLERGENS`, then
+    /// `extra_allergens`.
+    pub fn columns(&self) -> impl Iterator<Item = &str> {
+        ALLERGENS.iter().copied().chain(self.extra_allergens.iter().map(String::as_str))
+    }
+
+    /// Names of the allergen nodes: those of `columns` at
+    /// `taxonomy_level`, in order of their first allergen.
+    pub fn allergens(&self) -> Vec<&str> {
+        self.allergen_groups().into_iter().map(|(node, _)| node).collect()
+    }
+
+    /// Each allergen node with the allergens it counts.
+    pub fn allergen_groups(&self) -> Vec<(&str, Vec<&str>)> {
+        let mut groups: Vec<(&str, Vec<&str>)> = Vec::new();
+        for allergy in self.columns() {
+            let node = self.taxonomy.node(allergy, self.taxonomy_level);
+            match groups.iter_mut().find(|(name, _)| *name == node) {
+                Some((_, members)) => members.push(allergy),
+                None => groups.push((node, vec![allergy])),
+            }
+        }
+        if !self.only_allergens.is_empty() {
+            groups.retain(|(node, _)| self.only_allergens.iter().any(|name| name.eq_ignore_ascii_case(node)));
+        }
+        groups
+    }
+
+    pub fn includes_onset(&self, onset: f64) -> bool {
+        self.onset_before.is_none_or(|before| onset < before)
+            && self.onset_after.is_none_or(|after| onset >= after)
+    }
+
+    /// Whether `record`'s observation window covers the snapshot age.
+    pub fn includes_record(&self, record: &Record) -> bool {
+        self.snapshot_age.is_none_or(|age| (record.age_start_years..=record.age_end_years).contains(&age))
+    }
+
+    /// Whether an edge belongs in the graph.
+    pub fn includes_edge(&self, weight: &EdgeWeight) -> bool {
+        self.includes_onset(weight.onset)
+            && self.snapshot_age.is_none_or(|age| weight.active_at(age))
+            && (!self.unresolved_only || weight.end.is_none())
+    }
+
+    /// Description for `--explain`, or `None` when every edge is kept.
+    pub fn describe(&self) -> Option<String> {
+        let onset = match (self.onset_after, self.onset_before) {
+            (None, None) => None,
+            (Some(after), None) => Some(format!("allergy onset at or after age {}", after)),
+            (None, Some(before)) => Some(format!("allergy onset before age {}", before)),
+            (Some(after), Some(before)) => {
+                Some(format!("allergy onset between ages {} and {}", after, before))
+            }
+        };
+        let snapshot = self
+            .snapshot_age
+            .map(|age| format!("snapshot at age {}: individuals observed then, allergies active then", age));
+        let taxonomy = (self.taxonomy_level != TaxonomyLevel::Leaf)
+            .then(|| format!("allergens merged to taxonomy level {}", self.taxonomy_level));
+        let unresolved =
+            self.unresolved_only.then(|| "only allergies unresolved at the end of observation".to_string());
+        let only = match self.only_allergens.as_slice() {
+            [] => None,
+            names => Some(format!("only allergens {}", names.join(", "))),
+        };
+        let parts: Vec<String> = [onset, snapshot, taxonomy, unresolved, only].into_iter().flatten().collect();
+        (!parts.is_empty()).then(|| parts.join("; "))
+    }
+}
+
+/// Weight of an individual→allergy edge: when the allergy started and
+/// ended, and how long it lasted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct EdgeWeight {
+    pub onset: f64,
+    /// `None` when the allergy hadn't resolved by the end of observation.
+    pub end: Option<f64>,
+    /// Years from onset to end, or to the end of observation
+    /// (`age_end_years`) for an unresolved allergy; never negative.
+    pub duration: f64,
+}
+
+impl EdgeWeight {
+    /// The weight of `record`'s edge to `allergy`, or `None` if they have
+    /// no onset for it. An end at or after `age_end_years` wasn't observed,
+    /// so is dropped.
+    pub fn of(record: &Record, allergy: &str) -> Option<Self> {
+        let onset = record.get_allergy_start(allergy)?;
+        let end = record.get_allergy_end(allergy).filter(|&end| end < record.age_end_years);
+        let duration = (end.unwrap_or(record.age_end_years) - onset).max(0.0);
+        Some(EdgeWeight { onset, end, duration })
+    }
+
+    /// The weight of `record`'s edge to a node counting `allergies`, as
+    /// `quality::derive_treenut` aggregates: the earliest onset, and the
+    /// latest end unless one of them is unresolved.
+    pub fn merged(record: &Record, allergies: &[&str]) -> Option<Self> {
+        let weights: Vec<EdgeWeight> = allergies.iter().filter_map(|allergy| EdgeWeight::of(record, allergy)).collect();
+        let onset = weights.iter().map(|weight| weight.onset).reduce(f64::min)?;
+        let ends: Option<Vec<f64>> = weights.iter().map(|weight| weight.end).collect();
+        let end = ends.and_then(|ends| ends.into_iter().reduce(f64::max));
+        let duration = (end.unwrap_or(record.age_end_years) - onset).max(0.0);
+        Some(EdgeWeight { onset, end, duration })
+    }
+
+    /// Whether the allergy had started by `age` and not yet resolved.
+    pub fn active_at(&self, age: f64) -> bool {
+        self.onset <= age && self.end.is_none_or(|end| end > age)
+    }
+}
+
+/// Builds the bipartite graph: one node per allergen (in
+/// `GraphOptions::allergens` order), one per individual `options` keeps (in record order), and an
+/// edge from each individual to every allergy `options` keeps, weighted by
+/// its `EdgeWeight`. Records are turned into nodes and edges in parallel;
+/// the graph is the same as building it one record at a time.
+pub fn create_graph(records: Vec<Record>, options: &GraphOptions) -> DiGraph<NodeType, EdgeWeight> {
+    let records = match options.unit {
+        Unit::Record => records,
+        Unit::Subject => quality::merge_subjects(records),
+    };
+    let mut graph = allergen_graph(options);
+    add_records(&mut graph, records, options);
+    if options.mode == GraphMode::Tripartite {
+        add_demographics(&mut graph);
+    }
+    graph
+}
+
+/// `create_graph` for records read one at a time, e.g. from a
+/// `RecordStream`, so the input never has to fit in memory. Records are
+/// added a chunk of `CHUNK_ROWS` at a time. `Unit::Subject` still holds
+/// one merged record per subject until the input is read. Stops at the
+/// first error.
+pub fn create_graph_from_stream<E>(
+    records: impl IntoIterator<Item = Result<Record, E>>,
+    options: &GraphOptions,
+) -> Result<DiGraph<NodeType, EdgeWeight>, E> {
+    let mut graph = allergen_graph(options);
+    let mut records = records.into_iter();
+    if options.unit == Unit::Subject {
+        let mut error = None;
+        let rows = records.by_ref().map_while(|record| record.map_err(|e| error = Some(e)).ok());
+        let merged = quality::merge_subjects(rows);
+        if let Some(e) = error {
+            return Err(e);
+        }
+        add_records(&mut graph, merged, options);
+    } else {
+        loop {
+            let chunk = records.by_ref().take(CHUNK_ROWS).collect::<Result<Vec<Record>, E>>()?;
+            let last = chunk.len() < CHUNK_ROWS;
+            add_records(&mut graph, chunk, options);
+            if last {
+                break;
+            }
+        }
+    }
+    if options.mode == GraphMode::Tripartite {
+        add_demographics(&mut graph);
+    }
+    Ok(graph)
+}
+
+/// Adds the demographic nodes of the tripartite graph after the
+/// individuals: for each of `DEMOGRAPHICS` in turn, one per value in
+/// order of first appearance, with an edge to each individual who has
+/// it. The edges have `EdgeWeight::default()`.
+fn add_demographics(graph: &mut DiGraph<NodeType, EdgeWeight>) {
+    let individuals: Vec<NodeIndex> =
+        graph.node_indices().filter(|&node| matches!(graph[node], NodeType::Individual(_))).collect();
+    for (name, dimension) in DEMOGRAPHICS {
+        let mut nodes: HashMap<String, NodeIndex> = HashMap::new();
+        for &individual in &individuals {
+            let NodeType::Individual(person) = &graph[individual] else { continue };
+            let Some(value) = dimension.value_of(person) else { continue };
+            let node = match nodes.get(&value) {
+                Some(&node) => node,
+                None => {
+                    let demographic = NodeType::Demographic { dimension: name.to_string(), value: value.clone() };
+                    let node = graph.add_node(demographic);
+                    nodes.insert(value, node);
+                    node
+                }
+            };
+            graph.add_edge(node, individual, EdgeWeight::default());
+        }
+    }
+}
+
+/// A graph of just the allergen nodes, in `GraphOptions::allergens` order.
+fn allergen_graph(options: &GraphOptions) -> DiGraph<NodeType, EdgeWeight> {
+    let mut graph = DiGraph::new();
+    for allergy in options.allergens() {
+        graph.add_node(NodeType::AllergenStatus(allergy.to_string()));
+    }
+    graph
+}
+
+/// Adds the records `options` keeps after the graph's nodes, with their
+/// edges.
+fn add_records(graph: &mut DiGraph<NodeType, EdgeWeight>, records: Vec<Record>, options: &GraphOptions) {
+    let records: Vec<Record> = records.into_par_iter().filter(|record| options.includes_record(record)).collect();
+    let individuals: Vec<Individual> = records.par_iter().map(Individual::from).collect();
+    let allergens = options.allergen_groups();
+    let bar = progress::bar(records.len() as u64, "graph");
+    // Each worker collects the edges of its run of records in its own
+    // buffer, as (record, allergen, weight); the buffers are joined in
+    // record order
+    let edges = records
+        .par_iter()
+        .enumerate()
+        .fold(Vec::new, |mut edges, (i, record)| {
+            for (allergen, (_, allergies)) in allergens.iter().enumerate() {
+                if let Some(weight) = EdgeWeight::merged(record, allergies).filter(|w| options.includes_edge(w)) {
+                    edges.push((i, allergen, weight));
+                }
+            }
+            bar.inc(1);
+            edges
+        })
+        .reduce(Vec::new, |mut left, mut right| {
+            left.append(&mut right);
+            left
+        });
+    bar.finish_and_clear();
+
+    let first = graph.node_count();
+    graph.reserve_nodes(individuals.len());
+    graph.reserve_edges(edges.len());
+    for individual in individuals {
+        graph.add_node(NodeType::Individual(individual));
+    }
+    for (i, allergen, weight) in edges {
+        graph.add_edge(NodeIndex::new(first + i), NodeIndex::new(allergen), weight);
+    }
+}
+
+/// Node-link representation of the graph in the layout NetworkX's
+/// `node_link_data`/`node_link_graph` use, which D3's force layout reads
+/// too: `nodes` carry their attributes and a `label` (the subject id,
+/// allergy or demographic value), `links` reference nodes by index and carry the edge weight,
+/// with the duration again as `weight` for weighted algorithms.
+pub fn node_link_json(graph: &DiGraph<NodeType, EdgeWeight>) -> serde_json::Value {
+    let nodes: Vec<serde_json::Value> = graph
+        .node_indices()
+        .map(|node| match &graph[node] {
+            NodeType::Individual(individual) => serde_json::json!({
+                "id": node.index(),
+                "label": individual.id,
+                "kind": "individual",
+                "subject_id": individual.id,
+                "gender": individual.gender,
+                "race": individual.race,
+                "ethnicity": individual.ethnicity,
+                "payer": individual.payer_factor,
+                "atopic_march_cohort": individual.atopic_march_cohort,
+                "age": individual.age,
+                "attributes": individual.attributes,
+            }),
+            NodeType::AllergenStatus(name) => serde_json::json!({
+                "id": node.index(),
+                "label": name,
+                "kind": "allergy",
+                "allergy": name,
+            }),
+            NodeType::Demographic { dimension, value } => serde_json::json!({
+                "id": node.index(),
+                "label": value,
+                "kind": "demographic",
+                "dimension": dimension,
+                "value": value,
+            }),
+        })
+        .collect();
+    let links: Vec<serde_json::Value> = graph
+        .edge_references()
+        .map(|edge| {
+            let weight = edge.weight();
+            serde_json::json!({
+                "source": edge.source().index(),
+                "target": edge.target().index(),
+                "onset": weight.onset,
+                "end": weight.end,
+                "duration": weight.duration,
+                "weight": weight.duration,
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "directed": true,
+        "multigraph": false,
+        "graph": {},
+        "nodes": nodes,
+        "links": links,
+    })
+}
+
+/// Rebuilds a graph from node-link JSON written by `node_link_json` or by
+/// NetworkX (which may name the edge list `edges` instead of `links`).
+/// Links without a weight get `EdgeWeight::default()`, taking the
+/// duration from a bare `weight` if there is one.
+pub fn graph_from_node_link(value: &serde_json::Value) -> Result<DiGraph<NodeType, EdgeWeight>, AllergyNetError> {
+    let invalid = |message: &str| AllergyNetError::Graph(message.to_string());
+    let text = |node: &serde_json::Value, key: &str| node[key].as_str().unwrap_or_default().to_string();
+    let mut graph = DiGraph::new();
+    let mut indices = HashMap::new();
+    let nodes = value["nodes"].as_array().ok_or_else(|| invalid("node-link JSON has no 'nodes' array"))?;
+    for node in nodes {
+        let weight = match node["kind"].as_str() {
+            Some("individual") => NodeType::Individual(Individual {
+                id: text(node, "subject_id"),
+                gender: text(node, "gender"),
+                race: text(node, "race"),
+                ethnicity: text(node, "ethnicity"),
+                payer_factor: text(node, "payer"),
+                atopic_march_cohort: node["atopic_march_cohort"].as_bool(),
+                age: node["age"].as_f64().unwrap_or_default(),
+                attributes: serde_json::from_value(node["attributes"].clone()).unwrap_or_default(),
+            }),
+            Some("allergy") => NodeType::AllergenStatus(text(node, "allergy")),
+            Some("demographic") => {
+                NodeType::Demographic { dimension: text(node, "dimension"), value: text(node, "value") }
+            }
+            _ => {
+                return Err(AllergyNetError::Graph(format!(
+                    "node {} has no kind 'individual', 'allergy' or 'demographic'",
+                    node["id"]
+                )))
+            }
+        };
+        indices.insert(node["id"].to_string(), graph.add_node(weight));
+    }
+    let links = value["links"]
+        .as_array()
+        .or_else(|| value["edges"].as_array())
+        .ok_or_else(|| invalid("node-link JSON has no 'links' array"))?;
+    for link in links {
+        let endpoint = |key: &str| {
+            indices
+                .get(&link[key].to_string())
+                .copied()
+                .ok_or_else(|| AllergyNetError::Graph(format!("link refers to unknown node {}", link[key])))
+        };
+        let weight = EdgeWeight {
+            onset: link["onset"].as_f64().unwrap_or_default(),
+            end: link["end"].as_f64(),
+            duration: link["duration"].as_f64().or_else(|| link["weight"].as_f64()).unwrap_or_default(),
+        };
+        graph.add_edge(endpoint("source")?, endpoint("target")?, weight);
+    }
+    Ok(graph)
+}
+
+/// Node attributes in GraphML: key, GraphML type, and the value for an
+/// individual. Allergy nodes only carry `kind` and `allergy`.
+type GraphmlAttribute = (&'static str, &'static str, fn(&Individual) -> Option<String>);
+
+const GRAPHML_ATTRIBUTES: &[GraphmlAttribute] = &[
+    ("subject_id", "string", |individual| Some(individual.id.clone())),
+    ("gender", "string", |individual| Some(individual.gender.clone())),
+    ("race", "string", |individual| Some(individual.race.clone())),
+    ("ethnicity", "string", |individual| Some(individual.ethnicity.clone())),
+    ("payer", "string", |individual| Some(individual.payer_factor.clone())),
+    ("atopic_march_cohort", "boolean", |individual| individual.atopic_march_cohort.map(|cohort| cohort.to_string())),
+    ("age", "double", |individual| Some(individual.age).filter(|age| !age.is_nan()).map(|age| age.to_string())),
+];
+
+/// Node keys of GraphML and GEXF besides the individuals' attributes.
+const NODE_KEYS: &[(&str, &str)] =
+    &[("kind", "string"), ("allergy", "string"), ("dimension", "string"), ("value", "string")];
+
+/// Whether `node` is an allergy, so edges to it carry an `EdgeWeight`.
+fn is_allergy(graph: &DiGraph<NodeType, EdgeWeight>, node: NodeIndex) -> bool {
+    matches!(graph[node], NodeType::AllergenStatus(_))
+}
+
+pub(crate) fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Writes the graph as GraphML, which Gephi and Cytoscape open directly.
+/// Nodes are `n0`, `n1`, ... in graph order, with a `kind` of
+/// `individual`, `allergy` or `demographic`, the demographics of
+/// individuals, the `allergy` name of allergy nodes and the `dimension`
+/// and `value` of demographic nodes; extra columns become `attr.<column>`
+/// attributes. Allergy edges carry their `onset`, `end` (if resolved) and
+/// `duration`. Each
+/// entry of the `metadata` object is written as graph-level data, JSON
+/// encoded unless it is a string.
+pub fn export_graphml(graph: &DiGraph<NodeType, EdgeWeight>, metadata: &serde_json::Value, out: &mut dyn Write) -> io::Result<()> {
+    let columns = attribute_columns(graph);
+    let metadata = metadata.as_object().cloned().unwrap_or_default();
+
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(out, r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#)?;
+    for key in metadata.keys() {
+        let key = xml_escape(key);
+        writeln!(out, r#"  <key id="{0}" for="graph" attr.name="{0}" attr.type="string"/>"#, key)?;
+    }
+    let node_keys = NODE_KEYS
+        .iter()
+        .copied()
+        .chain(GRAPHML_ATTRIBUTES.iter().map(|&(key, kind, _)| (key, kind)));
+    for (key, kind) in node_keys {
+        writeln!(out, r#"  <key id="{0}" for="node" attr.name="{0}" attr.type="{1}"/>"#, key, kind)?;
+    }
+    for column in &columns {
+        writeln!(out, r#"  <key id="attr.{0}" for="node" attr.name="{0}" attr.type="string"/>"#, xml_escape(column))?;
+    }
+    for key in ["onset", "end", "duration"] {
+        writeln!(out, r#"  <key id="{0}" for="edge" attr.name="{0}" attr.type="double"/>"#, key)?;
+    }
+    writeln!(out, r#"  <graph id="allergies" edgedefault="directed">"#)?;
+    for (key, value) in &metadata {
+        let value = value.as_str().map_or_else(|| value.to_string(), str::to_string);
+        writeln!(out, r#"    <data key="{}">{}</data>"#, xml_escape(key), xml_escape(&value))?;
+    }
+    for node in graph.node_indices() {
+        writeln!(out, r#"    <node id="n{}">"#, node.index())?;
+        let mut data = |key: &str, value: &str| writeln!(out, r#"      <data key="{}">{}</data>"#, key, xml_escape(value));
+        match &graph[node] {
+            NodeType::Individual(individual) => {
+                data("kind", "individual")?;
+                for (key, _, value) in GRAPHML_ATTRIBUTES {
+                    if let Some(value) = value(individual) {
+                        data(key, &value)?;
+                    }
+                }
+                for (column, value) in &individual.attributes {
+                    data(&format!("attr.{}", xml_escape(column)), value)?;
+                }
+            }
+            NodeType::AllergenStatus(name) => {
+                data("kind", "allergy")?;
+                data("allergy", name)?;
+            }
+            NodeType::Demographic { dimension, value } => {
+                data("kind", "demographic")?;
+                data("dimension", dimension)?;
+                data("value", value)?;
+            }
+        }
+        writeln!(out, "    </node>")?;
+    }
+    for edge in graph.edge_references() {
+        let weight = edge.weight();
+        if !is_allergy(graph, edge.target()) {
+            writeln!(out, r#"    <edge source="n{}" target="n{}"/>"#, edge.source().index(), edge.target().index())?;
+            continue;
+        }
+        writeln!(out, r#"    <edge source="n{}" target="n{}">"#, edge.source().index(), edge.target().index())?;
+        writeln!(out, r#"      <data key="onset">{}</data>"#, weight.onset)?;
+        if let Some(end) = weight.end {
+            writeln!(out, r#"      <data key="end">{}</data>"#, end)?;
+        }
+        writeln!(out, r#"      <data key="duration">{}</data>"#, weight.duration)?;
+        writeln!(out, "    </edge>")?;
+    }
+    writeln!(out, "  </graph>")?;
+    writeln!(out, "</graphml>")
+}
+
+/// Extra columns any individual has.
+fn attribute_columns(graph: &DiGraph<NodeType, EdgeWeight>) -> BTreeSet<&str> {
+    graph
+        .node_weights()
+        .filter_map(|node| match node {
+            NodeType::Individual(individual) => Some(individual.attributes.keys().map(String::as_str)),
+            NodeType::AllergenStatus(_) | NodeType::Demographic { .. } => None,
+        })
+        .flatten()
+        .collect()
+}
+
+/// Writes the graph as dynamic GEXF 1.3 for Gephi, with the nodes,
+/// attributes and edge data of `export_graphml`. Each allergy edge's spell
+/// starts at the allergy's onset and, if it resolved, ends at its end, so
+/// Gephi's timeline, which runs over age in years, animates allergies
+/// being acquired and outgrown. GEXF has no graph data, so `metadata` is
+/// written as JSON in the description.
+pub fn export_gexf(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    metadata: &serde_json::Value,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let columns = attribute_columns(graph);
+
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(out, r#"<gexf xmlns="http://gexf.net/1.3" version="1.3">"#)?;
+    writeln!(out, "  <meta>")?;
+    writeln!(out, "    <creator>{}</creator>", env!("CARGO_PKG_NAME"))?;
+    writeln!(out, "    <description>{}</description>", xml_escape(&metadata.to_string()))?;
+    writeln!(out, "  </meta>")?;
+    writeln!(out, r#"  <graph mode="dynamic" defaultedgetype="directed" timeformat="double">"#)?;
+    writeln!(out, r#"    <attributes class="node">"#)?;
+    let node_keys = NODE_KEYS
+        .iter()
+        .copied()
+        .chain(GRAPHML_ATTRIBUTES.iter().map(|&(key, kind, _)| (key, kind)));
+    for (key, kind) in node_keys {
+        writeln!(out, r#"      <attribute id="{0}" title="{0}" type="{1}"/>"#, key, kind)?;
+    }
+    for column in &columns {
+        writeln!(out, r#"      <attribute id="attr.{0}" title="{0}" type="string"/>"#, xml_escape(column))?;
+    }
+    writeln!(out, "    </attributes>")?;
+    writeln!(out, r#"    <attributes class="edge">"#)?;
+    for key in ["onset", "end", "duration"] {
+        writeln!(out, r#"      <attribute id="{0}" title="{0}" type="double"/>"#, key)?;
+    }
+    writeln!(out, "    </attributes>")?;
+
+    writeln!(out, "    <nodes>")?;
+    for node in graph.node_indices() {
+        let label = match &graph[node] {
+            NodeType::Individual(individual) => &individual.id,
+            NodeType::AllergenStatus(name) => name,
+            NodeType::Demographic { value, .. } => value,
+        };
+        writeln!(out, r#"      <node id="n{}" label="{}">"#, node.index(), xml_escape(label))?;
+        writeln!(out, "        <attvalues>")?;
+        let mut value = |key: &str, value: &str| {
+            writeln!(out, r#"          <attvalue for="{}" value="{}"/>"#, key, xml_escape(value))
+        };
+        match &graph[node] {
+            NodeType::Individual(individual) => {
+                value("kind", "individual")?;
+                for (key, _, attribute) in GRAPHML_ATTRIBUTES {
+                    if let Some(attribute) = attribute(individual) {
+                        value(key, &attribute)?;
+                    }
+                }
+                for (column, attribute) in &individual.attributes {
+                    value(&format!("attr.{}", xml_escape(column)), attribute)?;
+                }
+            }
+            NodeType::AllergenStatus(name) => {
+                value("kind", "allergy")?;
+                value("allergy", name)?;
+            }
+            NodeType::Demographic { dimension, value: name } => {
+                value("kind", "demographic")?;
+                value("dimension", dimension)?;
+                value("value", name)?;
+            }
+        }
+        writeln!(out, "        </attvalues>")?;
+        writeln!(out, "      </node>")?;
+    }
+    writeln!(out, "    </nodes>")?;
+
+    writeln!(out, "    <edges>")?;
+    for edge in graph.edge_references() {
+        let (id, source, target) = (edge.id().index(), edge.source().index(), edge.target().index());
+        // Demographic edges hold throughout
+        if !is_allergy(graph, edge.target()) {
+            writeln!(out, r#"      <edge id="e{}" source="n{}" target="n{}"/>"#, id, source, target)?;
+            continue;
+        }
+        let weight = edge.weight();
+        let end = weight.end.map_or_else(String::new, |end| format!(r#" end="{}""#, end));
+        writeln!(
+            out,
+            r#"      <edge id="e{}" source="n{}" target="n{}" start="{}"{}>"#,
+            id,
+            source,
+            target,
+            weight.onset,
+            end
+        )?;
+        writeln!(out, "        <attvalues>")?;
+        writeln!(out, r#"          <attvalue for="onset" value="{}"/>"#, weight.onset)?;
+        if let Some(end) = weight.end {
+            writeln!(out, r#"          <attvalue for="end" value="{}"/>"#, end)?;
+        }
+        writeln!(out, r#"          <attvalue for="duration" value="{}"/>"#, weight.duration)?;
+        writeln!(out, "        </attvalues>")?;
+        writeln!(out, "      </edge>")?;
+    }
+    writeln!(out, "    </edges>")?;
+    writeln!(out, "  </graph>")?;
+    writeln!(out, "</gexf>")
+}
+
+/// Fill colours of individuals in DOT and SVG, one per value of the
+/// `color_by` dimension in sorted order, repeating if there are more values.
+pub(crate) const DOT_PALETTE: &[&str] =
+    &["#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd", "#8c564b", "#e377c2", "#7f7f7f", "#bcbd22", "#17becf"];
+
+/// `text` as a quoted DOT string.
+fn dot_quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Writes the graph as Graphviz DOT, for a quick look at a small cohort.
+/// Individuals are circles labelled with their subject id, filled by
+/// their value of `color_by` with a legend of the values, and allergies
+/// are grey boxes. Resolved allergies are dashed edges. Demographic nodes
+/// are white diamonds, with dotted edges. `metadata` is
+/// written as JSON in the graph's `comment`.
+pub fn export_dot(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    color_by: Option<&Dimension>,
+    metadata: &serde_json::Value,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let value_of = |individual: &Individual| {
+        color_by.map(|dimension| dimension.value_of(individual).unwrap_or_else(|| "(missing)".to_string()))
+    };
+    let values: BTreeSet<String> = graph
+        .node_weights()
+        .filter_map(|node| match node {
+            NodeType::Individual(individual) => value_of(individual),
+            NodeType::AllergenStatus(_) | NodeType::Demographic { .. } => None,
+        })
+        .collect();
+    let color = |value: &str| DOT_PALETTE[values.iter().position(|v| v == value).unwrap_or(0) % DOT_PALETTE.len()];
+
+    writeln!(out, "digraph allergies {{")?;
+    writeln!(out, "  comment={};", dot_quote(&metadata.to_string()))?;
+    writeln!(out, "  rankdir=LR;")?;
+    writeln!(out, r#"  node [style=filled, fontname="Helvetica"];"#)?;
+    for node in graph.node_indices() {
+        match &graph[node] {
+            NodeType::Individual(individual) => {
+                let fill = value_of(individual).map_or(DOT_PALETTE[0], |value| color(&value));
+                let label = dot_quote(&individual.id);
+                writeln!(out, r#"  n{} [label={}, shape=circle, fillcolor="{}"];"#, node.index(), label, fill)?;
+            }
+            NodeType::AllergenStatus(name) => {
+                let label = dot_quote(name);
+                writeln!(out, "  n{} [label={}, shape=box, fillcolor=lightgrey];", node.index(), label)?;
+            }
+            NodeType::Demographic { value, .. } => {
+                let label = dot_quote(value);
+                writeln!(out, "  n{} [label={}, shape=diamond, fillcolor=white];", node.index(), label)?;
+            }
+        }
+    }
+    for edge in graph.edge_references() {
+        let style = match edge.weight().end {
+            _ if !is_allergy(graph, edge.target()) => " [style=dotted]",
+            Some(_) => " [style=dashed]",
+            None => "",
+        };
+        writeln!(out, "  n{} -> n{}{};", edge.source().index(), edge.target().index(), style)?;
+    }
+    if let Some(dimension) = color_by {
+        writeln!(out, "  subgraph cluster_legend {{")?;
+        writeln!(out, "    label={};", dot_quote(dimension.label()))?;
+        for (i, value) in values.iter().enumerate() {
+            let label = dot_quote(value);
+            writeln!(out, r#"    legend{} [label={}, shape=circle, fillcolor="{}"];"#, i, label, color(value))?;
+        }
+        writeln!(out, "  }}")?;
+    }
+    writeln!(out, "}}")
+}
+
+/// `text` as a single-quoted Cypher string.
+fn cypher_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// The Cypher map of an individual's properties: its graph `node` index,
+/// which keys it, and the fields `--push-neo4j` sets, with extra columns
+/// as `attr.<column>`. A missing age or cohort flag is left out.
+fn cypher_properties(node: NodeIndex, individual: &Individual) -> String {
+    let mut properties = vec![
+        format!("node: {}", node.index()),
+        format!("id: {}", cypher_quote(&individual.id)),
+        format!("gender: {}", cypher_quote(&individual.gender)),
+        format!("race: {}", cypher_quote(&individual.race)),
+        format!("ethnicity: {}", cypher_quote(&individual.ethnicity)),
+        format!("payer_factor: {}", cypher_quote(&individual.payer_factor)),
+    ];
+    if let Some(cohort) = individual.atopic_march_cohort {
+        properties.push(format!("atopic_march_cohort: {}", cohort));
+    }
+    if !individual.age.is_nan() {
+        properties.push(format!("age: {:?}", individual.age));
+    }
+    for (column, value) in &individual.attributes {
+        properties.push(format!("`attr.{}`: {}", column.replace('`', "``"), cypher_quote(value)));
+    }
+    format!("{{{}}}", properties.join(", "))
+}
+
+/// Writes the graph as a Cypher script that `cypher-shell` (or the Neo4j
+/// Browser) runs against an empty database: uniqueness constraints, a
+/// `CREATE` per node and a `MATCH ... CREATE` per edge, one statement per
+/// line. Individuals are `(:Individual)` with their demographics as
+/// properties, keyed by their node index since under `--unit record` a
+/// subject's rows share an id; allergies `(:Allergen {name})`, and allergy edges
+/// `[:HAS_ALLERGY]` with their `onset`, `end` (if resolved) and
+/// `duration`, as `--push-neo4j` writes them. Demographic nodes are
+/// `(:Demographic {dimension, value})`, joined from their individuals by
+/// `[:IN_GROUP]`. `metadata` is written as JSON in a leading comment.
+pub fn export_cypher(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    metadata: &serde_json::Value,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let pattern = |node: NodeIndex| match &graph[node] {
+        NodeType::Individual(_) => format!("(:Individual {{node: {}}})", node.index()),
+        NodeType::AllergenStatus(name) => format!("(:Allergen {{name: {}}})", cypher_quote(name)),
+        NodeType::Demographic { dimension, value } => format!(
+            "(:Demographic {{dimension: {}, value: {}}})",
+            cypher_quote(dimension),
+            cypher_quote(value)
+        ),
+    };
+
+    writeln!(out, "// {}", metadata)?;
+    writeln!(out, "CREATE CONSTRAINT IF NOT EXISTS FOR (i:Individual) REQUIRE i.node IS UNIQUE;")?;
+    writeln!(out, "CREATE CONSTRAINT IF NOT EXISTS FOR (a:Allergen) REQUIRE a.name IS UNIQUE;")?;
+    writeln!(out, "CREATE INDEX IF NOT EXISTS FOR (d:Demographic) ON (d.dimension, d.value);")?;
+    for node in graph.node_indices() {
+        match &graph[node] {
+            NodeType::Individual(individual) => {
+                writeln!(out, "CREATE (:Individual {});", cypher_properties(node, individual))?;
+            }
+            NodeType::AllergenStatus(_) | NodeType::Demographic { .. } => writeln!(out, "CREATE {};", pattern(node))?,
+        }
+    }
+    for edge in graph.edge_references() {
+        // Strip the parentheses to bind each pattern to a variable
+        let (source, target) = (pattern(edge.source()), pattern(edge.target()));
+        let (source, target) = (&source[1..source.len() - 1], &target[1..target.len() - 1]);
+        if !is_allergy(graph, edge.target()) {
+            // Demographic edges run from the group to the individual
+            writeln!(out, "MATCH (d{}), (i{}) CREATE (i)-[:IN_GROUP]->(d);", source, target)?;
+            continue;
+        }
+        let weight = edge.weight();
+        let end = weight.end.map_or_else(String::new, |end| format!(", end: {:?}", end));
+        writeln!(
+            out,
+            "MATCH (i{}), (a{}) CREATE (i)-[:HAS_ALLERGY {{onset: {:?}{}, duration: {:?}}}]->(a);",
+            source,
+            target,
+            weight.onset,
+            end,
+            weight.duration
+        )?;
+    }
+    Ok(())
+}
+
+/// First bytes of a binary graph file, ending in the format version.
+const BINARY_MAGIC: &[u8] = b"ALLERGYNET\x02";
+
+/// A graph as stored by `export_binary`: `metadata` is JSON text, and
+/// each edge is its source and target node index and weight.
+#[derive(Serialize, Deserialize)]
+struct BinaryGraph {
+    metadata: String,
+    nodes: Vec<NodeType>,
+    edges: Vec<(u32, u32, EdgeWeight)>,
+}
+
+/// Writes the graph in the compact binary format `graph_from_binary`
+/// reads back, with `metadata` alongside. Loading it skips parsing the CSV
+/// and rebuilding the graph, which dominates on large cohorts.
+pub fn export_binary(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    metadata: &serde_json::Value,
+    mut out: impl Write,
+) -> Result<(), AllergyNetError> {
+    let file = BinaryGraph {
+        metadata: metadata.to_string(),
+        nodes: graph.node_weights().cloned().collect(),
+        edges: graph
+            .edge_references()
+            .map(|edge| (edge.source().index() as u32, edge.target().index() as u32, *edge.weight()))
+            .collect(),
+    };
+    out.write_all(BINARY_MAGIC)?;
+    postcard::to_io(&file, out).map_err(|e| AllergyNetError::Export(e.to_string()))?;
+    Ok(())
+}
+
+/// Whether `bytes` start like a file written by `export_binary`.
+pub fn is_binary_graph(bytes: &[u8]) -> bool {
+    bytes.starts_with(&BINARY_MAGIC[..BINARY_MAGIC.len() - 1])
+}
+
+/// Rebuilds a graph written by `export_binary`, with its metadata.
+pub fn graph_from_binary(bytes: &[u8]) -> Result<(DiGraph<NodeType, EdgeWeight>, serde_json::Value), AllergyNetError> {
+    let version = BINARY_MAGIC.len() - 1;
+    if !is_binary_graph(bytes) {
+        return Err(AllergyNetError::Graph("not a binary graph file".to_string()));
+    }
+    if bytes.get(version) != BINARY_MAGIC.last() {
+        return Err(AllergyNetError::Graph(format!("unsupported binary graph version {:?}", bytes.get(version))));
+    }
+    let file: BinaryGraph = postcard::from_bytes(&bytes[BINARY_MAGIC.len()..])
+        .map_err(|e| AllergyNetError::Graph(format!("corrupt binary graph: {}", e)))?;
+    let metadata = serde_json::from_str(&file.metadata)
+        .map_err(|e| AllergyNetError::Graph(format!("corrupt binary graph metadata: {}", e)))?;
+    let mut graph = DiGraph::with_capacity(file.nodes.len(), file.edges.len());
+    for node in file.nodes {
+        graph.add_node(node);
+    }
+    for (source, target, weight) in file.edges {
+        let (source, target) = (NodeIndex::new(source as usize), NodeIndex::new(target as usize));
+        if source.index() >= graph.node_count() || target.index() >= graph.node_count() {
+            return Err(AllergyNetError::Graph(format!("edge refers to unknown node {}", source.max(target).index())));
+        }
+        graph.add_edge(source, target, weight);
+    }
+    Ok((graph, metadata))
+}
+
+/// Size of a built graph (`build` subcommand).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GraphSummary {
+    pub nodes: usize,
+    pub edges: usize,
+    /// Individual → allergy edges; `edges` also counts the demographic
+    /// ones of a tripartite graph.
+    pub allergy_edges: usize,
+    pub individuals: usize,
+    /// Individuals linked to each allergen.
+    pub allergens: BTreeMap<String, usize>,
+}
+
+impl GraphSummary {
+    pub fn of(graph: &DiGraph<NodeType, EdgeWeight>) -> Self {
+        let mut individuals = 0;
+        let mut allergens = BTreeMap::new();
+        for node in graph.node_indices() {
+            match &graph[node] {
+                NodeType::Individual(_) => individuals += 1,
+                NodeType::AllergenStatus(name) => {
+                    allergens.insert(name.clone(), graph.neighbors_directed(node, Direction::Incoming).count());
+                }
+                NodeType::Demographic { .. } => {}
+            }
+        }
+        let allergy_edges = graph
+            .edge_references()
+            .filter(|edge| {
+                matches!(graph[edge.source()], NodeType::Individual(_))
+                    && matches!(graph[edge.target()], NodeType::AllergenStatus(_))
+            })
+            .count();
+        GraphSummary { nodes: graph.node_count(), edges: graph.edge_count(), allergy_edges, individuals, allergens }
+    }
+
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        write!(out, "{}", self)
+    }
+}
+
+impl fmt::Display for GraphSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} nodes ({} individuals), {} edges", self.nodes, self.individuals, self.edges)?;
+        if self.allergy_edges != self.edges {
+            write!(f, " ({} allergy)", self.allergy_edges)?;
+        }
+        writeln!(f)?;
+        for (allergen, individuals) in &self.allergens {
+            writeln!(f, "  {}: {} individual(s)", allergen, individuals)?;
+        }
+        Ok(())
+    }
+}
+
+/// Copy of `graph` keeping every allergy node but only the individuals
+/// accepted by `keep`.
+pub fn filter_individuals(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    keep: impl Fn(&Individual) -> bool,
+) -> DiGraph<NodeType, EdgeWeight> {
+    graph.filter_map(
+        |_, node| match node {
+            NodeType::Individual(individual) if !keep(individual) => None,
+            other => Some(other.clone()),
+        },
+        |_, &edge| Some(edge),
+    )
+}
+
+/// The subgraph of the individuals `filter` selects, with their edges and
+/// every allergy and demographic node, for a deep dive into one cohort.
+pub fn subgraph(graph: &DiGraph<NodeType, EdgeWeight>, filter: &IndividualFilter) -> DiGraph<NodeType, EdgeWeight> {
+    filter_individuals(graph, |individual| filter.matches(individual))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::read_csv;
+    use crate::stream::IncrementalGraph;
+    use std::collections::BTreeMap;
+
+    // Mock data to simulate the CSV reading and graph creation
+    pub(crate) fn get_mock_records() -> Vec<Record> {
+        vec![
+            Record {
+                subject_id: "205650".to_string(),
+                birth_year: 2000,
+                gender_factor: "Male".to_string(),
+                race_factor: "Race1".to_string(),
+                ethnicity_factor: "Ethnicity1".to_string(),
+                payer_factor: "Payer1".to_string(),
+                atopic_march_cohort: true,
+                age_start_years: 5.0,
+                age_end_years: 10.0,
+                peanut_alg_start: Some(1.0),
+                peanut_alg_end: Some(2.0),
+                treenut_alg_start: None,
+                treenut_alg_end: None,
+                walnut_alg_start: None,
+                walnut_alg_end: None,
+                pecan_alg_start: None,
+                pecan_alg_end: None,
+                pistach_alg_start: None,
+                pistach_alg_end: None,
+                almond_alg_start: None,
+                almond_alg_end: None,
+                brazil_alg_start: None,
+                brazil_alg_end: None,
+                hazelnut_alg_start: None,
+                hazelnut_alg_end: None,
+                cashew_alg_start: None,
+                cashew_alg_end: None,
+                extra: BTreeMap::new(),
+                other_allergies: BTreeMap::new(),
+            },
+           
+        ]
+    }
+
+    #[test]
+    fn test_graph_creation() {
+        let records = get_mock_records();
+        let graph = create_graph(records, &GraphOptions::default());
+        assert!(graph.node_count() > 0); // Check that nodes are created
+    }
+
+    #[test]
+    fn test_onset_filters_limit_edges() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let early = GraphOptions { onset_before: Some(1.5), onset_after: None, ..Default::default() };
+        assert_eq!(create_graph(read_csv(path).unwrap(), &early).edge_count(), 5);
+        let late = GraphOptions { onset_before: None, onset_after: Some(1.5), ..Default::default() };
+        assert_eq!(create_graph(read_csv(path).unwrap(), &late).edge_count(), 5);
+        assert_eq!(late.describe().unwrap(), "allergy onset at or after age 1.5");
+        assert_eq!(create_graph(read_csv(path).unwrap(), &GraphOptions::default()).edge_count(), 10);
+    }
+
+    #[test]
+    fn test_allergen_and_resolution_filters() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        // 205650 outgrew Peanut at 4.5; no other allergy resolved
+        let unresolved = GraphOptions { unresolved_only: true, ..Default::default() };
+        assert_eq!(create_graph(records.clone(), &unresolved).edge_count(), 9);
+        let names = vec!["peanut".to_string(), "Cashew".to_string()];
+        let only = GraphOptions { only_allergens: names, ..Default::default() };
+        assert_eq!(only.allergens(), ["Peanut", "Cashew"]);
+        let graph = create_graph(records.clone(), &only);
+        assert_eq!((graph.node_count(), graph.edge_count()), (2 + records.len(), 5));
+        let both = GraphOptions { unresolved_only: true, ..only };
+        assert_eq!(create_graph(records, &both).edge_count(), 4);
+        let description = "only allergies unresolved at the end of observation; only allergens peanut, Cashew";
+        assert_eq!(both.describe().unwrap(), description);
+    }
+
+    #[test]
+    fn test_subgraph() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let filter: IndividualFilter = "gender=Male AND payer=Medicaid".parse().unwrap();
+        let summary = GraphSummary::of(&subgraph(&graph, &filter));
+        // Only 205654, with Peanut, Treenut, Pistachio and Cashew
+        assert_eq!((summary.individuals, summary.edges, summary.nodes), (1, 4, 1 + ALLERGENS.len()));
+        assert_eq!((summary.allergens["Peanut"], summary.allergens["Walnut"]), (1, 0));
+    }
+
+    #[test]
+    fn test_parallel_build_keeps_record_order() {
+        let records = crate::fixtures::cohort(500, 11);
+        let graph = create_graph(records.clone(), &GraphOptions::default());
+        let ids: Vec<&str> = graph
+            .node_weights()
+            .filter_map(|node| match node {
+                NodeType::Individual(individual) => Some(individual.id.as_str()),
+                NodeType::AllergenStatus(_) | NodeType::Demographic { .. } => None,
+            })
+            .collect();
+        assert!(ids.iter().zip(&records).all(|(id, record)| *id == record.subject_id));
+        let expected: usize = records
+            .iter()
+            .map(|record| ALLERGENS.iter().filter(|&&allergy| EdgeWeight::of(record, allergy).is_some()).count())
+            .sum();
+        assert_eq!(graph.edge_count(), expected);
+        // Edges come in record order, then allergen order
+        let ends: Vec<(usize, usize)> =
+            graph.edge_references().map(|edge| (edge.source().index(), edge.target().index())).collect();
+        assert!(ends.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_create_graph_from_stream() {
+        let mut records = crate::fixtures::cohort(40, 5);
+        while records.len() <= CHUNK_ROWS {
+            let more: Vec<Record> = records.iter().take(CHUNK_ROWS).cloned().collect();
+            records.extend(more);
+        }
+        for unit in [Unit::Record, Unit::Subject] {
+            let options = GraphOptions { unit, ..Default::default() };
+            let streamed = create_graph_from_stream(records.iter().cloned().map(Ok::<_, ()>), &options).unwrap();
+            let batch = create_graph(records.clone(), &options);
+            assert_eq!(node_link_json(&streamed), node_link_json(&batch));
+        }
+        let rows = vec![Ok(records[0].clone()), Err("bad row"), Ok(records[1].clone())];
+        assert_eq!(create_graph_from_stream(rows, &GraphOptions::default()).err(), Some("bad row"));
+    }
+
+    #[test]
+    fn test_snapshot_age() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        // At 5, subject 205652 is no longer observed and 205650 has
+        // outgrown Peanut
+        let at_five = GraphOptions { snapshot_age: Some(5.0), ..Default::default() };
+        let graph = create_graph(read_csv(path).unwrap(), &at_five);
+        assert_eq!((graph.node_count(), graph.edge_count()), (ALLERGENS.len() + 4, 6));
+        assert_eq!(at_five.describe().unwrap(), "snapshot at age 5: individuals observed then, allergies active then");
+        // At 2, 205653 isn't observed yet and 205652's Pecan hasn't started
+        let at_two = GraphOptions { snapshot_age: Some(2.0), ..Default::default() };
+        let graph = create_graph(read_csv(path).unwrap(), &at_two);
+        assert_eq!((graph.node_count(), graph.edge_count()), (ALLERGENS.len() + 4, 9));
+        let weight = EdgeWeight { onset: 1.0, end: Some(4.5), duration: 3.5 };
+        assert!(weight.active_at(1.0) && !weight.active_at(4.5) && !weight.active_at(0.5));
+    }
+
+    #[test]
+    fn test_binary_graph_round_trip() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let options = GraphOptions { mode: GraphMode::Tripartite, ..Default::default() };
+        let graph = create_graph(read_csv(path).unwrap(), &options);
+        let mut bytes = Vec::new();
+        export_binary(&graph, &serde_json::json!({ "unit": "record" }), &mut bytes).unwrap();
+        assert!(is_binary_graph(&bytes) && bytes.len() < serde_json::to_vec(&node_link_json(&graph)).unwrap().len());
+        let (restored, metadata) = graph_from_binary(&bytes).unwrap();
+        assert_eq!(node_link_json(&restored), node_link_json(&graph));
+        assert_eq!(metadata["unit"], "record");
+
+        assert!(graph_from_binary(b"{\"nodes\": []}").is_err());
+        assert!(graph_from_binary(&bytes[..bytes.len() - 3]).err().unwrap().to_string().contains("corrupt"));
+        let mut newer = bytes.clone();
+        newer[BINARY_MAGIC.len() - 1] = 3;
+        assert!(graph_from_binary(&newer).err().unwrap().to_string().contains("version"));
+    }
+
+    #[test]
+    fn test_node_link_json() {
+        let graph = create_graph(get_mock_records(), &GraphOptions::default());
+        let json = node_link_json(&graph);
+        assert_eq!(json["nodes"].as_array().unwrap().len(), 10);
+        assert_eq!(json["nodes"][9]["subject_id"], "205650");
+        assert_eq!(
+            json["links"][0],
+            serde_json::json!({ "source": 9, "target": 0, "onset": 1.0, "end": 2.0, "duration": 1.0, "weight": 1.0 })
+        );
+        assert_eq!((&json["nodes"][0]["label"], &json["nodes"][9]["label"]), (&"Peanut".into(), &"205650".into()));
+        assert_eq!(json["multigraph"], false);
+
+        let restored = graph_from_node_link(&json).unwrap();
+        assert_eq!(node_link_json(&restored), json);
+        let mut networkx = json.clone();
+        networkx["edges"] = networkx["links"].take();
+        assert_eq!(graph_from_node_link(&networkx).unwrap().edge_count(), graph.edge_count());
+        assert!(graph_from_node_link(&serde_json::json!({ "nodes": [{ "id": 0 }], "links": [] })).is_err());
+        // A plain weighted graph, e.g. from D3 or nx.node_link_data
+        let weighted = serde_json::json!({
+            "nodes": [{ "id": "a", "kind": "individual" }, { "id": "p", "kind": "allergy", "allergy": "Peanut" }],
+            "links": [{ "source": "a", "target": "p", "weight": 2.5 }],
+        });
+        assert_eq!(graph_from_node_link(&weighted).unwrap().edge_weights().next().unwrap().duration, 2.5);
+    }
+
+    #[test]
+    fn test_edge_weights() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        assert_eq!(EdgeWeight::of(&records[0], "Peanut"), Some(EdgeWeight { onset: 1.0, end: Some(4.5), duration: 3.5 }));
+        // An unresolved allergy lasts to the end of observation
+        assert_eq!(EdgeWeight::of(&records[0], "Cashew"), Some(EdgeWeight { onset: 2.0, end: None, duration: 8.0 }));
+        assert_eq!(EdgeWeight::of(&records[0], "Walnut"), None);
+        let mut resolved_late = records[1].clone();
+        resolved_late.peanut_alg_end = Some(0.5);
+        assert_eq!(EdgeWeight::of(&resolved_late, "Peanut").unwrap().duration, 0.0);
+        // Subject 205651 is observed to age 6
+        resolved_late.peanut_alg_end = Some(6.0);
+        assert_eq!(EdgeWeight::of(&resolved_late, "Peanut"), Some(EdgeWeight { onset: 1.5, end: None, duration: 4.5 }));
+
+        let graph = create_graph(records, &GraphOptions::default());
+        let total: f64 = graph.edge_weights().map(|weight| weight.duration).sum();
+        assert!((total - 52.4).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_taxonomy_level() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let top = GraphOptions { taxonomy_level: TaxonomyLevel::Depth(1), ..Default::default() };
+        assert_eq!(top.allergens(), ["Peanut", "Treenut"]);
+        assert_eq!(top.describe().unwrap(), "allergens merged to taxonomy level top");
+        let graph = create_graph(records.clone(), &top);
+        let groups = top.allergen_groups();
+        let expected: usize = records
+            .iter()
+            .map(|record| groups.iter().filter(|(_, nuts)| EdgeWeight::merged(record, nuts).is_some()).count())
+            .sum();
+        assert_eq!((graph.node_count(), graph.edge_count()), (2 + records.len(), expected));
+        // Subject 205650's only tree nut is an unresolved cashew allergy
+        let treenut = graph.edges_connecting(NodeIndex::new(2), NodeIndex::new(1)).next().unwrap();
+        assert_eq!(*treenut.weight(), EdgeWeight { onset: 2.0, end: None, duration: 8.0 });
+        let mut incremental = IncrementalGraph::new(top.clone());
+        incremental.merge_records(&records);
+        assert_eq!(incremental.graph().edge_count(), expected);
+    }
+
+    #[test]
+    fn test_allergy_node_creation() {
+        let records = get_mock_records();
+        let graph = create_graph(records, &GraphOptions::default());
+        let allergy_nodes = graph.node_indices()
+            .filter(|&n| matches!(graph[n], NodeType::AllergenStatus(_)))
+            .count();
+        assert!(allergy_nodes > 0); // Check that allergy nodes are created
+    }
+
+    #[test]
+    fn test_export_graphml() {
+        let mut records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        records[0].gender_factor = "S0 - Male <& \"other\">".to_string();
+        let graph = create_graph(records, &GraphOptions::default());
+        let mut out = Vec::new();
+        export_graphml(&graph, &serde_json::json!({ "unit": "record", "provenance": { "seed": 7 } }), &mut out).unwrap();
+        let xml = String::from_utf8(out).unwrap();
+        assert!(xml.contains(r#"<key id="attr.site" for="node" attr.name="site" attr.type="string"/>"#));
+        assert!(xml.contains(r#"<data key="provenance">{&quot;seed&quot;:7}</data>"#));
+        assert!(xml.contains("<node id=\"n0\">\n      <data key=\"kind\">allergy</data>\n      <data key=\"allergy\">Peanut</data>"));
+        assert!(xml.contains(r#"<data key="gender">S0 - Male &lt;&amp; &quot;other&quot;&gt;</data>"#));
+        assert!(xml.contains(r#"<data key="atopic_march_cohort">true</data>"#));
+        assert_eq!(xml.matches("<node ").count(), graph.node_count());
+        assert_eq!(xml.matches("<edge ").count(), graph.edge_count());
+        assert!(xml.contains("<edge source=\"n9\" target=\"n0\">\n      <data key=\"onset\">1</data>\n      <data key=\"end\">4.5</data>"));
+        // Cashew (n8) hasn't resolved, so has no end
+        assert!(xml.contains("<edge source=\"n9\" target=\"n8\">\n      <data key=\"onset\">2</data>\n      <data key=\"duration\">8</data>"));
+    }
+
+    #[test]
+    fn test_export_gexf() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let mut out = Vec::new();
+        export_gexf(&graph, &serde_json::json!({ "unit": "record" }), &mut out).unwrap();
+        let xml = String::from_utf8(out).unwrap();
+        assert!(xml.contains("<description>{&quot;unit&quot;:&quot;record&quot;}</description>"));
+        assert!(xml.contains(r#"<graph mode="dynamic" defaultedgetype="directed" timeformat="double">"#));
+        assert!(xml.contains(r#"<attribute id="attr.site" title="site" type="string"/>"#));
+        assert!(xml.contains("<node id=\"n9\" label=\"205650\">\n        <attvalues>\n          <attvalue for=\"kind\" value=\"individual\"/>"));
+        assert_eq!(xml.matches("<node ").count(), graph.node_count());
+        assert_eq!(xml.matches("<edge ").count(), graph.edge_count());
+        // Peanut resolved at 4.5; Cashew (n8) hasn't, so its spell is open
+        assert!(xml.contains(r#"<edge id="e0" source="n9" target="n0" start="1" end="4.5">"#));
+        assert!(xml.contains(r#"<edge id="e1" source="n9" target="n8" start="2">"#));
+    }
+
+    #[test]
+    fn test_export_dot() {
+        let mut records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        records[1].subject_id = "2056\"51".to_string();
+        let graph = create_graph(records, &GraphOptions::default());
+        let mut out = Vec::new();
+        export_dot(&graph, Some(&Dimension::Race), &serde_json::json!({ "unit": "record" }), &mut out).unwrap();
+        let dot = String::from_utf8(out).unwrap();
+        assert!(dot.starts_with("digraph allergies {\n  comment=\"{\\\"unit\\\":\\\"record\\\"}\";\n"));
+        assert!(dot.contains("  n0 [label=\"Peanut\", shape=box, fillcolor=lightgrey];\n"));
+        // Races in sorted order: R0 - White, R1 - Black, R2 - ...
+        assert!(dot.contains("  n9 [label=\"205650\", shape=circle, fillcolor=\"#1f77b4\"];\n"));
+        assert!(dot.contains("  n10 [label=\"2056\\\"51\", shape=circle, fillcolor=\"#ff7f0e\"];\n"));
+        // Peanut resolved for 205650; Cashew didn't
+        assert!(dot.contains("  n9 -> n0 [style=dashed];\n  n9 -> n8;\n"));
+        assert!(dot.contains("    label=\"race\";\n    legend0 [label=\"R0 - White\", shape=circle, fillcolor=\"#1f77b4\"];"));
+        assert_eq!(dot.matches(" -> ").count(), graph.edge_count());
+    }
+
+    #[test]
+    fn test_export_cypher() {
+        let mut records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        records[1].subject_id = "2056'51".to_string();
+        let graph = create_graph(records, &GraphOptions::default());
+        let mut out = Vec::new();
+        export_cypher(&graph, &serde_json::json!({ "unit": "record" }), &mut out).unwrap();
+        let cypher = String::from_utf8(out).unwrap();
+        assert!(cypher.starts_with("// {\"unit\":\"record\"}\nCREATE CONSTRAINT IF NOT EXISTS"));
+        assert!(cypher.contains("\nCREATE (:Allergen {name: 'Peanut'});\n"));
+        assert!(cypher.contains("\nCREATE (:Individual {node: 9, id: '205650', gender: 'S0 - Male', race: 'R0 - White', "));
+        assert!(cypher.contains("{node: 10, id: '2056\\'51', gender: 'S1 - Female'"));
+        assert!(cypher.contains("`attr.site`: "));
+        assert_eq!(cypher.matches("\nCREATE (").count(), graph.node_count());
+        assert_eq!(cypher.matches("\nMATCH ").count(), graph.edge_count());
+        // Peanut resolved at 4.5 for 205650; Cashew didn't
+        assert!(cypher.contains(
+            "MATCH (i:Individual {node: 9}), (a:Allergen {name: 'Peanut'}) \
+             CREATE (i)-[:HAS_ALLERGY {onset: 1.0, end: 4.5, duration: 3.5}]->(a);"
+        ));
+        assert!(cypher.contains("(a:Allergen {name: 'Cashew'}) CREATE (i)-[:HAS_ALLERGY {onset: 2.0, duration: "));
+
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let options = GraphOptions { mode: GraphMode::Tripartite, ..Default::default() };
+        let graph = create_graph(read_csv(path).unwrap(), &options);
+        let mut out = Vec::new();
+        export_cypher(&graph, &serde_json::json!({}), &mut out).unwrap();
+        let cypher = String::from_utf8(out).unwrap();
+        assert!(cypher.contains(
+            "MATCH (d:Demographic {dimension: 'gender', value: 'S0 - Male'}), (i:Individual {node: 9}) \
+             CREATE (i)-[:IN_GROUP]->(d);"
+        ));
+
+        // Under --unit record a subject's two rows are two individuals
+        // sharing an id, each created and matched by its own node index
+        let mut records = read_csv(path).unwrap();
+        records.push(records[0].clone());
+        let graph = create_graph(records, &GraphOptions::default());
+        let mut out = Vec::new();
+        export_cypher(&graph, &serde_json::json!({}), &mut out).unwrap();
+        let cypher = String::from_utf8(out).unwrap();
+        assert_eq!(cypher.matches("id: '205650'").count(), 2);
+        assert!(cypher.contains("CREATE (:Individual {node: 9, id: '205650'"));
+        assert!(cypher.contains("CREATE (:Individual {node: 14, id: '205650'"));
+        assert!(cypher.contains("MATCH (i:Individual {node: 14}), (a:Allergen {name: 'Peanut'})"));
+        assert!(!cypher.contains("MATCH (i:Individual {id:"));
+    }
+
+    #[test]
+    fn test_tripartite_graph() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let options = GraphOptions { mode: GraphMode::Tripartite, ..Default::default() };
+        let graph = create_graph(read_csv(path).unwrap(), &options);
+        // 2 genders, 3 races, 2 ethnicities, 2 payers and 2 cohorts, each
+        // individual linked to one of each
+        assert_eq!((graph.node_count(), graph.edge_count()), (14 + 11, 10 + 5 * 5));
+        assert!(matches!(
+            &graph[NodeIndex::new(14)],
+            NodeType::Demographic { dimension, value } if dimension == "gender" && value == "S0 - Male"
+        ));
+        let males: Vec<usize> = graph.neighbors(NodeIndex::new(14)).map(NodeIndex::index).collect();
+        assert_eq!(BTreeSet::from_iter(males), BTreeSet::from([9, 12, 13]));
+        // Individual→allergy edges, and so what's measured on them, are as
+        // in the bipartite graph
+        let bipartite = create_graph(read_csv(path).unwrap(), &GraphOptions::default());
+        let groupings = ["race".parse().unwrap()];
+        let report = crate::ReportOptions::default();
+        assert_eq!(
+            crate::metrics::centrality_results(&graph, &groupings, &report).unwrap(),
+            crate::metrics::centrality_results(&bipartite, &groupings, &report).unwrap()
+        );
+        assert_eq!(GraphSummary::of(&graph).allergens, GraphSummary::of(&bipartite).allergens);
+
+        let json = node_link_json(&graph);
+        assert_eq!(
+            json["nodes"][16],
+            serde_json::json!({ "id": 16, "kind": "demographic", "dimension": "race", "value": "R0 - White",
+                                "label": "R0 - White" })
+        );
+        assert_eq!(node_link_json(&graph_from_node_link(&json).unwrap()), json);
+        let mut out = Vec::new();
+        export_graphml(&graph, &serde_json::json!({}), &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains(r#"<edge source="n14" target="n9"/>"#));
+    }
+
+    #[test]
+    fn test_graph_summary() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let summary = GraphSummary::of(&create_graph(read_csv(path).unwrap(), &GraphOptions::default()));
+        assert_eq!((summary.nodes, summary.edges, summary.individuals), (14, 10, 5));
+        assert_eq!((summary.allergens["Peanut"], summary.allergens["Almond"]), (3, 0));
+        let mut out = Vec::new();
+        summary.write(&mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().starts_with("14 nodes (5 individuals), 10 edges\n  Almond: 0 individual(s)\n"));
+    }
+}
@@ -0,0 +1,131 @@
+//! gRPC service (`grpc` subcommand) defined in `proto/analysis.proto`.
+//!
+//! `Analyze` takes a CSV upload and streams one `MetricRow` per node and
+//! per group, mirroring the objects written by `--format ndjson`, subject
+//! ids de-identified as there.
+
+use std::error::Error;
+use std::pin::Pin;
+
+use log::info;
+use tokio_stream::Stream;
+use tonic::{Request, Response, Status};
+
+use crate::strata::{apply_age_bins, Grouping, DEFAULT_DIMENSIONS};
+use crate::{calculate_metric, check_dimensions, create_graph, read_csv_from_reader, Metric, Settings};
+
+pub mod proto {
+    tonic::include_proto!("allergy_net.v1");
+}
+
+use proto::analysis_server::{Analysis, AnalysisServer};
+use proto::metric_row::Row;
+use proto::{AnalyzeRequest, GroupMetric, MetricRow, NodeMetric};
+
+struct AnalysisService {
+    settings: Settings,
+}
+
+/// Serves the `Analysis` service on `addr` until the process is stopped.
+pub fn serve(addr: &str, settings: Settings) -> Result<(), Box<dyn Error>> {
+    let addr = addr.parse()?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        info!("gRPC listening on {}", addr);
+        tonic::transport::Server::builder()
+            .add_service(AnalysisServer::new(AnalysisService { settings }))
+            .serve(addr)
+            .await
+    })?;
+    Ok(())
+}
+
+impl AnalysisService {
+    /// Runs the analysis; every error is a problem with the request.
+    fn analyze(&self, request: AnalyzeRequest) -> Result<Vec<MetricRow>, String> {
+        if !request.metric.is_empty() && request.metric != "degree" {
+            return Err(format!("unknown metric '{}'", request.metric));
+        }
+        let mut groupings = if request.stratify_by.is_empty() {
+            DEFAULT_DIMENSIONS.split(',').map(str::parse).collect::<Result<Vec<Grouping>, _>>()
+        } else {
+            request.stratify_by.iter().map(|g| g.parse()).collect::<Result<Vec<Grouping>, _>>()
+        }?;
+        apply_age_bins(&mut groupings, &self.settings.age_bins);
+
+        let mut records = read_csv_from_reader(request.csv.as_slice()).map_err(|e| e.to_string())?;
+        self.settings.ingest.apply(&mut records)?;
+        check_dimensions(&records, &groupings).map_err(|e| e.to_string())?;
+        let graph = create_graph(records, &self.settings.graph);
+        let report = &self.settings.report;
+        let mut results = calculate_metric(&graph, &groupings, Metric::Degree, report)?;
+        results.deidentify(&self.settings.export.ids(&graph)?.0, self.settings.export.ids);
+        let results = results.json_rows(report);
+        Ok(results.iter().filter_map(metric_row).collect())
+    }
+}
+
+/// Converts one ndjson result object into a row; explain headers are skipped.
+fn metric_row(value: &serde_json::Value) -> Option<MetricRow> {
+    let text = |key: &str| value[key].as_str().unwrap_or_default().to_string();
+    let number = |key: &str| value[key].as_f64().unwrap_or_default();
+    let count = |key: &str| value[key].as_u64().unwrap_or_default();
+    let row = match value["type"].as_str()? {
+        "node" => Row::Node(NodeMetric {
+            node: count("node") as u32,
+            id: text("id"),
+            value: number("value"),
+        }),
+        "group" => Row::Group(GroupMetric {
+            grouping: text("grouping"),
+            group: text("group"),
+            mean: number("mean"),
+            total: number("total"),
+            n: count("n"),
+            denominator: count("denominator"),
+            small_cell: value["small_cell"].as_bool().unwrap_or_default(),
+            suppressed: value["suppressed"].as_bool().unwrap_or_default(),
+        }),
+        _ => return None,
+    };
+    Some(MetricRow { metric: text("metric"), row: Some(row), unit: text("unit") })
+}
+
+#[tonic::async_trait]
+impl Analysis for AnalysisService {
+    type AnalyzeStream = Pin<Box<dyn Stream<Item = Result<MetricRow, Status>> + Send>>;
+
+    async fn analyze(
+        &self,
+        request: Request<AnalyzeRequest>,
+    ) -> Result<Response<Self::AnalyzeStream>, Status> {
+        let rows = AnalysisService::analyze(self, request.into_inner()).map_err(Status::invalid_argument)?;
+        info!("Streaming {} metric rows", rows.len());
+        Ok(Response::new(Box::pin(tokio_stream::iter(rows.into_iter().map(Ok)))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_analyze_streams_node_and_group_rows() {
+        let service = AnalysisService {
+            settings: Settings::default(),
+        };
+        let request = AnalyzeRequest {
+            csv: include_bytes!("../tests/fixtures/mock_records.csv").to_vec(),
+            stratify_by: vec!["payer".to_string()],
+            metric: String::new(),
+        };
+        let rows = service.analyze(request).unwrap();
+        let nodes = rows.iter().filter(|r| matches!(r.row, Some(Row::Node(_)))).count();
+        let groups = rows.iter().filter(|r| matches!(r.row, Some(Row::Group(_)))).count();
+        assert_eq!((nodes, groups), (5, 2));
+        // Fixture ids are 2056xx; they leave renumbered
+        assert!(rows.iter().all(|r| !matches!(&r.row, Some(Row::Node(node)) if node.id.starts_with("2056"))));
+        let bad = AnalyzeRequest { metric: "pagerank".to_string(), ..Default::default() };
+        assert!(service.analyze(bad).unwrap_err().contains("pagerank"));
+    }
+}
@@ -0,0 +1,286 @@
+//! Tolerant header matching: input columns are matched onto the canonical
+//! record schema ignoring case, spaces and punctuation (`SUBJECT_ID`,
+//! `Subject ID`), and through aliases declared with `--header-aliases`
+//! (`schema check` reports the result).
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::Path;
+
+use csv::{ReaderBuilder, StringRecord, Writer};
+use log::info;
+
+use crate::error::AllergyNetError;
+use crate::{remote, RECORD_COLUMNS};
+
+/// How a header matched its canonical column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Match {
+    Exact,
+    /// Equal once case, spaces and punctuation are ignored.
+    Normalized,
+    /// Declared as an alias of the column.
+    Alias,
+}
+
+impl fmt::Display for Match {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Match::Exact => "exact",
+            Match::Normalized => "normalized",
+            Match::Alias => "alias",
+        })
+    }
+}
+
+/// `header` lowercased, with each run of spaces and punctuation made one
+/// underscore and any at the ends dropped: `" Subject-ID "` is
+/// `subject_id`.
+pub fn fold(header: &str) -> String {
+    let words: Vec<&str> = header.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()).collect();
+    words.join("_").to_lowercase()
+}
+
+/// Other names of canonical columns, as in the YAML file:
+///
+/// ```yaml
+/// subject_id: [patient_id, MRN]
+/// birth_year: [yob]
+/// ```
+///
+/// Aliases are matched as folded by `fold`. The default has none, so only
+/// differently spelled canonical names match.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderAliases {
+    /// Folded alias -> canonical column.
+    aliases: BTreeMap<String, &'static str>,
+}
+
+impl HeaderAliases {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let declared: BTreeMap<String, Vec<String>> = serde_yaml::from_slice(&remote::read(path)?)
+            .map_err(|e| format!("invalid header aliases file {}: {}", path.display(), e))?;
+        HeaderAliases::new(declared)
+            .map_err(|e| format!("invalid header aliases file {}: {}", path.display(), e).into())
+    }
+
+    /// From `canonical column -> aliases`; an alias may only name one
+    /// column, and can't be another column's name.
+    pub fn new(declared: BTreeMap<String, Vec<String>>) -> Result<Self, String> {
+        let mut aliases = BTreeMap::new();
+        for (column, names) in declared {
+            let canonical = RECORD_COLUMNS
+                .iter()
+                .copied()
+                .find(|&canonical| canonical == column)
+                .ok_or_else(|| format!("{} is not a column of the record schema", column))?;
+            for name in names {
+                let folded = fold(&name);
+                if let Some(other) = RECORD_COLUMNS.iter().find(|&&other| other == folded && other != canonical) {
+                    return Err(format!("alias {} of {} is the column {}", name, canonical, other));
+                }
+                match aliases.insert(folded, canonical) {
+                    Some(other) if other != canonical => {
+                        return Err(format!("alias {} names both {} and {}", name, other, canonical))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(HeaderAliases { aliases })
+    }
+
+    /// The canonical column each header feeds, if any, and how it matched.
+    /// A header matching exactly keeps its column from every other
+    /// spelling, which stays an extra column; two other spellings of one
+    /// column are ambiguous.
+    pub fn resolve(&self, headers: &StringRecord) -> Result<Vec<Option<(&'static str, Match)>>, AllergyNetError> {
+        let mut resolved: Vec<Option<(&'static str, Match)>> = headers
+            .iter()
+            .map(|header| RECORD_COLUMNS.iter().copied().find(|&column| column == header).map(|c| (c, Match::Exact)))
+            .collect();
+        for (i, header) in headers.iter().enumerate() {
+            if resolved[i].is_some() {
+                continue;
+            }
+            let folded = fold(header);
+            let found = match RECORD_COLUMNS.iter().copied().find(|&column| column == folded) {
+                Some(column) => Some((column, Match::Normalized)),
+                None => self.aliases.get(&folded).map(|&column| (column, Match::Alias)),
+            };
+            let Some((column, how)) = found else { continue };
+            match resolved.iter().position(|other| other.is_some_and(|(other, _)| other == column)) {
+                Some(j) if resolved[j].is_some_and(|(_, how)| how == Match::Exact) => {}
+                Some(j) => {
+                    let message = format!("columns {} and {} both match {}", &headers[j], header, column);
+                    return Err(AllergyNetError::Schema(message));
+                }
+                None => resolved[i] = Some((column, how)),
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// `headers` with each matched header replaced by its canonical name.
+    pub fn rename(&self, headers: &StringRecord) -> Result<StringRecord, AllergyNetError> {
+        let resolved = self.resolve(headers)?;
+        let matched: Vec<String> = headers
+            .iter()
+            .zip(&resolved)
+            .filter_map(|(header, resolved)| match resolved {
+                Some((column, how)) if *how != Match::Exact => Some(format!("{} -> {}", header, column)),
+                _ => None,
+            })
+            .collect();
+        if !matched.is_empty() {
+            info!("Matched headers: {}", matched.join(", "));
+        }
+        let renamed = headers.iter().zip(resolved).map(|(header, resolved)| resolved.map_or(header, |(c, _)| c));
+        Ok(renamed.collect())
+    }
+
+    /// CSV `contents` with the header row renamed, for the checks that
+    /// read CSV text by column name (`--normalize`, `--strict`).
+    pub fn rename_csv<'a>(&self, contents: &'a [u8]) -> Result<Cow<'a, [u8]>, AllergyNetError> {
+        let mut rdr = ReaderBuilder::new().from_reader(contents);
+        let headers = rdr.headers()?.clone();
+        let renamed = self.rename(&headers)?;
+        if renamed == headers {
+            return Ok(Cow::Borrowed(contents));
+        }
+        let offset = rdr.position().byte() as usize;
+        let mut out = Writer::from_writer(Vec::new());
+        out.write_record(&renamed)?;
+        let mut out = out.into_inner().map_err(|e| AllergyNetError::from(e.into_error()))?;
+        out.extend_from_slice(&contents[offset..]);
+        Ok(Cow::Owned(out))
+    }
+}
+
+/// One canonical column, and the header found for it.
+#[derive(Debug, PartialEq)]
+pub struct ExpectedColumn {
+    pub column: &'static str,
+    /// Whether records can't be read without it; allergy columns may be
+    /// left out.
+    pub required: bool,
+    pub found: Option<(String, Match)>,
+}
+
+/// Which canonical columns a header row has (`schema check`).
+#[derive(Debug, PartialEq)]
+pub struct SchemaCheck {
+    /// In schema order.
+    pub expected: Vec<ExpectedColumn>,
+    /// Headers matching no column, read as extra columns.
+    pub unmatched: Vec<String>,
+}
+
+impl SchemaCheck {
+    pub fn of(headers: &StringRecord, aliases: &HeaderAliases) -> Result<Self, AllergyNetError> {
+        let resolved = aliases.resolve(headers)?;
+        let expected = RECORD_COLUMNS
+            .iter()
+            .map(|&column| ExpectedColumn {
+                column,
+                required: !column.contains("_alg_"),
+                found: headers.iter().zip(&resolved).find_map(|(header, resolved)| match resolved {
+                    Some((matched, how)) if *matched == column => Some((header.to_string(), *how)),
+                    _ => None,
+                }),
+            })
+            .collect();
+        let unmatched = headers
+            .iter()
+            .zip(&resolved)
+            .filter(|(_, resolved)| resolved.is_none())
+            .map(|(header, _)| header.to_string())
+            .collect();
+        Ok(SchemaCheck { expected, unmatched })
+    }
+
+    /// Required columns with no header.
+    pub fn missing_required(&self) -> Vec<&'static str> {
+        self.expected.iter().filter(|c| c.required && c.found.is_none()).map(|c| c.column).collect()
+    }
+
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        let columns = RECORD_COLUMNS.iter().map(|c| c.len()).max().unwrap_or(0);
+        let found = self.expected.iter().filter_map(|c| c.found.as_ref());
+        let width = found.map(|(header, _)| header.len()).max().unwrap_or(0).max(9);
+        writeln!(out, "{:columns$}  {:width$}  match", "column", "header")?;
+        for column in &self.expected {
+            match &column.found {
+                Some((header, how)) => writeln!(out, "{:columns$}  {:width$}  {}", column.column, header, how)?,
+                None if column.required => writeln!(out, "{:columns$}  (missing)", column.column)?,
+                None => writeln!(out, "{:columns$}  {:width$}  optional", column.column, "(missing)")?,
+            }
+        }
+        let found = self.expected.iter().filter(|c| c.found.is_some()).count();
+        writeln!(out, "{} of {} expected columns found", found, self.expected.len())?;
+        if !self.unmatched.is_empty() {
+            writeln!(out, "Unmatched columns, read as extra columns: {}", self.unmatched.join(", "))?;
+        }
+        let missing = self.missing_required();
+        if !missing.is_empty() {
+            writeln!(out, "Missing required columns: {}", missing.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_headers() {
+        assert_eq!(fold(" Subject-ID "), "subject_id");
+        assert_eq!(fold("AGE__START.years"), "age_start_years");
+
+        let declared = BTreeMap::from([("birth_year".to_string(), vec!["YOB".to_string()])]);
+        let aliases = HeaderAliases::new(declared).unwrap();
+        let headers = StringRecord::from(vec!["SUBJECT_ID", "yob", "Gender Factor", "gender_factor", "site"]);
+        let resolved = aliases.resolve(&headers).unwrap();
+        assert_eq!(resolved[0], Some(("subject_id", Match::Normalized)));
+        assert_eq!(resolved[1], Some(("birth_year", Match::Alias)));
+        // The exact header keeps the column
+        assert_eq!(resolved[2..], [None, Some(("gender_factor", Match::Exact)), None]);
+        let renamed = aliases.rename(&headers).unwrap();
+        let expected = ["subject_id", "birth_year", "Gender Factor", "gender_factor", "site"];
+        assert_eq!(renamed, StringRecord::from(expected.to_vec()));
+
+        let ambiguous = StringRecord::from(vec!["Subject ID", "SUBJECT_ID"]);
+        let error = HeaderAliases::default().resolve(&ambiguous).unwrap_err();
+        assert_eq!(error.to_string(), "schema mismatch: columns Subject ID and SUBJECT_ID both match subject_id");
+
+        let clash = BTreeMap::from([("subject_id".to_string(), vec!["Birth Year".to_string()])]);
+        assert!(HeaderAliases::new(clash).unwrap_err().contains("is the column birth_year"));
+        let unknown = BTreeMap::from([("patient".to_string(), vec!["id".to_string()])]);
+        assert!(HeaderAliases::new(unknown).is_err());
+    }
+
+    #[test]
+    fn test_rename_csv_and_check() {
+        let csv = "\u{feff}Subject ID,YOB,site\n1,2000,north\n";
+        let declared = BTreeMap::from([("birth_year".to_string(), vec!["yob".to_string()])]);
+        let aliases = HeaderAliases::new(declared).unwrap();
+        assert_eq!(aliases.rename_csv(csv.as_bytes()).unwrap().as_ref(), b"subject_id,birth_year,site\n1,2000,north\n");
+        let canonical = "subject_id,site\n1,north\n";
+        assert!(matches!(aliases.rename_csv(canonical.as_bytes()).unwrap(), Cow::Borrowed(_)));
+
+        let check = SchemaCheck::of(&StringRecord::from(vec!["Subject ID", "YOB", "site"]), &aliases).unwrap();
+        assert_eq!(check.expected[0].found, Some(("Subject ID".to_string(), Match::Normalized)));
+        assert_eq!(check.unmatched, ["site"]);
+        assert_eq!(check.missing_required().len(), 7);
+        let mut out = Vec::new();
+        check.write(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("2 of 27 expected columns found\n"), "{}", text);
+        assert!(text.contains("peanut_alg_start     (missing)   optional\n"), "{}", text);
+    }
+}
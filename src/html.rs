@@ -0,0 +1,524 @@
+//! Self-contained HTML report (`report` subcommand): the graph's size,
+//! each allergy's prevalence, heatmaps of how pairs co-occur, the degree
+//! distribution and each metric's group averages, as tables with inline
+//! SVG charts, for readers who won't read the text reports. The page is
+//! `report.html` with its placeholders filled in; it needs no scripts,
+//! fonts or files besides itself.
+
+use std::io::{self, Write};
+
+use petgraph::graph::DiGraph;
+
+use crate::metrics::distributions::{degree_distribution, DistributionReport};
+use crate::stats::association::{association, matrix, Association, Statistic};
+use crate::stats::prevalence::{prevalence, Prevalence};
+use crate::stats::OVERALL;
+use crate::strata::Grouping;
+use crate::{calculate_metric, CentralityReport, EdgeWeight, GraphSummary, Metric, NodeType, ReportOptions};
+
+const TEMPLATE: &str = include_str!("report.html");
+
+/// Width of the labels to the left of a chart's bars, and of the bars at
+/// their longest, in pixels.
+const LABEL_WIDTH: usize = 260;
+const BAR_WIDTH: usize = 400;
+const ROW_HEIGHT: usize = 22;
+/// Size of a heatmap cell, and room for the row and column labels.
+const CELL_WIDTH: f64 = 56.0;
+const CELL_HEIGHT: f64 = 28.0;
+const HEATMAP_LEFT: f64 = 80.0;
+const HEATMAP_TOP: f64 = 70.0;
+
+/// Everything the report shows, computed as the matching subcommands
+/// compute it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HtmlReport {
+    pub summary: GraphSummary,
+    pub prevalence: Vec<Prevalence>,
+    /// Every ordered pair of allergies, as for `association`.
+    pub association: Vec<Association>,
+    pub distribution: DistributionReport,
+    /// One per metric.
+    pub centrality: Vec<CentralityReport>,
+}
+
+/// One bar of a chart; `value` is `None` for a suppressed group.
+struct Bar {
+    label: String,
+    value: Option<f64>,
+    interval: Option<(f64, f64)>,
+}
+
+/// Computes the report's contents from `graph`, broken down by
+/// `groupings`. Like `prevalence` and `distribution`, it is refused under
+/// differential privacy.
+pub fn html_report(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    groupings: &[Grouping],
+    metrics: &[Metric],
+    options: &ReportOptions,
+) -> Result<HtmlReport, String> {
+    if options.noise.is_some() {
+        return Err("the HTML report can't be released under differential privacy".to_string());
+    }
+    let centrality = metrics
+        .iter()
+        .zip(options.per_metric(metrics.len()))
+        .map(|(&metric, report)| calculate_metric(graph, groupings, metric, &report))
+        .collect::<Result<_, _>>()?;
+    Ok(HtmlReport {
+        summary: GraphSummary::of(graph),
+        prevalence: prevalence(graph, groupings, options)?,
+        association: association(graph, options)?,
+        distribution: degree_distribution(graph, &[], options)?,
+        centrality,
+    })
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// `template` with each `{{name}}` replaced by its value in `values`, in
+/// one pass, so values can't introduce placeholders of their own.
+fn render(template: &str, values: &[(&str, String)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(length) = rest[start..].find("}}") else { break };
+        let name = &rest[start + 2..start + length];
+        out.push_str(&rest[..start]);
+        match values.iter().find(|(key, _)| *key == name) {
+            Some((_, value)) => out.push_str(value),
+            None => out.push_str(&rest[start..start + length + 2]),
+        }
+        rest = &rest[start + length + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn number(value: Option<f64>) -> String {
+    value.map_or_else(|| "suppressed".to_string(), |value| format!("{:.3}", value))
+}
+
+fn count(value: Option<usize>) -> String {
+    value.map_or_else(|| "suppressed".to_string(), |value| value.to_string())
+}
+
+/// A table with a header row; columns after the first two are numbers.
+/// Rows that are small cells are greyed out.
+fn table(headers: &[&str], rows: &[(Vec<String>, bool)]) -> String {
+    let mut html = String::from("<table>\n<tr>");
+    for header in headers {
+        html.push_str(&format!("<th>{}</th>", escape(header)));
+    }
+    html.push_str("</tr>\n");
+    for (cells, small_cell) in rows {
+        html.push_str(if *small_cell { "<tr class=\"small-cell\">" } else { "<tr>" });
+        for (i, cell) in cells.iter().enumerate() {
+            let class = if i >= 2 { " class=\"number\"" } else { "" };
+            html.push_str(&format!("<td{}>{}</td>", class, escape(cell)));
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</table>\n");
+    html
+}
+
+/// A horizontal bar chart, scaled so the longest bar or interval fills
+/// the width, with each interval drawn as a whisker.
+fn bar_chart(title: &str, bars: &[Bar]) -> String {
+    let longest = bars
+        .iter()
+        .filter_map(|bar| bar.interval.map_or(bar.value, |(_, upper)| Some(upper)))
+        .fold(0.0_f64, f64::max);
+    let scale = if longest > 0.0 { BAR_WIDTH as f64 / longest } else { 0.0 };
+    let (width, height) = (LABEL_WIDTH + BAR_WIDTH + 60, ROW_HEIGHT * bars.len() + 4);
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" role=\"img\" aria-label=\"{}\">\n",
+        width,
+        height,
+        escape(title)
+    );
+    for (i, bar) in bars.iter().enumerate() {
+        let (top, middle) = (i * ROW_HEIGHT + 2, i * ROW_HEIGHT + ROW_HEIGHT / 2 + 6);
+        svg.push_str(&format!(
+            "<text x=\"{}\" y=\"{}\" text-anchor=\"end\">{}</text>",
+            LABEL_WIDTH - 6,
+            middle,
+            escape(&bar.label)
+        ));
+        let Some(value) = bar.value else {
+            let text = format!("<text x=\"{}\" y=\"{}\" class=\"suppressed\">suppressed</text>\n", LABEL_WIDTH, middle);
+            svg.push_str(&text);
+            continue;
+        };
+        let length = value * scale;
+        svg.push_str(&format!(
+            "<rect class=\"bar\" x=\"{}\" y=\"{}\" width=\"{:.1}\" height=\"{}\"><title>{:.3}</title></rect>",
+            LABEL_WIDTH,
+            top + 2,
+            length,
+            ROW_HEIGHT - 6,
+            value
+        ));
+        if let Some((lower, upper)) = bar.interval {
+            let centre = top + ROW_HEIGHT / 2 - 1;
+            svg.push_str(&format!(
+                "<line class=\"interval\" x1=\"{:.1}\" x2=\"{:.1}\" y1=\"{}\" y2=\"{}\"/>",
+                LABEL_WIDTH as f64 + lower * scale,
+                LABEL_WIDTH as f64 + upper * scale,
+                centre,
+                centre
+            ));
+        }
+        let end = bar.interval.map_or(length, |(_, upper)| upper * scale);
+        let x = LABEL_WIDTH as f64 + end + 4.0;
+        svg.push_str(&format!("<text x=\"{:.1}\" y=\"{}\">{:.3}</text>\n", x, middle, value));
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// `from` to `to` by `t` from 0 to 1, as an SVG colour.
+fn mix(from: [u8; 3], to: [u8; 3], t: f64) -> String {
+    let channel = |i: usize| (from[i] as f64 + (to[i] as f64 - from[i] as f64) * t.clamp(0.0, 1.0)).round() as u8;
+    format!("#{:02x}{:02x}{:02x}", channel(0), channel(1), channel(2))
+}
+
+const WHITE: [u8; 3] = [255, 255, 255];
+const RED: [u8; 3] = [178, 24, 43];
+const BLUE: [u8; 3] = [33, 102, 172];
+
+/// Where `value` of `statistic` falls on the heatmap's scale, from -1 to
+/// 1: counts from 0 to `largest`, ratios on a log scale from 1/8 to 8, and
+/// p-values from 1 down to 0.0001.
+fn intensity(statistic: Statistic, value: f64, largest: f64) -> f64 {
+    match statistic {
+        Statistic::Both if largest > 0.0 => value / largest,
+        Statistic::Both => 0.0,
+        Statistic::OddsRatio | Statistic::RelativeRisk => (value.log2() / 3.0).clamp(-1.0, 1.0),
+        Statistic::ChiSquareP | Statistic::FisherP => (-value.max(f64::MIN_POSITIVE).log10() / 4.0).clamp(0.0, 1.0),
+    }
+}
+
+fn heat(intensity: f64) -> String {
+    if intensity < 0.0 {
+        mix(WHITE, BLUE, -intensity)
+    } else {
+        mix(WHITE, RED, intensity)
+    }
+}
+
+fn annotation(statistic: Statistic, value: f64) -> String {
+    match statistic {
+        Statistic::Both => format!("{:.0}", value),
+        Statistic::OddsRatio | Statistic::RelativeRisk => format!("{:.2}", value),
+        Statistic::ChiSquareP | Statistic::FisherP if value < 0.001 => "<0.001".to_string(),
+        Statistic::ChiSquareP | Statistic::FisherP => format!("{:.3}", value),
+    }
+}
+
+/// An allergy × allergy heatmap of `statistic`, as `association --matrix`
+/// lays it out, with each cell annotated with its value and a colour scale
+/// below. The diagonal and missing values are grey.
+fn heatmap(title: &str, rows: &[Association], statistic: Statistic) -> String {
+    let (allergies, cells) = matrix(rows, statistic);
+    let largest = cells.iter().flatten().flatten().copied().fold(0.0_f64, f64::max);
+    let size = allergies.len() as f64;
+    let (width, height) = (HEATMAP_LEFT + size * CELL_WIDTH + 10.0, HEATMAP_TOP + size * CELL_HEIGHT + 60.0);
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{:.0}\" height=\"{:.0}\" role=\"img\" aria-label=\"{}\">\n",
+        width,
+        height,
+        escape(title)
+    );
+    for (i, allergy) in allergies.iter().enumerate() {
+        let (x, y) = (HEATMAP_LEFT + (i as f64 + 0.5) * CELL_WIDTH, HEATMAP_TOP + (i as f64 + 0.5) * CELL_HEIGHT);
+        svg.push_str(&format!(
+            "<text x=\"{:.1}\" y=\"{:.1}\" transform=\"rotate(-45 {:.1} {:.1})\">{}</text>",
+            x,
+            HEATMAP_TOP - 6.0,
+            x,
+            HEATMAP_TOP - 6.0,
+            escape(allergy)
+        ));
+        svg.push_str(&format!(
+            "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"end\">{}</text>\n",
+            HEATMAP_LEFT - 6.0,
+            y + 4.0,
+            escape(allergy)
+        ));
+    }
+    for (i, row) in cells.iter().enumerate() {
+        for (j, value) in row.iter().enumerate() {
+            let (x, y) = (HEATMAP_LEFT + j as f64 * CELL_WIDTH, HEATMAP_TOP + i as f64 * CELL_HEIGHT);
+            let Some(value) = value else {
+                svg.push_str(&format!(
+                    "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{}\" height=\"{}\" fill=\"#eee\" stroke=\"white\"/>\n",
+                    x, y, CELL_WIDTH, CELL_HEIGHT
+                ));
+                continue;
+            };
+            let strength = intensity(statistic, *value, largest);
+            let text = if strength.abs() > 0.6 { "white" } else { "black" };
+            svg.push_str(&format!(
+                "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{}\" height=\"{}\" fill=\"{}\" stroke=\"white\"/>",
+                x,
+                y,
+                CELL_WIDTH,
+                CELL_HEIGHT,
+                heat(strength)
+            ));
+            svg.push_str(&format!(
+                "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\" fill=\"{}\">{}</text>\n",
+                x + CELL_WIDTH / 2.0,
+                y + CELL_HEIGHT / 2.0 + 4.0,
+                text,
+                annotation(statistic, *value)
+            ));
+        }
+    }
+    // The colour scale: a swatch per step, labelled with its value
+    let steps: Vec<(f64, String)> = match statistic {
+        Statistic::Both => (0..=4).map(|i| (i as f64 / 4.0, format!("{:.0}", largest * i as f64 / 4.0))).collect(),
+        Statistic::OddsRatio | Statistic::RelativeRisk => ["≤1/8", "1/4", "1/2", "1", "2", "4", "≥8"]
+            .iter()
+            .enumerate()
+            .map(|(i, label)| ((i as f64 - 3.0) / 3.0, label.to_string()))
+            .collect(),
+        Statistic::ChiSquareP | Statistic::FisherP => ["1", "0.1", "0.01", "0.001", "≤0.0001"]
+            .iter()
+            .enumerate()
+            .map(|(i, label)| (i as f64 / 4.0, label.to_string()))
+            .collect(),
+    };
+    let top = HEATMAP_TOP + size * CELL_HEIGHT + 16.0;
+    for (i, (strength, label)) in steps.iter().enumerate() {
+        let x = HEATMAP_LEFT + i as f64 * CELL_WIDTH;
+        svg.push_str(&format!(
+            "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{}\" height=\"12\" fill=\"{}\" stroke=\"#ccc\"/>",
+            x,
+            top,
+            CELL_WIDTH,
+            heat(*strength)
+        ));
+        svg.push_str(&format!(
+            "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\">{}</text>\n",
+            x + CELL_WIDTH / 2.0,
+            top + 26.0,
+            escape(label)
+        ));
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+impl HtmlReport {
+    /// Rows of the prevalence, association, distribution and centrality
+    /// tables reported unsuppressed despite being small cells.
+    pub fn small_cells(&self) -> usize {
+        let prevalence = self.prevalence.iter().filter(|row| row.small_cell && !row.suppressed).count();
+        let association = self.association.iter().filter(|row| row.small_cell && !row.suppressed).count();
+        let centrality: usize = self.centrality.iter().map(CentralityReport::small_cells).sum();
+        prevalence + association + self.distribution.small_cells() + centrality
+    }
+
+    fn summary_section(&self) -> String {
+        let mut html = format!(
+            "<h2>Graph</h2>\n<p>{} individuals, {} allergy edges and {} nodes in all.</p>\n",
+            self.summary.individuals, self.summary.edges, self.summary.nodes
+        );
+        let rows: Vec<_> = self
+            .summary
+            .allergens
+            .iter()
+            .map(|(allergen, individuals)| (vec![allergen.clone(), individuals.to_string()], false))
+            .collect();
+        html.push_str(&table(&["allergen", "individuals"], &rows));
+        html
+    }
+
+    fn prevalence_section(&self) -> String {
+        let mut html = String::from("<h2>Prevalence</h2>\n");
+        html.push_str("<p>Share of individuals with each allergy, with 95% Wilson intervals.</p>\n");
+        let overall: Vec<Bar> = self
+            .prevalence
+            .iter()
+            .filter(|row| row.grouping == OVERALL)
+            .map(|row| Bar {
+                label: row.allergy.clone(),
+                value: row.proportion,
+                interval: row.ci_lower.zip(row.ci_upper),
+            })
+            .collect();
+        html.push_str(&bar_chart("Prevalence of each allergy", &overall));
+        let rows: Vec<_> = self
+            .prevalence
+            .iter()
+            .map(|row| {
+                let interval = row.ci_lower.zip(row.ci_upper).map_or_else(
+                    || "suppressed".to_string(),
+                    |(lower, upper)| format!("{:.3}–{:.3}", lower, upper),
+                );
+                let cells = vec![
+                    row.allergy.clone(),
+                    format!("{} {}", row.grouping, row.group),
+                    count(row.cases),
+                    count(row.n),
+                    number(row.proportion),
+                    interval,
+                ];
+                (cells, row.small_cell)
+            })
+            .collect();
+        html.push_str(&table(&["allergy", "group", "cases", "n", "proportion", "95% CI"], &rows));
+        html
+    }
+
+    fn co_occurrence_section(&self) -> String {
+        let mut html = String::from("<h2>Co-occurrence</h2>\n");
+        html.push_str("<p>Individuals with both allergies of each pair.</p>\n");
+        html.push_str(&heatmap("Individuals with both allergies", &self.association, Statistic::Both));
+        html.push_str(
+            "<p>Odds ratio of the pair, red where having one allergy makes the other more likely and blue \
+             where less. 0.5 is added to every cell of a table with an empty cell.</p>\n",
+        );
+        html.push_str(&heatmap("Odds ratios of each pair of allergies", &self.association, Statistic::OddsRatio));
+        html.push_str("<p>Grey cells are the diagonal, pairs with no estimate, and small cells left out.</p>\n");
+        html
+    }
+
+    fn distribution_section(&self) -> String {
+        let mut html = String::from("<h2>Allergies per individual</h2>\n");
+        let bins: Vec<Bar> = self
+            .distribution
+            .histogram
+            .iter()
+            .filter(|bin| bin.grouping == OVERALL)
+            .map(|bin| Bar {
+                label: format!("{} allerg{}", bin.degree, if bin.degree == 1 { "y" } else { "ies" }),
+                value: bin.individuals.map(|individuals| individuals as f64),
+                interval: None,
+            })
+            .collect();
+        html.push_str(&bar_chart("Individuals by number of allergies", &bins));
+        let rows: Vec<_> = self
+            .distribution
+            .summaries
+            .iter()
+            .map(|row| {
+                let max = row.max.map(|max| max as f64);
+                let cells = vec![
+                    row.grouping.clone(),
+                    row.group.clone(),
+                    count(row.n),
+                    number(row.mean),
+                    number(row.median),
+                    number(row.p95),
+                    number(max),
+                ];
+                (cells, row.small_cell)
+            })
+            .collect();
+        html.push_str(&table(&["grouping", "group", "n", "mean", "median", "95th percentile", "max"], &rows));
+        html
+    }
+
+    fn centrality_section(&self, report: &CentralityReport) -> String {
+        let mut html = format!("<h2>Average {} centrality by group</h2>\n", escape(&report.metric.to_string()));
+        let mut groupings: Vec<&str> = Vec::new();
+        for group in &report.groups {
+            if !groupings.contains(&group.grouping.as_str()) {
+                groupings.push(&group.grouping);
+            }
+        }
+        for grouping in groupings {
+            let groups: Vec<_> = report.groups.iter().filter(|group| group.grouping == grouping).collect();
+            html.push_str(&format!("<h3>{}</h3>\n", escape(grouping)));
+            let bars: Vec<Bar> = groups
+                .iter()
+                .map(|group| Bar {
+                    label: group.group.clone(),
+                    value: group.mean,
+                    interval: group.ci_lower.zip(group.ci_upper),
+                })
+                .collect();
+            html.push_str(&bar_chart(&format!("Average {} by {}", report.metric, grouping), &bars));
+            let rows: Vec<_> = groups
+                .iter()
+                .map(|group| {
+                    let cells = vec![grouping.to_string(), group.group.clone(), count(group.n), number(group.mean)];
+                    (cells, group.small_cell)
+                })
+                .collect();
+            html.push_str(&table(&["grouping", "group", "n", "mean"], &rows));
+        }
+        html
+    }
+
+    /// Writes the page, with `provenance` at the foot if there is one.
+    pub fn write(&self, options: &ReportOptions, out: &mut dyn Write) -> io::Result<()> {
+        let mut body = self.summary_section();
+        if !options.filters.is_empty() {
+            body.push_str(&format!("<p>Filters: {}.</p>\n", escape(&options.filters.join("; "))));
+        }
+        body.push_str(&self.prevalence_section());
+        body.push_str(&self.co_occurrence_section());
+        body.push_str(&self.distribution_section());
+        for report in &self.centrality {
+            body.push_str(&self.centrality_section(report));
+        }
+        let provenance = options.provenance.as_ref().map_or_else(|| "none recorded".to_string(), |p| p.to_string());
+        let page = render(
+            TEMPLATE,
+            &[
+                ("title", "Nut allergy network report".to_string()),
+                ("body", body),
+                ("provenance", escape(&provenance)),
+            ],
+        );
+        out.write_all(page.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disclosure::Suppression;
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    #[test]
+    fn test_html_report() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let options = ReportOptions { small_cell_threshold: 2, suppression: Suppression::Mask, ..Default::default() };
+        let report = html_report(&graph, &["gender".parse().unwrap()], &[Metric::Degree], &options).unwrap();
+        let mut out = Vec::new();
+        report.write(&options, &mut out).unwrap();
+        let html = String::from_utf8(out).unwrap();
+        assert!(html.starts_with("<!DOCTYPE html>") && html.trim_end().ends_with("</html>"));
+        assert!(!html.contains("{{"), "every placeholder is filled");
+        assert!(html.contains("<p>5 individuals, 10 allergy edges"));
+        // Prevalence, the two heatmaps, the histogram and the gender
+        // averages
+        assert_eq!(html.matches("<svg").count(), 5);
+        // Peanut and Cashew: 2 individuals with both, odds ratio 8.33
+        assert!(html.contains("fill=\"white\">2</text>") || html.contains("fill=\"black\">2</text>"));
+        assert!(html.contains(">8.33</text>"));
+        // Peanut's overall prevalence is 3 of 5
+        assert!(html.contains("<td>Peanut</td><td>overall overall</td><td class=\"number\">3</td>"), "{}", html);
+        assert!(html.contains("<h3>gender</h3>"));
+        // Only one woman has a peanut allergy, so both genders are masked
+        assert!(html.contains("<td>Peanut</td><td>gender S1 - Female</td><td class=\"number\">suppressed</td>"));
+        assert_eq!(report.small_cells(), 0);
+    }
+
+    #[test]
+    fn test_render_and_escape() {
+        let values = [("name", "{{other}}".to_string()), ("other", "x".to_string())];
+        assert_eq!(render("<{{name}}|{{other}}|{{missing}}>", &values), "<{{other}}|x|{{missing}}>");
+        assert_eq!(escape("<a & \"b\">"), "&lt;a &amp; &quot;b&quot;&gt;");
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use log::info;
+
+use crate::allergens::AllergenMap;
+use crate::error::AllergyNetError;
+use crate::exit::Failure;
+use crate::headers::HeaderAliases;
+use crate::io::{decompress, is_arrow, is_compressed, is_parquet};
+use crate::normalize::Normalization;
+use crate::plausibility::{PlausibilityPolicy, PlausibilityRules};
+use crate::quality::{self, DedupPolicy};
+use crate::remote;
+use crate::schema::{self, AllowedValues, Violation};
+use crate::validation::{OnInvalid, RowValidation};
+use crate::io::{read_csv_with_aliases, RecordStream};
+use crate::{read_csv_from_reader, Record};
+
+/// Adjustments applied to records as they are loaded, before any graph is
+/// built.
+#[derive(Debug, Clone, Default)]
+pub struct IngestOptions {
+    /// Subject ids dropped at load time (withdrawn consent, known bad data).
+    pub exclude_ids: HashSet<String>,
+    /// Rewrites variant categorical values in CSV input (`--normalize`).
+    pub normalize: Option<Normalization>,
+    /// Where each allergen's onset and end are read from, and allergens
+    /// beyond the nine nuts (`--allergen-map`, `--allergen-column`).
+    pub allergens: Option<AllergenMap>,
+    /// Reject CSV input with any schema violation (`--strict`).
+    pub strict: Option<AllowedValues>,
+    /// Checks for impossible records, and what to do with them
+    /// (`--on-invalid`).
+    pub validation: Option<RowValidation>,
+    /// Birth year and age ranges, and what to do with values outside them
+    /// (`--plausibility`).
+    pub plausibility: Option<PlausibilityRules>,
+    /// Fill in missing Treenut intervals from the specific tree nuts
+    /// (`--derive-treenut`).
+    pub derive_treenut: bool,
+    /// How rows repeating a subject id are resolved.
+    pub dedup: DedupPolicy,
+    /// Other names of canonical columns in CSV input (`--header-aliases`).
+    pub header_aliases: HeaderAliases,
+}
+
+impl IngestOptions {
+    pub fn exclude_ids_from(&mut self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let path = path.as_ref();
+        let contents = remote::read(path)
+            .map_err(|e| format!("cannot read exclusion list {}: {}", path.display(), e))?;
+        self.exclude_ids.extend(parse_id_list(&String::from_utf8_lossy(&contents)));
+        Ok(())
+    }
+}
+
+impl IngestOptions {
+    /// Applies the load-time adjustments to already parsed records. Fails
+    /// when a mapped allergy column isn't a number, the validation or
+    /// plausibility policy rejects the input, or the rejects file can't be
+    /// written. Invalid rows are numbered by their position in the input,
+    /// as they are checked first, after the allergen map.
+    pub fn apply(&self, records: &mut Vec<Record>) -> Result<(), String> {
+        if let Some(allergens) = &self.allergens {
+            allergens.apply(records)?;
+        }
+        if let Some(validation) = &self.validation {
+            let report = validation.enforce(records);
+            if let Some(path) = &validation.rejects {
+                let cannot_write =
+                    |e: &dyn fmt::Display| format!("cannot write rejects file {}: {}", path.display(), e);
+                let file = File::create(path).map_err(|e| cannot_write(&e))?;
+                report.write_rejects(file).map_err(|e| cannot_write(&e))?;
+                info!("Wrote {} invalid row(s) to {}", report.rejected.len(), path.display());
+            }
+            report.log();
+            if validation.on_invalid == OnInvalid::Fail && !report.rejected.is_empty() {
+                return Err(report.summary());
+            }
+        }
+        if !self.exclude_ids.is_empty() {
+            let before = records.len();
+            records.retain(|record| !self.exclude_ids.contains(&record.subject_id));
+            info!("Excluded {} records by subject id", before - records.len());
+        }
+        if let Some(rules) = &self.plausibility {
+            let report = rules.enforce(records);
+            if rules.policy == PlausibilityPolicy::Reject && !report.values.is_empty() {
+                return Err(report.summary());
+            }
+            report.log();
+        }
+        if self.derive_treenut {
+            let derived = quality::derive_treenut(records);
+            info!("Derived the Treenut interval of {} records from specific tree nuts", derived);
+        }
+        if self.dedup != DedupPolicy::None {
+            let report = quality::deduplicate(records, self.dedup);
+            if self.dedup == DedupPolicy::Error && !report.groups.is_empty() {
+                return Err(report.summary());
+            }
+            info!(
+                "Resolved {} subjects with multiple rows under --dedup {}, dropping {} rows",
+                report.groups.len(),
+                self.dedup,
+                report.dropped_rows()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// One subject id per line; blank lines and `#` comments are ignored.
+fn parse_id_list(contents: &str) -> impl Iterator<Item = String> + '_ {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+}
+
+/// Reads a local or remote Parquet file.
+#[cfg(feature = "parquet")]
+fn read_parquet_input(path: &Path) -> Result<Result<Vec<Record>, AllergyNetError>, Box<dyn Error>> {
+    use crate::io::parquet::{read_parquet, read_parquet_bytes};
+
+    Ok(if remote::is_remote(path) { read_parquet_bytes(remote::read(path)?) } else { read_parquet(path) })
+}
+
+#[cfg(not(feature = "parquet"))]
+fn read_parquet_input(path: &Path) -> Result<Result<Vec<Record>, AllergyNetError>, Box<dyn Error>> {
+    Err(format!("{}: Parquet input needs a build with the `parquet` feature", path.display()).into())
+}
+
+/// Reads a local or remote Arrow IPC file.
+#[cfg(feature = "arrow")]
+fn read_arrow_input(path: &Path) -> Result<Result<Vec<Record>, AllergyNetError>, Box<dyn Error>> {
+    use crate::io::arrow::{read_arrow, read_arrow_from_reader};
+
+    Ok(if remote::is_remote(path) {
+        read_arrow_from_reader(std::io::Cursor::new(remote::read(path)?))
+    } else {
+        read_arrow(path)
+    })
+}
+
+#[cfg(not(feature = "arrow"))]
+fn read_arrow_input(path: &Path) -> Result<Result<Vec<Record>, AllergyNetError>, Box<dyn Error>> {
+    Err(format!("{}: Arrow IPC input needs a build with the `arrow` feature", path.display()).into())
+}
+
+/// The CSV at `path`, local or remote, decompressed if it is compressed.
+fn read_contents(path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    let contents = remote::read(path)?;
+    if !is_compressed(path) {
+        return Ok(contents);
+    }
+    let mut decompressed = Vec::new();
+    decompress(path, contents.as_slice())
+        .and_then(|mut reader| reader.read_to_end(&mut decompressed))
+        .map_err(|e| AllergyNetError::from(e).in_file(path))?;
+    Ok(decompressed)
+}
+
+pub fn load_records(path: impl AsRef<Path>, options: &IngestOptions) -> Result<Vec<Record>, Box<dyn Error>> {
+    let path = path.as_ref();
+    // Normalization runs first, so --strict checks the canonical values
+    let columnar = is_parquet(path) || is_arrow(path);
+    if columnar && (options.strict.is_some() || options.normalize.is_some()) {
+        return Err(format!("{}: --strict and --normalize check CSV text, not Parquet or Arrow", path.display()).into());
+    }
+    let records = if is_parquet(path) {
+        read_parquet_input(path)?
+    } else if is_arrow(path) {
+        read_arrow_input(path)?
+    } else if options.strict.is_some() || options.normalize.is_some() {
+        let contents = read_contents(path)?;
+        // The checks read the CSV text by column name
+        let mut contents = options.header_aliases.rename_csv(&contents).map_err(|e| e.in_file(path))?.into_owned();
+        if let Some(normalization) = &options.normalize {
+            let (normalized, report) = normalization.apply_csv(&contents)?;
+            report.log(path);
+            contents = normalized;
+        }
+        let mut violations = match &options.strict {
+            Some(allowed) => schema::validate(contents.as_slice(), allowed).map_err(|e| e.in_file(path))?,
+            None => Vec::new(),
+        };
+        // Mapped allergy columns stand in for the canonical ones
+        if let Some(allergens) = &options.allergens {
+            violations.retain(|violation| match violation {
+                Violation::MissingColumn(column) => !allergens.replaces(column),
+                Violation::UnmappedAllergenColumn(column) => !allergens.declares(column),
+                Violation::DisallowedValue { .. } => true,
+            });
+        }
+        if !violations.is_empty() {
+            return Err(Failure::Validation(format!("{}: {}", path.display(), schema::report(&violations))).into());
+        }
+        read_csv_from_reader(contents.as_slice())
+    } else if remote::is_remote(path) {
+        RecordStream::with_aliases(read_contents(path)?.as_slice(), &options.header_aliases)
+            .and_then(|records| records.collect())
+    } else {
+        read_csv_with_aliases(path, &options.header_aliases)
+    };
+    // Exits with the validation code, naming the file, line and column
+    let mut records = records.map_err(|e| e.in_file(path))?;
+    info!("Read {} records from {}", records.len(), path.display());
+    options.apply(&mut records).map_err(|e| Failure::Validation(format!("{}: {}", path.display(), e)))?;
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exclude_ids() {
+        let ids: Vec<String> = parse_id_list("205650\n\n# withdrawn 2024-03\n 205652 \n").collect();
+        assert_eq!(ids, vec!["205650", "205652"]);
+
+        let options = IngestOptions { exclude_ids: ids.into_iter().collect(), ..Default::default() };
+        let records = load_records(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv"),
+            &options,
+        )
+        .unwrap();
+        let kept: Vec<&str> = records.iter().map(|r| r.subject_id.as_str()).collect();
+        assert_eq!(kept, vec!["205651", "205653", "205654"]);
+    }
+
+    #[test]
+    fn test_strict_rejects_undeclared_values() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let mut allowed = AllowedValues::default();
+        allowed.columns.insert("site".to_string(), ["north", "south"].map(String::from).into());
+        let options = IngestOptions { strict: Some(allowed), ..Default::default() };
+        let error = load_records(path, &options).unwrap_err();
+        let failure = error.downcast_ref::<Failure>().unwrap();
+        assert_eq!(failure.code(), 3);
+        assert!(failure.to_string().ends_with("1 schema violation(s)\n  site has disallowed value 'east' on line(s) 5"));
+    }
+
+    #[test]
+    fn test_plausibility_reject_fails_validation() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let rules = PlausibilityRules { birth_years: 2012..=2020, ..Default::default() };
+        let options = IngestOptions { plausibility: Some(rules.clone()), ..Default::default() };
+        let error = load_records(path, &options).unwrap_err();
+        let failure = error.downcast_ref::<Failure>().unwrap();
+        assert_eq!(failure.code(), 3);
+        assert!(failure.to_string().contains("implausible value(s)"), "{}", failure);
+
+        let clamp = PlausibilityRules { policy: PlausibilityPolicy::Clamp, ..rules };
+        let options = IngestOptions { plausibility: Some(clamp), ..Default::default() };
+        assert!(load_records(path, &options).unwrap().iter().all(|r| r.birth_year >= 2012));
+    }
+
+    #[test]
+    fn test_on_invalid() {
+        // Subject 205653 has no allergies but is coded outside the defaults
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let mut codes = AllowedValues::default();
+        let races = ["R1 - Black", "R2 - Asian or Pacific Islander"];
+        codes.columns.insert("race_factor".to_string(), races.map(String::from).into());
+        let rejects = std::env::temp_dir().join(format!("rejects-{}.csv", std::process::id()));
+        let validation = RowValidation { on_invalid: OnInvalid::Fail, codes, rejects: Some(rejects.clone()) };
+        let options = IngestOptions { validation: Some(validation.clone()), ..Default::default() };
+        let error = load_records(path, &options).unwrap_err();
+        assert_eq!(error.downcast_ref::<Failure>().unwrap().code(), 3);
+        assert!(error.to_string().ends_with("2 invalid row(s) of 5: 2 unknown race_factor"), "{}", error);
+        let written = std::fs::read_to_string(&rejects).unwrap();
+        assert_eq!(written.lines().count(), 3);
+        assert!(written.contains(",unknown race_factor 'R0 - White',4,"));
+
+        let skip = RowValidation { on_invalid: OnInvalid::Skip, rejects: None, ..validation };
+        let options = IngestOptions { validation: Some(skip), ..Default::default() };
+        let kept: Vec<String> = load_records(path, &options).unwrap().into_iter().map(|r| r.subject_id).collect();
+        assert_eq!(kept, ["205651", "205652", "205654"]);
+        std::fs::remove_file(rejects).unwrap();
+    }
+
+    #[test]
+    fn test_normalization_precedes_strict() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let sites = [("N", "north"), ("S", "south"), ("E", "east")];
+        let declared = [("site".to_string(), sites.map(|(code, name)| (code.to_string(), vec![name.to_string()])).into())];
+        let mut allowed = AllowedValues::default();
+        allowed.columns.insert("site".to_string(), sites.map(|(code, _)| code.to_string()).into());
+        let options = IngestOptions {
+            normalize: Some(Normalization::new(declared.into()).unwrap()),
+            strict: Some(allowed),
+            ..Default::default()
+        };
+        let records = load_records(path, &options).unwrap();
+        let sites: Vec<&str> = records.iter().map(|r| r.extra["site"].as_str()).collect();
+        assert_eq!(sites, vec!["N", "S", "N", "E", "S"]);
+    }
+}
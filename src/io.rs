@@ -0,0 +1,310 @@
+//! Reading and writing records as CSV, gzip- or zstd-compressed or not, and
+//! reading them from Parquet and Arrow IPC files with the `parquet` and
+//! `arrow` features.
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use csv::{ReaderBuilder, StringRecord};
+use rayon::prelude::*;
+
+use crate::error::AllergyNetError;
+use crate::allergens::AllergenColumns;
+use crate::headers::HeaderAliases;
+use crate::progress;
+use crate::{Record, ALLERGENS, RECORD_COLUMNS};
+
+#[cfg(feature = "arrow")]
+pub mod arrow;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+pub mod sql;
+
+/// Whether `path` names a Parquet file, by its extension.
+pub fn is_parquet(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("parquet"))
+}
+
+/// Whether `path` names an Arrow IPC file, by its extension.
+pub fn is_arrow(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| {
+        ["arrow", "feather", "ipc"].iter().any(|arrow| extension.eq_ignore_ascii_case(arrow))
+    })
+}
+
+/// Whether `path` names a compressed file, by its extension: `.gz` for
+/// gzip or `.zst` for zstd.
+pub fn is_compressed(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| {
+        ["gz", "zst"].iter().any(|compressed| extension.eq_ignore_ascii_case(compressed))
+    })
+}
+
+/// `reader`, decompressed if `path` names a compressed file. Concatenated
+/// gzip members and zstd frames, as parallel compressors write, are read
+/// through.
+pub fn decompress<'a>(path: &Path, reader: impl io::Read + Send + 'a) -> io::Result<Box<dyn io::Read + Send + 'a>> {
+    let extension = path.extension().and_then(|extension| extension.to_str()).map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("gz") => Ok(Box::new(flate2::read::MultiGzDecoder::new(reader))),
+        #[cfg(not(target_arch = "wasm32"))]
+        Some("zst") => Ok(Box::new(zstd::Decoder::new(reader)?)),
+        #[cfg(target_arch = "wasm32")]
+        Some("zst") => Err(io::Error::new(io::ErrorKind::Unsupported, "zstd isn't supported in WebAssembly builds")),
+        _ => Ok(Box::new(reader)),
+    }
+}
+
+/// Reads records from a CSV file with a header row, decompressing a
+/// `.csv.gz` or `.csv.zst` as it goes. Headers are matched onto the
+/// canonical schema ignoring case, spaces and punctuation, and columns
+/// outside it are kept in each record's `extra`.
+pub fn read_csv(file_path: impl AsRef<Path>) -> Result<Vec<Record>, AllergyNetError> {
+    read_csv_with_aliases(file_path, &HeaderAliases::default())
+}
+
+/// Reads records from a CSV file as `read_csv` does, also matching headers
+/// through `aliases`.
+pub fn read_csv_with_aliases(
+    file_path: impl AsRef<Path>,
+    aliases: &HeaderAliases,
+) -> Result<Vec<Record>, AllergyNetError> {
+    let path = file_path.as_ref();
+    let file = File::open(path).map_err(|e| AllergyNetError::from(e).in_file(path))?;
+    // Progress is through the file as stored, compressed or not
+    let bar = progress::bytes(file.metadata().map_or(0, |metadata| metadata.len()), "reading");
+    let records = decompress(path, bar.wrap_read(file))
+        .map_err(AllergyNetError::from)
+        .and_then(|reader| RecordStream::with_aliases(reader, aliases))
+        .and_then(|records| records.collect());
+    bar.finish_and_clear();
+    records.map_err(|e| e.in_file(path))
+}
+
+/// Reads records from any CSV source, such as an in-memory string.
+pub fn read_csv_from_reader(reader: impl io::Read) -> Result<Vec<Record>, AllergyNetError> {
+    RecordStream::from_reader(reader)?.collect()
+}
+
+/// Rows split into fields before being deserialized together.
+#[cfg(not(test))]
+pub const CHUNK_ROWS: usize = 64 * 1024;
+/// Small enough for tests to cross chunk boundaries cheaply.
+#[cfg(test)]
+pub const CHUNK_ROWS: usize = 256;
+
+/// Records read from a CSV source as they are needed, for inputs too big
+/// to hold in memory; pass it to `create_graph_from_stream`. Rows are
+/// split into fields in order, a chunk of `CHUNK_ROWS` at a time, and each
+/// chunk's rows are deserialized in parallel. Bad rows are yielded as
+/// errors in their place.
+pub struct RecordStream<R> {
+    headers: StringRecord,
+    rows: csv::StringRecordsIntoIter<R>,
+    parsed: std::vec::IntoIter<Result<Record, AllergyNetError>>,
+    finished: bool,
+}
+
+impl RecordStream<Box<dyn io::Read + Send>> {
+    /// Streams the CSV file at `path`, decompressing it and matching its
+    /// headers as `read_csv` does.
+    pub fn open(file_path: impl AsRef<Path>) -> Result<Self, AllergyNetError> {
+        let path = file_path.as_ref();
+        let file = File::open(path).map_err(|e| AllergyNetError::from(e).in_file(path))?;
+        let reader = decompress(path, io::BufReader::new(file)).map_err(|e| AllergyNetError::from(e).in_file(path))?;
+        RecordStream::from_reader(reader).map_err(|e| e.in_file(path))
+    }
+}
+
+impl<R: io::Read> RecordStream<R> {
+    pub fn from_reader(reader: R) -> Result<Self, AllergyNetError> {
+        RecordStream::with_aliases(reader, &HeaderAliases::default())
+    }
+
+    /// Streams CSV from `reader`, also matching headers through `aliases`.
+    pub fn with_aliases(reader: R, aliases: &HeaderAliases) -> Result<Self, AllergyNetError> {
+        let mut reader = ReaderBuilder::new().from_reader(reader);
+        let headers = aliases.rename(reader.headers()?)?;
+        Ok(RecordStream { headers, rows: reader.into_records(), parsed: Vec::new().into_iter(), finished: false })
+    }
+
+    pub fn headers(&self) -> &StringRecord {
+        &self.headers
+    }
+
+    /// Reads the next chunk of rows and deserializes them.
+    fn fill(&mut self) {
+        let chunk: Vec<Result<StringRecord, csv::Error>> = self.rows.by_ref().take(CHUNK_ROWS).collect();
+        self.finished = chunk.len() < CHUNK_ROWS;
+        let headers = &self.headers;
+        let parsed: Vec<Result<Record, AllergyNetError>> =
+            chunk.into_par_iter().map(|row| record_from_row(headers, &row?)).collect();
+        self.parsed = parsed.into_iter();
+    }
+}
+
+impl<R: io::Read> Iterator for RecordStream<R> {
+    type Item = Result<Record, AllergyNetError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(record) = self.parsed.next() {
+            return Some(record);
+        }
+        if self.finished {
+            return None;
+        }
+        self.fill();
+        self.parsed.next()
+    }
+}
+
+/// Writes records as CSV in the canonical column order, followed by the
+/// onset and end of every other allergy any record has (as
+/// `AllergenColumns::canonical`), then every extra
+/// column any record has (blank where a record lacks it).
+pub fn write_csv(records: &[Record], writer: impl io::Write) -> Result<(), AllergyNetError> {
+    let others: BTreeSet<&str> = records.iter().flat_map(|r| r.other_allergies.keys().map(String::as_str)).collect();
+    let extra: BTreeSet<&str> = records.iter().flat_map(|r| r.extra.keys().map(String::as_str)).collect();
+    let mut out = csv::Writer::from_writer(writer);
+    let mut headers: Vec<String> = RECORD_COLUMNS.iter().map(|column| column.to_string()).collect();
+    for allergy in &others {
+        let columns = AllergenColumns::canonical(allergy);
+        headers.extend([columns.start, columns.end]);
+    }
+    headers.extend(extra.iter().map(|column| column.to_string()));
+    out.write_record(&headers)?;
+    let number = |value: Option<f64>| value.map(|v| v.to_string()).unwrap_or_default();
+    for record in records {
+        let mut row = vec![
+            record.subject_id.clone(),
+            record.birth_year.to_string(),
+            record.gender_factor.clone(),
+            record.race_factor.clone(),
+            record.ethnicity_factor.clone(),
+            record.payer_factor.clone(),
+            record.atopic_march_cohort.to_string(),
+            record.age_start_years.to_string(),
+            record.age_end_years.to_string(),
+        ];
+        for &allergy in ALLERGENS.iter().chain(&others) {
+            row.push(number(record.get_allergy_start(allergy)));
+            row.push(number(record.get_allergy_end(allergy)));
+        }
+        row.extend(extra.iter().map(|column| record.extra.get(*column).cloned().unwrap_or_default()));
+        out.write_record(&row)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Deserializes one row, keeping columns outside the schema in `extra`.
+pub fn record_from_row(headers: &StringRecord, row: &StringRecord) -> Result<Record, AllergyNetError> {
+    let mut record: Record = row.deserialize(Some(headers)).map_err(|e| {
+        let field = match e.kind() {
+            csv::ErrorKind::Deserialize { err, .. } => err.field(),
+            _ => None,
+        };
+        let mut error = AllergyNetError::from(e);
+        if let AllergyNetError::Csv { column, .. } = &mut error {
+            *column = field.and_then(|field| headers.get(field as usize)).map(String::from);
+        }
+        error
+    })?;
+    for (header, value) in headers.iter().zip(row.iter()) {
+        if !RECORD_COLUMNS.contains(&header) {
+            record.extra.insert(header.to_string(), value.to_string());
+        }
+    }
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csv_reading() {
+        let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let records = read_csv(file_path).unwrap();
+        assert!(!records.is_empty()); // Check that records are read
+        assert_eq!(records[0].extra.get("site").map(String::as_str), Some("north"));
+        assert!(!records[0].extra.contains_key("subject_id"));
+    }
+
+    #[test]
+    fn test_read_keeps_row_order_across_chunks() {
+        let cohort = crate::fixtures::cohort(100, 3);
+        let mut records = Vec::new();
+        while records.len() <= CHUNK_ROWS {
+            records.extend(cohort.iter().cloned());
+        }
+        for (i, record) in records.iter_mut().enumerate() {
+            record.subject_id = i.to_string();
+        }
+        let mut csv = Vec::new();
+        write_csv(&records, &mut csv).unwrap();
+        let read = read_csv_from_reader(csv.as_slice()).unwrap();
+        assert_eq!(read.len(), records.len());
+        assert!(read.iter().enumerate().all(|(i, record)| record.subject_id == i.to_string()));
+
+        // The first bad row is reported, although the rows are
+        // deserialized in parallel, and the stream carries on after it
+        let text = String::from_utf8(csv).unwrap();
+        let mut lines: Vec<String> = text.lines().map(String::from).collect();
+        for line in [CHUNK_ROWS + 1, CHUNK_ROWS + 5] {
+            let mut fields: Vec<&str> = lines[line - 1].split(',').collect();
+            fields[1] = "not-a-year";
+            lines[line - 1] = fields.join(",");
+        }
+        let text = lines.join("\n");
+        let error = read_csv_from_reader(text.as_bytes()).unwrap_err();
+        assert_eq!(error.line(), Some(CHUNK_ROWS as u64 + 1));
+        let stream = RecordStream::from_reader(text.as_bytes()).unwrap();
+        let lines: Vec<Option<u64>> =
+            stream.filter_map(Result::err).map(|error| error.line()).collect();
+        assert_eq!(lines, [Some(CHUNK_ROWS as u64 + 1), Some(CHUNK_ROWS as u64 + 5)]);
+    }
+
+    #[test]
+    fn test_compressed_csv() {
+        use std::io::Write;
+
+        let fixture = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let expected = read_csv(fixture).unwrap();
+        let contents = std::fs::read(fixture).unwrap();
+        // Two gzip members, as pigz or a split-and-join writes
+        let (head, tail) = contents.split_at(contents.len() / 2);
+        let mut gzip = Vec::new();
+        for part in [head, tail] {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(part).unwrap();
+            gzip.extend(encoder.finish().unwrap());
+        }
+        let zstd = zstd::encode_all(contents.as_slice(), 0).unwrap();
+
+        let dir = std::env::temp_dir();
+        for (name, compressed) in [("records.csv.gz", gzip), ("records.csv.ZST", zstd)] {
+            let path = dir.join(format!("allergy-net-{}-{}", std::process::id(), name));
+            std::fs::write(&path, &compressed).unwrap();
+            let records = read_csv(&path);
+            let streamed = RecordStream::open(&path).and_then(|records| records.collect::<Result<Vec<_>, _>>());
+            std::fs::remove_file(&path).unwrap();
+            assert!(is_compressed(&path));
+            assert_eq!(records.unwrap(), expected, "{}", name);
+            assert_eq!(streamed.unwrap(), expected, "{}", name);
+        }
+
+        // A truncated stream fails, naming the file
+        let path = dir.join(format!("allergy-net-{}-truncated.csv.gz", std::process::id()));
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&contents).unwrap();
+        let gzip = encoder.finish().unwrap();
+        std::fs::write(&path, &gzip[..gzip.len() - 12]).unwrap();
+        let error = read_csv(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.to_string().contains("truncated.csv.gz"), "{}", error);
+    }
+}
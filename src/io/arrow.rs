@@ -0,0 +1,219 @@
+//! Arrow IPC files (Feather v2): reading records from one, and writing
+//! result tables as one for pandas, Polars or Spark to pick up.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use arrow_array::cast::AsArray;
+use arrow_array::{Array, RecordBatch};
+use arrow_cast::cast;
+use arrow_ipc::reader::FileReader;
+use arrow_ipc::writer::FileWriter;
+use arrow_json::reader::infer_json_schema_from_iterator;
+use arrow_json::ReaderBuilder;
+use arrow_schema::{ArrowError, DataType, Schema};
+use csv::StringRecord;
+use serde::de::{IgnoredAny, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::error::AllergyNetError;
+use crate::headers::HeaderAliases;
+use crate::{record_from_row, Record};
+
+/// Reads records from an Arrow IPC file. Columns are matched by name as
+/// with CSV headers, and those outside the canonical schema are kept in
+/// each record's `extra`.
+pub fn read_arrow(file_path: impl AsRef<Path>) -> Result<Vec<Record>, AllergyNetError> {
+    let path = file_path.as_ref();
+    let file = File::open(path).map_err(|e| AllergyNetError::from(e).in_file(path))?;
+    read_arrow_from_reader(file).map_err(|e| e.in_file(path))
+}
+
+/// Reads records from any Arrow IPC file source, such as a downloaded
+/// object in a `Cursor`.
+pub fn read_arrow_from_reader(reader: impl Read + Seek) -> Result<Vec<Record>, AllergyNetError> {
+    let invalid = |e: ArrowError| AllergyNetError::Arrow { path: None, message: e.to_string() };
+    let mut records = Vec::new();
+    for batch in FileReader::try_new(reader, None).map_err(invalid)? {
+        records.extend(records_from_batch(&batch.map_err(invalid)?)?);
+    }
+    Ok(records)
+}
+
+/// The rows of `batch` as records. Columns are matched as CSV headers
+/// are, and every column is cast to text, so the CSV deserializer parses
+/// the values exactly as it would a file, as for DuckDB.
+pub(crate) fn records_from_batch(batch: &RecordBatch) -> Result<Vec<Record>, AllergyNetError> {
+    let schema = batch.schema();
+    let headers: StringRecord = schema.fields().iter().map(|field| field.name()).collect();
+    let headers = HeaderAliases::default().rename(&headers)?;
+    let columns = batch
+        .columns()
+        .iter()
+        .zip(schema.fields())
+        .map(|(column, field)| {
+            cast(column, &DataType::Utf8).map_err(|e| {
+                AllergyNetError::Schema(format!("column {} can't be read as text: {}", field.name(), e))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let columns: Vec<_> = columns.iter().map(|column| column.as_string::<i32>()).collect();
+    (0..batch.num_rows())
+        .map(|row| {
+            let values = columns.iter().map(|column| if column.is_null(row) { "" } else { column.value(row) });
+            record_from_row(&headers, &values.collect())
+        })
+        .collect()
+}
+
+/// Keys of a JSON object in the order written.
+struct Keys(Vec<String>);
+
+impl<'de> Deserialize<'de> for Keys {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct KeysVisitor;
+        impl<'de> Visitor<'de> for KeysVisitor {
+            type Value = Keys;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Keys, A::Error> {
+                let mut keys = Vec::new();
+                while let Some((key, IgnoredAny)) = map.next_entry::<String, IgnoredAny>()? {
+                    keys.push(key);
+                }
+                Ok(Keys(keys))
+            }
+        }
+        deserializer.deserialize_map(KeysVisitor)
+    }
+}
+
+/// Writes `rows` as an Arrow IPC file, with a column per field in field
+/// order and the types inferred from the values: whole numbers as 64-bit
+/// integers, other numbers as doubles, `None` as null. The provenance, if
+/// any, is kept as JSON in the schema's `provenance` metadata.
+pub fn write_arrow<T: Serialize>(
+    rows: &[T],
+    provenance: Option<&serde_json::Value>,
+    out: impl Write,
+) -> Result<(), AllergyNetError> {
+    let export = |e: &dyn fmt::Display| AllergyNetError::Export(e.to_string());
+    let texts = rows.iter().map(serde_json::to_string).collect::<Result<Vec<_>, _>>().map_err(|e| export(&e))?;
+    // serde_json sorts the keys of a `Value`, so the order comes from the text
+    let mut order: Vec<String> = Vec::new();
+    for text in &texts {
+        let Keys(keys) = serde_json::from_str(text).map_err(|e| export(&e))?;
+        for key in keys {
+            if !order.contains(&key) {
+                order.push(key);
+            }
+        }
+    }
+    let values = texts.iter().map(|text| {
+        serde_json::from_str::<serde_json::Value>(text).map_err(|e| ArrowError::JsonError(e.to_string()))
+    });
+    let inferred = infer_json_schema_from_iterator(values).map_err(|e| export(&e))?;
+    let fields: Vec<_> = order.iter().filter_map(|name| inferred.field_with_name(name).ok().cloned()).collect();
+    let metadata: HashMap<String, String> =
+        provenance.map(|provenance| ("provenance".to_string(), provenance.to_string())).into_iter().collect();
+    let schema = Arc::new(Schema::new(fields).with_metadata(metadata));
+
+    let mut decoder = ReaderBuilder::new(schema.clone()).build_decoder().map_err(|e| export(&e))?;
+    decoder.serialize(rows).map_err(|e| export(&e))?;
+    let mut writer = FileWriter::try_new(out, &schema).map_err(|e| export(&e))?;
+    if let Some(batch) = decoder.flush().map_err(|e| export(&e))? {
+        writer.write(&batch).map_err(|e| export(&e))?;
+    }
+    writer.finish().map_err(|e| export(&e))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Cursor;
+
+    use arrow_array::{ArrayRef, BooleanArray, Float64Array, Int32Array, Int64Array, StringArray};
+
+    use super::*;
+    use crate::read_csv;
+
+    /// The fixture as a batch with typed columns.
+    pub(crate) fn fixture_batch() -> RecordBatch {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let mut reader = csv::Reader::from_path(path).unwrap();
+        let headers = reader.headers().unwrap().clone();
+        let rows: Vec<StringRecord> = reader.records().map(Result::unwrap).collect();
+        let columns = headers.iter().enumerate().map(|(i, name)| {
+            let values = rows.iter().map(|row| Some(&row[i]).filter(|value| !value.is_empty()));
+            let column: ArrayRef = match name {
+                "birth_year" => Arc::new(values.map(|value| value.map(|v| v.parse().unwrap())).collect::<Int32Array>()),
+                "atopic_march_cohort" => {
+                    Arc::new(values.map(|value| value.map(|v| v.parse().unwrap())).collect::<BooleanArray>())
+                }
+                _ if name.starts_with("age_") || name.contains("_alg_") => {
+                    Arc::new(values.map(|value| value.map(|v| v.parse().unwrap())).collect::<Float64Array>())
+                }
+                // Ids, factors and the extra `site` column
+                _ => Arc::new(values.collect::<StringArray>()),
+            };
+            (name, column)
+        });
+        RecordBatch::try_from_iter(columns).unwrap()
+    }
+
+    #[test]
+    fn test_read_arrow() {
+        let batch = fixture_batch();
+        let mut out = Vec::new();
+        let mut writer = FileWriter::try_new(&mut out, &batch.schema()).unwrap();
+        writer.write(&batch).unwrap();
+        writer.finish().unwrap();
+        let expected = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        assert_eq!(read_arrow_from_reader(Cursor::new(out)).unwrap(), expected);
+
+        let error = read_arrow_from_reader(Cursor::new(b"subject_id\n205650\n".to_vec())).unwrap_err();
+        assert!(matches!(error, AllergyNetError::Arrow { .. }) && error.is_invalid_input(), "{}", error);
+    }
+
+    #[derive(Serialize)]
+    struct Row {
+        group: String,
+        n: Option<usize>,
+        mean: Option<f64>,
+        ci_lower: Option<f64>,
+        small_cell: bool,
+    }
+
+    #[test]
+    fn test_write_arrow() {
+        let rows = [
+            Row { group: "S0 - Male".to_string(), n: Some(3), mean: Some(1.5), ci_lower: None, small_cell: false },
+            Row { group: "S1 - Female".to_string(), n: None, mean: None, ci_lower: None, small_cell: true },
+        ];
+        let mut out = Vec::new();
+        write_arrow(&rows, Some(&serde_json::json!({ "version": "0.1.0" })), &mut out).unwrap();
+        let reader = FileReader::try_new(Cursor::new(out), None).unwrap();
+        let schema = reader.schema();
+        assert_eq!(schema.metadata()["provenance"], r#"{"version":"0.1.0"}"#);
+        // In field order, with the all-empty column as nulls
+        let types: Vec<(&str, &DataType)> =
+            schema.fields().iter().map(|field| (field.name().as_str(), field.data_type())).collect();
+        let expected = [
+            ("group", &DataType::Utf8),
+            ("n", &DataType::Int64),
+            ("mean", &DataType::Float64),
+            ("ci_lower", &DataType::Null),
+            ("small_cell", &DataType::Boolean),
+        ];
+        assert_eq!(types, expected);
+        let batch = reader.into_iter().next().unwrap().unwrap();
+        let n = batch.column(1).as_any().downcast_ref::<Int64Array>().unwrap();
+        assert_eq!((n.value(0), n.is_null(1)), (3, true));
+    }
+}
@@ -0,0 +1,82 @@
+//! Reading records from Parquet, for extracts that would lose their types
+//! or precision on the way through CSV.
+
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+
+use bytes::Bytes;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::file::reader::ChunkReader;
+
+use super::arrow::records_from_batch;
+use crate::error::AllergyNetError;
+use crate::progress;
+use crate::Record;
+
+/// Reads records from a Parquet file. Columns are matched by name as with
+/// CSV headers, and those outside the canonical schema are kept in each
+/// record's `extra`.
+pub fn read_parquet(file_path: impl AsRef<Path>) -> Result<Vec<Record>, AllergyNetError> {
+    let path = file_path.as_ref();
+    let file = File::open(path).map_err(|e| AllergyNetError::from(e).in_file(path))?;
+    read_records(file).map_err(|e| e.in_file(path))
+}
+
+/// Reads records from Parquet file contents, such as a downloaded object.
+pub fn read_parquet_bytes(contents: impl Into<Bytes>) -> Result<Vec<Record>, AllergyNetError> {
+    read_records(contents.into())
+}
+
+fn invalid(error: impl fmt::Display) -> AllergyNetError {
+    AllergyNetError::Parquet { path: None, message: error.to_string() }
+}
+
+fn read_records(reader: impl ChunkReader + 'static) -> Result<Vec<Record>, AllergyNetError> {
+    let builder = ParquetRecordBatchReaderBuilder::try_new(reader).map_err(invalid)?;
+    let rows = builder.metadata().file_metadata().num_rows();
+    let bar = progress::bar(rows.max(0) as u64, "reading");
+    let mut records = Vec::with_capacity(rows.max(0) as usize);
+    for batch in builder.build().map_err(invalid)? {
+        let batch = batch.map_err(invalid)?;
+        records.extend(records_from_batch(&batch)?);
+        bar.inc(batch.num_rows() as u64);
+    }
+    bar.finish_and_clear();
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use parquet::arrow::ArrowWriter;
+
+    use super::*;
+    use crate::io::arrow::tests::fixture_batch;
+    use crate::read_csv;
+
+    /// The fixture as a Parquet file with typed columns.
+    fn fixture_parquet() -> Vec<u8> {
+        let batch = fixture_batch();
+        let mut out = Vec::new();
+        let mut writer = ArrowWriter::try_new(&mut out, batch.schema(), None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+        out
+    }
+
+    #[test]
+    fn test_read_parquet() {
+        let expected = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let contents = fixture_parquet();
+        assert_eq!(read_parquet_bytes(contents.clone()).unwrap(), expected);
+
+        let path = std::env::temp_dir().join(format!("allergy-net-{}.parquet", std::process::id()));
+        std::fs::write(&path, &contents).unwrap();
+        let records = read_parquet(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.unwrap(), expected);
+
+        let error = read_parquet_bytes(b"subject_id\n205650\n".to_vec()).unwrap_err();
+        assert!(matches!(error, AllergyNetError::Parquet { .. }) && error.is_invalid_input(), "{}", error);
+    }
+}
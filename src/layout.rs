@@ -0,0 +1,270 @@
+//! Built-in drawing of the graph as SVG (`export --format svg`), for
+//! looking at a small-to-medium cohort without Graphviz or Gephi. Nodes
+//! are placed in columns (bipartite) or by a force-directed layout.
+
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+
+use clap::ValueEnum;
+use petgraph::graph::{DiGraph, NodeIndex};
+use petgraph::visit::EdgeRef;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::graph::{xml_escape, DOT_PALETTE};
+use crate::strata::Dimension;
+use crate::{EdgeWeight, Individual, NodeType};
+
+/// Gap between neighbouring nodes of a column, and around the drawing.
+const SPACING: f64 = 16.0;
+const MARGIN: f64 = 60.0;
+/// Size of the force-directed drawing.
+const FORCE_WIDTH: f64 = 900.0;
+const FORCE_HEIGHT: f64 = 700.0;
+/// Iterations of the force-directed layout.
+const ITERATIONS: usize = 300;
+/// Height of the legend above the drawing.
+const LEGEND_HEIGHT: f64 = 30.0;
+
+/// How the nodes are placed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Layout {
+    /// Individuals in one column and allergies in another, with
+    /// demographic nodes in a third on the left
+    #[default]
+    Bipartite,
+    /// Fruchterman–Reingold: linked nodes pull together and all nodes push
+    /// apart, seeded by --seed
+    Force,
+}
+
+/// Where each node of a graph goes, in graph order, on a `width` by
+/// `height` canvas.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Positions {
+    pub width: f64,
+    pub height: f64,
+    pub points: Vec<(f64, f64)>,
+}
+
+/// Places the nodes of `graph`. In the bipartite layout individuals are
+/// ordered by their value of `order_by`, so those coloured alike sit
+/// together; the force-directed layout starts from positions drawn from
+/// `seed`.
+pub fn positions(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    layout: Layout,
+    order_by: Option<&Dimension>,
+    seed: u64,
+) -> Positions {
+    match layout {
+        Layout::Bipartite => bipartite(graph, order_by),
+        Layout::Force => force_directed(graph, seed),
+    }
+}
+
+fn bipartite(graph: &DiGraph<NodeType, EdgeWeight>, order_by: Option<&Dimension>) -> Positions {
+    let mut columns: [Vec<usize>; 3] = Default::default();
+    for node in graph.node_indices() {
+        let column = match &graph[node] {
+            NodeType::Demographic { .. } => 0,
+            NodeType::Individual(_) => 1,
+            NodeType::AllergenStatus(_) => 2,
+        };
+        columns[column].push(node.index());
+    }
+    // Stable, so individuals of one value stay in graph order
+    columns[1].sort_by_key(|&node| match &graph[NodeIndex::new(node)] {
+        NodeType::Individual(individual) => order_by.and_then(|dimension| dimension.value_of(individual)),
+        NodeType::AllergenStatus(_) | NodeType::Demographic { .. } => None,
+    });
+    let tallest = columns.iter().map(Vec::len).max().unwrap_or(0);
+    let height = tallest as f64 * SPACING + 2.0 * MARGIN;
+    // Demographic labels sit left of their column
+    let xs = if columns[0].is_empty() {
+        [0.0, 2.0 * MARGIN, 6.0 * MARGIN]
+    } else {
+        [3.0 * MARGIN, 6.0 * MARGIN, 10.0 * MARGIN]
+    };
+    let mut points = vec![(0.0, 0.0); graph.node_count()];
+    for (column, x) in columns.iter().zip(xs) {
+        let top = (height - (column.len().max(1) - 1) as f64 * SPACING) / 2.0;
+        for (i, &node) in column.iter().enumerate() {
+            points[node] = (x, top + i as f64 * SPACING);
+        }
+    }
+    Positions { width: xs[2] + 2.0 * MARGIN, height, points }
+}
+
+fn force_directed(graph: &DiGraph<NodeType, EdgeWeight>, seed: u64) -> Positions {
+    let n = graph.node_count();
+    let mut rng = StdRng::seed_from_u64(seed);
+    let (left, right, top, bottom) = (MARGIN, FORCE_WIDTH - MARGIN, MARGIN, FORCE_HEIGHT - MARGIN);
+    let mut points: Vec<(f64, f64)> =
+        (0..n).map(|_| (rng.gen_range(left..right), rng.gen_range(top..bottom))).collect();
+    // Ideal distance between nodes
+    let k = ((right - left) * (bottom - top) / n.max(1) as f64).sqrt();
+    let start = (right - left) / 10.0;
+    for iteration in 0..ITERATIONS {
+        let mut moves = vec![(0.0, 0.0); n];
+        for i in 0..n {
+            for j in i + 1..n {
+                let (dx, dy) = (points[i].0 - points[j].0, points[i].1 - points[j].1);
+                let distance = dx.hypot(dy).max(0.01);
+                let push = k * k / distance / distance;
+                moves[i] = (moves[i].0 + dx * push, moves[i].1 + dy * push);
+                moves[j] = (moves[j].0 - dx * push, moves[j].1 - dy * push);
+            }
+        }
+        for edge in graph.edge_references() {
+            let (a, b) = (edge.source().index(), edge.target().index());
+            let (dx, dy) = (points[a].0 - points[b].0, points[a].1 - points[b].1);
+            let pull = dx.hypot(dy) / k;
+            moves[a] = (moves[a].0 - dx * pull, moves[a].1 - dy * pull);
+            moves[b] = (moves[b].0 + dx * pull, moves[b].1 + dy * pull);
+        }
+        // Each node moves at most the temperature, which cools to 0
+        let temperature = start * (1.0 - iteration as f64 / ITERATIONS as f64);
+        for (point, (dx, dy)) in points.iter_mut().zip(moves) {
+            let length = dx.hypot(dy).max(0.01);
+            let step = length.min(temperature) / length;
+            point.0 = (point.0 + dx * step).clamp(left, right);
+            point.1 = (point.1 + dy * step).clamp(top, bottom);
+        }
+    }
+    Positions { width: FORCE_WIDTH, height: FORCE_HEIGHT, points }
+}
+
+/// Writes the graph as a standalone SVG drawing, laid out by `layout`.
+/// Individuals are circles filled by their value of `color_by`, with a
+/// legend of the values, and their subject id shown on hover. Allergies
+/// are grey boxes labelled with their name, and demographic nodes white
+/// diamonds labelled with their value. Resolved allergies are dashed
+/// edges and demographic edges dotted, as in DOT. `metadata` is written
+/// as JSON in the drawing's `desc`.
+pub fn export_svg(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    layout: Layout,
+    color_by: Option<&Dimension>,
+    seed: u64,
+    metadata: &serde_json::Value,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let value_of = |individual: &Individual| {
+        color_by.map(|dimension| dimension.value_of(individual).unwrap_or_else(|| "(missing)".to_string()))
+    };
+    let values: BTreeSet<String> = graph
+        .node_weights()
+        .filter_map(|node| match node {
+            NodeType::Individual(individual) => value_of(individual),
+            NodeType::AllergenStatus(_) | NodeType::Demographic { .. } => None,
+        })
+        .collect();
+    let color = |value: &str| DOT_PALETTE[values.iter().position(|v| v == value).unwrap_or(0) % DOT_PALETTE.len()];
+    let Positions { width, height, points } = positions(graph, layout, color_by, seed);
+    let shift = if color_by.is_some() { LEGEND_HEIGHT } else { 0.0 };
+    let at = |node: usize| (points[node].0, points[node].1 + shift);
+
+    write!(out, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{:.0}" height="{:.0}""#, width, height + shift)?;
+    writeln!(out, r#" font-family="Helvetica, sans-serif" font-size="11">"#)?;
+    writeln!(out, "<desc>{}</desc>", xml_escape(&metadata.to_string()))?;
+    writeln!(out, r#"<rect width="100%" height="100%" fill="white"/>"#)?;
+    writeln!(out, r##"<g stroke="#999" stroke-opacity="0.7">"##)?;
+    for edge in graph.edge_references() {
+        let ((x1, y1), (x2, y2)) = (at(edge.source().index()), at(edge.target().index()));
+        let dash = match (&graph[edge.target()], edge.weight().end) {
+            (NodeType::AllergenStatus(_), Some(_)) => r#" stroke-dasharray="4 3""#,
+            (NodeType::AllergenStatus(_), None) => "",
+            _ => r#" stroke-dasharray="1 3""#,
+        };
+        writeln!(out, r#"<line x1="{:.1}" y1="{:.1}" x2="{:.1}" y2="{:.1}"{}/>"#, x1, y1, x2, y2, dash)?;
+    }
+    writeln!(out, "</g>")?;
+    for node in graph.node_indices() {
+        let (x, y) = at(node.index());
+        match &graph[node] {
+            NodeType::Individual(individual) => {
+                let fill = value_of(individual).map_or(DOT_PALETTE[0], |value| color(&value));
+                writeln!(
+                    out,
+                    r#"<circle cx="{:.1}" cy="{:.1}" r="5" fill="{}" stroke="white"><title>{}</title></circle>"#,
+                    x,
+                    y,
+                    fill,
+                    xml_escape(&individual.id)
+                )?;
+            }
+            NodeType::AllergenStatus(name) => {
+                let box_width = name.chars().count() as f64 * 6.5 + 10.0;
+                writeln!(
+                    out,
+                    r##"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="16" fill="lightgrey" stroke="#666"/>"##,
+                    x - box_width / 2.0,
+                    y - 8.0,
+                    box_width
+                )?;
+                let label = xml_escape(name);
+                writeln!(out, r#"<text x="{:.1}" y="{:.1}" text-anchor="middle">{}</text>"#, x, y + 4.0, label)?;
+            }
+            NodeType::Demographic { value, .. } => {
+                let corners = [(x, y - 7.0), (x + 7.0, y), (x, y + 7.0), (x - 7.0, y)];
+                let corners: Vec<String> = corners.iter().map(|(x, y)| format!("{:.1},{:.1}", x, y)).collect();
+                writeln!(out, r#"<polygon points="{}" fill="white" stroke="black"/>"#, corners.join(" "))?;
+                let label = xml_escape(value);
+                writeln!(out, r#"<text x="{:.1}" y="{:.1}" text-anchor="end">{}</text>"#, x - 10.0, y + 4.0, label)?;
+            }
+        }
+    }
+    if let Some(dimension) = color_by {
+        writeln!(out, r#"<g font-size="12">"#)?;
+        writeln!(out, r#"<text x="10" y="20">{}:</text>"#, xml_escape(dimension.label()))?;
+        let mut x = 20.0 + dimension.label().chars().count() as f64 * 7.0;
+        for value in &values {
+            writeln!(out, r#"<circle cx="{:.1}" cy="16" r="5" fill="{}"/>"#, x, color(value))?;
+            writeln!(out, r#"<text x="{:.1}" y="20">{}</text>"#, x + 9.0, xml_escape(value))?;
+            x += value.chars().count() as f64 * 7.0 + 30.0;
+        }
+        writeln!(out, "</g>")?;
+    }
+    writeln!(out, "</svg>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    #[test]
+    fn test_layouts() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        // Nine allergies then five individuals, ordered by race: 205650
+        // and 205653 (R0) before 205651 and 205654 (R1) before 205652
+        let bipartite = positions(&graph, Layout::Bipartite, Some(&Dimension::Race), 0);
+        let ys: Vec<f64> = [9, 12, 10, 13, 11].iter().map(|&node| bipartite.points[node].1).collect();
+        assert!(ys.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", ys);
+        assert!(bipartite.points[9].0 < bipartite.points[0].0);
+
+        let force = positions(&graph, Layout::Force, None, 7);
+        assert_eq!(force, positions(&graph, Layout::Force, None, 7));
+        let inside = |&(x, y): &(f64, f64)| (0.0..=force.width).contains(&x) && (0.0..=force.height).contains(&y);
+        assert!(force.points.iter().all(inside));
+        // 205654 sits nearer its own allergy, Pistachio, than 205652 does
+        let distance = |a: usize, b: usize| {
+            (force.points[a].0 - force.points[b].0).hypot(force.points[a].1 - force.points[b].1)
+        };
+        assert!(distance(13, 4) < distance(11, 4));
+
+        let mut out = Vec::new();
+        let metadata = serde_json::json!({ "unit": "record" });
+        export_svg(&graph, Layout::Bipartite, Some(&Dimension::Race), 0, &metadata, &mut out).unwrap();
+        let svg = String::from_utf8(out).unwrap();
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""));
+        assert!(svg.contains("<desc>{&quot;unit&quot;:&quot;record&quot;}</desc>"));
+        assert_eq!(svg.matches("<line ").count(), graph.edge_count());
+        assert_eq!(svg.matches("stroke-dasharray=\"4 3\"").count(), 1, "only 205650's peanut allergy resolved");
+        assert!(svg.contains(">Peanut</text>") && svg.contains("<title>205650</title>"));
+        assert!(svg.contains("<text x=\"10\" y=\"20\">race:</text>"));
+        assert!(svg.trim_end().ends_with("</svg>"));
+    }
+}
@@ -0,0 +1,281 @@
+//! Network analysis of nut allergy prevalence across demographic and
+//! clinical cohorts. Individuals and allergens are nodes of a graph, and
+//! group-average degree centrality measures how allergies spread across
+//! demographics.
+//!
+//! The core steps are public for embedding: [`io::read_csv`] loads
+//! records, [`graph::create_graph`] builds the graph and
+//! [`metrics::calculate_centrality`] computes the stratified report, which
+//! [`metrics::CentralityReport::write`] formats. They are re-exported at
+//! the crate root.
+
+pub mod allergens;
+pub mod audit;
+pub mod centrality;
+pub mod checksum;
+pub mod cohort;
+pub mod columns;
+pub mod disclosure;
+#[cfg(feature = "polars")]
+pub mod dataframe;
+#[cfg(feature = "duckdb")]
+pub mod duckdb_io;
+pub mod error;
+pub mod exit;
+pub mod fhir;
+pub mod fixtures;
+pub mod graph;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod headers;
+pub mod html;
+pub mod ingest;
+pub mod io;
+pub mod layout;
+pub mod manifest;
+#[cfg(feature = "neo4j")]
+pub mod neo4j;
+#[cfg(feature = "matrix")]
+pub mod matrix;
+pub mod metrics;
+pub mod normalize;
+pub mod notebook;
+pub mod plausibility;
+pub mod privacy;
+pub mod profiling;
+pub mod progress;
+pub mod projection;
+pub mod quality;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod remote;
+pub mod schema;
+pub mod stats;
+pub mod strata;
+pub mod stream;
+pub mod tables;
+pub mod taxonomy;
+pub mod validation;
+pub mod verify;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use ingest::IngestOptions;
+use strata::AgeBins;
+
+pub use error::AllergyNetError;
+pub use graph::{
+    create_graph, create_graph_from_stream, filter_individuals, graph_from_binary, graph_from_node_link, node_link_json,
+    subgraph, EdgeWeight, GraphMode, GraphOptions, GraphSummary,
+};
+pub use io::{read_csv, read_csv_from_reader, record_from_row, write_csv, RecordStream};
+pub use metrics::{
+    calculate_centrality, calculate_metric, calculate_weighted_centrality, centrality_results, check_dimensions,
+    check_grouping_columns, emit_json, CentralityReport, GroupScore, Metric, NodeScore, OutputFormat, ReportOptions,
+    Show,
+};
+pub use projection::{project_allergies, project_individuals};
+
+/// One row of the input: a subject's demographics, observation window and
+/// the onset and end age of each nut allergy.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct Record {
+    pub subject_id: String,
+    pub birth_year: i32,
+    pub gender_factor: String,
+    pub race_factor: String,
+    pub ethnicity_factor: String,
+    pub payer_factor: String,
+    pub atopic_march_cohort: bool,
+    pub age_start_years: f64,
+    pub age_end_years: f64,
+    pub peanut_alg_start: Option<f64>,
+    pub peanut_alg_end: Option<f64>,
+    pub treenut_alg_start: Option<f64>,
+    pub treenut_alg_end: Option<f64>,
+    pub walnut_alg_start: Option<f64>,
+    pub walnut_alg_end: Option<f64>,
+    pub pecan_alg_start: Option<f64>,
+    pub pecan_alg_end: Option<f64>,
+    pub pistach_alg_start: Option<f64>,
+    pub pistach_alg_end: Option<f64>,
+    pub almond_alg_start: Option<f64>,
+    pub almond_alg_end: Option<f64>,
+    pub brazil_alg_start: Option<f64>,
+    pub brazil_alg_end: Option<f64>,
+    pub hazelnut_alg_start: Option<f64>,
+    pub hazelnut_alg_end: Option<f64>,
+    pub cashew_alg_start: Option<f64>,
+    pub cashew_alg_end: Option<f64>,
+    /// Columns outside the canonical schema, keyed by header.
+    #[serde(skip)]
+    pub extra: BTreeMap<String, String>,
+    /// Onset and end of allergies beyond the nine nuts, declared in an
+    /// `allergens::AllergenMap`, by allergen.
+    #[serde(skip)]
+    pub other_allergies: BTreeMap<String, (Option<f64>, Option<f64>)>,
+}
+
+/// Header names of the canonical `Record` schema.
+pub const RECORD_COLUMNS: &[&str] = &[
+    "subject_id", "birth_year", "gender_factor", "race_factor", "ethnicity_factor",
+    "payer_factor", "atopic_march_cohort", "age_start_years", "age_end_years",
+    "peanut_alg_start", "peanut_alg_end", "treenut_alg_start", "treenut_alg_end",
+    "walnut_alg_start", "walnut_alg_end", "pecan_alg_start", "pecan_alg_end",
+    "pistach_alg_start", "pistach_alg_end", "almond_alg_start", "almond_alg_end",
+    "brazil_alg_start", "brazil_alg_end", "hazelnut_alg_start", "hazelnut_alg_end",
+    "cashew_alg_start", "cashew_alg_end",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Individual {
+    pub id: String,
+    pub gender: String,
+    pub race: String,
+    pub ethnicity: String,
+    pub payer_factor: String,
+    /// `None` once de-identification drops it.
+    pub atopic_march_cohort: Option<bool>,
+    /// Midpoint of the observation window, in years.
+    pub age: f64,
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl From<&Record> for Individual {
+    fn from(record: &Record) -> Self {
+        Individual {
+            id: record.subject_id.clone(),
+            gender: record.gender_factor.clone(),
+            race: record.race_factor.clone(),
+            ethnicity: record.ethnicity_factor.clone(),
+            payer_factor: record.payer_factor.clone(),
+            atopic_march_cohort: Some(record.atopic_march_cohort),
+            age: (record.age_start_years + record.age_end_years) / 2.0,
+            attributes: record.extra.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum NodeType {
+    Individual(Individual),
+    AllergenStatus(String),
+    /// A value of a demographic dimension in the tripartite graph
+    /// (`GraphMode::Tripartite`), with an edge to each individual who has
+    /// it.
+    Demographic { dimension: String, value: String },
+}
+
+/// What an individual node stands for when a subject has several rows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Unit {
+    /// Every row is an individual, so a subject with several rows is
+    /// counted once per row.
+    #[default]
+    Record,
+    /// A subject's rows are merged into one individual before the graph is
+    /// built (see `quality::merge_subjects`).
+    Subject,
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.to_possible_value().expect("no skipped variants").get_name())
+    }
+}
+
+/// Everything shared by the commands besides their own arguments.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub age_bins: AgeBins,
+    pub ingest: IngestOptions,
+    pub graph: GraphOptions,
+    pub report: ReportOptions,
+    /// De-identification of exported graphs.
+    pub export: privacy::Deidentify,
+}
+
+/// Allergen node names, in the order `create_graph` adds them.
+pub const ALLERGENS: &[&str] = &[
+    "Peanut", "Treenut", "Walnut", "Pecan", "Pistachio", "Almond", "Brazil",
+    "Hazelnut", "Cashew",
+];
+
+/// Common food allergens beyond the nuts, named as `--allergen` takes
+/// them. None is an allergen node unless declared.
+pub const FOOD_ALLERGENS: &[&str] = &["Egg", "Milk", "Soy", "Wheat", "Shellfish", "Sesame"];
+
+impl Record {
+    pub fn get_allergy_start(&self, allergy: &str) -> Option<f64> {
+        match allergy {
+            "Peanut" => self.peanut_alg_start,
+            "Treenut" => self.treenut_alg_start,
+            "Walnut" => self.walnut_alg_start,
+            "Pecan" => self.pecan_alg_start,
+            "Pistachio" => self.pistach_alg_start,
+            "Almond" => self.almond_alg_start,
+            "Brazil" => self.brazil_alg_start,
+            "Hazelnut" => self.hazelnut_alg_start,
+            "Cashew" => self.cashew_alg_start,
+            other => self.other_allergies.get(other).and_then(|&(start, _)| start),
+        }
+    }
+
+    pub fn get_allergy_end(&self, allergy: &str) -> Option<f64> {
+        match allergy {
+            "Peanut" => self.peanut_alg_end,
+            "Treenut" => self.treenut_alg_end,
+            "Walnut" => self.walnut_alg_end,
+            "Pecan" => self.pecan_alg_end,
+            "Pistachio" => self.pistach_alg_end,
+            "Almond" => self.almond_alg_end,
+            "Brazil" => self.brazil_alg_end,
+            "Hazelnut" => self.hazelnut_alg_end,
+            "Cashew" => self.cashew_alg_end,
+            other => self.other_allergies.get(other).and_then(|&(_, end)| end),
+        }
+    }
+
+    pub fn set_allergy_start(&mut self, allergy: &str, onset: f64) {
+        let field = match allergy {
+            "Peanut" => &mut self.peanut_alg_start,
+            "Treenut" => &mut self.treenut_alg_start,
+            "Walnut" => &mut self.walnut_alg_start,
+            "Pecan" => &mut self.pecan_alg_start,
+            "Pistachio" => &mut self.pistach_alg_start,
+            "Almond" => &mut self.almond_alg_start,
+            "Brazil" => &mut self.brazil_alg_start,
+            "Hazelnut" => &mut self.hazelnut_alg_start,
+            "Cashew" => &mut self.cashew_alg_start,
+            other => &mut self.other_allergies.entry(other.to_string()).or_default().0,
+        };
+        *field = Some(onset);
+    }
+
+    pub fn set_allergy_end(&mut self, allergy: &str, end: f64) {
+        let field = match allergy {
+            "Peanut" => &mut self.peanut_alg_end,
+            "Treenut" => &mut self.treenut_alg_end,
+            "Walnut" => &mut self.walnut_alg_end,
+            "Pecan" => &mut self.pecan_alg_end,
+            "Pistachio" => &mut self.pistach_alg_end,
+            "Almond" => &mut self.almond_alg_end,
+            "Brazil" => &mut self.brazil_alg_end,
+            "Hazelnut" => &mut self.hazelnut_alg_end,
+            "Cashew" => &mut self.cashew_alg_end,
+            other => &mut self.other_allergies.entry(other.to_string()).or_default().1,
+        };
+        *field = Some(end);
+    }
+
+    /// The nine nuts, then the other allergies this record has an onset
+    /// or end for.
+    pub fn allergies(&self) -> impl Iterator<Item = &str> {
+        ALLERGENS.iter().copied().chain(self.other_allergies.keys().map(String::as_str))
+    }
+}
@@ -0,0 +1,226 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use log::info;
+use serde::Deserialize;
+
+use crate::cohort::{CohortDef, CohortFile};
+use crate::exit::Failure;
+use crate::ingest::load_records;
+use crate::remote::{self, Destination};
+use crate::strata::{apply_age_bins, AgeBins, Grouping, DEFAULT_DIMENSIONS};
+use crate::{
+    calculate_metric, check_dimensions, create_graph, filter_individuals, Metric,
+    NodeType, OutputFormat, Settings,
+};
+
+/// A batch of analyses sharing one input, e.g.
+///
+/// ```yaml
+/// input: data/cohort.csv
+/// cohort_def: cohorts.yml
+/// analyses:
+///   - name: medicaid_by_race
+///     cohort: young_medicaid
+///     stratify_by: [race, payer]
+///     output: reports/medicaid_by_race.txt
+///   - name: atopic_march
+///     filter:
+///       include:
+///         cohort: ["true"]
+/// ```
+///
+/// Relative paths are resolved against the manifest's directory; `input`,
+/// `exclude_ids` and `output` may also be `s3://` or `gs://` locations.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub input: PathBuf,
+    pub cohort_def: Option<PathBuf>,
+    /// Subject ids to drop, in addition to any given on the command line.
+    pub exclude_ids: Option<PathBuf>,
+    pub analyses: Vec<AnalysisSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnalysisSpec {
+    pub name: String,
+    /// Named cohort from the manifest's `cohort_def` file.
+    pub cohort: Option<String>,
+    /// Inline criteria, combined with `cohort` when both are given.
+    pub filter: Option<CohortDef>,
+    #[serde(default = "default_metrics")]
+    pub metrics: Vec<Metric>,
+    /// Groupings as on the command line, e.g. `race` or `race*payer`.
+    #[serde(default)]
+    pub stratify_by: Vec<String>,
+    /// Report destination; stdout when omitted.
+    pub output: Option<PathBuf>,
+}
+
+fn default_metrics() -> Vec<Metric> {
+    vec![Metric::Degree]
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(path)
+            .map_err(|e| format!("cannot open manifest {}: {}", path.display(), e))?;
+        let mut manifest: Manifest = serde_yaml::from_reader(file)
+            .map_err(|e| format!("invalid manifest {}: {}", path.display(), e))?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        manifest.input = resolve(base, manifest.input);
+        manifest.cohort_def = manifest.cohort_def.map(|p| resolve(base, p));
+        manifest.exclude_ids = manifest.exclude_ids.map(|p| resolve(base, p));
+        for analysis in manifest.analyses.iter_mut() {
+            analysis.output = analysis.output.take().map(|p| resolve(base, p));
+        }
+        Ok(manifest)
+    }
+}
+
+/// `path` relative to `base`, unless it is absolute or a bucket location.
+fn resolve(base: &Path, path: PathBuf) -> PathBuf {
+    if remote::is_remote(&path) {
+        path
+    } else {
+        base.join(path)
+    }
+}
+
+impl AnalysisSpec {
+    fn groupings(&self, age_bins: &AgeBins) -> Result<Vec<Grouping>, String> {
+        let mut groupings = if self.stratify_by.is_empty() {
+            DEFAULT_DIMENSIONS.split(',').map(str::parse).collect::<Result<Vec<_>, _>>()?
+        } else {
+            self.stratify_by.iter().map(|d| d.parse()).collect::<Result<Vec<_>, _>>()?
+        };
+        apply_age_bins(&mut groupings, age_bins);
+        Ok(groupings)
+    }
+}
+
+/// Loads the manifest's input once and runs each analysis on a filtered
+/// view of the resulting graph.
+pub fn run(manifest_path: &Path, settings: &Settings) -> Result<(), Box<dyn Error>> {
+    let age_bins = &settings.age_bins;
+    let manifest = Manifest::load(manifest_path)?;
+    let cohorts = match &manifest.cohort_def {
+        Some(path) => Some(CohortFile::load(&path.to_string_lossy())?),
+        None => None,
+    };
+
+    // Resolve everything up front so a typo fails before the expensive load.
+    let mut plans = Vec::new();
+    for analysis in &manifest.analyses {
+        let cohort = match (&analysis.cohort, &cohorts) {
+            (Some(name), Some(file)) => Some(file.get(name)?),
+            (Some(name), None) => {
+                return Err(format!("analysis {} uses cohort {} but the manifest has no cohort_def", analysis.name, name).into())
+            }
+            (None, _) => None,
+        };
+        plans.push((analysis, cohort, analysis.groupings(age_bins)?));
+    }
+
+    let mut ingest = settings.ingest.clone();
+    if let Some(path) = &manifest.exclude_ids {
+        ingest.exclude_ids_from(path)?;
+    }
+    let records = load_records(&manifest.input, &ingest)?;
+    let columns: Vec<String> = records.first().map(|r| r.extra.keys().cloned().collect()).unwrap_or_default();
+    for (analysis, cohort, groupings) in &plans {
+        check_dimensions(&records, groupings)?;
+        if let (Some(name), Some(cohort)) = (&analysis.cohort, cohort) {
+            cohort.check_keys(&columns).map_err(|e| format!("cohort {}: {}", name, e))?;
+        }
+    }
+    let graph = create_graph(records, &settings.graph);
+
+    // Remaining analyses still run when one comes up empty or small; the
+    // most severe condition decides the exit code afterwards.
+    let mut empty = Vec::new();
+    let mut small_cells = 0;
+    for (analysis, cohort, groupings) in plans {
+        let subgraph = filter_individuals(&graph, |individual| {
+            cohort.as_ref().is_none_or(|c| c.matches(individual, age_bins))
+                && analysis.filter.as_ref().is_none_or(|f| f.matches(individual, age_bins))
+        });
+        info!("Analysis {}: {} nodes", analysis.name, subgraph.node_count());
+        if subgraph.node_weights().all(|node| !matches!(node, NodeType::Individual(_))) {
+            empty.push(analysis.name.clone());
+            continue;
+        }
+        let mut options = settings.report.clone();
+        if let (Some(name), Some(cohort)) = (&analysis.cohort, &cohort) {
+            options.filters.push(format!("cohort {}: {}", name, cohort.describe()));
+        }
+        if let Some(filter) = &analysis.filter {
+            options.filters.push(filter.describe());
+        }
+
+        let mut destination = analysis.output.as_deref().map(Destination::create).transpose()?;
+        if destination.is_none() && options.format == OutputFormat::Text {
+            println!("== {} ==", analysis.name);
+        }
+        let mut stdout = io::stdout().lock();
+        let out: &mut dyn Write = match &mut destination {
+            Some(destination) => destination,
+            None => &mut stdout,
+        };
+        let ids = match options.format {
+            OutputFormat::Ndjson => Some(settings.export.ids(&subgraph)?.0),
+            OutputFormat::Text => None,
+        };
+        for (&metric, options) in analysis.metrics.iter().zip(options.per_metric(analysis.metrics.len())) {
+            let mut report = calculate_metric(&subgraph, &groupings, metric, &options)?;
+            if let Some(ids) = &ids {
+                report.deidentify(ids, settings.export.ids);
+            }
+            small_cells += report.small_cells();
+            report.write(&options, out)?;
+        }
+        out.flush()?;
+        if let Some(destination) = destination {
+            destination.finish()?;
+        }
+    }
+    if !empty.is_empty() {
+        return Err(Failure::EmptyCohort(format!("analyses {}", empty.join(", "))).into());
+    }
+    if small_cells > 0 {
+        return Err(Failure::SmallCells(small_cells).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_run_manifest_writes_each_output() {
+        let dir = std::env::temp_dir().join(format!("allergy-manifest-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let fixture = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let manifest = format!(
+            "input: {}\nanalyses:\n  - name: medicaid\n    filter:\n      include:\n        payer: [\"P1 - Medicaid\"]\n    stratify_by: [site]\n    output: out/medicaid.txt\n  - name: all\n    stratify_by: [gender]\n    output: out/all.txt\n",
+            fixture
+        );
+        let manifest_path = dir.join("manifest.yml");
+        fs::write(&manifest_path, manifest).unwrap();
+
+        run(&manifest_path, &Settings::default()).unwrap();
+
+        let medicaid = fs::read_to_string(dir.join("out/medicaid.txt")).unwrap();
+        assert!(medicaid.contains("Average degree centrality for site south: 2.5"));
+        assert!(!medicaid.contains("site east"));
+        let all = fs::read_to_string(dir.join("out/all.txt")).unwrap();
+        assert!(all.contains("Average degree centrality for gender S0 - Male"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
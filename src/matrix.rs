@@ -0,0 +1,130 @@
+//! The network as matrices, for running custom linear algebra on it.
+//!
+//! The incidence matrix has one row per individual and one column per
+//! allergen, with a 1 where the individual has that allergy. Projections
+//! multiply it by its transpose, so entry `(i, j)` counts the allergens two
+//! individuals share (or the individuals two allergens share); the
+//! diagonal is zeroed.
+
+use std::collections::HashMap;
+
+use ndarray::Array2;
+use petgraph::graph::{DiGraph, NodeIndex};
+use sprs::{CsMat, TriMat};
+
+use crate::{EdgeWeight, NodeType};
+
+/// Row and column labels of the matrices built from one graph.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatrixLabels {
+    /// Subject ids, in row order.
+    pub individuals: Vec<String>,
+    /// Allergen names, in column order.
+    pub allergens: Vec<String>,
+}
+
+/// Which side of the bipartite graph a projection keeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Individuals,
+    Allergens,
+}
+
+/// Labels plus the `(row, column)` of every allergy edge.
+fn incidence_entries(graph: &DiGraph<NodeType, EdgeWeight>) -> (MatrixLabels, Vec<(usize, usize)>) {
+    let mut labels = MatrixLabels { individuals: Vec::new(), allergens: Vec::new() };
+    let mut position: HashMap<NodeIndex, usize> = HashMap::new();
+    for node in graph.node_indices() {
+        match &graph[node] {
+            NodeType::Individual(individual) => {
+                position.insert(node, labels.individuals.len());
+                labels.individuals.push(individual.id.clone());
+            }
+            NodeType::AllergenStatus(name) => {
+                position.insert(node, labels.allergens.len());
+                labels.allergens.push(name.clone());
+            }
+            NodeType::Demographic { .. } => {}
+        }
+    }
+    let entries = graph
+        .edge_indices()
+        .filter_map(|edge| graph.edge_endpoints(edge))
+        .filter(|(source, target)| {
+            matches!((&graph[*source], &graph[*target]), (NodeType::Individual(_), NodeType::AllergenStatus(_)))
+        })
+        .map(|(source, target)| (position[&source], position[&target]))
+        .collect();
+    (labels, entries)
+}
+
+pub fn incidence_matrix(graph: &DiGraph<NodeType, EdgeWeight>) -> (MatrixLabels, Array2<f64>) {
+    let (labels, entries) = incidence_entries(graph);
+    let mut matrix = Array2::zeros((labels.individuals.len(), labels.allergens.len()));
+    for (row, column) in entries {
+        matrix[[row, column]] = 1.0;
+    }
+    (labels, matrix)
+}
+
+pub fn sparse_incidence_matrix(graph: &DiGraph<NodeType, EdgeWeight>) -> (MatrixLabels, CsMat<f64>) {
+    let (labels, entries) = incidence_entries(graph);
+    let mut triplets = TriMat::new((labels.individuals.len(), labels.allergens.len()));
+    for (row, column) in entries {
+        triplets.add_triplet(row, column, 1.0);
+    }
+    (labels, triplets.to_csr())
+}
+
+/// Dense one-mode projection onto `side`.
+pub fn projected_adjacency(graph: &DiGraph<NodeType, EdgeWeight>, side: Side) -> (MatrixLabels, Array2<f64>) {
+    let (labels, incidence) = incidence_matrix(graph);
+    let mut matrix = match side {
+        Side::Individuals => incidence.dot(&incidence.t()),
+        Side::Allergens => incidence.t().dot(&incidence),
+    };
+    matrix.diag_mut().fill(0.0);
+    (labels, matrix)
+}
+
+/// Sparse one-mode projection onto `side`.
+pub fn sparse_projected_adjacency(graph: &DiGraph<NodeType, EdgeWeight>, side: Side) -> (MatrixLabels, CsMat<f64>) {
+    let (labels, incidence) = sparse_incidence_matrix(graph);
+    let transpose = incidence.transpose_view().to_csr();
+    let product: CsMat<f64> = match side {
+        Side::Individuals => &incidence * &transpose,
+        Side::Allergens => &transpose * &incidence,
+    };
+    let mut triplets = TriMat::new(product.shape());
+    for (&value, (row, column)) in product.iter() {
+        if row != column {
+            triplets.add_triplet(row, column, value);
+        }
+    }
+    (labels, triplets.to_csr())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    #[test]
+    fn test_dense_and_sparse_agree() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let graph = create_graph(read_csv(path).unwrap(), &GraphOptions::default());
+        let (labels, incidence) = incidence_matrix(&graph);
+        assert_eq!(incidence.dim(), (5, labels.allergens.len()));
+        assert_eq!(incidence.sum(), 10.0);
+        let (_, sparse) = sparse_incidence_matrix(&graph);
+        assert_eq!(sparse.to_dense(), incidence);
+
+        for side in [Side::Individuals, Side::Allergens] {
+            let (_, dense) = projected_adjacency(&graph, side);
+            let (_, sparse) = sparse_projected_adjacency(&graph, side);
+            assert_eq!(sparse.to_dense(), dense);
+            assert_eq!(dense.diag().sum(), 0.0);
+            assert_eq!(dense, dense.t());
+        }
+    }
+}
@@ -0,0 +1,840 @@
+//! Degree, weighted-degree, betweenness and closeness centrality averaged
+//! over groups of individuals, and the reports that present them.
+
+pub mod assortativity;
+pub mod clustering;
+pub mod communities;
+pub mod components;
+pub mod cross_reactivity;
+pub mod distributions;
+pub mod kcore;
+pub mod null_model;
+pub mod ranking;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, Write};
+
+use clap::ValueEnum;
+use log::debug;
+use petgraph::graph::DiGraph;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+
+use crate::centrality::{betweenness, closeness};
+use crate::disclosure::{suppress, Budget, Cell, Mechanism, NoiseOptions, Suppression};
+use crate::error::AllergyNetError;
+use crate::privacy::ExportIds;
+use crate::quality::PLAUSIBLE_AGES;
+use crate::stats::bootstrap_interval;
+use crate::strata::{Dimension, Grouping};
+use crate::{EdgeWeight, NodeType, Record, Unit};
+
+/// Presentation settings shared by every report.
+#[derive(Debug, Clone, Default)]
+pub struct ReportOptions {
+    /// Annotate each number with how it was computed.
+    pub explain: bool,
+    /// Descriptions of the filters applied before the graph was analysed.
+    pub filters: Vec<String>,
+    /// Seed shared by all stochastic routines in the run.
+    pub seed: u64,
+    /// Groups smaller than this are small cells.
+    pub small_cell_threshold: usize,
+    /// Whether small cells are flagged, masked or merged.
+    pub suppression: Suppression,
+    /// Differential privacy noise on released counts and averages.
+    ///
+    /// Only reports whose sensitivity is bounded and calibrated, the
+    /// centrality group statistics and prevalence, are released with noise
+    /// (see [`Budget`]). Every other report returns an error instead: its
+    /// counts would need noise that isn't calibrated for them, and releasing
+    /// them exact would undo the guarantee of the noisy ones.
+    pub noise: Option<NoiseOptions>,
+    /// Bootstrap resamples for the intervals of group averages; none
+    /// without any.
+    pub bootstrap: usize,
+    /// How group sizes are presented.
+    pub show: Show,
+    /// Leave individuals with no allergies out of centrality averages and
+    /// their denominator.
+    pub exclude_isolates: bool,
+    /// Human-readable text or one JSON object per line.
+    pub format: OutputFormat,
+    /// Unit the graph was built with, stated on every result.
+    pub unit: Unit,
+    /// Inputs, parameters and build of the run (`AuditRecord::provenance`),
+    /// written at the top of the report.
+    pub provenance: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    /// Newline-delimited JSON, one result per line
+    Ndjson,
+}
+
+/// Writes `value` as a single NDJSON line.
+pub fn emit_json(out: &mut dyn Write, value: &serde_json::Value) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, value)?;
+    writeln!(out)
+}
+
+/// Whether tables report group sizes as raw counts, percentages of the
+/// denominator, or both. The denominator is always stated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Show {
+    #[default]
+    Counts,
+    Percent,
+    Both,
+}
+
+impl Show {
+    pub fn format(self, count: usize, total: usize) -> String {
+        let percent = if total == 0 { 0.0 } else { 100.0 * count as f64 / total as f64 };
+        match self {
+            Show::Counts => format!("n={} of {}", count, total),
+            Show::Percent => format!("{:.1}% of {}", percent, total),
+            Show::Both => format!("n={}, {:.1}% of {}", count, percent, total),
+        }
+    }
+}
+
+/// Metrics that can be requested for an analysis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Metric {
+    /// Number of allergies of each individual
+    Degree,
+    /// Total years each individual had their allergies (`EdgeWeight::duration`)
+    WeightedDegree,
+    /// Share of shortest paths between other nodes through each individual
+    Betweenness,
+    /// Inverse mean distance from each individual to the nodes it reaches
+    Closeness,
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.to_possible_value().expect("no skipped variants").get_name())
+    }
+}
+
+impl Metric {
+    /// Name used in text reports, e.g. "weighted degree".
+    fn label(self) -> &'static str {
+        match self {
+            Metric::Degree => "degree",
+            Metric::WeightedDegree => "weighted degree",
+            Metric::Betweenness => "betweenness",
+            Metric::Closeness => "closeness",
+        }
+    }
+
+    /// What the metric measures for one individual, for `--explain`.
+    fn definition(self) -> &'static str {
+        match self {
+            Metric::Degree => "number of allergy nodes they link to",
+            Metric::WeightedDegree => "sum of the durations in years of their allergies",
+            Metric::Betweenness => "share of shortest paths between other nodes that pass through them",
+            Metric::Closeness => "nodes reached / sum of distances to them, scaled by the share of other nodes reached",
+        }
+    }
+}
+
+impl ReportOptions {
+    /// Options for each of `count` metrics written to one report: the
+    /// provenance is written once, and under differential privacy the
+    /// metrics share the epsilon.
+    pub fn per_metric(&self, count: usize) -> impl Iterator<Item = ReportOptions> + '_ {
+        (0..count).map(move |i| {
+            let mut options = self.clone();
+            if i > 0 {
+                options.provenance = None;
+            }
+            if let Some(noise) = &mut options.noise {
+                noise.epsilon /= count as f64;
+            }
+            options
+        })
+    }
+}
+
+/// Centrality of one individual.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NodeScore {
+    /// Index of the individual's node in the graph; left out once the ids
+    /// are de-identified (see `CentralityReport::deidentify`).
+    pub node: Option<usize>,
+    pub id: String,
+    pub value: f64,
+}
+
+/// A metric averaged over one group. The values are `None` when the
+/// group is suppressed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GroupScore {
+    pub grouping: String,
+    pub group: String,
+    pub mean: Option<f64>,
+    pub total: Option<f64>,
+    pub n: Option<usize>,
+    /// Bounds of the 95% percentile bootstrap interval for `mean`, when
+    /// `ReportOptions::bootstrap` asks for one.
+    pub ci_lower: Option<f64>,
+    pub ci_upper: Option<f64>,
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+/// What a report spent of the differential privacy budget.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PrivacySpend {
+    pub mechanism: Mechanism,
+    pub epsilon: f64,
+    pub epsilon_spent: f64,
+    pub epsilon_per_query: f64,
+    /// Only set for the Gaussian mechanism.
+    pub delta: Option<f64>,
+}
+
+/// The released values of one metric: a score per individual and an
+/// average per group. Under differential privacy the per-node scores are
+/// left out and the counts and totals are noisy.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CentralityReport {
+    pub metric: Metric,
+    pub unit: Unit,
+    /// Allergen nodes in the graph, in graph order.
+    pub allergens: Vec<String>,
+    /// Individuals in the graph.
+    pub denominator: usize,
+    pub nodes: Vec<NodeScore>,
+    pub groups: Vec<GroupScore>,
+    pub privacy: Option<PrivacySpend>,
+}
+
+/// Group-average degree centrality. Errors if `options.noise` can't be
+/// calibrated (see `Budget::new`).
+pub fn calculate_centrality(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    groupings: &[Grouping],
+    options: &ReportOptions,
+) -> Result<CentralityReport, String> {
+    calculate_metric(graph, groupings, Metric::Degree, options)
+}
+
+/// Group-average weighted degree centrality: the sum of the durations of
+/// an individual's allergies, in years.
+pub fn calculate_weighted_centrality(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    groupings: &[Grouping],
+    options: &ReportOptions,
+) -> Result<CentralityReport, String> {
+    calculate_metric(graph, groupings, Metric::WeightedDegree, options)
+}
+
+/// Group averages of any `metric`, as `calculate_centrality` computes
+/// them for degree. Betweenness and closeness depend on every other
+/// individual, so have no bounded sensitivity and are refused under
+/// differential privacy.
+pub fn calculate_metric(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    groupings: &[Grouping],
+    metric: Metric,
+    options: &ReportOptions,
+) -> Result<CentralityReport, String> {
+    let path_values = match metric {
+        Metric::Degree | Metric::WeightedDegree => None,
+        Metric::Betweenness => Some(betweenness(graph)),
+        Metric::Closeness => Some(closeness(graph)),
+    };
+    if path_values.is_some() && options.noise.is_some() {
+        return Err(format!("{} can't be released under differential privacy", metric));
+    }
+    let allergens: Vec<String> = graph
+        .node_weights()
+        .filter_map(|node| match node {
+            NodeType::AllergenStatus(name) => Some(name.clone()),
+            NodeType::Individual(_) | NodeType::Demographic { .. } => None,
+        })
+        .collect();
+    // Most one individual can add to a group total: under differential
+    // privacy, weighted degrees are clipped to it
+    let sensitivity = match metric {
+        Metric::WeightedDegree => allergens.len() as f64 * PLAUSIBLE_AGES.end(),
+        _ => allergens.len() as f64,
+    };
+
+    // Per grouping: group value -> member values
+    let mut group_centrality: Vec<BTreeMap<String, Vec<f64>>> = vec![BTreeMap::new(); groupings.len()];
+    let mut nodes = Vec::new();
+    let mut individuals = 0;
+    for node in graph.node_indices() {
+        let NodeType::Individual(individual) = &graph[node] else { continue };
+        if options.exclude_isolates && graph.neighbors(node).next().is_none() {
+            continue;
+        }
+        individuals += 1;
+        let value = match metric {
+            Metric::Degree => graph.neighbors(node).count() as f64,
+            Metric::WeightedDegree => {
+                let total: f64 = graph.edges(node).map(|edge| edge.weight().duration).sum();
+                if options.noise.is_some() { total.min(sensitivity) } else { total }
+            }
+            Metric::Betweenness | Metric::Closeness => path_values.as_ref().map_or(0.0, |values| values[node.index()]),
+        };
+        debug!("{} centrality for node {} (ID: {}): {}", metric.label(), node.index(), individual.id, value);
+        // Per-node values can't be released under differential privacy
+        if options.noise.is_none() {
+            nodes.push(NodeScore { node: Some(node.index()), id: individual.id.clone(), value });
+        }
+        for (grouping, groups) in groupings.iter().zip(group_centrality.iter_mut()) {
+            if let Some(group) = grouping.value_of(individual) {
+                groups.entry(group).or_default().push(value);
+            }
+        }
+    }
+
+    // Under differential privacy the denominator and each grouping's counts
+    // and totals are released with noise, one query each
+    let mut budget = options.noise.as_ref().map(|noise| Budget::new(noise, 1 + 2 * groupings.len())).transpose()?;
+    let denominator = match &mut budget {
+        Some(budget) => {
+            budget.charge();
+            budget.noisy_count(individuals)
+        }
+        None => individuals,
+    };
+    let mut groups = Vec::new();
+    // Intervals would need noise too, so none are released with it
+    let resamples = if options.noise.is_some() { 0 } else { options.bootstrap };
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    for (grouping, members) in groupings.iter().zip(group_centrality) {
+        let mut cells: Vec<Cell> =
+            members.iter().map(|(group, values)| Cell::new(group.clone(), values.iter().sum(), values.len())).collect();
+        if let Some(budget) = &mut budget {
+            for cell in &mut cells {
+                cell.count = budget.noisy_count(cell.count);
+                cell.total = budget.noise(cell.total, sensitivity).max(0.0);
+            }
+            budget.charge();
+            budget.charge();
+        }
+        let cells = suppress(cells, options.small_cell_threshold, options.suppression);
+        for Cell { group, total, count, suppressed } in &cells {
+            let (total, count, suppressed) = (*total, *count, *suppressed);
+            let shown = |value| if suppressed { None } else { Some(value) };
+            let interval = (resamples > 0 && !suppressed)
+                .then(|| match members.get(group) {
+                    Some(values) => bootstrap_interval(values, resamples, &mut rng),
+                    // A merged group pools the groups no other cell kept
+                    None => {
+                        let pooled: Vec<f64> = members
+                            .iter()
+                            .filter(|(name, _)| cells.iter().all(|cell| cell.group != **name))
+                            .flat_map(|(_, values)| values.iter().copied())
+                            .collect();
+                        bootstrap_interval(&pooled, resamples, &mut rng)
+                    }
+                })
+                .flatten();
+            groups.push(GroupScore {
+                grouping: grouping.label(),
+                group: group.clone(),
+                mean: shown(total / count.max(1) as f64),
+                total: shown(total),
+                n: (!suppressed).then_some(count),
+                ci_lower: interval.map(|(lower, _)| lower),
+                ci_upper: interval.map(|(_, upper)| upper),
+                small_cell: count < options.small_cell_threshold,
+                suppressed,
+            });
+        }
+    }
+    let privacy = budget.map(|budget| {
+        let noise = budget.options();
+        PrivacySpend {
+            mechanism: noise.mechanism,
+            epsilon: noise.epsilon,
+            epsilon_spent: budget.spent(),
+            epsilon_per_query: budget.per_query(),
+            delta: (noise.mechanism == Mechanism::Gaussian).then_some(noise.delta),
+        }
+    });
+    Ok(CentralityReport { metric, unit: options.unit, allergens, denominator, nodes, groups, privacy })
+}
+
+impl CentralityReport {
+    /// Groups reported unsuppressed despite being small cells.
+    pub fn small_cells(&self) -> usize {
+        self.groups.iter().filter(|group| group.small_cell && !group.suppressed).count()
+    }
+
+    /// Replaces each individual's id by the one it is exported under, from
+    /// `Deidentify::ids`. Unless `mode` keeps the ids, the node indices,
+    /// which follow the input rows, are left out too and the scores are
+    /// ordered by their new id.
+    pub fn deidentify(&mut self, ids: &HashMap<usize, String>, mode: ExportIds) {
+        for node in &mut self.nodes {
+            if let Some(id) = node.node.and_then(|index| ids.get(&index)) {
+                node.id = id.clone();
+            }
+            if mode != ExportIds::Keep {
+                node.node = None;
+            }
+        }
+        if mode != ExportIds::Keep {
+            // Renumbered ids sort numerically
+            self.nodes.sort_by(|a, b| (a.id.len(), &a.id).cmp(&(b.id.len(), &b.id)));
+        }
+    }
+
+    /// The report as NDJSON objects: the provenance and explanation when
+    /// `options` ask for them, then a `node` row per individual, a `group`
+    /// row per group and the `privacy` spend.
+    pub fn json_rows(&self, options: &ReportOptions) -> Vec<serde_json::Value> {
+        let metric = self.metric.to_string();
+        let unit = self.unit.to_string();
+        let mut rows = Vec::new();
+        if let Some(provenance) = &options.provenance {
+            let mut row = provenance.clone();
+            row["type"] = "provenance".into();
+            rows.push(row);
+        }
+        if options.explain {
+            rows.push(serde_json::json!({
+                "type": "explain",
+                "metric": metric,
+                "formula": format!(
+                    "{} = {}; group mean = sum of member {}s / individuals in group",
+                    self.metric.label(),
+                    self.metric.definition(),
+                    self.metric.label()
+                ),
+                "allergens": self.allergens,
+                "filters": options.filters,
+                "seed": options.seed,
+                "unit": unit,
+            }));
+        }
+        for node in &self.nodes {
+            rows.push(serde_json::json!({
+                "type": "node",
+                "metric": metric,
+                "node": node.node,
+                "id": node.id,
+                "value": node.value,
+                "unit": unit,
+            }));
+        }
+        for group in &self.groups {
+            rows.push(serde_json::json!({
+                "type": "group",
+                "metric": metric,
+                "grouping": group.grouping,
+                "group": group.group,
+                "mean": group.mean,
+                "total": group.total,
+                "n": group.n,
+                "ci_lower": group.ci_lower,
+                "ci_upper": group.ci_upper,
+                "denominator": self.denominator,
+                "small_cell": group.small_cell,
+                "suppressed": group.suppressed,
+                "unit": unit,
+            }));
+        }
+        if let Some(privacy) = &self.privacy {
+            rows.push(serde_json::json!({
+                "type": "privacy",
+                "mechanism": privacy.mechanism.to_string(),
+                "epsilon": privacy.epsilon,
+                "epsilon_spent": privacy.epsilon_spent,
+                "epsilon_per_query": privacy.epsilon_per_query,
+                "delta": privacy.delta,
+            }));
+        }
+        rows
+    }
+
+    /// Writes the report in `options.format`, with the presentation
+    /// `options` asks for.
+    pub fn write(&self, options: &ReportOptions, out: &mut dyn Write) -> io::Result<()> {
+        if options.format == OutputFormat::Ndjson {
+            for row in self.json_rows(options) {
+                emit_json(out, &row)?;
+            }
+            return Ok(());
+        }
+        if let Some(provenance) = &options.provenance {
+            writeln!(out, "# Provenance: {}", provenance)?;
+        }
+        writeln!(out, "# Unit of analysis: {}", self.unit)?;
+        let label = self.metric.label();
+        if options.explain {
+            let mut name = label.to_string();
+            name[..1].make_ascii_uppercase();
+            writeln!(out, "# {} centrality of an individual = {}", name, self.metric.definition())?;
+            if matches!(self.metric, Metric::Betweenness | Metric::Closeness) {
+                writeln!(out, "# Paths ignore edge direction; values are normalised as in NetworkX")?;
+            }
+            writeln!(out, "# Group average = sum of member {}s / number of individuals in the group", label)?;
+            if options.bootstrap > 0 && self.privacy.is_none() {
+                let resamples = options.bootstrap;
+                writeln!(out, "# 95% CI = percentile bootstrap of the members' {}s, {} resamples", label, resamples)?;
+            }
+            writeln!(out, "# Allergens counted: {}", self.allergens.join(", "))?;
+            if options.filters.is_empty() {
+                writeln!(out, "# Filters applied: none")?;
+            } else {
+                writeln!(out, "# Filters applied: {}", options.filters.join("; "))?;
+            }
+            writeln!(out, "# Random seed: {} (pass --seed {} to reproduce)", options.seed, options.seed)?;
+        }
+        for group in &self.groups {
+            let (Some(mean), Some(total), Some(count)) = (group.mean, group.total, group.n) else {
+                writeln!(out, "Average {} centrality for {} {}: suppressed", label, group.grouping, group.group)?;
+                continue;
+            };
+            write!(out, "Average {} centrality for {} {}: {}", label, group.grouping, group.group, mean)?;
+            if let (Some(lower), Some(upper)) = (group.ci_lower, group.ci_upper) {
+                write!(out, " (95% CI {:.3} to {:.3})", lower, upper)?;
+            }
+            write!(out, " [{}]", options.show.format(count, self.denominator))?;
+            if options.explain {
+                match self.metric {
+                    Metric::Degree => write!(out, " (= {} allergies / {} individuals)", total, count)?,
+                    Metric::WeightedDegree => write!(out, " (= {} allergy-years / {} individuals)", total, count)?,
+                    Metric::Betweenness | Metric::Closeness => write!(out, " (= {} / {} individuals)", total, count)?,
+                }
+            }
+            if group.small_cell {
+                write!(out, " [small cell: n={}]", count)?;
+            }
+            writeln!(out)?;
+        }
+        if let Some(privacy) = &self.privacy {
+            writeln!(
+                out,
+                "# Differential privacy: {} noise, epsilon {} of {} spent ({} per query)",
+                privacy.mechanism, privacy.epsilon_spent, privacy.epsilon, privacy.epsilon_per_query
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Computes `calculate_centrality` and collects its NDJSON rows, for
+/// callers that hand results on as JSON.
+pub fn centrality_results(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    groupings: &[Grouping],
+    options: &ReportOptions,
+) -> Result<Vec<serde_json::Value>, String> {
+    Ok(calculate_centrality(graph, groupings, options)?.json_rows(options))
+}
+
+/// Checks that every extra-column dimension exists in the input.
+pub fn check_dimensions(records: &[Record], groupings: &[Grouping]) -> Result<(), AllergyNetError> {
+    let Some(first) = records.first() else { return Ok(()) };
+    let columns: Vec<String> = first.extra.keys().cloned().collect();
+    check_grouping_columns(&columns, groupings)
+}
+
+/// Checks that every extra-column dimension is one of `columns`.
+pub fn check_grouping_columns(columns: &[String], groupings: &[Grouping]) -> Result<(), AllergyNetError> {
+    for dimension in groupings.iter().flat_map(Grouping::dimensions) {
+        if let Dimension::Column(name) = dimension {
+            if !columns.contains(name) {
+                return Err(AllergyNetError::Schema(format!("unknown stratification column '{}'", name)));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::tests::get_mock_records;
+    use crate::strata::DEFAULT_DIMENSIONS;
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    #[test]
+    fn test_check_dimensions() {
+        let mut records = get_mock_records();
+        records[0].extra.insert("site".to_string(), "north".to_string());
+        assert!(check_dimensions(&records, &["race*site".parse().unwrap()]).is_ok());
+        assert!(check_dimensions(&records, &[Dimension::Column("clinic".to_string()).into()]).is_err());
+    }
+
+    #[test]
+    fn test_centrality_calculation() {
+        let records = get_mock_records();
+        let graph = create_graph(records, &GraphOptions::default());
+        let groupings: Vec<Grouping> = DEFAULT_DIMENSIONS.split(',').map(|d| d.parse().unwrap()).collect();
+        let mut out = Vec::new();
+        let options = ReportOptions::default();
+        calculate_centrality(&graph, &groupings, &options).unwrap().write(&options, &mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("Average degree centrality for gender Male: 1"));
+    }
+
+    #[test]
+    fn test_exclude_isolates() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let gender: Vec<Grouping> = vec![Dimension::Gender.into()];
+        let all = calculate_centrality(&graph, &gender, &ReportOptions::default()).unwrap();
+        let options = ReportOptions { exclude_isolates: true, ..Default::default() };
+        let linked = calculate_centrality(&graph, &gender, &options).unwrap();
+        // 205653 has no allergies
+        assert_eq!((all.denominator, linked.denominator), (5, 4));
+        let n = |report: &CentralityReport| report.groups.iter().filter_map(|group| group.n).sum::<usize>();
+        assert_eq!((n(&all), n(&linked)), (5, 4));
+        let totals = |report: &CentralityReport| report.groups.iter().filter_map(|group| group.total).sum::<f64>();
+        assert_eq!(totals(&all), totals(&linked));
+        assert!(linked.nodes.iter().all(|node| node.id != "205653"));
+    }
+
+    #[test]
+    fn test_centrality_explain() {
+        let graph = create_graph(get_mock_records(), &GraphOptions::default());
+        let options = ReportOptions {
+            explain: true,
+            filters: vec!["cohort infants: age < 2".to_string()],
+            seed: 7,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        calculate_centrality(&graph, &[Dimension::Gender.into()], &options).unwrap().write(&options, &mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("# Allergens counted: Peanut, Treenut"));
+        assert!(report.contains("# Filters applied: cohort infants: age < 2"));
+        assert!(report.contains("# Random seed: 7"));
+        assert!(report.contains("Average degree centrality for gender Male: 1 [n=1 of 1] (= 1 allergies / 1 individuals)"));
+        
+    }
+
+    #[test]
+    fn test_centrality_report() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let options = ReportOptions { small_cell_threshold: 3, suppression: Suppression::Flag, ..Default::default() };
+        let report = calculate_centrality(&graph, &["gender".parse().unwrap()], &options).unwrap();
+        assert_eq!((report.metric, report.denominator, report.allergens.len()), (Metric::Degree, 5, 9));
+        assert_eq!(report.nodes[4], NodeScore { node: Some(13), id: "205654".to_string(), value: 4.0 });
+        let female = GroupScore {
+            grouping: "gender".to_string(),
+            group: "S1 - Female".to_string(),
+            mean: Some(2.0),
+            total: Some(4.0),
+            n: Some(2),
+            ci_lower: None,
+            ci_upper: None,
+            small_cell: true,
+            suppressed: false,
+        };
+        assert_eq!(report.groups[1], female);
+        assert_eq!(report.small_cells(), 1);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!((json["metric"].as_str(), json["unit"].as_str()), (Some("degree"), Some("record")));
+        assert!(json["privacy"].is_null());
+
+        let options = ReportOptions { bootstrap: 200, seed: 7, explain: true, ..options };
+        let report = calculate_centrality(&graph, &["gender".parse().unwrap()], &options).unwrap();
+        // Female degrees are 1 and 3, so resampled means are 1, 2 or 3
+        let female = &report.groups[1];
+        assert_eq!((female.ci_lower, female.ci_upper), (Some(1.0), Some(3.0)));
+        assert_eq!(report, calculate_centrality(&graph, &["gender".parse().unwrap()], &options).unwrap(), "seeded");
+        let mut out = Vec::new();
+        report.write(&options, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("# 95% CI = percentile bootstrap of the members' degrees, 200 resamples\n"));
+        assert!(text.contains("S1 - Female: 2 (95% CI 1.000 to 3.000) [n=2 of 5]"), "{}", text);
+        let noise = NoiseOptions { epsilon: 1.0, mechanism: Mechanism::Laplace, delta: 0.0 };
+        let noisy = ReportOptions { noise: Some(noise), ..options };
+        let report = calculate_centrality(&graph, &["gender".parse().unwrap()], &noisy).unwrap();
+        assert!(report.groups.iter().all(|group| group.ci_lower.is_none()));
+        let gaussian = NoiseOptions { epsilon: 3.0, mechanism: Mechanism::Gaussian, delta: 1e-6 };
+        let uncalibrated = ReportOptions { noise: Some(gaussian), ..ReportOptions::default() };
+        assert!(calculate_centrality(&graph, &["gender".parse().unwrap()], &uncalibrated).is_err());
+    }
+
+    #[test]
+    fn test_weighted_centrality() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let options = ReportOptions { explain: true, ..Default::default() };
+        let mut out = Vec::new();
+        let report = calculate_weighted_centrality(&graph, &["gender".parse().unwrap()], &options).unwrap();
+        report.write(&options, &mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("# Weighted degree centrality of an individual = sum of the durations in years"));
+        assert!(report.contains(
+            "Average weighted degree centrality for gender S0 - Male: 15 [n=3 of 5] (= 45 allergy-years / 3 individuals)"
+        ));
+
+        let noise = NoiseOptions { epsilon: 1.0, mechanism: Mechanism::Laplace, delta: 0.0 };
+        let provenance = Some(serde_json::json!({}));
+        let options = ReportOptions { noise: Some(noise), provenance, ..Default::default() };
+        let split: Vec<ReportOptions> = options.per_metric(2).collect();
+        assert_eq!(split.iter().map(|o| o.noise.as_ref().unwrap().epsilon).collect::<Vec<_>>(), [0.5, 0.5]);
+        assert_eq!((split[0].provenance.is_some(), split[1].provenance.is_some()), (true, false));
+        assert_eq!(split[0].seed, split[1].seed);
+    }
+
+    #[test]
+    fn test_path_metrics() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let options = ReportOptions { format: OutputFormat::Ndjson, ..Default::default() };
+        let mut out = Vec::new();
+        let report = calculate_metric(&graph, &["gender".parse().unwrap()], Metric::Closeness, &options).unwrap();
+        report.write(&options, &mut out).unwrap();
+        let rows: Vec<serde_json::Value> =
+            String::from_utf8(out).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let node = rows.iter().find(|row| row["id"] == "205653").unwrap();
+        assert_eq!((&node["metric"], node["value"].as_f64()), (&serde_json::json!("closeness"), Some(0.0)));
+        assert_eq!(rows.iter().filter(|row| row["type"] == "group").count(), 2);
+
+        let mut out = Vec::new();
+        let options = ReportOptions { explain: true, ..Default::default() };
+        let report = calculate_metric(&graph, &["gender".parse().unwrap()], Metric::Betweenness, &options).unwrap();
+        report.write(&options, &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("# Paths ignore edge direction"));
+
+        let noise = NoiseOptions { epsilon: 1.0, mechanism: Mechanism::Laplace, delta: 0.0 };
+        let options = ReportOptions { noise: Some(noise), ..Default::default() };
+        let error = calculate_metric(&graph, &[], Metric::Betweenness, &options).unwrap_err();
+        assert_eq!(error, "betweenness can't be released under differential privacy");
+    }
+
+    #[test]
+    fn test_show_formats() {
+        assert_eq!(Show::Counts.format(3, 12), "n=3 of 12");
+        assert_eq!(Show::Percent.format(3, 12), "25.0% of 12");
+        assert_eq!(Show::Both.format(3, 12), "n=3, 25.0% of 12");
+        assert_eq!(Show::Percent.format(0, 0), "0.0% of 0");
+    }
+
+    #[test]
+    fn test_crossed_grouping_flags_small_cells() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let options = ReportOptions { small_cell_threshold: 2, suppression: Suppression::Flag, ..Default::default() };
+        let mut out = Vec::new();
+        let report = calculate_centrality(&graph, &["gender*payer".parse().unwrap()], &options).unwrap();
+        report.write(&options, &mut out).unwrap();
+        let small_cells = report.small_cells();
+        assert_eq!(small_cells, 1);
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("Average degree centrality for gender × payer factor S0 - Male × P0 - Non-Medicaid: 1 [n=2 of 5]\n"));
+        assert!(report.contains("Average degree centrality for gender × payer factor S0 - Male × P1 - Medicaid: 4 [n=1 of 5] [small cell: n=1]"));
+    }
+
+    #[test]
+    fn test_default_options_mask_small_cells() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let options = ReportOptions { small_cell_threshold: 11, ..ReportOptions::default() };
+        let report = calculate_centrality(&graph, &["payer".parse().unwrap()], &options).unwrap();
+        assert!(!report.groups.is_empty());
+        assert!(report.groups.iter().all(|group| group.small_cell && group.suppressed && group.mean.is_none()));
+        assert_eq!(report.small_cells(), 0);
+    }
+
+    #[test]
+    fn test_masked_groups_hide_values() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let options = ReportOptions { small_cell_threshold: 3, suppression: Suppression::Mask, ..Default::default() };
+        let mut out = Vec::new();
+        let report = calculate_centrality(&graph, &["payer".parse().unwrap()], &options).unwrap();
+        report.write(&options, &mut out).unwrap();
+        let small_cells = report.small_cells();
+        assert_eq!(small_cells, 0);
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("for payer factor P0 - Non-Medicaid: suppressed\n"));
+        // The other payer group is masked too, or it would give away the first
+        assert!(report.contains("for payer factor P1 - Medicaid: suppressed\n"));
+
+        let results = centrality_results(&graph, &["payer".parse().unwrap()], &options).unwrap();
+        let group = results.iter().find(|r| r["type"] == "group").unwrap();
+        assert_eq!(group["mean"], serde_json::Value::Null);
+        assert_eq!((group["suppressed"].as_bool(), group["denominator"].as_u64()), (Some(true), Some(5)));
+    }
+
+    #[test]
+    fn test_noise_spends_the_budget() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let noise = NoiseOptions { epsilon: 1.0, mechanism: Mechanism::Laplace, delta: 0.0 };
+        let options = ReportOptions { noise: Some(noise), ..Default::default() };
+        let groupings = ["race".parse().unwrap(), "payer".parse().unwrap()];
+        let results = centrality_results(&graph, &groupings, &options).unwrap();
+        assert!(results.iter().all(|r| r["type"] != "node"));
+        let privacy = results.last().unwrap();
+        assert_eq!((privacy["epsilon_spent"].as_f64(), privacy["epsilon_per_query"].as_f64()), (Some(1.0), Some(0.2)));
+    }
+
+    #[test]
+    fn test_unit_of_analysis() {
+        let mut records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let mut repeat = records[0].clone();
+        repeat.walnut_alg_start = Some(3.0);
+        records.push(repeat);
+        let groupings = ["gender".parse().unwrap()];
+        let male = |unit: Unit| {
+            let graph = create_graph(records.clone(), &GraphOptions { unit, ..Default::default() });
+            let results = centrality_results(&graph, &groupings, &ReportOptions { unit, ..Default::default() }).unwrap();
+            let group = results.into_iter().find(|r| r["group"] == "S0 - Male").unwrap();
+            assert_eq!(group["unit"], unit.to_string());
+            (group["n"].as_u64(), group["denominator"].as_u64(), group["total"].as_f64())
+        };
+        // Subject 205650's two rows are two individuals unless merged
+        assert_eq!(male(Unit::Record), (Some(4), Some(6), Some(9.0)));
+        assert_eq!(male(Unit::Subject), (Some(3), Some(5), Some(7.0)));
+
+        let graph = create_graph(records, &GraphOptions::default());
+        let mut out = Vec::new();
+        calculate_centrality(&graph, &groupings, &ReportOptions::default()).unwrap().write(&ReportOptions::default(), &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().starts_with("# Unit of analysis: record\n"));
+
+        let provenance = serde_json::json!({ "seed": 7, "git_commit": "abc123" });
+        let mut options = ReportOptions { provenance: Some(provenance.clone()), ..Default::default() };
+        let mut out = Vec::new();
+        calculate_centrality(&graph, &groupings, &options).unwrap().write(&options, &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().starts_with(&format!("# Provenance: {}\n", provenance)));
+        options.format = OutputFormat::Ndjson;
+        let mut out = Vec::new();
+        calculate_centrality(&graph, &groupings, &options).unwrap().write(&options, &mut out).unwrap();
+        let first: serde_json::Value = serde_json::from_slice(out.split(|&b| b == b'\n').next().unwrap()).unwrap();
+        assert_eq!(first, serde_json::json!({ "type": "provenance", "seed": 7, "git_commit": "abc123" }));
+    }
+
+    #[test]
+    fn test_centrality_ndjson() {
+        let graph = create_graph(get_mock_records(), &GraphOptions::default());
+        let options = ReportOptions { format: OutputFormat::Ndjson, ..Default::default() };
+        let mut out = Vec::new();
+        calculate_centrality(&graph, &[Dimension::Gender.into()], &options).unwrap().write(&options, &mut out).unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["type"], "node");
+        assert_eq!(lines[0]["id"], "205650");
+        assert_eq!(lines[1]["type"], "group");
+        assert_eq!(lines[1]["group"], "Male");
+        assert_eq!(lines[1]["mean"], 1.0);
+        assert_eq!(lines[1]["n"], 1);
+    }
+}
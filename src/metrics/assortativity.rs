@@ -0,0 +1,179 @@
+//! Attribute assortativity of the co-allergy projection
+//! (`project_individuals`): whether individuals of the same race, payer,
+//! cohort and so on share allergies with each other more than chance
+//! would have, with bootstrap confidence intervals.
+
+use std::collections::BTreeMap;
+
+use petgraph::graph::{DiGraph, UnGraph};
+use petgraph::visit::EdgeRef;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+
+use super::ReportOptions;
+use crate::progress;
+use crate::projection::project_individuals;
+use crate::stats::quantile;
+use crate::strata::Grouping;
+use crate::{EdgeWeight, Individual, NodeType};
+
+/// Assortativity of one grouping.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Assortativity {
+    pub grouping: String,
+    /// Newman's coefficient: 1 when individuals only share allergies within
+    /// their group, 0 when groups mix as chance would have, negative when
+    /// they share them across groups more than chance. `None` without
+    /// edges between individuals with a group, or when all are in one.
+    pub coefficient: Option<f64>,
+    /// Bounds of the 95% percentile bootstrap interval for `coefficient`.
+    pub ci_lower: Option<f64>,
+    pub ci_upper: Option<f64>,
+    /// Edges between two individuals who both have a group.
+    pub edges: usize,
+    /// Groups with at least one such edge.
+    pub groups: usize,
+    pub resamples: usize,
+}
+
+/// Newman's assortativity coefficient of the mixing matrix `mixing`, whose
+/// entry `[a][b]` counts edge ends from group `a` to group `b`.
+fn coefficient(mixing: &[Vec<usize>]) -> Option<f64> {
+    let total: usize = mixing.iter().flatten().sum();
+    if total == 0 {
+        return None;
+    }
+    let total = total as f64;
+    let within: f64 = (0..mixing.len()).map(|a| mixing[a][a] as f64 / total).sum();
+    let chance: f64 = (0..mixing.len())
+        .map(|a| {
+            let row: usize = mixing[a].iter().sum();
+            let column: usize = mixing.iter().map(|row| row[a]).sum();
+            row as f64 / total * column as f64 / total
+        })
+        .sum();
+    (chance < 1.0).then(|| (within - chance) / (1.0 - chance))
+}
+
+/// Mixing matrix of the edges `pairs` between groups numbered below
+/// `groups`, counting each edge in both directions.
+fn mixing(pairs: impl Iterator<Item = (usize, usize)>, groups: usize) -> Vec<Vec<usize>> {
+    let mut mixing = vec![vec![0; groups]; groups];
+    for (a, b) in pairs {
+        mixing[a][b] += 1;
+        mixing[b][a] += 1;
+    }
+    mixing
+}
+
+/// Nominal assortativity of `graph` by `labels`, one per node; edges to
+/// an individual without a label are left out. Edge weights are ignored.
+pub fn nominal_assortativity(graph: &UnGraph<Individual, usize>, labels: &[Option<String>]) -> Option<f64> {
+    let (pairs, groups) = labelled_edges(graph, labels);
+    coefficient(&mixing(pairs.into_iter(), groups))
+}
+
+/// The edges of `graph` between labelled nodes, as pairs of group
+/// numbers, and the number of groups.
+fn labelled_edges(graph: &UnGraph<Individual, usize>, labels: &[Option<String>]) -> (Vec<(usize, usize)>, usize) {
+    let mut numbers: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut pairs = Vec::new();
+    for edge in graph.edge_references() {
+        let (Some(a), Some(b)) = (&labels[edge.source().index()], &labels[edge.target().index()]) else { continue };
+        let next = numbers.len();
+        let a = *numbers.entry(a).or_insert(next);
+        let next = numbers.len();
+        let b = *numbers.entry(b).or_insert(next);
+        pairs.push((a, b));
+    }
+    (pairs, numbers.len())
+}
+
+/// Assortativity of the co-allergy projection of `graph` by each of
+/// `groupings`. The interval resamples the edges with replacement
+/// `resamples` times, seeded by `options.seed`; resamples where the
+/// coefficient is undefined are skipped. No counts are released per
+/// group, so there are no small cells, but every coefficient depends on
+/// other individuals, so the report is refused under differential privacy.
+pub fn assortativity(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    groupings: &[Grouping],
+    resamples: usize,
+    options: &ReportOptions,
+) -> Result<Vec<Assortativity>, String> {
+    if options.noise.is_some() {
+        return Err("assortativity can't be released under differential privacy".to_string());
+    }
+    let projection = project_individuals(graph);
+    let mut rows = Vec::new();
+    for (i, grouping) in groupings.iter().enumerate() {
+        let labels: Vec<Option<String>> = projection.node_weights().map(|node| grouping.value_of(node)).collect();
+        let (pairs, groups) = labelled_edges(&projection, &labels);
+        let mut rng = StdRng::seed_from_u64(options.seed.wrapping_add(i as u64));
+        let bar = progress::bar(resamples as u64, "bootstrap");
+        let mut samples: Vec<f64> = Vec::with_capacity(resamples);
+        if !pairs.is_empty() {
+            for _ in 0..resamples {
+                let resampled = (0..pairs.len()).map(|_| pairs[rng.gen_range(0..pairs.len())]);
+                samples.extend(coefficient(&mixing(resampled, groups)));
+                bar.inc(1);
+            }
+        }
+        bar.finish_and_clear();
+        samples.sort_by(f64::total_cmp);
+        rows.push(Assortativity {
+            grouping: grouping.label(),
+            coefficient: coefficient(&mixing(pairs.iter().copied(), groups)),
+            ci_lower: quantile(&samples, 0.025),
+            ci_upper: quantile(&samples, 0.975),
+            edges: pairs.len(),
+            groups,
+            resamples,
+        });
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_graph, read_csv, GraphOptions, Record};
+    use petgraph::graph::NodeIndex;
+
+    #[test]
+    fn test_nominal_assortativity() {
+        // Two triangles, one per group, joined by one edge
+        let mut graph = UnGraph::<Individual, usize>::default();
+        let nodes: Vec<NodeIndex> = (0..6).map(|_| graph.add_node(Individual::from(&Record::default()))).collect();
+        for (a, b) in [(0, 1), (1, 2), (0, 2), (3, 4), (4, 5), (3, 5), (2, 3)] {
+            graph.add_edge(nodes[a], nodes[b], 1);
+        }
+        let labels: Vec<Option<String>> = ["a", "a", "a", "b", "b", "b"].map(|label| Some(label.to_string())).into();
+        // Within-group share 6/7 against 1/2 by chance
+        let r = nominal_assortativity(&graph, &labels).unwrap();
+        assert!((r - 5.0 / 7.0).abs() < 1e-12, "{}", r);
+        let alternating: Vec<Option<String>> = ["a", "b", "a", "b", "a", "b"].map(|label| Some(label.to_string())).into();
+        assert!(nominal_assortativity(&graph, &alternating).unwrap() < 0.0);
+        assert_eq!(nominal_assortativity(&graph, &vec![Some("a".to_string()); 6]), None);
+        assert_eq!(nominal_assortativity(&graph, &vec![None; 6]), None);
+    }
+
+    #[test]
+    fn test_assortativity() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let options = ReportOptions { seed: 7, ..Default::default() };
+        let groupings = ["gender".parse().unwrap(), "race".parse().unwrap()];
+        let rows = assortativity(&graph, &groupings, 200, &options).unwrap();
+        assert_eq!(rows.len(), 2);
+        // Co-allergy edges: 650-651, 650-654, 651-654 and 652-654
+        let gender = &rows[0];
+        assert_eq!((gender.grouping.as_str(), gender.edges, gender.groups), ("gender", 4, 2));
+        // One edge of four within a gender, against 34/64 by chance
+        assert!((gender.coefficient.unwrap() + 0.6).abs() < 1e-12);
+        let (lower, upper) = (gender.ci_lower.unwrap(), gender.ci_upper.unwrap());
+        assert!(lower <= upper && (-1.0..=1.0).contains(&lower) && (-1.0..=1.0).contains(&upper));
+        assert_eq!(rows, assortativity(&graph, &groupings, 200, &options).unwrap(), "seeded");
+    }
+}
@@ -0,0 +1,281 @@
+//! Local clustering coefficients and transitivity of the co-allergy
+//! projection (`project_individuals`), overall and per demographic group:
+//! how often two individuals who each share an allergy with a third share
+//! one with each other.
+
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Write};
+
+use petgraph::graph::{DiGraph, NodeIndex, UnGraph};
+use serde::Serialize;
+
+use super::{emit_json, OutputFormat, ReportOptions};
+use crate::disclosure::{suppress, Cell};
+use crate::projection::project_individuals;
+use crate::strata::Grouping;
+use crate::{EdgeWeight, Individual, NodeType};
+
+/// Triangles through each node of `graph` and the pairs of its neighbours
+/// that could close one, in node order. Edge weights are ignored.
+pub fn triangles(graph: &UnGraph<Individual, usize>) -> Vec<(usize, usize)> {
+    let neighbours: Vec<HashSet<NodeIndex>> = graph
+        .node_indices()
+        .map(|node| graph.neighbors(node).filter(|&neighbour| neighbour != node).collect())
+        .collect();
+    neighbours
+        .iter()
+        .map(|around| {
+            let around: Vec<NodeIndex> = around.iter().copied().collect();
+            let closed = around
+                .iter()
+                .enumerate()
+                .map(|(i, a)| around[i + 1..].iter().filter(|b| neighbours[a.index()].contains(b)).count())
+                .sum();
+            (closed, around.len() * around.len().saturating_sub(1) / 2)
+        })
+        .collect()
+}
+
+/// Local clustering coefficient of one individual: the share of pairs of
+/// their neighbours who are neighbours themselves, or 0 with fewer than
+/// two neighbours.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NodeClustering {
+    pub id: String,
+    pub triangles: usize,
+    pub coefficient: f64,
+}
+
+/// Clustering within one group. The values are `None` when the group is
+/// suppressed, and `transitivity` also when no member has two neighbours.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GroupClustering {
+    pub grouping: String,
+    pub group: String,
+    pub n: Option<usize>,
+    /// Mean local clustering coefficient of the group's members.
+    pub mean_clustering: Option<f64>,
+    /// Triangles through the group's members over the pairs of neighbours
+    /// around them.
+    pub transitivity: Option<f64>,
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClusteringReport {
+    /// Share of connected triples that are closed, over the whole
+    /// projection; `None` without any.
+    pub transitivity: Option<f64>,
+    /// Mean local clustering coefficient over every individual.
+    pub mean_clustering: Option<f64>,
+    /// Individuals in the graph.
+    pub denominator: usize,
+    pub nodes: Vec<NodeClustering>,
+    pub groups: Vec<GroupClustering>,
+}
+
+/// Coefficient sum, triangles and triples of some individuals.
+#[derive(Debug, Clone, Copy, Default)]
+struct Totals {
+    n: usize,
+    coefficients: f64,
+    triangles: usize,
+    triples: usize,
+}
+
+impl Totals {
+    fn add(&mut self, other: Totals) {
+        self.n += other.n;
+        self.coefficients += other.coefficients;
+        self.triangles += other.triangles;
+        self.triples += other.triples;
+    }
+
+    fn mean_clustering(&self) -> Option<f64> {
+        (self.n > 0).then(|| self.coefficients / self.n as f64)
+    }
+
+    fn transitivity(&self) -> Option<f64> {
+        (self.triples > 0).then(|| self.triangles as f64 / self.triples as f64)
+    }
+}
+
+/// Clustering of the co-allergy projection of `graph`, overall and per
+/// group of each of `groupings`. Groups are judged on their size and
+/// flagged, masked or merged per `options`; a merged group pools its
+/// members. Each coefficient depends on other individuals, so the report
+/// is refused under differential privacy.
+pub fn clustering(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    groupings: &[Grouping],
+    options: &ReportOptions,
+) -> Result<ClusteringReport, String> {
+    if options.noise.is_some() {
+        return Err("clustering can't be released under differential privacy".to_string());
+    }
+    let projection = project_individuals(graph);
+    let mut overall = Totals::default();
+    let mut nodes = Vec::new();
+    // Per grouping: group -> totals
+    let mut by_group: Vec<BTreeMap<String, Totals>> = vec![BTreeMap::new(); groupings.len()];
+    for (individual, (closed, triples)) in projection.node_weights().zip(triangles(&projection)) {
+        let coefficient = if triples == 0 { 0.0 } else { closed as f64 / triples as f64 };
+        nodes.push(NodeClustering { id: individual.id.clone(), triangles: closed, coefficient });
+        let totals = Totals { n: 1, coefficients: coefficient, triangles: closed, triples };
+        overall.add(totals);
+        for (grouping, groups) in groupings.iter().zip(&mut by_group) {
+            if let Some(group) = grouping.value_of(individual) {
+                groups.entry(group).or_default().add(totals);
+            }
+        }
+    }
+    let mut groups = Vec::new();
+    for (grouping, by_group) in groupings.iter().zip(by_group) {
+        let cells = by_group.iter().map(|(group, totals)| Cell::new(group.clone(), totals.n as f64, totals.n)).collect();
+        let cells = suppress(cells, options.small_cell_threshold, options.suppression);
+        for Cell { group, count, suppressed, .. } in &cells {
+            let totals = by_group.get(group).copied().unwrap_or_else(|| {
+                // A merged group pools the groups no other cell kept
+                let mut pooled = Totals::default();
+                for (_, totals) in by_group.iter().filter(|(name, _)| cells.iter().all(|cell| cell.group != **name)) {
+                    pooled.add(*totals);
+                }
+                pooled
+            });
+            groups.push(GroupClustering {
+                grouping: grouping.label(),
+                group: group.clone(),
+                n: (!suppressed).then_some(*count),
+                mean_clustering: totals.mean_clustering().filter(|_| !suppressed),
+                transitivity: totals.transitivity().filter(|_| !suppressed),
+                small_cell: *count < options.small_cell_threshold,
+                suppressed: *suppressed,
+            });
+        }
+    }
+    Ok(ClusteringReport {
+        transitivity: overall.transitivity(),
+        mean_clustering: overall.mean_clustering(),
+        denominator: projection.node_count(),
+        nodes,
+        groups,
+    })
+}
+
+impl ClusteringReport {
+    /// Groups reported unsuppressed despite being small cells.
+    pub fn small_cells(&self) -> usize {
+        self.groups.iter().filter(|group| group.small_cell && !group.suppressed).count()
+    }
+
+    /// Writes the report as text, or as `clustering`, `node_clustering` and
+    /// `group_clustering` NDJSON rows, after the provenance if there is
+    /// one.
+    pub fn write(&self, options: &ReportOptions, out: &mut dyn Write) -> io::Result<()> {
+        if options.format == OutputFormat::Ndjson {
+            if let Some(provenance) = &options.provenance {
+                let mut row = provenance.clone();
+                row["type"] = "provenance".into();
+                emit_json(out, &row)?;
+            }
+            let row = serde_json::json!({
+                "type": "clustering",
+                "transitivity": self.transitivity,
+                "mean_clustering": self.mean_clustering,
+                "n": self.denominator,
+            });
+            emit_json(out, &row)?;
+            for node in &self.nodes {
+                let mut row = serde_json::to_value(node)?;
+                row["type"] = "node_clustering".into();
+                emit_json(out, &row)?;
+            }
+            for group in &self.groups {
+                let mut row = serde_json::to_value(group)?;
+                row["type"] = "group_clustering".into();
+                emit_json(out, &row)?;
+            }
+            return Ok(());
+        }
+        if let Some(provenance) = &options.provenance {
+            writeln!(out, "# Provenance: {}", provenance)?;
+        }
+        let value = |value: Option<f64>| value.map_or("n/a".to_string(), |value| format!("{:.3}", value));
+        writeln!(
+            out,
+            "# Clustering of the co-allergy projection: transitivity {}, mean local clustering {} over {} individuals",
+            value(self.transitivity),
+            value(self.mean_clustering),
+            self.denominator
+        )?;
+        for group in &self.groups {
+            let Some(n) = group.n else {
+                writeln!(out, "{} {}: suppressed", group.grouping, group.group)?;
+                continue;
+            };
+            write!(
+                out,
+                "{} {}: {}, mean local clustering {}, transitivity {}",
+                group.grouping,
+                group.group,
+                options.show.format(n, self.denominator),
+                value(group.mean_clustering),
+                value(group.transitivity)
+            )?;
+            if group.small_cell {
+                write!(out, " [small cell: n={}]", n)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disclosure::Suppression;
+    use crate::{create_graph, read_csv, GraphOptions, Record};
+
+    #[test]
+    fn test_triangles() {
+        // A triangle with a tail: 0-1-2 closed, 2-3
+        let mut graph = UnGraph::<Individual, usize>::default();
+        let nodes: Vec<NodeIndex> = (0..4).map(|_| graph.add_node(Individual::from(&Record::default()))).collect();
+        for (a, b) in [(0, 1), (1, 2), (0, 2), (2, 3)] {
+            graph.add_edge(nodes[a], nodes[b], 1);
+        }
+        assert_eq!(triangles(&graph), [(1, 1), (1, 1), (1, 3), (0, 0)]);
+    }
+
+    #[test]
+    fn test_clustering() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let options = ReportOptions { small_cell_threshold: 3, suppression: Suppression::Flag, ..Default::default() };
+        let report = clustering(&graph, &["gender".parse().unwrap()], &options).unwrap();
+        // 205654 shares an allergy with 205650, 205651 and 205652, of whom
+        // only 205650 and 205651 share one; 205653 has no allergies
+        let coefficients: Vec<f64> = report.nodes.iter().map(|node| node.coefficient).collect();
+        assert_eq!(coefficients, [1.0, 1.0, 0.0, 0.0, 1.0 / 3.0]);
+        assert_eq!((report.transitivity, report.denominator), (Some(0.6), 5));
+        assert!((report.mean_clustering.unwrap() - 7.0 / 15.0).abs() < 1e-12);
+        let male = &report.groups[0];
+        assert_eq!((male.group.as_str(), male.n, male.transitivity), ("S0 - Male", Some(3), Some(0.5)));
+        assert!((male.mean_clustering.unwrap() - 4.0 / 9.0).abs() < 1e-12);
+        let female = &report.groups[1];
+        assert_eq!((female.mean_clustering, female.transitivity, female.small_cell), (Some(0.5), Some(1.0), true));
+        let mut out = Vec::new();
+        report.write(&options, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("# Clustering of the co-allergy projection: transitivity 0.600, mean local clustering 0.467"));
+        assert!(text.contains("\ngender S0 - Male: n=3 of 5, mean local clustering 0.444, transitivity 0.500\n"));
+
+        // Both genders pooled
+        let merged = ReportOptions { suppression: Suppression::Merge, ..options };
+        let report = clustering(&graph, &["gender".parse().unwrap()], &merged).unwrap();
+        let other = &report.groups[1];
+        assert!(other.group.starts_with("other (") && other.suppressed && other.transitivity.is_none());
+    }
+}
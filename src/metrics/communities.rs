@@ -0,0 +1,252 @@
+//! Communities of individuals who share allergies, found by label
+//! propagation over the co-allergy projection (`project_individuals`), and
+//! each community's make-up by demographic group.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use petgraph::graph::{DiGraph, NodeIndex, UnGraph};
+use petgraph::visit::EdgeRef;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::Serialize;
+
+use super::{emit_json, OutputFormat, ReportOptions};
+use crate::disclosure::{suppress, Cell};
+use crate::projection::project_individuals;
+use crate::strata::Grouping;
+use crate::{EdgeWeight, Individual, NodeType};
+
+/// Rounds of label propagation before giving up on convergence.
+const MAX_ROUNDS: usize = 100;
+
+/// Community of each node of `graph`, numbered from 0, or `None` for an
+/// individual who shares no allergy with anyone. Nodes are visited in an
+/// order shuffled by `seed` each round and take the label with the most
+/// edge weight among their neighbours, keeping their own on a tie and
+/// otherwise taking the lowest, so the result is reproducible.
+pub fn label_propagation(graph: &UnGraph<Individual, usize>, seed: u64) -> Vec<Option<usize>> {
+    let mut labels: Vec<usize> = (0..graph.node_count()).collect();
+    let mut order: Vec<NodeIndex> = graph.node_indices().collect();
+    let mut rng = StdRng::seed_from_u64(seed);
+    for _ in 0..MAX_ROUNDS {
+        order.shuffle(&mut rng);
+        let mut changed = false;
+        for &node in &order {
+            let mut weights: BTreeMap<usize, usize> = BTreeMap::new();
+            for edge in graph.edges(node) {
+                let neighbour = if edge.source() == node { edge.target() } else { edge.source() };
+                *weights.entry(labels[neighbour.index()]).or_default() += edge.weight();
+            }
+            let Some(&best) = weights.values().max() else { continue };
+            let current = labels[node.index()];
+            if weights.get(&current) == Some(&best) {
+                continue;
+            }
+            labels[node.index()] = weights.iter().find(|&(_, &weight)| weight == best).map_or(current, |(&label, _)| label);
+            changed = true;
+        }
+        if !changed {
+            break;
+        }
+    }
+    // Number communities by size, largest first, then by first member
+    let mut sizes: BTreeMap<usize, (usize, usize)> = BTreeMap::new();
+    for node in graph.node_indices().filter(|&node| graph.neighbors(node).next().is_some()) {
+        let entry = sizes.entry(labels[node.index()]).or_insert((0, node.index()));
+        entry.0 += 1;
+    }
+    let mut ranked: Vec<(usize, (usize, usize))> = sizes.into_iter().collect();
+    ranked.sort_by_key(|&(_, (size, first))| (std::cmp::Reverse(size), first));
+    let number: BTreeMap<usize, usize> = ranked.iter().enumerate().map(|(i, &(label, _))| (label, i)).collect();
+    graph
+        .node_indices()
+        .map(|node| graph.neighbors(node).next().and(number.get(&labels[node.index()]).copied()))
+        .collect()
+}
+
+/// The community of one individual.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Membership {
+    pub id: String,
+    pub community: usize,
+}
+
+/// Members of one community in one group. `n` is `None` when the group is
+/// suppressed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CommunityGroup {
+    pub community: usize,
+    pub grouping: String,
+    pub group: String,
+    pub n: Option<usize>,
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CommunityReport {
+    /// Members of each community, largest first.
+    pub sizes: Vec<usize>,
+    /// Individuals in the graph, including those in no community.
+    pub denominator: usize,
+    pub members: Vec<Membership>,
+    pub groups: Vec<CommunityGroup>,
+}
+
+/// Finds the communities of `graph` and breaks each down by `groupings`.
+/// Membership depends on every other individual, so it is refused under
+/// differential privacy.
+pub fn detect_communities(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    groupings: &[Grouping],
+    options: &ReportOptions,
+) -> Result<CommunityReport, String> {
+    if options.noise.is_some() {
+        return Err("communities can't be released under differential privacy".to_string());
+    }
+    let projection = project_individuals(graph);
+    let communities = label_propagation(&projection, options.seed);
+    let mut sizes = Vec::new();
+    let mut members = Vec::new();
+    // Per community and grouping: group -> members
+    let mut counts: Vec<Vec<BTreeMap<String, usize>>> = Vec::new();
+    for (individual, community) in projection.node_weights().zip(&communities) {
+        let Some(community) = *community else { continue };
+        if community >= sizes.len() {
+            sizes.resize(community + 1, 0);
+            counts.resize(community + 1, vec![BTreeMap::new(); groupings.len()]);
+        }
+        sizes[community] += 1;
+        members.push(Membership { id: individual.id.clone(), community });
+        for (grouping, groups) in groupings.iter().zip(&mut counts[community]) {
+            if let Some(group) = grouping.value_of(individual) {
+                *groups.entry(group).or_default() += 1;
+            }
+        }
+    }
+    let mut groups = Vec::new();
+    for (community, by_grouping) in counts.into_iter().enumerate() {
+        for (grouping, by_group) in groupings.iter().zip(by_grouping) {
+            let cells = by_group.into_iter().map(|(group, n)| Cell::new(group, n as f64, n)).collect();
+            let cells = suppress(cells, options.small_cell_threshold, options.suppression);
+            for Cell { group, count, suppressed, .. } in cells {
+                groups.push(CommunityGroup {
+                    community,
+                    grouping: grouping.label(),
+                    group,
+                    n: (!suppressed).then_some(count),
+                    small_cell: count < options.small_cell_threshold,
+                    suppressed,
+                });
+            }
+        }
+    }
+    Ok(CommunityReport { sizes, denominator: projection.node_count(), members, groups })
+}
+
+impl CommunityReport {
+    /// Groups reported unsuppressed despite being small cells.
+    pub fn small_cells(&self) -> usize {
+        self.groups.iter().filter(|group| group.small_cell && !group.suppressed).count()
+    }
+
+    /// Writes the report as text, or as `membership`, `community` and
+    /// `community_group` NDJSON rows, after the provenance if there is one.
+    pub fn write(&self, options: &ReportOptions, out: &mut dyn Write) -> io::Result<()> {
+        if options.format == OutputFormat::Ndjson {
+            if let Some(provenance) = &options.provenance {
+                let mut row = provenance.clone();
+                row["type"] = "provenance".into();
+                emit_json(out, &row)?;
+            }
+            for member in &self.members {
+                let row = serde_json::json!({ "type": "membership", "id": member.id, "community": member.community });
+                emit_json(out, &row)?;
+            }
+            for (community, size) in self.sizes.iter().enumerate() {
+                emit_json(out, &serde_json::json!({ "type": "community", "community": community, "n": size }))?;
+            }
+            for group in &self.groups {
+                let mut row = serde_json::to_value(group)?;
+                row["type"] = "community_group".into();
+                emit_json(out, &row)?;
+            }
+            return Ok(());
+        }
+        if let Some(provenance) = &options.provenance {
+            writeln!(out, "# Provenance: {}", provenance)?;
+        }
+        let assigned: usize = self.sizes.iter().sum();
+        writeln!(
+            out,
+            "# Communities: {}; {} of {} individuals share an allergy with someone",
+            self.sizes.len(),
+            assigned,
+            self.denominator
+        )?;
+        for (community, size) in self.sizes.iter().enumerate() {
+            writeln!(out, "Community {}: {} individuals", community, size)?;
+            for group in self.groups.iter().filter(|group| group.community == community) {
+                let Some(n) = group.n else {
+                    writeln!(out, "  {} {}: suppressed", group.grouping, group.group)?;
+                    continue;
+                };
+                write!(out, "  {} {}: {}", group.grouping, group.group, options.show.format(n, *size))?;
+                if group.small_cell {
+                    write!(out, " [small cell: n={}]", n)?;
+                }
+                writeln!(out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disclosure::Suppression;
+    use crate::{create_graph, read_csv, GraphOptions, Record};
+
+    #[test]
+    fn test_communities() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let projection = project_individuals(&graph);
+        // The fixture's co-allergy graph is connected apart from 205653
+        assert_eq!(label_propagation(&projection, 7), [Some(0), Some(0), Some(0), None, Some(0)]);
+
+        let options = ReportOptions { seed: 7, small_cell_threshold: 2, ..Default::default() };
+        let report = detect_communities(&graph, &["gender".parse().unwrap()], &options).unwrap();
+        assert_eq!((report.sizes.as_slice(), report.denominator, report.members.len()), (&[4][..], 5, 4));
+        let male = &report.groups[0];
+        assert_eq!((male.group.as_str(), male.n, male.small_cell), ("S0 - Male", Some(2), false));
+        let mut out = Vec::new();
+        report.write(&options, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("# Communities: 1; 4 of 5 individuals share an allergy with someone\n"));
+        assert!(text.contains("Community 0: 4 individuals\n  gender S0 - Male: n=2 of 4\n"));
+
+        let masked = ReportOptions { small_cell_threshold: 3, suppression: Suppression::Mask, ..options };
+        let report = detect_communities(&graph, &["payer".parse().unwrap()], &masked).unwrap();
+        assert!(report.groups.iter().all(|group| group.suppressed && group.n.is_none()));
+    }
+
+    #[test]
+    fn test_label_propagation_separates_clusters() {
+        // Two triangles joined by one light edge
+        let mut graph = UnGraph::<Individual, usize>::default();
+        let nodes: Vec<NodeIndex> = (0..6).map(|_| graph.add_node(Individual::from(&Record::default()))).collect();
+        for (a, b) in [(0, 1), (1, 2), (0, 2), (3, 4), (4, 5), (3, 5)] {
+            graph.add_edge(nodes[a], nodes[b], 3);
+        }
+        graph.add_edge(nodes[2], nodes[3], 1);
+        let communities = label_propagation(&graph, 1);
+        assert_eq!(communities[0], communities[1]);
+        assert_eq!(communities[3], communities[5]);
+        assert_ne!(communities[0], communities[3]);
+        assert_eq!(communities, label_propagation(&graph, 1));
+    }
+}
@@ -0,0 +1,175 @@
+//! Connected components of the individual-allergy graph: how many there
+//! are, the giant component's share of the individuals, and the isolated
+//! individuals, who have no allergies.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use petgraph::graph::DiGraph;
+use petgraph::unionfind::UnionFind;
+use serde::Serialize;
+
+use super::{emit_json, OutputFormat, ReportOptions};
+use crate::{EdgeWeight, NodeType};
+
+/// Members of one component.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Component {
+    pub individuals: usize,
+    pub allergens: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ComponentReport {
+    /// Individuals in the graph.
+    pub denominator: usize,
+    /// Components with an individual, largest first; the first is the
+    /// giant component.
+    pub components: Vec<Component>,
+    /// Subject ids of the individuals with no allergies, each a component
+    /// of their own.
+    pub isolates: Vec<String>,
+    /// Allergies no individual has.
+    pub unlinked_allergens: Vec<String>,
+}
+
+/// Finds the components of `graph`, joined by individual→allergy edges
+/// regardless of direction; demographic nodes don't join anyone. Isolates
+/// are listed by subject id, so the report is refused under differential
+/// privacy.
+pub fn components(graph: &DiGraph<NodeType, EdgeWeight>, options: &ReportOptions) -> Result<ComponentReport, String> {
+    if options.noise.is_some() {
+        return Err("components can't be released under differential privacy".to_string());
+    }
+    let mut sets = UnionFind::new(graph.node_count());
+    for edge in graph.edge_indices() {
+        let (source, target) = graph.edge_endpoints(edge).expect("edge of the graph");
+        if matches!((&graph[source], &graph[target]), (NodeType::Individual(_), NodeType::AllergenStatus(_))) {
+            sets.union(source.index(), target.index());
+        }
+    }
+    let roots = sets.into_labeling();
+    // Components in order of their first node, and the index of each root
+    let mut found: Vec<Component> = Vec::new();
+    let mut index: HashMap<usize, usize> = HashMap::new();
+    let mut isolates = Vec::new();
+    let mut unlinked_allergens = Vec::new();
+    let mut denominator = 0;
+    for node in graph.node_indices() {
+        let component = *index.entry(roots[node.index()]).or_insert_with(|| {
+            found.push(Component { individuals: 0, allergens: 0 });
+            found.len() - 1
+        });
+        let alone = graph.neighbors_undirected(node).all(|other| matches!(graph[other], NodeType::Demographic { .. }));
+        match &graph[node] {
+            NodeType::Individual(individual) => {
+                denominator += 1;
+                found[component].individuals += 1;
+                if alone {
+                    isolates.push(individual.id.clone());
+                }
+            }
+            NodeType::AllergenStatus(name) => {
+                found[component].allergens += 1;
+                if alone {
+                    unlinked_allergens.push(name.clone());
+                }
+            }
+            NodeType::Demographic { .. } => {}
+        }
+    }
+    let mut components: Vec<Component> = found.into_iter().filter(|component| component.individuals > 0).collect();
+    // Stable, so ties stay in graph order
+    components.sort_by_key(|component| std::cmp::Reverse(component.individuals));
+    Ok(ComponentReport { denominator, components, isolates, unlinked_allergens })
+}
+
+impl ComponentReport {
+    /// Individuals in the giant component.
+    pub fn giant(&self) -> usize {
+        self.components.first().map_or(0, |component| component.individuals)
+    }
+
+    /// Writes the report as text, or as a `components` NDJSON summary row,
+    /// a `component` row per component and an `isolate` row per isolated
+    /// individual, after the provenance if there is one.
+    pub fn write(&self, options: &ReportOptions, out: &mut dyn Write) -> io::Result<()> {
+        if options.format == OutputFormat::Ndjson {
+            if let Some(provenance) = &options.provenance {
+                let mut row = provenance.clone();
+                row["type"] = "provenance".into();
+                emit_json(out, &row)?;
+            }
+            emit_json(
+                out,
+                &serde_json::json!({
+                    "type": "components",
+                    "components": self.components.len(),
+                    "giant": self.giant(),
+                    "denominator": self.denominator,
+                    "isolates": self.isolates.len(),
+                    "unlinked_allergens": self.unlinked_allergens,
+                }),
+            )?;
+            for (i, component) in self.components.iter().enumerate() {
+                let mut row = serde_json::to_value(component)?;
+                row["type"] = "component".into();
+                row["component"] = (i + 1).into();
+                emit_json(out, &row)?;
+            }
+            for id in &self.isolates {
+                emit_json(out, &serde_json::json!({ "type": "isolate", "id": id }))?;
+            }
+            return Ok(());
+        }
+        if let Some(provenance) = &options.provenance {
+            writeln!(out, "# Provenance: {}", provenance)?;
+        }
+        writeln!(
+            out,
+            "# Components: {}; giant component {}",
+            self.components.len(),
+            options.show.format(self.giant(), self.denominator)
+        )?;
+        for (i, component) in self.components.iter().enumerate() {
+            writeln!(out, "Component {}: {} individuals, {} allergies", i + 1, component.individuals, component.allergens)?;
+        }
+        writeln!(out, "Isolated individuals (no allergies): {}", self.isolates.len())?;
+        for id in &self.isolates {
+            writeln!(out, "  {}", id)?;
+        }
+        if !self.unlinked_allergens.is_empty() {
+            writeln!(out, "Allergies no one has: {}", self.unlinked_allergens.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::GraphMode;
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    #[test]
+    fn test_components() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records.clone(), &GraphOptions::default());
+        let options = ReportOptions::default();
+        let report = components(&graph, &options).unwrap();
+        // Peanut and Treenut join everyone with an allergy; 205653 has none
+        let expected = [Component { individuals: 4, allergens: 6 }, Component { individuals: 1, allergens: 0 }];
+        assert_eq!((report.denominator, report.components.as_slice()), (5, &expected[..]));
+        assert_eq!(report.isolates, ["205653"]);
+        assert_eq!(report.unlinked_allergens, ["Almond", "Brazil", "Hazelnut"]);
+        let mut out = Vec::new();
+        report.write(&options, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("# Components: 2; giant component n=4 of 5\nComponent 1: 4 individuals, 6"));
+        assert!(text.contains("Isolated individuals (no allergies): 1\n  205653\n"), "{}", text);
+
+        // Demographic nodes don't join the isolate to anyone
+        let tripartite = GraphOptions { mode: GraphMode::Tripartite, ..Default::default() };
+        assert_eq!(components(&create_graph(records, &tripartite), &options).unwrap(), report);
+    }
+}
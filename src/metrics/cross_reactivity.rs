@@ -0,0 +1,319 @@
+//! Cross-reactivity clusters (`cross-reactivity` subcommand): allergies
+//! grouped by average-linkage hierarchical clustering of the co-occurrence
+//! projection (`project_allergies`), checked against the botanically
+//! related clusters expected to co-occur.
+
+use std::io::{self, Write};
+
+use petgraph::graph::DiGraph;
+use petgraph::Direction;
+use serde::Serialize;
+
+use super::{emit_json, OutputFormat, ReportOptions};
+use crate::disclosure::Suppression;
+use crate::projection::project_allergies;
+use crate::taxonomy::Taxonomy;
+use crate::{EdgeWeight, NodeType};
+
+/// Allergies known to cross-react, being botanically related: cashew and
+/// pistachio (Anacardiaceae), walnut and pecan (Juglandaceae).
+pub const KNOWN_CLUSTERS: &[&[&str]] = &[&["Cashew", "Pistachio"], &["Walnut", "Pecan"]];
+
+/// Whether a known cluster turned up in the data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Detection {
+    /// Its allergies all fell in one cluster.
+    Detected,
+    /// Its allergies were split between clusters.
+    Missed,
+    /// One of its allergies isn't in the graph, or no one has it.
+    Absent,
+}
+
+/// Allergies clustered together. `similarity` and `n` are `None` when it
+/// is suppressed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Cluster {
+    /// In graph order.
+    pub allergies: Vec<String>,
+    /// Average Jaccard similarity of its pairs of allergies.
+    pub similarity: Option<f64>,
+    /// Individuals with at least two of its allergies.
+    pub n: Option<usize>,
+    /// Whether its allergies all belong to one of `KNOWN_CLUSTERS`.
+    pub expected: bool,
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+/// One of `KNOWN_CLUSTERS`, and whether it was found.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KnownCluster {
+    pub allergies: Vec<String>,
+    pub detection: Detection,
+    /// As for `Cluster`, over its allergies in the graph.
+    pub similarity: Option<f64>,
+    pub n: Option<usize>,
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CrossReactivityReport {
+    /// Clusters are merged while their similarity is at least this.
+    pub min_similarity: f64,
+    /// Allergy nodes left out as aggregates of others in the graph, such
+    /// as Treenut.
+    pub excluded: Vec<String>,
+    /// Clusters of two or more allergies, by their first allergy.
+    pub clusters: Vec<Cluster>,
+    pub known: Vec<KnownCluster>,
+}
+
+/// Clusters the allergies of `graph` by the Jaccard similarity of who has
+/// them: individuals with both over individuals with either. Starting from
+/// one cluster per allergy someone has, the two clusters with the highest
+/// average similarity between their allergies are merged, first in graph
+/// order on a tie, until none reaches `min_similarity`. Allergies with
+/// descendants in the graph under `taxonomy` are left out, since they
+/// aggregate them. Clusters supported by fewer individuals than the
+/// small-cell threshold are flagged, or masked under `--suppress mask` or
+/// `merge`. The clusters depend on every individual, so they are refused
+/// under differential privacy.
+pub fn cross_reactivity(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    taxonomy: &Taxonomy,
+    min_similarity: f64,
+    options: &ReportOptions,
+) -> Result<CrossReactivityReport, String> {
+    if options.noise.is_some() {
+        return Err("cross-reactivity clusters can't be released under differential privacy".to_string());
+    }
+    let projection = project_allergies(graph);
+    let names: Vec<&str> = projection.node_weights().map(String::as_str).collect();
+    let excluded: Vec<String> = names
+        .iter()
+        .filter(|&&name| names.iter().any(|&other| taxonomy.is_ancestor(name, other)))
+        .map(|name| name.to_string())
+        .collect();
+    // Allergies of each individual, as positions in `names`
+    let allergens: Vec<_> = graph.node_indices().filter(|&n| matches!(graph[n], NodeType::AllergenStatus(_))).collect();
+    let individuals: Vec<Vec<usize>> = graph
+        .node_indices()
+        .filter(|&node| matches!(graph[node], NodeType::Individual(_)))
+        .map(|node| {
+            let allergies = graph.neighbors_directed(node, Direction::Outgoing);
+            let mut allergies: Vec<usize> = allergies.filter_map(|a| allergens.iter().position(|&b| a == b)).collect();
+            allergies.sort();
+            allergies.dedup();
+            allergies
+        })
+        .collect();
+    let mut counts = vec![0; names.len()];
+    for &allergy in individuals.iter().flatten() {
+        counts[allergy] += 1;
+    }
+    let mut both = vec![vec![0; names.len()]; names.len()];
+    for edge in projection.edge_indices() {
+        let (a, b) = projection.edge_endpoints(edge).expect("edge of the projection");
+        both[a.index()][b.index()] = projection[edge];
+        both[b.index()][a.index()] = projection[edge];
+    }
+    let jaccard = |a: usize, b: usize| both[a][b] as f64 / (counts[a] + counts[b] - both[a][b]) as f64;
+    let similarity = |cluster: &[usize]| {
+        let pairs = cluster.iter().enumerate().flat_map(|(i, &a)| cluster[i + 1..].iter().map(move |&b| (a, b)));
+        let similarities: Vec<f64> = pairs.map(|(a, b)| jaccard(a, b)).collect();
+        similarities.iter().sum::<f64>() / similarities.len() as f64
+    };
+    let support = |cluster: &[usize]| {
+        individuals.iter().filter(|allergies| allergies.iter().filter(|a| cluster.contains(a)).count() >= 2).count()
+    };
+
+    let mut clusters: Vec<Vec<usize>> = (0..names.len())
+        .filter(|&allergy| counts[allergy] > 0 && !excluded.iter().any(|name| name == names[allergy]))
+        .map(|allergy| vec![allergy])
+        .collect();
+    loop {
+        let mut best: Option<(f64, usize, usize)> = None;
+        for i in 0..clusters.len() {
+            for j in i + 1..clusters.len() {
+                let pairs = clusters[i].iter().flat_map(|&a| clusters[j].iter().map(move |&b| (a, b)));
+                let average = pairs.clone().map(|(a, b)| jaccard(a, b)).sum::<f64>() / pairs.count() as f64;
+                if average >= min_similarity && best.is_none_or(|(highest, _, _)| average > highest) {
+                    best = Some((average, i, j));
+                }
+            }
+        }
+        let Some((_, i, j)) = best else { break };
+        let merged = clusters.remove(j);
+        clusters[i].extend(merged);
+        clusters[i].sort();
+    }
+
+    let hidden = |n: usize| n < options.small_cell_threshold && options.suppression != Suppression::Flag;
+    let found: Vec<Cluster> = clusters
+        .iter()
+        .filter(|cluster| cluster.len() >= 2)
+        .map(|cluster| {
+            let n = support(cluster);
+            let allergies: Vec<String> = cluster.iter().map(|&allergy| names[allergy].to_string()).collect();
+            Cluster {
+                expected: KNOWN_CLUSTERS.iter().any(|known| allergies.iter().all(|a| known.contains(&a.as_str()))),
+                allergies,
+                similarity: (!hidden(n)).then(|| similarity(cluster)),
+                n: (!hidden(n)).then_some(n),
+                small_cell: n < options.small_cell_threshold,
+                suppressed: hidden(n),
+            }
+        })
+        .collect();
+    let known = KNOWN_CLUSTERS
+        .iter()
+        .map(|known| {
+            let members: Option<Vec<usize>> = known
+                .iter()
+                .map(|name| names.iter().position(|other| other == name).filter(|&allergy| counts[allergy] > 0))
+                .collect();
+            let allergies = known.iter().map(|name| name.to_string()).collect();
+            let Some(mut members) = members else {
+                let detection = Detection::Absent;
+                let (similarity, n, small_cell, suppressed) = (None, None, false, false);
+                return KnownCluster { allergies, detection, similarity, n, small_cell, suppressed };
+            };
+            members.sort();
+            let together = clusters.iter().any(|cluster| members.iter().all(|allergy| cluster.contains(allergy)));
+            let n = support(&members);
+            KnownCluster {
+                allergies,
+                detection: if together { Detection::Detected } else { Detection::Missed },
+                similarity: (!hidden(n)).then(|| similarity(&members)),
+                n: (!hidden(n)).then_some(n),
+                small_cell: n < options.small_cell_threshold,
+                suppressed: hidden(n),
+            }
+        })
+        .collect();
+    Ok(CrossReactivityReport { min_similarity, excluded, clusters: found, known })
+}
+
+impl CrossReactivityReport {
+    /// Clusters reported unsuppressed despite being small cells.
+    pub fn small_cells(&self) -> usize {
+        let clusters = self.clusters.iter().map(|c| (c.small_cell, c.suppressed));
+        let known = self.known.iter().map(|c| (c.small_cell, c.suppressed));
+        clusters.chain(known).filter(|&(small_cell, suppressed)| small_cell && !suppressed).count()
+    }
+
+    /// Writes the report as text, or as `cluster` and `known_cluster`
+    /// NDJSON rows, after the provenance if there is one.
+    pub fn write(&self, options: &ReportOptions, out: &mut dyn Write) -> io::Result<()> {
+        if options.format == OutputFormat::Ndjson {
+            if let Some(provenance) = &options.provenance {
+                let mut row = provenance.clone();
+                row["type"] = "provenance".into();
+                emit_json(out, &row)?;
+            }
+            for cluster in &self.clusters {
+                let mut row = serde_json::to_value(cluster)?;
+                row["type"] = "cluster".into();
+                emit_json(out, &row)?;
+            }
+            for known in &self.known {
+                let mut row = serde_json::to_value(known)?;
+                row["type"] = "known_cluster".into();
+                emit_json(out, &row)?;
+            }
+            return Ok(());
+        }
+        if let Some(provenance) = &options.provenance {
+            writeln!(out, "# Provenance: {}", provenance)?;
+        }
+        writeln!(
+            out,
+            "# Cross-reactivity clusters: average-linkage clustering of Jaccard similarity, merged down to {}",
+            self.min_similarity
+        )?;
+        if !self.excluded.is_empty() {
+            writeln!(out, "# Left out as aggregates of other allergies: {}", self.excluded.join(", "))?;
+        }
+        if self.clusters.is_empty() {
+            writeln!(out, "No clusters")?;
+        }
+        for (i, cluster) in self.clusters.iter().enumerate() {
+            write!(out, "Cluster {}: {}", i + 1, cluster.allergies.join(", "))?;
+            write_support(out, cluster.similarity, cluster.n, cluster.small_cell)?;
+            writeln!(out, "{}", if cluster.expected { "" } else { " [unexpected]" })?;
+        }
+        writeln!(out, "Known clusters:")?;
+        for known in &self.known {
+            let detection = match known.detection {
+                Detection::Detected => "detected",
+                Detection::Missed => "missed",
+                Detection::Absent => "absent from the data",
+            };
+            write!(out, "  {}: {}", known.allergies.join("–"), detection)?;
+            if known.detection != Detection::Absent {
+                write_support(out, known.similarity, known.n, known.small_cell)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_support(out: &mut dyn Write, similarity: Option<f64>, n: Option<usize>, small_cell: bool) -> io::Result<()> {
+    let (Some(similarity), Some(n)) = (similarity, n) else { return write!(out, " (suppressed)") };
+    write!(out, " (similarity {:.2}, n={})", similarity, n)?;
+    if small_cell {
+        write!(out, " [small cell: n={}]", n)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::taxonomy::TaxonomyLevel;
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    #[test]
+    fn test_cross_reactivity() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records.clone(), &GraphOptions::default());
+        let options = ReportOptions { small_cell_threshold: 2, suppression: Suppression::Flag, ..Default::default() };
+        let report = cross_reactivity(&graph, &Taxonomy::default(), 0.5, &options).unwrap();
+        assert_eq!(report.excluded, ["Treenut"]);
+        // Walnut and Pecan: only 205652, so 1; Peanut and Cashew: 2 of 3.
+        // Pistachio joins neither: 1/2 with Cashew but 1/3 with Peanut
+        let clusters: Vec<(Vec<String>, bool)> =
+            report.clusters.iter().map(|cluster| (cluster.allergies.clone(), cluster.expected)).collect();
+        let walnut = (vec!["Walnut".to_string(), "Pecan".to_string()], true);
+        let peanut = (vec!["Peanut".to_string(), "Cashew".to_string()], false);
+        assert_eq!(clusters, [peanut, walnut]);
+        assert!((report.clusters[0].similarity.unwrap() - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!((report.clusters[1].n, report.clusters[1].small_cell), (Some(1), true));
+        let cashew = &report.known[0];
+        assert_eq!((cashew.detection, cashew.similarity, cashew.n), (Detection::Missed, Some(0.5), Some(1)));
+        assert_eq!(report.known[1].detection, Detection::Detected);
+        let mut out = Vec::new();
+        report.write(&options, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("Cluster 1: Peanut, Cashew (similarity 0.67, n=2) [unexpected]\n"), "{}", text);
+        assert!(text.contains("  Cashew–Pistachio: missed (similarity 0.50, n=1) [small cell: n=1]\n"), "{}", text);
+
+        // Lower, Pistachio joins Peanut and Cashew at 5/12
+        let report = cross_reactivity(&graph, &Taxonomy::default(), 0.4, &options).unwrap();
+        assert_eq!(report.clusters[0].allergies, ["Peanut", "Pistachio", "Cashew"]);
+        assert_eq!(report.known[0].detection, Detection::Detected);
+
+        let masked = ReportOptions { suppression: Suppression::Mask, ..options };
+        let report = cross_reactivity(&graph, &Taxonomy::default(), 0.5, &masked).unwrap();
+        assert!(report.clusters[1].suppressed && report.clusters[1].similarity.is_none());
+
+        let top = GraphOptions { taxonomy_level: TaxonomyLevel::Depth(1), ..Default::default() };
+        let report = cross_reactivity(&create_graph(records, &top), &Taxonomy::default(), 0.5, &masked).unwrap();
+        assert!(report.excluded.is_empty());
+        assert!(report.known.iter().all(|known| known.detection == Detection::Absent));
+    }
+}
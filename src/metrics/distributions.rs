@@ -0,0 +1,217 @@
+//! The distribution of individuals' degrees, i.e. how many allergies each
+//! has: a histogram and summary statistics, overall and per demographic
+//! group (`distribution` subcommand).
+
+use std::collections::BTreeMap;
+
+use petgraph::graph::DiGraph;
+use petgraph::Direction;
+use serde::Serialize;
+
+use super::ReportOptions;
+use crate::disclosure::{suppress, Cell, Suppression};
+use crate::stats::{count_cells, group_of, label, with_overall};
+use crate::strata::Grouping;
+use crate::{EdgeWeight, NodeType};
+
+/// Summary of the degrees in one group. The statistics are `None` when
+/// the group is suppressed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DegreeSummary {
+    pub grouping: String,
+    pub group: String,
+    /// Individuals in the group.
+    pub n: Option<usize>,
+    pub mean: Option<f64>,
+    pub median: Option<f64>,
+    /// 95th percentile, interpolated between ranks as `numpy.percentile`
+    /// does by default.
+    pub p95: Option<f64>,
+    pub max: Option<usize>,
+    /// Fewer individuals than the small-cell threshold.
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+/// Individuals in one group with one degree. `individuals` is `None` when
+/// the bin is suppressed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DegreeBin {
+    pub grouping: String,
+    pub group: String,
+    pub degree: usize,
+    pub individuals: Option<usize>,
+    /// Fewer individuals than the small-cell threshold.
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DistributionReport {
+    pub summaries: Vec<DegreeSummary>,
+    /// A bin for every degree from 0 to the largest in the graph, for each
+    /// unsuppressed row of `summaries`, in the same order.
+    pub histogram: Vec<DegreeBin>,
+}
+
+/// The `q`th percentile (0 to 100) of ascending `sorted` values, linearly
+/// interpolated between the two nearest ranks, or `None` if there are none.
+pub fn percentile(sorted: &[usize], q: f64) -> Option<f64> {
+    let last = sorted.len().checked_sub(1)?;
+    let rank = q / 100.0 * last as f64;
+    let (below, above) = (rank.floor() as usize, rank.ceil() as usize);
+    let fraction = rank - below as f64;
+    Some(sorted[below] as f64 + fraction * (sorted[above] as f64 - sorted[below] as f64))
+}
+
+/// Degree distribution of the individuals in `graph`, first over all of
+/// them, then per group of each of `groupings`. Only edges to allergens
+/// count, so a tripartite graph's demographic edges don't. Small cells are
+/// judged on the group's size and flagged, masked or merged per `options`
+/// (masked for the overall row). Each histogram bin is judged on its own
+/// count, and masked under `mask` and `merge`. Refused under differential
+/// privacy; see [`ReportOptions::noise`].
+pub fn degree_distribution(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    groupings: &[Grouping],
+    options: &ReportOptions,
+) -> Result<DistributionReport, String> {
+    if options.noise.is_some() {
+        return Err("degree distributions can't be released under differential privacy".to_string());
+    }
+    let individuals: Vec<_> = graph
+        .node_indices()
+        .filter_map(|node| {
+            let NodeType::Individual(individual) = &graph[node] else { return None };
+            let degree = graph
+                .neighbors_directed(node, Direction::Outgoing)
+                .filter(|&target| matches!(graph[target], NodeType::AllergenStatus(_)))
+                .count();
+            Some((individual, degree))
+        })
+        .collect();
+    let largest = individuals.iter().map(|&(_, degree)| degree).max().unwrap_or(0);
+    let bin_mode = match options.suppression {
+        Suppression::Flag => Suppression::Flag,
+        Suppression::Mask | Suppression::Merge => Suppression::Mask,
+    };
+    let mut summaries = Vec::new();
+    let mut histogram = Vec::new();
+    for grouping in with_overall(groupings) {
+        let mut groups: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+        for &(individual, degree) in &individuals {
+            if let Some(group) = group_of(grouping, individual) {
+                groups.entry(group).or_default().push(degree);
+            }
+        }
+        let sizes = groups.iter().map(|(group, degrees)| (group.clone(), (degrees.len(), degrees.len()))).collect();
+        let cells = count_cells(sizes, grouping, options);
+        let label = label(grouping);
+        for Cell { group, count: n, suppressed, .. } in &cells {
+            // A merged group pools the groups no other cell kept
+            let mut degrees: Vec<usize> = match groups.get(group) {
+                Some(degrees) => degrees.clone(),
+                None => groups
+                    .iter()
+                    .filter(|(name, _)| cells.iter().all(|cell| cell.group != **name))
+                    .flat_map(|(_, degrees)| degrees.iter().copied())
+                    .collect(),
+            };
+            degrees.sort_unstable();
+            let released = |value: Option<f64>| value.filter(|_| !suppressed);
+            summaries.push(DegreeSummary {
+                grouping: label.clone(),
+                group: group.clone(),
+                n: (!suppressed).then_some(*n),
+                mean: released((!degrees.is_empty()).then(|| degrees.iter().sum::<usize>() as f64 / *n as f64)),
+                median: released(percentile(&degrees, 50.0)),
+                p95: released(percentile(&degrees, 95.0)),
+                max: degrees.last().copied().filter(|_| !suppressed),
+                small_cell: *n < options.small_cell_threshold,
+                suppressed: *suppressed,
+            });
+            if *suppressed {
+                continue;
+            }
+            let mut counts = vec![0; largest + 1];
+            for &degree in &degrees {
+                counts[degree] += 1;
+            }
+            let bins = counts.into_iter().enumerate().map(|(degree, count)| Cell::new(degree.to_string(), 0.0, count));
+            for (degree, bin) in suppress(bins.collect(), options.small_cell_threshold, bin_mode).into_iter().enumerate() {
+                histogram.push(DegreeBin {
+                    grouping: label.clone(),
+                    group: group.clone(),
+                    degree,
+                    individuals: (!bin.suppressed).then_some(bin.count),
+                    small_cell: bin.count < options.small_cell_threshold,
+                    suppressed: bin.suppressed,
+                });
+            }
+        }
+    }
+    Ok(DistributionReport { summaries, histogram })
+}
+
+impl DistributionReport {
+    /// Groups and bins reported unsuppressed despite being small cells.
+    pub fn small_cells(&self) -> usize {
+        let summaries = self.summaries.iter().filter(|row| row.small_cell && !row.suppressed).count();
+        summaries + self.histogram.iter().filter(|bin| bin.small_cell && !bin.suppressed).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::{write_csv, OVERALL};
+    use crate::{create_graph, read_csv, GraphMode, GraphOptions};
+
+    #[test]
+    fn test_percentile() {
+        assert_eq!(percentile(&[0, 1, 2, 3, 4], 50.0), Some(2.0));
+        assert_eq!(percentile(&[1, 3], 50.0), Some(2.0));
+        assert!((percentile(&[0, 1, 2, 3, 4], 95.0).unwrap() - 3.8).abs() < 1e-12);
+        assert_eq!(percentile(&[5], 95.0), Some(5.0));
+        assert_eq!(percentile(&[], 50.0), None);
+    }
+
+    #[test]
+    fn test_degree_distribution() {
+        // Degrees: 205650 2, 205651 1, 205652 3, 205653 0, 205654 4
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let options = GraphOptions { mode: GraphMode::Tripartite, ..Default::default() };
+        let graph = create_graph(records, &options);
+        let report_options = ReportOptions { small_cell_threshold: 3, suppression: Suppression::Flag, ..Default::default() };
+        let report = degree_distribution(&graph, &["gender".parse().unwrap()], &report_options).unwrap();
+        let overall = &report.summaries[0];
+        assert_eq!((overall.grouping.as_str(), overall.n, overall.mean), (OVERALL, Some(5), Some(2.0)));
+        assert_eq!((overall.median, overall.max), (Some(2.0), Some(4)));
+        assert!((overall.p95.unwrap() - 3.8).abs() < 1e-12);
+        let female = &report.summaries[2];
+        assert_eq!((female.group.as_str(), female.n, female.small_cell), ("S1 - Female", Some(2), true));
+        assert!((female.p95.unwrap() - 2.9).abs() < 1e-12);
+        // Degrees 0 to 4 for overall and each gender
+        assert_eq!(report.histogram.len(), 15);
+        let counts: Vec<Option<usize>> = report.histogram[5..10].iter().map(|bin| bin.individuals).collect();
+        assert_eq!(counts, [Some(1), Some(0), Some(1), Some(0), Some(1)]);
+        assert_eq!(report.small_cells(), 1 + 15);
+
+        let masked = ReportOptions { suppression: Suppression::Mask, ..report_options.clone() };
+        let report = degree_distribution(&graph, &["gender".parse().unwrap()], &masked).unwrap();
+        assert!(report.summaries[1..].iter().all(|row| row.suppressed && row.mean.is_none()));
+        assert!(report.histogram.iter().all(|bin| bin.suppressed && bin.individuals.is_none()));
+
+        // Every race is a small cell, so all are pooled
+        let merged = ReportOptions { suppression: Suppression::Merge, ..report_options };
+        let report = degree_distribution(&graph, &["race".parse().unwrap()], &merged).unwrap();
+        assert_eq!(report.summaries.len(), 2);
+        let other = &report.summaries[1];
+        assert!(other.group.starts_with("other (R0 - White, R1 - Black"));
+        assert_eq!((other.n, other.mean, other.max), (Some(5), Some(2.0), Some(4)));
+        let mut out = Vec::new();
+        write_csv(&report.summaries[..1], &mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        assert!(csv.starts_with("grouping,group,n,mean,median,p95,max,small_cell,suppressed\noverall,overall,5,2.0,"));
+    }
+}
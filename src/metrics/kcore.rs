@@ -0,0 +1,248 @@
+//! K-core decomposition of the co-allergy projection
+//! (`project_individuals`): each individual's core number, and the
+//! demographic make-up of the innermost cores, where the most
+//! polysensitized individuals who share allergies with each other sit.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use petgraph::graph::{DiGraph, NodeIndex, UnGraph};
+use serde::Serialize;
+
+use super::{emit_json, OutputFormat, ReportOptions};
+use crate::disclosure::{suppress, Cell};
+use crate::projection::project_individuals;
+use crate::strata::Grouping;
+use crate::{EdgeWeight, Individual, NodeType};
+
+/// Core number of each node of `graph`: the largest k such that it belongs
+/// to a subgraph where every node has at least k neighbours. Edge weights
+/// are ignored. Nodes are removed in order of least remaining degree, kept
+/// in degree buckets (Batagelj and Zaversnik), as `networkx.core_number`
+/// does.
+pub fn core_numbers(graph: &UnGraph<Individual, usize>) -> Vec<usize> {
+    let n = graph.node_count();
+    let mut degree: Vec<usize> = graph.node_indices().map(|node| graph.neighbors(node).count()).collect();
+    // Nodes sorted by degree, where each is, and where each degree starts
+    let mut starts = vec![0; degree.iter().copied().max().unwrap_or(0) + 1];
+    for &d in &degree {
+        starts[d] += 1;
+    }
+    let mut total = 0;
+    for start in starts.iter_mut() {
+        (*start, total) = (total, total + *start);
+    }
+    let mut sorted = vec![0; n];
+    let mut position = vec![0; n];
+    let mut next = starts.clone();
+    for node in 0..n {
+        position[node] = next[degree[node]];
+        sorted[position[node]] = node;
+        next[degree[node]] += 1;
+    }
+    for i in 0..n {
+        let node = sorted[i];
+        for neighbour in graph.neighbors(NodeIndex::new(node)).map(|neighbour| neighbour.index()) {
+            if degree[neighbour] > degree[node] {
+                // Move the neighbour to the front of its bucket, then shrink
+                // the bucket past it
+                let d = degree[neighbour];
+                let first = sorted[starts[d]];
+                sorted.swap(position[neighbour], starts[d]);
+                position[first] = position[neighbour];
+                position[neighbour] = starts[d];
+                starts[d] += 1;
+                degree[neighbour] -= 1;
+            }
+        }
+    }
+    degree
+}
+
+/// The core number of one individual.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CoreNumber {
+    pub id: String,
+    pub core: usize,
+}
+
+/// Members of one k-core in one group. `n` is `None` when the group is
+/// suppressed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CoreGroup {
+    pub k: usize,
+    pub grouping: String,
+    pub group: String,
+    pub n: Option<usize>,
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KCoreReport {
+    /// Individuals in the graph.
+    pub denominator: usize,
+    /// Individuals in the k-core (core number at least k), for k from 1
+    /// to the innermost core's.
+    pub sizes: Vec<usize>,
+    pub members: Vec<CoreNumber>,
+    /// Breakdown of the innermost cores, innermost first.
+    pub groups: Vec<CoreGroup>,
+}
+
+/// Finds the core number of every individual of `graph`, and breaks the
+/// `levels` innermost k-cores down by `groupings`. Core numbers depend on
+/// every other individual, so they are refused under differential privacy.
+pub fn k_cores(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    groupings: &[Grouping],
+    levels: usize,
+    options: &ReportOptions,
+) -> Result<KCoreReport, String> {
+    if options.noise.is_some() {
+        return Err("k-cores can't be released under differential privacy".to_string());
+    }
+    let projection = project_individuals(graph);
+    let cores = core_numbers(&projection);
+    let innermost = cores.iter().copied().max().unwrap_or(0);
+    let sizes = (1..=innermost).map(|k| cores.iter().filter(|&&core| core >= k).count()).collect();
+    let members = projection
+        .node_weights()
+        .zip(&cores)
+        .map(|(individual, &core)| CoreNumber { id: individual.id.clone(), core })
+        .collect();
+    let mut groups = Vec::new();
+    for k in (1..=innermost).rev().take(levels) {
+        for grouping in groupings {
+            let mut by_group: BTreeMap<String, usize> = BTreeMap::new();
+            for (individual, _) in projection.node_weights().zip(&cores).filter(|&(_, &core)| core >= k) {
+                if let Some(group) = grouping.value_of(individual) {
+                    *by_group.entry(group).or_default() += 1;
+                }
+            }
+            let cells = by_group.into_iter().map(|(group, n)| Cell::new(group, n as f64, n)).collect();
+            for Cell { group, count, suppressed, .. } in
+                suppress(cells, options.small_cell_threshold, options.suppression)
+            {
+                groups.push(CoreGroup {
+                    k,
+                    grouping: grouping.label(),
+                    group,
+                    n: (!suppressed).then_some(count),
+                    small_cell: count < options.small_cell_threshold,
+                    suppressed,
+                });
+            }
+        }
+    }
+    Ok(KCoreReport { denominator: projection.node_count(), sizes, members, groups })
+}
+
+impl KCoreReport {
+    /// Groups reported unsuppressed despite being small cells.
+    pub fn small_cells(&self) -> usize {
+        self.groups.iter().filter(|group| group.small_cell && !group.suppressed).count()
+    }
+
+    /// Writes the report as text, or as `core_number`, `k_core` and
+    /// `core_group` NDJSON rows, after the provenance if there is one.
+    pub fn write(&self, options: &ReportOptions, out: &mut dyn Write) -> io::Result<()> {
+        if options.format == OutputFormat::Ndjson {
+            if let Some(provenance) = &options.provenance {
+                let mut row = provenance.clone();
+                row["type"] = "provenance".into();
+                emit_json(out, &row)?;
+            }
+            for member in &self.members {
+                let row = serde_json::json!({ "type": "core_number", "id": member.id, "core": member.core });
+                emit_json(out, &row)?;
+            }
+            for (k, size) in self.sizes.iter().enumerate() {
+                emit_json(out, &serde_json::json!({ "type": "k_core", "k": k + 1, "n": size }))?;
+            }
+            for group in &self.groups {
+                let mut row = serde_json::to_value(group)?;
+                row["type"] = "core_group".into();
+                emit_json(out, &row)?;
+            }
+            return Ok(());
+        }
+        if let Some(provenance) = &options.provenance {
+            writeln!(out, "# Provenance: {}", provenance)?;
+        }
+        writeln!(
+            out,
+            "# K-cores of the co-allergy graph: innermost k={}; {} individuals",
+            self.sizes.len(),
+            self.denominator
+        )?;
+        for (k, size) in self.sizes.iter().enumerate() {
+            writeln!(out, "{}-core: {} individuals", k + 1, size)?;
+        }
+        let mut k = 0;
+        for group in &self.groups {
+            let size = self.sizes[group.k - 1];
+            if group.k != k {
+                k = group.k;
+                writeln!(out, "{}-core make-up ({} individuals):", k, size)?;
+            }
+            let Some(n) = group.n else {
+                writeln!(out, "  {} {}: suppressed", group.grouping, group.group)?;
+                continue;
+            };
+            write!(out, "  {} {}: {}", group.grouping, group.group, options.show.format(n, size))?;
+            if group.small_cell {
+                write!(out, " [small cell: n={}]", n)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disclosure::Suppression;
+    use crate::{create_graph, read_csv, GraphOptions, Record};
+
+    #[test]
+    fn test_k_cores() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        // Peanut joins 205650, 205651 and 205654; Treenut joins 205652 to
+        // 205654; 205653 has no allergies
+        assert_eq!(core_numbers(&project_individuals(&graph)), [2, 2, 1, 0, 2]);
+
+        let options = ReportOptions { small_cell_threshold: 2, suppression: Suppression::Flag, ..Default::default() };
+        let report = k_cores(&graph, &["gender".parse().unwrap()], 2, &options).unwrap();
+        assert_eq!((report.sizes.as_slice(), report.denominator), (&[4, 3][..], 5));
+        assert_eq!(report.members[2], CoreNumber { id: "205652".to_string(), core: 1 });
+        let innermost: Vec<(usize, &str, Option<usize>)> =
+            report.groups.iter().map(|group| (group.k, group.group.as_str(), group.n)).collect();
+        assert_eq!(innermost[..2], [(2, "S0 - Male", Some(2)), (2, "S1 - Female", Some(1))]);
+        assert_eq!(innermost[2].0, 1);
+        let mut out = Vec::new();
+        report.write(&options, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("# K-cores of the co-allergy graph: innermost k=2; 5 individuals\n1-core: 4"));
+        assert!(text.contains("2-core make-up (3 individuals):\n  gender S0 - Male: n=2 of 3\n"), "{}", text);
+        assert_eq!(report.small_cells(), 1);
+
+        let masked = ReportOptions { suppression: Suppression::Mask, ..options };
+        let report = k_cores(&graph, &["gender".parse().unwrap()], 1, &masked).unwrap();
+        assert!(report.groups.iter().all(|group| group.k == 2));
+        assert_eq!(report.groups[1].n, None);
+    }
+
+    #[test]
+    fn test_core_numbers_of_clique_and_tail() {
+        // A 4-clique with a path of two hanging off it
+        let mut graph = UnGraph::<Individual, usize>::default();
+        let nodes: Vec<_> = (0..6).map(|_| graph.add_node(Individual::from(&Record::default()))).collect();
+        for (a, b) in [(0, 1), (0, 2), (0, 3), (1, 2), (1, 3), (2, 3), (3, 4), (4, 5)] {
+            graph.add_edge(nodes[a], nodes[b], 1);
+        }
+        assert_eq!(core_numbers(&graph), [3, 3, 3, 3, 1, 1]);
+    }
+}
@@ -0,0 +1,234 @@
+//! Null models of the individual-allergy graph (`null-model` subcommand):
+//! random rewirings that keep every individual's and every allergy's
+//! degree, against which observed co-occurrence is compared. A pair of
+//! allergies that co-occurs far more than in the rewirings reflects more
+//! than how common the two allergies are and how many allergies
+//! individuals tend to have.
+
+use std::collections::{BTreeMap, HashSet};
+
+use petgraph::graph::DiGraph;
+use petgraph::Direction;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+
+use super::ReportOptions;
+use crate::disclosure::Suppression;
+use crate::progress;
+use crate::{EdgeWeight, NodeType};
+
+/// Swaps attempted per edge in each rewiring, enough for the edges to mix.
+pub const SWAPS_PER_EDGE: usize = 10;
+
+/// Allergy edges as `(individual, allergen)` numbers, individuals and
+/// allergens each numbered from 0 in graph order, and the allergen names.
+fn allergy_edges(graph: &DiGraph<NodeType, EdgeWeight>) -> (Vec<(usize, usize)>, Vec<String>) {
+    let mut allergens = BTreeMap::new();
+    let mut names = Vec::new();
+    for node in graph.node_indices() {
+        if let NodeType::AllergenStatus(name) = &graph[node] {
+            allergens.insert(node, names.len());
+            names.push(name.clone());
+        }
+    }
+    let mut edges = Vec::new();
+    let individuals = graph.node_indices().filter(|&node| matches!(graph[node], NodeType::Individual(_)));
+    for (individual, node) in individuals.enumerate() {
+        let mut targets: Vec<usize> = graph
+            .neighbors_directed(node, Direction::Outgoing)
+            .filter_map(|target| allergens.get(&target).copied())
+            .collect();
+        targets.sort_unstable();
+        targets.dedup();
+        edges.extend(targets.into_iter().map(|allergen| (individual, allergen)));
+    }
+    (edges, names)
+}
+
+/// Rewires `edges` in place by `swaps` attempted double-edge swaps: two
+/// edges `(a, x)` and `(b, y)` become `(a, y)` and `(b, x)` unless either
+/// already exists. Every individual and allergen keeps its degree.
+pub fn rewire(edges: &mut [(usize, usize)], swaps: usize, rng: &mut StdRng) {
+    if edges.len() < 2 {
+        return;
+    }
+    let mut present: HashSet<(usize, usize)> = edges.iter().copied().collect();
+    for _ in 0..swaps {
+        let (i, j) = (rng.gen_range(0..edges.len()), rng.gen_range(0..edges.len()));
+        let ((a, x), (b, y)) = (edges[i], edges[j]);
+        if a == b || x == y || present.contains(&(a, y)) || present.contains(&(b, x)) {
+            continue;
+        }
+        present.remove(&(a, x));
+        present.remove(&(b, y));
+        present.insert((a, y));
+        present.insert((b, x));
+        edges[i] = (a, y);
+        edges[j] = (b, x);
+    }
+}
+
+/// Individuals with both allergies of each pair, indexed `[a][b]` for
+/// allergens `a < b`.
+fn co_occurrence(edges: &[(usize, usize)], allergens: usize) -> Vec<Vec<usize>> {
+    let mut by_individual: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for &(individual, allergen) in edges {
+        by_individual.entry(individual).or_default().push(allergen);
+    }
+    let mut counts = vec![vec![0; allergens]; allergens];
+    for mut allergies in by_individual.into_values() {
+        allergies.sort_unstable();
+        for (i, &a) in allergies.iter().enumerate() {
+            for &b in &allergies[i + 1..] {
+                counts[a][b] += 1;
+            }
+        }
+    }
+    counts
+}
+
+/// Observed co-occurrence of one pair of allergies against the null
+/// ensemble. Everything but the names is `None` when the pair is
+/// suppressed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NullPair {
+    pub allergy: String,
+    pub other: String,
+    /// Individuals with both allergies.
+    pub observed: Option<usize>,
+    /// Mean and standard deviation of the count over the rewirings.
+    pub null_mean: Option<f64>,
+    pub null_sd: Option<f64>,
+    /// `(observed - null_mean) / null_sd`; `None` when the rewirings never
+    /// change the count.
+    pub z_score: Option<f64>,
+    /// Share of rewirings with at least the observed count, counting the
+    /// observed graph as one of them.
+    pub p_value: Option<f64>,
+    /// Fewer individuals with both than the small-cell threshold.
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+/// Compares the co-occurrence of each pair of allergies, in graph order,
+/// with `samples` degree-preserving rewirings of `graph`, seeded by
+/// `options.seed`. Small cells are judged on the observed count; under
+/// `mask` and `merge` their row is left empty. Refused under differential
+/// privacy; see [`ReportOptions::noise`].
+pub fn null_model(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    samples: usize,
+    options: &ReportOptions,
+) -> Result<Vec<NullPair>, String> {
+    if options.noise.is_some() {
+        return Err("null models can't be released under differential privacy".to_string());
+    }
+    if samples == 0 {
+        return Err("--samples must be at least 1".to_string());
+    }
+    let (edges, names) = allergy_edges(graph);
+    let observed = co_occurrence(&edges, names.len());
+    // Per pair: sum and sum of squares of the null counts, and how many
+    // reached the observed count
+    let mut moments = vec![vec![(0.0, 0.0, 0); names.len()]; names.len()];
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let bar = progress::bar(samples as u64, "rewiring");
+    let mut rewired = edges.clone();
+    for _ in 0..samples {
+        rewired.copy_from_slice(&edges);
+        rewire(&mut rewired, SWAPS_PER_EDGE * edges.len(), &mut rng);
+        let counts = co_occurrence(&rewired, names.len());
+        for (a, row) in counts.iter().enumerate() {
+            for (b, &count) in row.iter().enumerate().skip(a + 1) {
+                let (sum, squares, reached) = &mut moments[a][b];
+                *sum += count as f64;
+                *squares += (count * count) as f64;
+                *reached += usize::from(count >= observed[a][b]);
+            }
+        }
+        bar.inc(1);
+    }
+    bar.finish_and_clear();
+
+    let mut rows = Vec::new();
+    for (a, allergy) in names.iter().enumerate() {
+        for (b, other) in names.iter().enumerate().skip(a + 1) {
+            let count = observed[a][b];
+            let (sum, squares, reached) = moments[a][b];
+            let mean = sum / samples as f64;
+            let sd = (squares / samples as f64 - mean * mean).max(0.0).sqrt();
+            let small_cell = count < options.small_cell_threshold;
+            let suppressed = small_cell && options.suppression != Suppression::Flag;
+            let shown = |value: f64| (!suppressed).then_some(value);
+            rows.push(NullPair {
+                allergy: allergy.clone(),
+                other: other.clone(),
+                observed: (!suppressed).then_some(count),
+                null_mean: shown(mean),
+                null_sd: shown(sd),
+                z_score: (sd > 1e-12).then(|| (count as f64 - mean) / sd).filter(|_| !suppressed),
+                p_value: shown((reached + 1) as f64 / (samples + 1) as f64),
+                small_cell,
+                suppressed,
+            });
+        }
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    fn degrees(edges: &[(usize, usize)]) -> (BTreeMap<usize, usize>, BTreeMap<usize, usize>) {
+        let (mut individuals, mut allergens) = (BTreeMap::new(), BTreeMap::new());
+        for &(individual, allergen) in edges {
+            *individuals.entry(individual).or_insert(0) += 1;
+            *allergens.entry(allergen).or_insert(0) += 1;
+        }
+        (individuals, allergens)
+    }
+
+    #[test]
+    fn test_rewire_preserves_degrees() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let (edges, names) = allergy_edges(&graph);
+        assert_eq!((edges.len(), names.len()), (10, 9));
+        let mut rewired = edges.clone();
+        rewire(&mut rewired, 100, &mut StdRng::seed_from_u64(3));
+        assert_ne!(rewired, edges);
+        assert_eq!(degrees(&rewired), degrees(&edges));
+        let unique: HashSet<_> = rewired.iter().collect();
+        assert_eq!(unique.len(), rewired.len(), "no edge is doubled");
+    }
+
+    #[test]
+    fn test_null_model() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let suppression = Suppression::Mask;
+        let options = ReportOptions { seed: 7, small_cell_threshold: 2, suppression, ..Default::default() };
+        let rows = null_model(&graph, 200, &options).unwrap();
+        // One row per unordered pair of the nine allergens
+        assert_eq!(rows.len(), 36);
+        // 205650 and 205654 both have Peanut and Cashew
+        let peanut_cashew = rows.iter().find(|row| row.allergy == "Peanut" && row.other == "Cashew").unwrap();
+        assert_eq!((peanut_cashew.observed, peanut_cashew.small_cell), (Some(2), false));
+        let mean = peanut_cashew.null_mean.unwrap();
+        assert!(mean < 2.0 && peanut_cashew.z_score.unwrap() > 0.0, "{:?}", peanut_cashew);
+        assert!(peanut_cashew.p_value.unwrap() > 0.0 && peanut_cashew.p_value.unwrap() <= 1.0);
+        // Nobody has an almond allergy, so no rewiring gives them one
+        let almond = rows.iter().find(|row| row.allergy == "Peanut" && row.other == "Almond").unwrap();
+        assert!(almond.suppressed && almond.null_mean.is_none());
+        assert_eq!(rows, null_model(&graph, 200, &options).unwrap(), "seeded");
+
+        let flagged = ReportOptions { suppression: Suppression::Flag, ..options };
+        let rows = null_model(&graph, 50, &flagged).unwrap();
+        let almond = rows.iter().find(|row| row.allergy == "Peanut" && row.other == "Almond").unwrap();
+        assert_eq!((almond.observed, almond.null_mean, almond.z_score), (Some(0), Some(0.0), None));
+        assert_eq!(almond.p_value, Some(1.0));
+    }
+}
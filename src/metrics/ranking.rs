@@ -0,0 +1,350 @@
+//! Eigenvector centrality and PageRank of the allergies, ranking which
+//! nuts are most central in co-occurrence. Both are power iterations over
+//! the allergy co-occurrence projection (`project_allergies`) or the
+//! individual-allergy graph itself, computed as NetworkX's
+//! `eigenvector_centrality` and `pagerank` compute them.
+
+use std::io::{self, Write};
+
+use clap::ValueEnum;
+use petgraph::graph::DiGraph;
+use petgraph::visit::EdgeRef;
+use petgraph::Direction;
+use serde::Serialize;
+
+use super::{emit_json, OutputFormat, ReportOptions};
+use crate::disclosure::{suppress, Cell, Suppression};
+use crate::projection::project_allergies;
+use crate::{EdgeWeight, NodeType};
+
+/// Neighbours of each node, with the weight of the edge to them. Edges
+/// are listed from both ends.
+pub type Adjacency = Vec<Vec<(usize, f64)>>;
+
+/// Graph the allergies are ranked over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RankOver {
+    /// Allergies joined by the number of individuals who have both.
+    #[default]
+    Projected,
+    /// Individuals and allergies, each edge weighing 1, ignoring direction.
+    Bipartite,
+}
+
+impl RankOver {
+    fn describe(self) -> &'static str {
+        match self {
+            RankOver::Projected => "the allergy co-occurrence projection",
+            RankOver::Bipartite => "the individual-allergy graph",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RankOptions {
+    pub over: RankOver,
+    /// PageRank's probability of following an edge rather than jumping to
+    /// a random node, in `[0, 1)`.
+    pub damping: f64,
+    /// Convergence is reached when the scores change by less than this per
+    /// node, summed over the nodes.
+    pub tolerance: f64,
+    pub max_iterations: usize,
+}
+
+impl Default for RankOptions {
+    fn default() -> Self {
+        RankOptions { over: RankOver::Projected, damping: 0.85, tolerance: 1e-6, max_iterations: 100 }
+    }
+}
+
+/// Eigenvector centrality of every node, with unit Euclidean norm. Each
+/// iteration adds the neighbours' weighted scores to a node's own, which
+/// has the same eigenvector as the adjacency matrix but also converges on
+/// bipartite graphs, whose largest eigenvalues come in ± pairs.
+pub fn eigenvector_centrality(
+    adjacency: &Adjacency,
+    tolerance: f64,
+    max_iterations: usize,
+) -> Result<Vec<f64>, String> {
+    let n = adjacency.len();
+    if n == 0 {
+        return Err("eigenvector centrality of an empty graph".to_string());
+    }
+    let mut scores = vec![1.0 / n as f64; n];
+    for _ in 0..max_iterations {
+        let last = scores.clone();
+        for (node, neighbours) in adjacency.iter().enumerate() {
+            for &(neighbour, weight) in neighbours {
+                scores[neighbour] += last[node] * weight;
+            }
+        }
+        let norm = scores.iter().map(|score| score * score).sum::<f64>().sqrt();
+        let norm = if norm == 0.0 { 1.0 } else { norm };
+        scores.iter_mut().for_each(|score| *score /= norm);
+        if converged(&scores, &last, tolerance) {
+            return Ok(scores);
+        }
+    }
+    Err(format!("eigenvector centrality didn't converge in {} iterations", max_iterations))
+}
+
+/// PageRank of every node, summing to 1. A random walker follows an edge,
+/// chosen by weight, with probability `damping` and otherwise jumps to any
+/// node; nodes without edges pass their score to every node.
+pub fn pagerank(
+    adjacency: &Adjacency,
+    damping: f64,
+    tolerance: f64,
+    max_iterations: usize,
+) -> Result<Vec<f64>, String> {
+    let n = adjacency.len();
+    if n == 0 {
+        return Err("PageRank of an empty graph".to_string());
+    }
+    let strength: Vec<f64> = adjacency.iter().map(|neighbours| neighbours.iter().map(|&(_, w)| w).sum()).collect();
+    let jump = 1.0 / n as f64;
+    let mut scores = vec![jump; n];
+    for _ in 0..max_iterations {
+        let last = std::mem::replace(&mut scores, vec![0.0; n]);
+        let dangling: f64 = (0..n).filter(|&node| strength[node] == 0.0).map(|node| last[node]).sum();
+        for (node, neighbours) in adjacency.iter().enumerate() {
+            for &(neighbour, weight) in neighbours {
+                scores[neighbour] += damping * last[node] * weight / strength[node];
+            }
+        }
+        let base = (damping * dangling + 1.0 - damping) * jump;
+        scores.iter_mut().for_each(|score| *score += base);
+        if converged(&scores, &last, tolerance) {
+            return Ok(scores);
+        }
+    }
+    Err(format!("PageRank didn't converge in {} iterations", max_iterations))
+}
+
+fn converged(scores: &[f64], last: &[f64], tolerance: f64) -> bool {
+    let change: f64 = scores.iter().zip(last).map(|(score, last)| (score - last).abs()).sum();
+    change < scores.len() as f64 * tolerance
+}
+
+/// The graph `over` as an adjacency, and the node and name of each allergy.
+fn allergy_adjacency(graph: &DiGraph<NodeType, EdgeWeight>, over: RankOver) -> (Adjacency, Vec<(usize, String)>) {
+    match over {
+        RankOver::Projected => {
+            let projection = project_allergies(graph);
+            let mut adjacency: Adjacency = vec![Vec::new(); projection.node_count()];
+            for edge in projection.edge_references() {
+                let (a, b, weight) = (edge.source().index(), edge.target().index(), *edge.weight() as f64);
+                adjacency[a].push((b, weight));
+                adjacency[b].push((a, weight));
+            }
+            let allergies = projection.node_indices().map(|node| (node.index(), projection[node].clone())).collect();
+            (adjacency, allergies)
+        }
+        RankOver::Bipartite => {
+            let mut adjacency: Adjacency = vec![Vec::new(); graph.node_count()];
+            for edge in graph.edge_references() {
+                let (a, b) = (edge.source().index(), edge.target().index());
+                adjacency[a].push((b, 1.0));
+                adjacency[b].push((a, 1.0));
+            }
+            let allergies = graph
+                .node_indices()
+                .filter_map(|node| match &graph[node] {
+                    NodeType::AllergenStatus(name) => Some((node.index(), name.clone())),
+                    NodeType::Individual(_) | NodeType::Demographic { .. } => None,
+                })
+                .collect();
+            (adjacency, allergies)
+        }
+    }
+}
+
+/// One allergy's centrality. The values are `None` when it is suppressed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AllergyRank {
+    /// 1 for the highest PageRank.
+    pub rank: usize,
+    pub allergy: String,
+    pub individuals: Option<usize>,
+    pub pagerank: Option<f64>,
+    pub eigenvector: Option<f64>,
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AllergyRankReport {
+    pub over: RankOver,
+    pub damping: f64,
+    pub tolerance: f64,
+    /// Allergies by PageRank, highest first, then by name.
+    pub allergies: Vec<AllergyRank>,
+}
+
+/// Ranks the allergies of `graph` by PageRank, with their eigenvector
+/// centrality. An allergy with fewer individuals than the small-cell
+/// threshold is flagged, or masked under `--suppress mask` or `merge`,
+/// since pooling allergies wouldn't leave a score to report. Scores depend
+/// on every individual, so they are refused under differential privacy.
+pub fn rank_allergies(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    rank: &RankOptions,
+    options: &ReportOptions,
+) -> Result<AllergyRankReport, String> {
+    if options.noise.is_some() {
+        return Err("allergy centrality can't be released under differential privacy".to_string());
+    }
+    let (adjacency, allergies) = allergy_adjacency(graph, rank.over);
+    let pageranks = pagerank(&adjacency, rank.damping, rank.tolerance, rank.max_iterations)?;
+    let eigenvectors = eigenvector_centrality(&adjacency, rank.tolerance, rank.max_iterations)?;
+
+    let individuals: Vec<usize> = graph
+        .node_indices()
+        .filter(|&node| matches!(graph[node], NodeType::AllergenStatus(_)))
+        .map(|node| graph.neighbors_directed(node, Direction::Incoming).count())
+        .collect();
+    let cells =
+        allergies.iter().zip(&individuals).map(|((_, name), &n)| Cell::new(name.clone(), n as f64, n)).collect();
+    let mode = if options.suppression == Suppression::Flag { Suppression::Flag } else { Suppression::Mask };
+    let cells = suppress(cells, options.small_cell_threshold, mode);
+
+    let mut ranked: Vec<AllergyRank> = allergies
+        .iter()
+        .zip(cells)
+        .map(|(&(node, ref allergy), cell)| AllergyRank {
+            rank: 0,
+            allergy: allergy.clone(),
+            individuals: (!cell.suppressed).then_some(cell.count),
+            pagerank: (!cell.suppressed).then_some(pageranks[node]),
+            eigenvector: (!cell.suppressed).then_some(eigenvectors[node]),
+            small_cell: cell.count < options.small_cell_threshold,
+            suppressed: cell.suppressed,
+        })
+        .collect();
+    // Masked allergies rank by their hidden score too, so their place
+    // doesn't give it away any more than the others' do
+    let order: Vec<f64> = allergies.iter().map(|&(node, _)| pageranks[node]).collect();
+    let mut by_score: Vec<usize> = (0..ranked.len()).collect();
+    by_score.sort_by(|&a, &b| order[b].total_cmp(&order[a]).then_with(|| ranked[a].allergy.cmp(&ranked[b].allergy)));
+    for (position, &i) in by_score.iter().enumerate() {
+        ranked[i].rank = position + 1;
+    }
+    ranked.sort_by_key(|row| row.rank);
+    Ok(AllergyRankReport { over: rank.over, damping: rank.damping, tolerance: rank.tolerance, allergies: ranked })
+}
+
+impl AllergyRankReport {
+    /// Allergies reported unsuppressed despite being small cells.
+    pub fn small_cells(&self) -> usize {
+        self.allergies.iter().filter(|row| row.small_cell && !row.suppressed).count()
+    }
+
+    /// Writes the report as text, or as an `allergy_rank` NDJSON row per
+    /// allergy, after the provenance if there is one.
+    pub fn write(&self, options: &ReportOptions, out: &mut dyn Write) -> io::Result<()> {
+        if options.format == OutputFormat::Ndjson {
+            if let Some(provenance) = &options.provenance {
+                let mut row = provenance.clone();
+                row["type"] = "provenance".into();
+                emit_json(out, &row)?;
+            }
+            for allergy in &self.allergies {
+                let mut row = serde_json::to_value(allergy)?;
+                row["type"] = "allergy_rank".into();
+                row["over"] = serde_json::to_value(self.over)?;
+                emit_json(out, &row)?;
+            }
+            return Ok(());
+        }
+        if let Some(provenance) = &options.provenance {
+            writeln!(out, "# Provenance: {}", provenance)?;
+        }
+        writeln!(
+            out,
+            "# Allergy centrality over {} (PageRank damping {}, tolerance {})",
+            self.over.describe(),
+            self.damping,
+            self.tolerance
+        )?;
+        for row in &self.allergies {
+            let (Some(n), Some(pagerank), Some(eigenvector)) = (row.individuals, row.pagerank, row.eigenvector) else {
+                writeln!(out, "{}. {}: suppressed", row.rank, row.allergy)?;
+                continue;
+            };
+            let (rank, allergy) = (row.rank, &row.allergy);
+            write!(out, "{}. {}: PageRank {:.4}, eigenvector {:.4}, n={}", rank, allergy, pagerank, eigenvector, n)?;
+            if row.small_cell {
+                write!(out, " [small cell: n={}]", n)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    /// A star of `leaves` around node 0, and one edge each way.
+    fn star(leaves: usize) -> Adjacency {
+        let mut adjacency: Adjacency = vec![Vec::new(); leaves + 1];
+        for leaf in 1..=leaves {
+            adjacency[0].push((leaf, 1.0));
+            adjacency[leaf].push((0, 1.0));
+        }
+        adjacency
+    }
+
+    #[test]
+    fn test_power_iterations() {
+        // As NetworkX gives for nx.star_graph(3)
+        let scores = pagerank(&star(3), 0.85, 1e-10, 200).unwrap();
+        assert!((scores[0] - 0.4797).abs() < 1e-4 && (scores[1] - 0.1734).abs() < 1e-4);
+        assert!((scores.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        let scores = eigenvector_centrality(&star(3), 1e-10, 200).unwrap();
+        assert!((scores[0] - std::f64::consts::FRAC_1_SQRT_2).abs() < 1e-4 && (scores[1] - 0.4082).abs() < 1e-4);
+        // A node without edges passes its score on evenly
+        let mut isolated = star(3);
+        isolated.push(Vec::new());
+        assert!(pagerank(&isolated, 0.85, 1e-10, 200).unwrap()[4] > 0.0);
+        assert!(pagerank(&star(3), 0.85, 1e-12, 1).is_err());
+        assert!(eigenvector_centrality(&Vec::new(), 1e-6, 100).is_err());
+    }
+
+    #[test]
+    fn test_rank_allergies() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let options = ReportOptions { small_cell_threshold: 2, suppression: Suppression::Flag, ..Default::default() };
+        let report = rank_allergies(&graph, &RankOptions::default(), &options).unwrap();
+        assert_eq!(report.allergies.len(), 9);
+        assert!(report.allergies.windows(2).all(|pair| pair[0].pagerank >= pair[1].pagerank));
+        // Tree nut co-occurs with Peanut, Cashew and Pistachio
+        let top = &report.allergies[0];
+        assert_eq!((top.allergy.as_str(), top.rank, top.individuals, top.small_cell), ("Treenut", 1, Some(2), false));
+        // Almond, Brazil and Hazelnut co-occur with nothing, so only get the
+        // random jumps, and tie
+        let almond = report.allergies.iter().find(|row| row.allergy == "Almond").unwrap();
+        assert_eq!((almond.rank, almond.individuals, almond.small_cell), (7, Some(0), true));
+        assert_eq!(report.allergies[8].allergy, "Hazelnut");
+        assert_eq!(almond.eigenvector.map(|score| score < 1e-3), Some(true));
+
+        let bipartite = RankOptions { over: RankOver::Bipartite, ..Default::default() };
+        let report = rank_allergies(&graph, &bipartite, &options).unwrap();
+        assert_eq!((report.allergies[0].allergy.as_str(), report.allergies[0].individuals), ("Peanut", Some(3)));
+        let mut out = Vec::new();
+        report.write(&options, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("# Allergy centrality over the individual-allergy graph (PageRank damping 0.85, "));
+
+        let masked = ReportOptions { suppression: Suppression::Mask, ..options };
+        let report = rank_allergies(&graph, &RankOptions::default(), &masked).unwrap();
+        let almond = report.allergies.iter().find(|row| row.allergy == "Almond").unwrap();
+        assert!(almond.suppressed && almond.pagerank.is_none());
+        assert_eq!(report.small_cells(), 0);
+    }
+}
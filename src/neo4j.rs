@@ -0,0 +1,111 @@
+//! Writes the graph to a running Neo4j instance over bolt (`--push-neo4j`).
+//!
+//! Nodes and relationships are sent in batches of `UNWIND`ed rows, one
+//! transaction per batch, and every statement uses `MERGE` keyed on the
+//! subject id or allergen name, so pushing the same data twice leaves the
+//! database unchanged.
+//!
+//! Credentials come from `NEO4J_USER` (default `neo4j`) and
+//! `NEO4J_PASSWORD`.
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use log::info;
+use neo4rs::{query, BoltType, Graph};
+use petgraph::graph::DiGraph;
+
+use crate::{EdgeWeight, NodeType};
+
+/// Rows per transaction.
+pub const BATCH_SIZE: usize = 1000;
+
+type Row = HashMap<String, BoltType>;
+
+const MERGE_ALLERGENS: &str = "UNWIND $rows AS row MERGE (:Allergen {name: row.name})";
+const MERGE_INDIVIDUALS: &str = "UNWIND $rows AS row \
+     MERGE (i:Individual {id: row.id}) \
+     SET i.gender = row.gender, i.race = row.race, i.ethnicity = row.ethnicity, \
+         i.payer_factor = row.payer_factor, i.atopic_march_cohort = row.atopic_march_cohort, \
+         i.age = row.age";
+const MERGE_ALLERGIES: &str = "UNWIND $rows AS row \
+     MATCH (i:Individual {id: row.id}) \
+     MATCH (a:Allergen {name: row.allergen}) \
+     MERGE (i)-[:HAS_ALLERGY]->(a)";
+
+/// Allergen, individual and allergy-edge rows, in the order they must be
+/// written.
+fn rows(graph: &DiGraph<NodeType, EdgeWeight>) -> [(&'static str, Vec<Row>); 3] {
+    let mut allergens = Vec::new();
+    let mut individuals = Vec::new();
+    for node in graph.node_weights() {
+        match node {
+            NodeType::AllergenStatus(name) => {
+                allergens.push(Row::from([("name".to_string(), name.as_str().into())]));
+            }
+            NodeType::Individual(individual) => individuals.push(Row::from([
+                ("id".to_string(), individual.id.as_str().into()),
+                ("gender".to_string(), individual.gender.as_str().into()),
+                ("race".to_string(), individual.race.as_str().into()),
+                ("ethnicity".to_string(), individual.ethnicity.as_str().into()),
+                ("payer_factor".to_string(), individual.payer_factor.as_str().into()),
+                ("atopic_march_cohort".to_string(), individual.atopic_march_cohort.into()),
+                ("age".to_string(), individual.age.into()),
+            ])),
+            // Already properties of the individuals
+            NodeType::Demographic { .. } => {}
+        }
+    }
+    let allergies = graph
+        .edge_indices()
+        .filter_map(|edge| graph.edge_endpoints(edge))
+        .filter_map(|(source, target)| match (&graph[source], &graph[target]) {
+            (NodeType::Individual(individual), NodeType::AllergenStatus(name)) => Some(Row::from([
+                ("id".to_string(), individual.id.as_str().into()),
+                ("allergen".to_string(), name.as_str().into()),
+            ])),
+            _ => None,
+        })
+        .collect();
+    [(MERGE_ALLERGENS, allergens), (MERGE_INDIVIDUALS, individuals), (MERGE_ALLERGIES, allergies)]
+}
+
+/// Pushes every node and allergy edge to the database at `uri`; returns
+/// the number of rows written.
+pub fn push(graph: &DiGraph<NodeType, EdgeWeight>, uri: &str) -> Result<usize, Box<dyn Error>> {
+    let user = std::env::var("NEO4J_USER").unwrap_or_else(|_| "neo4j".to_string());
+    let password = std::env::var("NEO4J_PASSWORD").map_err(|_| "NEO4J_PASSWORD must be set for --push-neo4j")?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let db = Graph::new(uri, user, password).await?;
+        db.run(query("CREATE CONSTRAINT IF NOT EXISTS FOR (i:Individual) REQUIRE i.id IS UNIQUE")).await?;
+        db.run(query("CREATE CONSTRAINT IF NOT EXISTS FOR (a:Allergen) REQUIRE a.name IS UNIQUE")).await?;
+        let mut written = 0;
+        for (statement, rows) in rows(graph) {
+            for batch in rows.chunks(BATCH_SIZE) {
+                let mut txn = db.start_txn().await?;
+                txn.run(query(statement).param("rows", batch.to_vec())).await?;
+                txn.commit().await?;
+                written += batch.len();
+            }
+        }
+        info!("Pushed {} rows to {}", written, uri);
+        Ok(written)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    #[test]
+    fn test_rows_cover_every_node_and_edge() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let graph = create_graph(read_csv(path).unwrap(), &GraphOptions::default());
+        let [allergens, individuals, allergies] = rows(&graph);
+        assert_eq!(allergens.1.len() + individuals.1.len(), graph.node_count());
+        assert_eq!(allergies.1.len(), graph.edge_count());
+        assert!(allergies.0.contains("MERGE (i)-[:HAS_ALLERGY]->(a)"));
+    }
+}
@@ -0,0 +1,166 @@
+//! Normalization of messy categorical values (`--normalize`): each declared
+//! column's variants (`F`, `female`, `Female `) are rewritten to one
+//! canonical value before the input is validated and parsed.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+
+use csv::{Error as CsvError, ReaderBuilder, StringRecord, Writer};
+use log::{info, warn};
+
+use crate::remote;
+
+/// Canonical values per column, each with the variants that map onto it:
+///
+/// ```yaml
+/// gender_factor:
+///   S0 - Male: [M, male]
+///   S1 - Female: [F, female]
+/// race_factor:
+///   R0 - White: [White, Caucasian, "2106-3"]
+/// ```
+///
+/// Matching ignores case and surrounding or repeated whitespace, and every
+/// canonical value also matches itself.
+#[derive(Debug, Clone, Default)]
+pub struct Normalization {
+    /// Column -> folded variant -> canonical value.
+    columns: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+/// Values seen in the normalized columns, with how many rows had each.
+#[derive(Debug, Default, PartialEq)]
+pub struct NormalizationReport {
+    /// (column, original, canonical) for values that were rewritten.
+    pub mapped: BTreeMap<(String, String, String), usize>,
+    /// (column, value) for values matching no variant; they are kept as is.
+    pub unmapped: BTreeMap<(String, String), usize>,
+}
+
+fn fold(value: &str) -> String {
+    value.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+impl Normalization {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let declared: BTreeMap<String, BTreeMap<String, Vec<String>>> = serde_yaml::from_slice(&remote::read(path)?)
+            .map_err(|e| format!("invalid normalization file {}: {}", path.display(), e))?;
+        Normalization::new(declared).map_err(|e| format!("invalid normalization file {}: {}", path.display(), e).into())
+    }
+
+    /// From `column -> canonical -> variants`; a variant may only map onto
+    /// one canonical value per column.
+    pub fn new(declared: BTreeMap<String, BTreeMap<String, Vec<String>>>) -> Result<Self, String> {
+        let mut columns = BTreeMap::new();
+        for (column, canonical_values) in declared {
+            let mut variants: BTreeMap<String, String> = BTreeMap::new();
+            for (canonical, aliases) in canonical_values {
+                for alias in aliases.iter().chain([&canonical]) {
+                    match variants.insert(fold(alias), canonical.clone()) {
+                        Some(other) if other != canonical => {
+                            return Err(format!("{}: '{}' maps to both '{}' and '{}'", column, alias, other, canonical))
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            columns.insert(column, variants);
+        }
+        Ok(Normalization { columns })
+    }
+
+    /// Rewrites the declared columns of a CSV, returning the normalized CSV
+    /// and what was changed. Blank values are left alone.
+    pub fn apply_csv(&self, contents: &[u8]) -> Result<(Vec<u8>, NormalizationReport), CsvError> {
+        let mut rdr = ReaderBuilder::new().from_reader(contents);
+        let headers = rdr.headers()?.clone();
+        let normalized: Vec<Option<&BTreeMap<String, String>>> =
+            headers.iter().map(|header| self.columns.get(header)).collect();
+        let mut report = NormalizationReport::default();
+        let mut out = Writer::from_writer(Vec::new());
+        out.write_record(&headers)?;
+        for row in rdr.records() {
+            let row = row?;
+            let values: StringRecord = row
+                .iter()
+                .zip(&normalized)
+                .zip(&headers)
+                .map(|((value, variants), column)| {
+                    let Some(variants) = variants.filter(|_| !value.trim().is_empty()) else {
+                        return value.to_string();
+                    };
+                    match variants.get(&fold(value)) {
+                        Some(canonical) if canonical == value => value.to_string(),
+                        Some(canonical) => {
+                            *report.mapped.entry((column.to_string(), value.to_string(), canonical.clone())).or_default() += 1;
+                            canonical.clone()
+                        }
+                        None => {
+                            *report.unmapped.entry((column.to_string(), value.to_string())).or_default() += 1;
+                            value.to_string()
+                        }
+                    }
+                })
+                .collect();
+            out.write_record(&values)?;
+        }
+        let contents = out.into_inner().map_err(|e| CsvError::from(e.into_error()))?;
+        Ok((contents, report))
+    }
+}
+
+impl NormalizationReport {
+    /// Logs a summary of the rewritten values, and each unmapped value as a
+    /// warning.
+    pub fn log(&self, source: &Path) {
+        let rewritten: usize = self.mapped.values().sum();
+        info!("Normalized {} value(s) in {}", rewritten, source.display());
+        for ((column, original, canonical), count) in &self.mapped {
+            info!("  {}: '{}' -> '{}' ({} row(s))", column, original, canonical, count);
+        }
+        for ((column, value), count) in &self.unmapped {
+            warn!("{}: unmapped {} value '{}' ({} row(s))", source.display(), column, value, count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_csv() {
+        let declared: BTreeMap<String, BTreeMap<String, Vec<String>>> = serde_yaml::from_str(
+            "gender_factor:\n  S0 - Male: [M, male]\n  S1 - Female: [F, female]\n\
+             race_factor:\n  R0 - White: [White, Caucasian]\n",
+        )
+        .unwrap();
+        let normalization = Normalization::new(declared).unwrap();
+        let csv = "subject_id,gender_factor,race_factor,site\n\
+                   1,F,caucasian,north\n\
+                   2,Female ,R0 - White,south\n\
+                   3, s1 - female,,east\n\
+                   4,f,Martian,west\n";
+        let (normalized, report) = normalization.apply_csv(csv.as_bytes()).unwrap();
+        assert_eq!(
+            String::from_utf8(normalized).unwrap(),
+            "subject_id,gender_factor,race_factor,site\n\
+             1,S1 - Female,R0 - White,north\n\
+             2,S1 - Female,R0 - White,south\n\
+             3,S1 - Female,,east\n\
+             4,S1 - Female,Martian,west\n"
+        );
+        assert_eq!(report.mapped.len(), 5);
+        let female = ("gender_factor".to_string(), "F".to_string(), "S1 - Female".to_string());
+        assert_eq!(report.mapped[&female], 1);
+        assert_eq!(report.unmapped, BTreeMap::from([(("race_factor".to_string(), "Martian".to_string()), 1)]));
+
+        let clash = BTreeMap::from([(
+            "payer_factor".to_string(),
+            BTreeMap::from([("P0".to_string(), vec!["x".to_string()]), ("P1".to_string(), vec!["X ".to_string()])]),
+        )]);
+        assert_eq!(Normalization::new(clash).unwrap_err(), "payer_factor: 'X ' maps to both 'P0' and 'P1'");
+    }
+}
@@ -0,0 +1,211 @@
+//! High-level API for interactive use, e.g. from an evcxr notebook:
+//!
+//! ```no_run
+//! let analysis = project_name::notebook::Analysis::from_csv("records.csv").unwrap();
+//! println!("{}", analysis.summary());
+//! println!("{}", analysis.centrality("race*payer").unwrap());
+//! ```
+//!
+//! With the `evcxr` feature the summary structs also render as HTML tables
+//! when they are the last expression of a notebook cell.
+
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+use petgraph::graph::DiGraph;
+
+use crate::strata::Grouping;
+use crate::{calculate_centrality, create_graph, read_csv, EdgeWeight, GraphOptions, NodeType, ReportOptions};
+
+pub use crate::GraphSummary;
+
+/// A loaded graph plus the options used to report on it.
+pub struct Analysis {
+    pub graph: DiGraph<NodeType, EdgeWeight>,
+    pub report: ReportOptions,
+}
+
+/// Mean degree of one group; `mean` and `n` are `None` when the group is
+/// suppressed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupCentrality {
+    pub grouping: String,
+    pub group: String,
+    pub mean: Option<f64>,
+    pub n: Option<usize>,
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+/// Mean degree per group for one or more groupings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CentralityTable {
+    pub rows: Vec<GroupCentrality>,
+}
+
+impl Analysis {
+    pub fn from_csv(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        Self::from_csv_with(path, &GraphOptions::default())
+    }
+
+    pub fn from_csv_with(path: impl AsRef<Path>, options: &GraphOptions) -> Result<Self, Box<dyn Error>> {
+        let records = read_csv(path)?;
+        Ok(Analysis {
+            graph: create_graph(records, options),
+            report: ReportOptions { small_cell_threshold: 11, ..Default::default() },
+        })
+    }
+
+    pub fn summary(&self) -> GraphSummary {
+        GraphSummary::of(&self.graph)
+    }
+
+    /// Mean degree per group for comma-separated `stratify_by`, written as
+    /// on the command line (e.g. `"race,race*payer"`).
+    pub fn centrality(&self, stratify_by: &str) -> Result<CentralityTable, String> {
+        let groupings = stratify_by.split(',').map(str::parse).collect::<Result<Vec<Grouping>, _>>()?;
+        let report = calculate_centrality(&self.graph, &groupings, &self.report)?;
+        let rows = report
+            .groups
+            .into_iter()
+            .map(|group| GroupCentrality {
+                grouping: group.grouping,
+                group: group.group,
+                mean: group.mean,
+                n: group.n,
+                small_cell: group.small_cell,
+                suppressed: group.suppressed,
+            })
+            .collect();
+        Ok(CentralityTable { rows })
+    }
+}
+
+impl GroupCentrality {
+    /// `mean` to three decimals, or `-` when suppressed.
+    fn mean_cell(&self) -> String {
+        self.mean.map_or_else(|| "-".to_string(), |mean| format!("{:.3}", mean))
+    }
+
+    fn n_cell(&self) -> String {
+        self.n.map_or_else(|| "-".to_string(), |n| n.to_string())
+    }
+}
+
+impl fmt::Display for CentralityTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = |cell: fn(&GroupCentrality) -> &str, header: &str| {
+            self.rows.iter().map(|row| cell(row).chars().count()).chain([header.len()]).max().unwrap_or(0)
+        };
+        let grouping_width = width(|row| &row.grouping, "grouping");
+        let group_width = width(|row| &row.group, "group");
+        writeln!(f, "{:<gw$}  {:<vw$}  {:>8}  {:>6}", "grouping", "group", "mean", "n", gw = grouping_width, vw = group_width)?;
+        for row in &self.rows {
+            write!(
+                f,
+                "{:<gw$}  {:<vw$}  {:>8}  {:>6}",
+                row.grouping,
+                row.group,
+                row.mean_cell(),
+                row.n_cell(),
+                gw = grouping_width,
+                vw = group_width
+            )?;
+            if row.suppressed {
+                write!(f, "  (suppressed)")?;
+            } else if row.small_cell {
+                write!(f, "  (small cell)")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "evcxr")]
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[cfg(feature = "evcxr")]
+impl GraphSummary {
+    /// Called by evcxr to render the value of a cell.
+    pub fn evcxr_display(&self) {
+        println!(
+            "EVCXR_BEGIN_CONTENT text/html\n<table><tr><th>individuals</th><th>allergens</th><th>edges</th></tr>\
+             <tr><td>{}</td><td>{}</td><td>{}</td></tr></table>\nEVCXR_END_CONTENT",
+            self.individuals,
+            self.allergens.len(),
+            self.allergy_edges
+        );
+    }
+}
+
+#[cfg(feature = "evcxr")]
+impl CentralityTable {
+    /// Called by evcxr to render the value of a cell.
+    pub fn evcxr_display(&self) {
+        let mut html = String::from("<table><tr><th>grouping</th><th>group</th><th>mean</th><th>n</th></tr>");
+        for row in &self.rows {
+            let style = if row.small_cell || row.suppressed { " style=\"color:gray\"" } else { "" };
+            html.push_str(&format!(
+                "<tr{}><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                style,
+                escape_html(&row.grouping),
+                escape_html(&row.group),
+                row.mean_cell(),
+                row.n_cell()
+            ));
+        }
+        html.push_str("</table>");
+        println!("EVCXR_BEGIN_CONTENT text/html\n{}\nEVCXR_END_CONTENT", html);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disclosure::Suppression;
+    use crate::GraphMode;
+
+    #[test]
+    fn test_summary_and_centrality_table() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let analysis = Analysis::from_csv(path).unwrap();
+        let summary = analysis.summary();
+        assert_eq!((summary.individuals, summary.edges), (5, 10));
+        assert!(summary.to_string().contains("(5 individuals)"));
+
+        let table = analysis.centrality("payer").unwrap();
+        assert_eq!(table.rows.len(), 2);
+        assert!(table.rows.iter().all(|row| row.small_cell));
+        let text = table.to_string();
+        assert!(text.lines().next().unwrap().starts_with("grouping"));
+        assert_eq!(text.lines().count(), 3);
+        assert!(analysis.centrality("race*").is_err());
+    }
+
+    #[test]
+    fn test_suppressed_groups_have_no_mean() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let mut analysis = Analysis::from_csv(path).unwrap();
+        analysis.report = ReportOptions { small_cell_threshold: 3, suppression: Suppression::Mask, ..Default::default() };
+        let table = analysis.centrality("payer").unwrap();
+        assert!(table.rows.iter().all(|row| row.suppressed && row.mean.is_none() && row.n.is_none()));
+        let text = table.to_string();
+        assert!(text.lines().skip(1).all(|line| line.ends_with("-       -  (suppressed)")));
+    }
+
+    #[test]
+    fn test_tripartite_summary_counts_allergies_only() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let bipartite = Analysis::from_csv(path).unwrap().summary();
+        let options = GraphOptions { mode: GraphMode::Tripartite, ..Default::default() };
+        let summary = Analysis::from_csv_with(path, &options).unwrap().summary();
+        assert!(summary.nodes > bipartite.nodes && summary.edges > bipartite.edges);
+        assert_eq!(summary.allergens, bipartite.allergens);
+        assert_eq!((summary.individuals, summary.allergy_edges), (5, 10));
+        assert!(summary.to_string().lines().next().unwrap().ends_with(" (10 allergy)"));
+    }
+}
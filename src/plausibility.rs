@@ -0,0 +1,216 @@
+//! Plausibility rules for birth years and ages (`--plausibility`): values
+//! outside the configured ranges are rejected, clamped into range or
+//! flagged at ingest.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::time::SystemTime;
+
+use clap::ValueEnum;
+use log::warn;
+
+use crate::audit;
+use crate::quality::PLAUSIBLE_AGES;
+use crate::{Record, ALLERGENS, RECORD_COLUMNS};
+
+/// Extra column naming the implausible fields of each record under
+/// `Flag`, or `none`.
+pub const FLAG_COLUMN: &str = "implausible";
+
+/// What ingest does with a record that breaks a plausibility rule.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum PlausibilityPolicy {
+    /// Refuse the input, listing the out-of-range values.
+    #[default]
+    Reject,
+    /// Move each value to the nearest bound of its range.
+    Clamp,
+    /// Keep the values, naming each record's implausible fields (or
+    /// `none`) in an `implausible` extra column that can be stratified by.
+    Flag,
+}
+
+impl fmt::Display for PlausibilityPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.to_possible_value().expect("no skipped variants").get_name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlausibilityRules {
+    pub birth_years: RangeInclusive<i32>,
+    /// Range for the observation window and every allergy onset and end.
+    /// A negative lower bound admits prenatal ages.
+    pub ages: RangeInclusive<f64>,
+    pub policy: PlausibilityPolicy,
+}
+
+impl Default for PlausibilityRules {
+    /// Birth years from 1900 to the current year, and `PLAUSIBLE_AGES`.
+    fn default() -> Self {
+        let year = audit::timestamp(SystemTime::now())[..4].parse().expect("four-digit year");
+        PlausibilityRules { birth_years: 1900..=year, ages: PLAUSIBLE_AGES, policy: PlausibilityPolicy::default() }
+    }
+}
+
+/// One value outside its range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Implausible {
+    /// 1-based position of the record in the input.
+    pub row: usize,
+    pub subject_id: String,
+    pub field: String,
+    pub value: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct PlausibilityReport {
+    pub policy: PlausibilityPolicy,
+    pub values: Vec<Implausible>,
+}
+
+impl PlausibilityRules {
+    /// Finds the out-of-range values and, under `Clamp` or `Flag`, applies
+    /// the policy to them. Under `Reject` the records are left unchanged.
+    pub fn enforce(&self, records: &mut [Record]) -> PlausibilityReport {
+        let mut values = Vec::new();
+        for (i, record) in records.iter_mut().enumerate() {
+            let mut found = |field: String, value: f64| {
+                values.push(Implausible { row: i + 1, subject_id: record.subject_id.clone(), field, value });
+            };
+            let mut fields = Vec::new();
+            if !self.birth_years.contains(&record.birth_year) {
+                found("birth_year".to_string(), f64::from(record.birth_year));
+                fields.push("birth_year".to_string());
+                if self.policy == PlausibilityPolicy::Clamp {
+                    record.birth_year = record.birth_year.clamp(*self.birth_years.start(), *self.birth_years.end());
+                }
+            }
+            let clamp = |age: f64| age.clamp(*self.ages.start(), *self.ages.end());
+            for (field, age) in [("age_start_years", record.age_start_years), ("age_end_years", record.age_end_years)] {
+                if !self.ages.contains(&age) {
+                    found(field.to_string(), age);
+                    fields.push(field.to_string());
+                }
+            }
+            // The allergy columns follow the demographics in allergen order
+            for (&allergy, columns) in ALLERGENS.iter().zip(RECORD_COLUMNS[9..].chunks(2)) {
+                if let Some(onset) = record.get_allergy_start(allergy).filter(|onset| !self.ages.contains(onset)) {
+                    found(columns[0].to_string(), onset);
+                    fields.push(columns[0].to_string());
+                }
+                if let Some(end) = record.get_allergy_end(allergy).filter(|end| !self.ages.contains(end)) {
+                    found(columns[1].to_string(), end);
+                    fields.push(columns[1].to_string());
+                }
+            }
+            match self.policy {
+                PlausibilityPolicy::Reject => {}
+                PlausibilityPolicy::Clamp => {
+                    record.age_start_years = clamp(record.age_start_years);
+                    record.age_end_years = clamp(record.age_end_years);
+                    for &allergy in ALLERGENS {
+                        if let Some(onset) = record.get_allergy_start(allergy) {
+                            record.set_allergy_start(allergy, clamp(onset));
+                        }
+                        if let Some(end) = record.get_allergy_end(allergy) {
+                            record.set_allergy_end(allergy, clamp(end));
+                        }
+                    }
+                }
+                PlausibilityPolicy::Flag if fields.is_empty() => {
+                    record.extra.insert(FLAG_COLUMN.to_string(), "none".to_string());
+                }
+                PlausibilityPolicy::Flag => {
+                    record.extra.insert(FLAG_COLUMN.to_string(), fields.join(","));
+                }
+            }
+        }
+        PlausibilityReport { policy: self.policy, values }
+    }
+}
+
+impl PlausibilityReport {
+    /// Out-of-range values per field.
+    pub fn counts(&self) -> BTreeMap<&str, usize> {
+        let mut counts = BTreeMap::new();
+        for value in &self.values {
+            *counts.entry(value.field.as_str()).or_default() += 1;
+        }
+        counts
+    }
+
+    /// e.g. `3 implausible value(s) in 2 row(s): birth_year 1, peanut_alg_start 2`.
+    pub fn summary(&self) -> String {
+        let mut rows: Vec<usize> = self.values.iter().map(|value| value.row).collect();
+        rows.dedup();
+        let counts: Vec<String> = self.counts().iter().map(|(field, count)| format!("{} {}", field, count)).collect();
+        format!("{} implausible value(s) in {} row(s): {}", self.values.len(), rows.len(), counts.join(", "))
+    }
+
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "{}", self.summary())?;
+        for value in &self.values {
+            writeln!(out, "  row {} (subject {}): {} {}", value.row, value.subject_id, value.field, value.value)?;
+        }
+        Ok(())
+    }
+
+    /// Logs the summary and each value as warnings.
+    pub fn log(&self) {
+        if self.values.is_empty() {
+            return;
+        }
+        warn!("{} (--plausibility {})", self.summary(), self.policy);
+        for value in &self.values {
+            warn!("  row {} (subject {}): {} {}", value.row, value.subject_id, value.field, value.value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_csv;
+
+    #[test]
+    fn test_plausibility_policies() {
+        let mut records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let mut rules = PlausibilityRules { birth_years: 1990..=2020, ages: 0.0..=18.0, ..Default::default() };
+        assert!(rules.enforce(&mut records).values.is_empty());
+
+        records[1].birth_year = 1850;
+        records[3].peanut_alg_start = Some(-0.5);
+        records[4].pistach_alg_end = Some(20.0);
+        records[3].age_end_years = 40.0;
+        let original = records.clone();
+        let report = rules.enforce(&mut records);
+        assert_eq!(records, original);
+        assert_eq!(
+            report.summary(),
+            "4 implausible value(s) in 3 row(s): age_end_years 1, birth_year 1, peanut_alg_start 1, pistach_alg_end 1"
+        );
+        assert_eq!((report.values[0].row, report.values[0].subject_id.as_str()), (2, "205651"));
+
+        rules.policy = PlausibilityPolicy::Clamp;
+        let mut clamped = original.clone();
+        assert_eq!(rules.enforce(&mut clamped).values, report.values);
+        assert_eq!(clamped[1].birth_year, 1990);
+        assert_eq!((clamped[3].peanut_alg_start, clamped[3].age_end_years), (Some(0.0), 18.0));
+        assert!(rules.enforce(&mut clamped).values.is_empty());
+
+        rules.policy = PlausibilityPolicy::Flag;
+        let mut flagged = original.clone();
+        rules.enforce(&mut flagged);
+        assert_eq!(flagged[3].extra[FLAG_COLUMN], "age_end_years,peanut_alg_start");
+        assert_eq!(flagged[1].extra[FLAG_COLUMN], "birth_year");
+        assert_eq!(flagged[0].extra[FLAG_COLUMN], "none");
+
+        // A negative lower bound admits prenatal onsets
+        rules.ages = -0.75..=110.0;
+        let mut prenatal = original;
+        assert_eq!(rules.enforce(&mut prenatal).counts(), BTreeMap::from([("birth_year", 1)]));
+    }
+}
@@ -0,0 +1,327 @@
+//! De-identification applied to graphs before they leave the process
+//! (`--save-graph`, `export`, `--push-neo4j`, the server's `graph.json`).
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+
+use clap::ValueEnum;
+use petgraph::graph::DiGraph;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use sha2::{Digest, Sha256};
+
+use crate::error::AllergyNetError;
+use crate::graph::{export_binary, export_cypher, export_dot, export_gexf, export_graphml};
+use crate::layout::{export_svg, Layout};
+use crate::strata::Dimension;
+use crate::{node_link_json, EdgeWeight, NodeType, Unit};
+
+/// How subject ids appear in exported graphs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ExportIds {
+    /// Replace ids with 1, 2, ... in a random order drawn from OS entropy
+    /// and never recorded, so neither the input order nor a rerun links
+    /// them back to the source rows.
+    #[default]
+    Renumber,
+    /// Replace ids with a salted SHA-256, stable across runs with the same
+    /// salt so exports can be linked.
+    Hash,
+    /// Export the original ids.
+    Keep,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Deidentify {
+    pub ids: ExportIds,
+    /// Salt for `ExportIds::Hash`; required in that mode.
+    pub salt: String,
+    /// Quasi-identifiers to remove. `gender`, `race`, `ethnicity` and
+    /// `payer` are blanked, along with a tripartite graph's nodes for
+    /// them, and `age` and `cohort` become null; any other name removes
+    /// the extra column (attribute) of that name.
+    pub drop: Vec<String>,
+}
+
+impl Deidentify {
+    /// A copy of `graph` with the transformations applied, and a
+    /// description of each transformation for recording alongside the
+    /// export.
+    pub fn apply(&self, graph: &DiGraph<NodeType, EdgeWeight>) -> Result<(DiGraph<NodeType, EdgeWeight>, Vec<String>), String> {
+        if self.ids == ExportIds::Hash && self.salt.is_empty() {
+            return Err("hashing subject ids needs a salt (--id-salt)".to_string());
+        }
+        let mut graph = graph.clone();
+        let mut applied = Vec::new();
+        let mut dropped = vec![0; self.drop.len()];
+        let individuals = graph.node_weights().filter(|node| matches!(node, NodeType::Individual(_))).count();
+        let mut numbers: Vec<usize> = (1..=individuals).collect();
+        numbers.shuffle(&mut StdRng::from_entropy());
+        let mut numbers = numbers.into_iter();
+        for node in graph.node_weights_mut() {
+            let NodeType::Individual(individual) = node else { continue };
+            match self.ids {
+                ExportIds::Renumber => individual.id = numbers.next().expect("one number per individual").to_string(),
+                ExportIds::Hash => individual.id = salted_hash(&self.salt, &individual.id),
+                ExportIds::Keep => {}
+            }
+            for (field, count) in self.drop.iter().zip(&mut dropped) {
+                let present = match field.as_str() {
+                    "gender" => !std::mem::take(&mut individual.gender).is_empty(),
+                    "race" => !std::mem::take(&mut individual.race).is_empty(),
+                    "ethnicity" => !std::mem::take(&mut individual.ethnicity).is_empty(),
+                    "payer" => !std::mem::take(&mut individual.payer_factor).is_empty(),
+                    "age" => !std::mem::replace(&mut individual.age, f64::NAN).is_nan(),
+                    "cohort" => individual.atopic_march_cohort.take().is_some(),
+                    attribute => individual.attributes.remove(attribute).is_some(),
+                };
+                *count += usize::from(present);
+            }
+        }
+        // A tripartite graph's nodes for a dropped dimension would give it away
+        graph = graph.filter_map(
+            |_, node| match node {
+                NodeType::Demographic { dimension, .. } if self.drop.contains(dimension) => None,
+                node => Some(node.clone()),
+            },
+            |_, edge| Some(*edge),
+        );
+        applied.push(match self.ids {
+            ExportIds::Renumber => "subject ids renumbered in random order".to_string(),
+            ExportIds::Hash => "subject ids replaced by salted SHA-256".to_string(),
+            ExportIds::Keep => "subject ids kept".to_string(),
+        });
+        for (field, count) in self.drop.iter().zip(dropped) {
+            applied.push(format!("dropped {} ({} individuals)", field, count));
+        }
+        Ok((graph, applied))
+    }
+}
+
+impl Deidentify {
+    /// The id each individual of `graph` is exported under, by node index,
+    /// and the transformations applied, as `apply` gives them.
+    pub fn ids(&self, graph: &DiGraph<NodeType, EdgeWeight>) -> Result<(HashMap<usize, String>, Vec<String>), String> {
+        let (deidentified, applied) = self.apply(graph)?;
+        // `apply` keeps every individual, in node order
+        let individuals = |graph: &DiGraph<NodeType, EdgeWeight>| -> Vec<(usize, String)> {
+            graph
+                .node_indices()
+                .filter_map(|node| match &graph[node] {
+                    NodeType::Individual(individual) => Some((node.index(), individual.id.clone())),
+                    NodeType::AllergenStatus(_) | NodeType::Demographic { .. } => None,
+                })
+                .collect()
+        };
+        let ids = individuals(graph).into_iter().zip(individuals(&deidentified)).map(|((node, _), (_, id))| (node, id));
+        Ok((ids.collect(), applied))
+    }
+}
+
+/// De-identifies `graph` and hands it to `export` with `metadata` plus
+/// the transformations applied, under `deidentification`, and the unit the
+/// graph was built with, under `unit`.
+fn export_deidentified<T>(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    unit: Unit,
+    options: &Deidentify,
+    mut metadata: serde_json::Value,
+    export: impl FnOnce(&DiGraph<NodeType, EdgeWeight>, &serde_json::Value) -> Result<T, AllergyNetError>,
+) -> Result<T, AllergyNetError> {
+    let (graph, applied) = options.apply(graph).map_err(AllergyNetError::Export)?;
+    metadata["deidentification"] = applied.into();
+    metadata["unit"] = unit.to_string().into();
+    export(&graph, &metadata)
+}
+
+/// Node-link JSON of the de-identified graph, with the transformations
+/// recorded under `graph.deidentification` and the unit the graph was
+/// built with under `graph.unit`.
+pub fn node_link_export(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    unit: Unit,
+    options: &Deidentify,
+) -> Result<serde_json::Value, AllergyNetError> {
+    export_deidentified(graph, unit, options, serde_json::json!({}), |graph, metadata| {
+        let mut json = node_link_json(graph);
+        json["graph"] = metadata.clone();
+        Ok(json)
+    })
+}
+
+/// GraphML of the de-identified graph, with the same `deidentification`
+/// and `unit` graph data as `node_link_export` plus each entry of
+/// `metadata` (such as the provenance).
+pub fn graphml_export(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    unit: Unit,
+    options: &Deidentify,
+    metadata: serde_json::Value,
+    out: &mut dyn io::Write,
+) -> Result<(), AllergyNetError> {
+    export_deidentified(graph, unit, options, metadata, |graph, metadata| Ok(export_graphml(graph, metadata, out)?))
+}
+
+/// Dynamic GEXF of the de-identified graph (see `export_gexf`), with the
+/// metadata of `graphml_export` in its description.
+pub fn gexf_export(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    unit: Unit,
+    options: &Deidentify,
+    metadata: serde_json::Value,
+    out: &mut dyn io::Write,
+) -> Result<(), AllergyNetError> {
+    export_deidentified(graph, unit, options, metadata, |graph, metadata| Ok(export_gexf(graph, metadata, out)?))
+}
+
+/// Cypher script loading the de-identified graph into Neo4j (see
+/// `export_cypher`), with the metadata of `graphml_export` in its leading
+/// comment.
+pub fn cypher_export(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    unit: Unit,
+    options: &Deidentify,
+    metadata: serde_json::Value,
+    out: &mut dyn io::Write,
+) -> Result<(), AllergyNetError> {
+    export_deidentified(graph, unit, options, metadata, |graph, metadata| Ok(export_cypher(graph, metadata, out)?))
+}
+
+/// Graphviz DOT of the de-identified graph (see `export_dot`), with the
+/// metadata of `graphml_export` in its comment. A `color_by` dimension
+/// the de-identification dropped colours every individual alike.
+pub fn dot_export(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    unit: Unit,
+    options: &Deidentify,
+    color_by: Option<&Dimension>,
+    metadata: serde_json::Value,
+    out: &mut dyn io::Write,
+) -> Result<(), AllergyNetError> {
+    export_deidentified(graph, unit, options, metadata, |graph, metadata| {
+        Ok(export_dot(graph, color_by, metadata, out)?)
+    })
+}
+
+/// SVG drawing of the de-identified graph (see `export_svg`), with the
+/// metadata of `graphml_export` in its `desc`. The force-directed layout
+/// is drawn from `seed`.
+#[allow(clippy::too_many_arguments)]
+pub fn svg_export(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    unit: Unit,
+    options: &Deidentify,
+    layout: Layout,
+    color_by: Option<&Dimension>,
+    seed: u64,
+    metadata: serde_json::Value,
+    out: &mut dyn io::Write,
+) -> Result<(), AllergyNetError> {
+    export_deidentified(graph, unit, options, metadata, |graph, metadata| {
+        Ok(export_svg(graph, layout, color_by, seed, metadata, out)?)
+    })
+}
+
+/// The de-identified graph as a binary file (see `export_binary`), with
+/// the metadata of `graphml_export`.
+pub fn binary_export(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    unit: Unit,
+    options: &Deidentify,
+    metadata: serde_json::Value,
+    out: &mut dyn io::Write,
+) -> Result<(), AllergyNetError> {
+    export_deidentified(graph, unit, options, metadata, |graph, metadata| export_binary(graph, metadata, out))
+}
+
+/// First 16 hex digits of SHA-256 over the salt and id.
+pub(crate) fn salted_hash(salt: &str, id: &str) -> String {
+    let digest = Sha256::new().chain_update(salt).chain_update([0]).chain_update(id).finalize();
+    digest[..8].iter().fold(String::new(), |mut hex, byte| {
+        write!(hex, "{:02x}", byte).unwrap();
+        hex
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    #[test]
+    fn test_deidentify() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let individuals = |graph: &DiGraph<NodeType, EdgeWeight>| -> Vec<crate::Individual> {
+            graph
+                .node_weights()
+                .filter_map(|node| match node {
+                    NodeType::Individual(individual) => Some(individual.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let (renumbered, applied) = Deidentify::default().apply(&graph).unwrap();
+        let mut ids: Vec<String> = individuals(&renumbered).into_iter().map(|i| i.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec!["1", "2", "3", "4", "5"]);
+        assert_eq!(applied, vec!["subject ids renumbered in random order"]);
+        assert_eq!(renumbered.edge_count(), graph.edge_count());
+        // The numbers don't follow node order: 20 identity orders in a row
+        // would have probability 120^-20
+        let in_order = (0..20).all(|_| {
+            let (renumbered, _) = Deidentify::default().apply(&graph).unwrap();
+            individuals(&renumbered).into_iter().map(|i| i.id).eq(["1", "2", "3", "4", "5"])
+        });
+        assert!(!in_order);
+
+        let options = Deidentify {
+            ids: ExportIds::Hash,
+            salt: "s3cret".to_string(),
+            drop: ["age", "site", "zip", "cohort"].map(String::from).to_vec(),
+        };
+        let (hashed, applied) = options.apply(&graph).unwrap();
+        let hashed = individuals(&hashed);
+        assert_eq!(hashed[0].id, salted_hash("s3cret", "205650"));
+        assert_eq!(hashed[0].id.len(), 16);
+        assert_ne!(hashed[0].id, salted_hash("other", "205650"));
+        assert!(hashed[0].age.is_nan() && hashed[0].attributes.is_empty());
+        assert_eq!(hashed[0].race, "R0 - White");
+        assert!(individuals(&graph).iter().any(|individual| individual.atopic_march_cohort == Some(true)));
+        assert!(hashed.iter().all(|individual| individual.atopic_march_cohort.is_none()));
+        assert_eq!(
+            &applied[1..],
+            [
+                "dropped age (5 individuals)",
+                "dropped site (5 individuals)",
+                "dropped zip (0 individuals)",
+                "dropped cohort (5 individuals)"
+            ]
+        );
+
+        let json = node_link_export(&graph, Unit::Subject, &Deidentify::default()).unwrap();
+        assert_eq!(json["graph"]["deidentification"][0], "subject ids renumbered in random order");
+        assert_eq!(json["graph"]["unit"], "subject");
+        assert!(["1", "2", "3", "4", "5"].iter().any(|&id| json["nodes"][9]["subject_id"] == id));
+
+        let unsalted = Deidentify { ids: ExportIds::Hash, ..Default::default() };
+        assert!(unsalted.apply(&graph).is_err());
+    }
+
+    #[test]
+    fn test_deidentify_drops_demographic_nodes() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions { mode: crate::GraphMode::Tripartite, ..Default::default() });
+        let options = Deidentify { drop: vec!["race".to_string()], ..Default::default() };
+        let (deidentified, _) = options.apply(&graph).unwrap();
+        // The 3 race nodes and their edge to each of the 5 individuals go
+        assert_eq!(deidentified.node_count(), graph.node_count() - 3);
+        assert_eq!(deidentified.edge_count(), graph.edge_count() - 5);
+        assert!(!deidentified.node_weights().any(|node| {
+            matches!(node, NodeType::Demographic { dimension, .. } if dimension == "race")
+        }));
+    }
+}
@@ -0,0 +1,180 @@
+//! Named profiles of global options (`--save-profile`, `--profile`), so a
+//! study's usual flags needn't be retyped on every run.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command};
+use log::{info, warn};
+
+/// Named sets of command-line flags, stored as YAML in the user config
+/// directory (`$ALLERGY_NET_CONFIG`, else `$XDG_CONFIG_HOME/allergy-net`,
+/// else `~/.config/allergy-net`).
+type Profiles = BTreeMap<String, Vec<String>>;
+
+const PROFILE_FLAGS: [&str; 2] = ["--profile", "--save-profile"];
+const PROFILE_IDS: [&str; 2] = ["profile", "save_profile"];
+
+fn config_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("ALLERGY_NET_CONFIG") {
+        return Some(PathBuf::from(dir));
+    }
+    if let Some(dir) = env::var_os("XDG_CONFIG_HOME") {
+        return Some(PathBuf::from(dir).join("allergy-net"));
+    }
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".config").join("allergy-net"))
+}
+
+fn profiles_path() -> Result<PathBuf, String> {
+    config_dir()
+        .map(|dir| dir.join("profiles.yml"))
+        .ok_or_else(|| "cannot locate a config directory; set ALLERGY_NET_CONFIG".to_string())
+}
+
+fn load() -> Result<Profiles, Box<dyn Error>> {
+    let path = profiles_path()?;
+    if !path.exists() {
+        return Ok(Profiles::new());
+    }
+    let contents = fs::read_to_string(&path)?;
+    serde_yaml::from_str(&contents)
+        .map_err(|e| format!("invalid profiles file {}: {}", path.display(), e).into())
+}
+
+/// Value of `--flag name` or `--flag=name` in `args`.
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let prefix = format!("{}=", flag);
+    args.iter().enumerate().find_map(|(i, arg)| {
+        if arg == flag {
+            args.get(i + 1).map(String::as_str)
+        } else {
+            arg.strip_prefix(&prefix)
+        }
+    })
+}
+
+/// The options before any subcommand that were typed on the command line,
+/// as `--save-profile` stores them: no subcommand or its arguments, no
+/// positionals, no profile flags, and none of `secrets`.
+fn global_flags(command: &Command, matches: &ArgMatches, secrets: &[&str]) -> Vec<String> {
+    let mut flags = Vec::new();
+    for arg in command.get_arguments().filter(|arg| !arg.is_positional()) {
+        let id = arg.get_id().as_str();
+        if PROFILE_IDS.contains(&id) || matches.value_source(id) != Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let flag = match arg.get_long() {
+            Some(long) => format!("--{}", long),
+            None => format!("-{}", arg.get_short().unwrap_or_default()),
+        };
+        if secrets.contains(&id) {
+            warn!("{} is not saved in profiles; pass it on each run", flag);
+            continue;
+        }
+        match arg.get_action() {
+            ArgAction::SetTrue | ArgAction::SetFalse => flags.push(flag),
+            ArgAction::Count => flags.extend(std::iter::repeat_n(flag, usize::from(matches.get_count(id)))),
+            _ => {
+                let Ok(Some(raw)) = matches.try_get_raw(id) else { continue };
+                let values: Vec<String> = raw.map(|v| v.to_string_lossy().into_owned()).collect();
+                match arg.get_value_delimiter() {
+                    Some(delimiter) => flags.push(format!("{}={}", flag, values.join(&delimiter.to_string()))),
+                    None => flags.extend(values.iter().map(|value| format!("{}={}", flag, value))),
+                }
+            }
+        }
+    }
+    flags
+}
+
+/// Splices the flags of any `--profile` right after the program name, so
+/// flags typed on the command line take precedence over the profile's.
+pub fn expand_args(args: Vec<String>) -> Result<Vec<String>, Box<dyn Error>> {
+    let Some(name) = flag_value(&args, "--profile") else { return Ok(args) };
+    let profiles = load()?;
+    let saved = profiles
+        .get(name)
+        .ok_or_else(|| format!("unknown profile '{}'", name))?;
+    let not_saved = |arg: &&String| !arg.starts_with('-') || PROFILE_FLAGS.iter().any(|flag| arg.starts_with(flag));
+    if let Some(arg) = saved.iter().find(not_saved) {
+        return Err(format!("profile '{}' saved '{}', which isn't a global option; save it again", name, arg).into());
+    }
+    let mut expanded = vec![args[0].clone()];
+    expanded.extend(saved.iter().cloned());
+    expanded.extend(args[1..].iter().cloned());
+    Ok(expanded)
+}
+
+/// Saves the invocation's global options under `name`, replacing any
+/// existing profile of that name. `secrets` (argument ids) are left out,
+/// as the audit log redacts them.
+pub fn save(name: &str, command: &Command, matches: &ArgMatches, secrets: &[&str]) -> Result<(), Box<dyn Error>> {
+    let path = profiles_path()?;
+    let mut profiles = load()?;
+    profiles.insert(name.to_string(), global_flags(command, matches, secrets));
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, serde_yaml::to_string(&profiles)?)?;
+    info!("Saved profile {} to {}", name, path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, Parser, Subcommand};
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[derive(Parser)]
+    struct Cli {
+        #[arg(short, long, action = ArgAction::Count, global = true)]
+        verbose: u8,
+        #[arg(long, value_delimiter = ',')]
+        stratify_by: Vec<String>,
+        #[arg(long, global = true, default_value_t = 11)]
+        small_cell_threshold: usize,
+        #[arg(long, global = true)]
+        id_salt: Option<String>,
+        #[arg(long, global = true)]
+        save_profile: Option<String>,
+        #[command(subcommand)]
+        command: Option<Sub>,
+    }
+
+    #[derive(Subcommand)]
+    enum Sub {
+        Filter { expression: String },
+    }
+
+    #[test]
+    fn test_find_profile_flags() {
+        let argv = args(&["--save-profile", "peds", "--stratify-by", "race", "--profile=old", "-v"]);
+        assert_eq!(flag_value(&argv, "--save-profile"), Some("peds"));
+        assert_eq!(flag_value(&argv, "--profile"), Some("old"));
+        assert_eq!(flag_value(&argv, "--cohort"), None);
+    }
+
+    #[test]
+    fn test_global_flags_leave_out_subcommand_and_secrets() {
+        let argv = [
+            "prog", "--save-profile", "peds", "-vv", "--stratify-by", "race,payer", "filter", "age < 5", "--id-salt",
+            "s3cret",
+        ];
+        let matches = Cli::command().get_matches_from(argv);
+        let flags = global_flags(&Cli::command(), &matches, &["id_salt"]);
+        assert_eq!(flags, args(&["--verbose", "--verbose", "--stratify-by=race,payer"]));
+        // Saved flags parse back to the same options
+        let reparsed = Cli::command().get_matches_from(args(&["prog"]).into_iter().chain(flags));
+        assert_eq!(reparsed.get_count("verbose"), 2);
+        assert_eq!(reparsed.get_many::<String>("stratify_by").unwrap().collect::<Vec<_>>(), ["race", "payer"]);
+        assert!(reparsed.subcommand().is_none());
+    }
+}
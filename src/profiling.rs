@@ -0,0 +1,330 @@
+//! Data profile of an input (`profile` subcommand): missingness per column,
+//! the distinct values of categorical columns and the range of each age
+//! column. It reads the CSV text before any record is parsed or graph is
+//! built, so data problems surface before they fail or skew an analysis.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use csv::ReaderBuilder;
+use serde::Serialize;
+
+use crate::columns::is_missing;
+use crate::error::AllergyNetError;
+use crate::headers::HeaderAliases;
+use crate::io::decompress;
+use crate::plausibility::PlausibilityRules;
+use crate::progress;
+use crate::{Show, RECORD_COLUMNS};
+
+/// Values listed per categorical column, most frequent first.
+pub const TOP_VALUES: usize = 10;
+/// Distinct values counted per categorical column; past this the column is
+/// reported as high-cardinality.
+pub const MAX_DISTINCT: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ColumnMissing {
+    pub column: String,
+    /// Empty or `NA` values.
+    pub missing: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ValueCount {
+    pub value: String,
+    pub rows: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CategoryValues {
+    pub column: String,
+    /// Distinct values, or `None` past `MAX_DISTINCT`.
+    pub distinct: Option<usize>,
+    /// Up to `TOP_VALUES` values, most frequent first; empty past
+    /// `MAX_DISTINCT`.
+    pub values: Vec<ValueCount>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AgeRange {
+    pub column: String,
+    /// Values that are numbers.
+    pub n: usize,
+    pub min: Option<f64>,
+    pub mean: Option<f64>,
+    pub max: Option<f64>,
+    pub non_numeric: usize,
+    /// Numbers outside the plausible birth years or ages.
+    pub out_of_range: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Profile {
+    pub rows: usize,
+    /// Distinct subject ids, if there is a `subject_id` column.
+    pub subjects: Option<usize>,
+    /// Every column, in input order.
+    pub missing: Vec<ColumnMissing>,
+    /// The demographic factors, the cohort flag and every extra column.
+    pub categories: Vec<CategoryValues>,
+    /// Birth year, the observation window and every allergy onset and end.
+    pub ages: Vec<AgeRange>,
+    /// Rows whose observation window ends before it starts, if the input
+    /// has both columns.
+    pub end_before_start: Option<usize>,
+}
+
+/// What a column is profiled as.
+#[derive(Debug, Clone)]
+enum Kind {
+    Id,
+    Category,
+    /// A number within the range, if plausible.
+    Age(RangeInclusive<f64>),
+}
+
+impl Kind {
+    fn of(column: &str, rules: &PlausibilityRules) -> Kind {
+        match column {
+            "subject_id" => Kind::Id,
+            "birth_year" => Kind::Age(*rules.birth_years.start() as f64..=*rules.birth_years.end() as f64),
+            _ if column.starts_with("age_") || column.contains("_alg_") => Kind::Age(rules.ages.clone()),
+            _ => Kind::Category,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Tally {
+    missing: usize,
+    /// Categorical values, until there are more than `MAX_DISTINCT`.
+    counts: HashMap<String, usize>,
+    overflowed: bool,
+    /// Count, minimum, maximum and sum of numbers.
+    n: usize,
+    min: Option<f64>,
+    max: Option<f64>,
+    sum: f64,
+    non_numeric: usize,
+    out_of_range: usize,
+}
+
+/// Profiles the CSV file at `path`, decompressing it and matching its
+/// headers as `read_csv` does, with the plausible ranges of `rules`.
+pub fn profile(
+    path: impl AsRef<Path>,
+    aliases: &HeaderAliases,
+    rules: &PlausibilityRules,
+) -> Result<Profile, AllergyNetError> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| AllergyNetError::from(e).in_file(path))?;
+    let bar = progress::bytes(file.metadata().map_or(0, |metadata| metadata.len()), "profiling");
+    let profile = decompress(path, bar.wrap_read(file))
+        .map_err(AllergyNetError::from)
+        .and_then(|reader| profile_reader(reader, aliases, rules));
+    bar.finish_and_clear();
+    profile.map_err(|e| e.in_file(path))
+}
+
+/// Profiles CSV from any source.
+pub fn profile_reader(
+    reader: impl io::Read,
+    aliases: &HeaderAliases,
+    rules: &PlausibilityRules,
+) -> Result<Profile, AllergyNetError> {
+    let mut rdr = ReaderBuilder::new().from_reader(reader);
+    let headers = aliases.rename(rdr.headers()?)?;
+    let kinds: Vec<Kind> = headers.iter().map(|column| Kind::of(column, rules)).collect();
+    let mut tallies = vec![Tally::default(); headers.len()];
+    let mut subjects = HashSet::new();
+    let position = |column: &str| headers.iter().position(|header| header == column);
+    let window = position("age_start_years").zip(position("age_end_years"));
+    let mut rows = 0;
+    let mut end_before_start = 0;
+    for row in rdr.records() {
+        let row = row?;
+        rows += 1;
+        for ((value, kind), tally) in row.iter().zip(&kinds).zip(&mut tallies) {
+            if is_missing(value) {
+                tally.missing += 1;
+                continue;
+            }
+            let value = value.trim();
+            match kind {
+                Kind::Id => {
+                    subjects.insert(value.to_string());
+                }
+                Kind::Category if tally.overflowed => {}
+                Kind::Category => {
+                    *tally.counts.entry(value.to_string()).or_default() += 1;
+                    if tally.counts.len() > MAX_DISTINCT {
+                        tally.overflowed = true;
+                        tally.counts = HashMap::new();
+                    }
+                }
+                Kind::Age(range) => match value.parse::<f64>() {
+                    Ok(number) => {
+                        tally.out_of_range += usize::from(!range.contains(&number));
+                        tally.n += 1;
+                        tally.min = Some(tally.min.map_or(number, |min| min.min(number)));
+                        tally.max = Some(tally.max.map_or(number, |max| max.max(number)));
+                        tally.sum += number;
+                    }
+                    Err(_) => tally.non_numeric += 1,
+                },
+            }
+        }
+        if let Some((start, end)) = window {
+            let age = |i: usize| row.get(i).and_then(|value| value.trim().parse::<f64>().ok());
+            end_before_start += usize::from(matches!((age(start), age(end)), (Some(start), Some(end)) if end < start));
+        }
+    }
+
+    let mut profile = Profile {
+        rows,
+        subjects: kinds.iter().any(|kind| matches!(kind, Kind::Id)).then_some(subjects.len()),
+        missing: Vec::new(),
+        categories: Vec::new(),
+        ages: Vec::new(),
+        end_before_start: window.map(|_| end_before_start),
+    };
+    for ((column, kind), tally) in headers.iter().zip(kinds).zip(tallies) {
+        profile.missing.push(ColumnMissing { column: column.to_string(), missing: tally.missing });
+        match kind {
+            Kind::Id => {}
+            Kind::Category => {
+                let mut values: Vec<ValueCount> =
+                    tally.counts.into_iter().map(|(value, rows)| ValueCount { value, rows }).collect();
+                values.sort_by(|a, b| b.rows.cmp(&a.rows).then_with(|| a.value.cmp(&b.value)));
+                let distinct = (!tally.overflowed).then_some(values.len());
+                values.truncate(TOP_VALUES);
+                profile.categories.push(CategoryValues { column: column.to_string(), distinct, values });
+            }
+            Kind::Age(_) => {
+                profile.ages.push(AgeRange {
+                    column: column.to_string(),
+                    n: tally.n,
+                    min: tally.min,
+                    mean: (tally.n > 0).then(|| tally.sum / tally.n as f64),
+                    max: tally.max,
+                    non_numeric: tally.non_numeric,
+                    out_of_range: tally.out_of_range,
+                });
+            }
+        }
+    }
+    Ok(profile)
+}
+
+/// `value` rounded to two decimals, or `-`.
+fn number(value: Option<f64>) -> String {
+    value.map_or("-".to_string(), |value| ((value * 100.0).round() / 100.0).to_string())
+}
+
+impl Profile {
+    pub fn write(&self, show: Show, out: &mut dyn Write) -> io::Result<()> {
+        match self.subjects {
+            Some(subjects) => writeln!(out, "{} rows, {} distinct subject ids", self.rows, subjects)?,
+            None => writeln!(out, "{} rows, no subject_id column", self.rows)?,
+        }
+        let width = self.missing.iter().map(|c| c.column.len()).max().unwrap_or(0).max(10);
+        writeln!(out, "\n{:width$}  missing", "column")?;
+        for column in &self.missing {
+            writeln!(out, "{:width$}  {}", column.column, show.format(column.missing, self.rows))?;
+        }
+
+        if !self.categories.is_empty() {
+            writeln!(out, "\nCategorical columns, most frequent values first:")?;
+        }
+        for category in &self.categories {
+            let values: Vec<String> =
+                category.values.iter().map(|v| format!("{} ({})", v.value, show.format(v.rows, self.rows))).collect();
+            match category.distinct {
+                Some(distinct) if distinct > values.len() => writeln!(
+                    out,
+                    "{:width$}  {} distinct: {}, ...",
+                    category.column,
+                    distinct,
+                    values.join(", ")
+                )?,
+                Some(distinct) => {
+                    writeln!(out, "{:width$}  {} distinct: {}", category.column, distinct, values.join(", "))?
+                }
+                None => writeln!(out, "{:width$}  more than {} distinct", category.column, MAX_DISTINCT)?,
+            }
+        }
+
+        if !self.ages.is_empty() {
+            writeln!(
+                out,
+                "\n{:width$}  {:>8}  {:>8}  {:>8}  {:>8}  {:>11}  {:>12}",
+                "age column", "n", "min", "mean", "max", "non-numeric", "out of range"
+            )?;
+        }
+        for age in &self.ages {
+            writeln!(
+                out,
+                "{:width$}  {:>8}  {:>8}  {:>8}  {:>8}  {:>11}  {:>12}",
+                age.column,
+                age.n,
+                number(age.min),
+                number(age.mean),
+                number(age.max),
+                age.non_numeric,
+                age.out_of_range
+            )?;
+        }
+        if let Some(rows) = self.end_before_start {
+            writeln!(out, "\nRows with age_end_years before age_start_years: {}", show.format(rows, self.rows))?;
+        }
+        let absent: Vec<&str> = RECORD_COLUMNS
+            .iter()
+            .copied()
+            .filter(|column| !self.missing.iter().any(|c| c.column == *column))
+            .collect();
+        if !absent.is_empty() {
+            writeln!(out, "Columns absent from the input: {}", absent.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile() {
+        let fixture = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let profile = profile(fixture, &HeaderAliases::default(), &PlausibilityRules::default()).unwrap();
+        assert_eq!((profile.rows, profile.subjects, profile.end_before_start), (5, Some(5), Some(0)));
+        let missing = |column: &str| profile.missing.iter().find(|c| c.column == column).unwrap().missing;
+        assert_eq!((missing("gender_factor"), missing("peanut_alg_start"), missing("almond_alg_start")), (0, 2, 5));
+
+        let site = profile.categories.iter().find(|c| c.column == "site").unwrap();
+        assert_eq!(site.distinct, Some(3));
+        // Ties in value order
+        assert_eq!(site.values[0], ValueCount { value: "north".to_string(), rows: 2 });
+        let birth_year = &profile.ages[0];
+        assert_eq!(birth_year.column, "birth_year");
+        assert_eq!((birth_year.min, birth_year.max), (Some(1999.0), Some(2010.0)));
+        assert_eq!(profile.ages.iter().find(|a| a.column == "almond_alg_start").unwrap().mean, None);
+
+        let csv = "Subject ID,age_start_years,age_end_years,gender_factor\n\
+                   1,2,1,M\n\
+                   1,abc,-300,\n";
+        let profile = profile_reader(csv.as_bytes(), &HeaderAliases::default(), &PlausibilityRules::default()).unwrap();
+        assert_eq!((profile.subjects, profile.end_before_start), (Some(1), Some(1)));
+        assert_eq!((profile.ages[0].non_numeric, profile.ages[1].out_of_range), (1, 1));
+        let mut out = Vec::new();
+        profile.write(Show::Counts, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("gender_factor    1 distinct: M (n=1 of 2)\n"), "{}", text);
+        assert!(text.contains("Rows with age_end_years before age_start_years: n=1 of 2\n"), "{}", text);
+        assert!(text.contains("Columns absent from the input: birth_year, race_factor"), "{}", text);
+    }
+}
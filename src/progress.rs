@@ -0,0 +1,90 @@
+//! Progress bars and stage timings for long runs. Bars are drawn on stderr
+//! once `enable` has turned them on (the CLI does unless `--quiet`), and
+//! only when stderr is a terminal. Each `Stage` logs how long it took when
+//! it ends, and `summary` logs them all.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use log::info;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TIMINGS: Mutex<Vec<(&str, Duration)>> = Mutex::new(Vec::new());
+
+/// Turns progress bars on or off for the rest of the run.
+pub fn enable(on: bool) {
+    ENABLED.store(on, Ordering::Relaxed);
+}
+
+fn styled(len: u64, message: &str, template: &str) -> ProgressBar {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return ProgressBar::hidden();
+    }
+    let style = ProgressStyle::with_template(template).expect("valid template").progress_chars("=> ");
+    ProgressBar::new(len).with_style(style).with_message(message.to_string())
+}
+
+/// A bar counting `len` steps, e.g. records or nodes; hidden unless
+/// enabled.
+pub fn bar(len: u64, message: &str) -> ProgressBar {
+    styled(len, message, "{msg:>12} [{bar:40}] {human_pos}/{human_len} ({eta})")
+}
+
+/// A bar counting `len` bytes read; hidden unless enabled.
+pub fn bytes(len: u64, message: &str) -> ProgressBar {
+    styled(len, message, "{msg:>12} [{bar:40}] {bytes}/{total_bytes} ({eta})")
+}
+
+/// Times a stage of the run, from `start` until it is dropped.
+pub struct Stage {
+    name: &'static str,
+    started: Instant,
+}
+
+impl Stage {
+    pub fn start(name: &'static str) -> Self {
+        Stage { name, started: Instant::now() }
+    }
+}
+
+impl Drop for Stage {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        info!("Finished {} in {:.2?}", self.name, elapsed);
+        TIMINGS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push((self.name, elapsed));
+    }
+}
+
+/// The stages timed so far and their total, e.g. `reading 1.20s, analysis
+/// 4.02s; total 5.22s`, or `None` if there were none.
+pub fn timings() -> Option<String> {
+    let timings = TIMINGS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if timings.is_empty() {
+        return None;
+    }
+    let stages: Vec<String> = timings.iter().map(|(name, elapsed)| format!("{} {:.2?}", name, elapsed)).collect();
+    let total: Duration = timings.iter().map(|(_, elapsed)| *elapsed).sum();
+    Some(format!("{}; total {:.2?}", stages.join(", "), total))
+}
+
+/// Logs the time of each stage and the total.
+pub fn summary() {
+    if let Some(timings) = timings() {
+        info!("Stage timings: {}", timings);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stage_timings() {
+        assert!(bar(10, "test").is_hidden());
+        drop(Stage::start("timed stage"));
+        let timings = timings().unwrap();
+        assert!(timings.starts_with("timed stage ") && timings.contains("; total "), "{}", timings);
+    }
+}
@@ -0,0 +1,125 @@
+//! One-mode projections of the individual-allergy graph: individuals
+//! joined by shared allergies, for clustering and community detection with
+//! petgraph's algorithms, and allergies joined by shared individuals. They are the graph
+//! counterparts of `matrix::projected_adjacency`, without the `matrix`
+//! feature.
+
+use std::collections::BTreeMap;
+
+use petgraph::graph::{DiGraph, NodeIndex, UnGraph};
+use petgraph::Direction;
+
+use crate::{EdgeWeight, Individual, NodeType};
+
+/// Co-allergy graph of the individuals: one node per individual, in graph
+/// order, and an edge between two individuals weighted by the number of
+/// nut allergies they share. Individuals who share none are not joined.
+pub fn project_individuals(graph: &DiGraph<NodeType, EdgeWeight>) -> UnGraph<Individual, usize> {
+    let mut projection = UnGraph::default();
+    let mut nodes = BTreeMap::new();
+    for node in graph.node_indices() {
+        if let NodeType::Individual(individual) = &graph[node] {
+            nodes.insert(node, projection.add_node(individual.clone()));
+        }
+    }
+    let mut shared: BTreeMap<(NodeIndex, NodeIndex), usize> = BTreeMap::new();
+    for allergen in graph.node_indices() {
+        if !matches!(graph[allergen], NodeType::AllergenStatus(_)) {
+            continue;
+        }
+        let mut members: Vec<NodeIndex> = graph
+            .neighbors_directed(allergen, Direction::Incoming)
+            .filter_map(|node| nodes.get(&node).copied())
+            .collect();
+        members.sort();
+        for (i, &a) in members.iter().enumerate() {
+            for &b in &members[i + 1..] {
+                *shared.entry((a, b)).or_default() += 1;
+            }
+        }
+    }
+    for ((a, b), count) in shared {
+        projection.add_edge(a, b, count);
+    }
+    projection
+}
+
+/// Co-occurrence graph of the allergies, for exploring cross-reactivity:
+/// one node per allergen, in graph order, and an edge between two
+/// allergies weighted by the number of individuals who have both.
+pub fn project_allergies(graph: &DiGraph<NodeType, EdgeWeight>) -> UnGraph<String, usize> {
+    let mut projection = UnGraph::default();
+    let mut nodes = BTreeMap::new();
+    for node in graph.node_indices() {
+        if let NodeType::AllergenStatus(name) = &graph[node] {
+            nodes.insert(node, projection.add_node(name.clone()));
+        }
+    }
+    let mut shared: BTreeMap<(NodeIndex, NodeIndex), usize> = BTreeMap::new();
+    for individual in graph.node_indices() {
+        if !matches!(graph[individual], NodeType::Individual(_)) {
+            continue;
+        }
+        let mut allergies: Vec<NodeIndex> = graph
+            .neighbors_directed(individual, Direction::Outgoing)
+            .filter_map(|node| nodes.get(&node).copied())
+            .collect();
+        allergies.sort();
+        allergies.dedup();
+        for (i, &a) in allergies.iter().enumerate() {
+            for &b in &allergies[i + 1..] {
+                *shared.entry((a, b)).or_default() += 1;
+            }
+        }
+    }
+    for ((a, b), count) in shared {
+        projection.add_edge(a, b, count);
+    }
+    projection
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    #[test]
+    fn test_project_individuals() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let projection = project_individuals(&create_graph(records, &GraphOptions::default()));
+        let ids: Vec<&str> = projection.node_weights().map(|individual| individual.id.as_str()).collect();
+        assert_eq!(ids, ["205650", "205651", "205652", "205653", "205654"]);
+        let edges: Vec<(&str, &str, usize)> = projection
+            .edge_indices()
+            .map(|edge| {
+                let (a, b) = projection.edge_endpoints(edge).unwrap();
+                (ids[a.index()], ids[b.index()], projection[edge])
+            })
+            .collect();
+        // 205650 and 205654 share Peanut and Cashew; 205653 has no allergies
+        assert_eq!(
+            edges,
+            [("205650", "205651", 1), ("205650", "205654", 2), ("205651", "205654", 1), ("205652", "205654", 1)]
+        );
+    }
+
+    #[test]
+    fn test_project_allergies() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let projection = project_allergies(&create_graph(records, &GraphOptions::default()));
+        assert_eq!(projection.node_count(), crate::ALLERGENS.len());
+        let pairs: BTreeMap<(&str, &str), usize> = projection
+            .edge_indices()
+            .map(|edge| {
+                let (a, b) = projection.edge_endpoints(edge).unwrap();
+                ((projection[a].as_str(), projection[b].as_str()), projection[edge])
+            })
+            .collect();
+        // Subjects 205650 and 205654 both have Peanut and Cashew
+        assert_eq!(pairs[&("Peanut", "Cashew")], 2);
+        assert_eq!(pairs[&("Treenut", "Pistachio")], 1);
+        assert_eq!(pairs[&("Walnut", "Pecan")], 1);
+        assert_eq!(pairs.len(), 9);
+        assert!(!pairs.keys().any(|&(a, b)| a == "Almond" || b == "Almond"));
+    }
+}
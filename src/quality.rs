@@ -0,0 +1,500 @@
+//! Data-quality checks on loaded records (`check` subcommand).
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+use clap::ValueEnum;
+
+use crate::Record;
+
+/// Ages, in years, an onset can plausibly be recorded at.
+pub const PLAUSIBLE_AGES: RangeInclusive<f64> = 0.0..=110.0;
+
+/// The specific tree nuts the `Treenut` columns aggregate.
+pub const TREE_NUTS: &[&str] = &["Walnut", "Pecan", "Pistachio", "Almond", "Brazil", "Hazelnut", "Cashew"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Rule {
+    /// `age_start_years <= age_end_years`.
+    ObservationWindow,
+    /// Every `*_alg_start <= *_alg_end`.
+    AllergyInterval,
+    /// Onsets fall within `PLAUSIBLE_AGES`.
+    OnsetPlausible,
+    /// Onsets are no later than the end of the observation window.
+    OnsetInWindow,
+    /// The `Treenut` onset is set exactly when a specific tree nut's is.
+    TreenutAggregate,
+}
+
+impl Rule {
+    pub const ALL: [Rule; 5] = [
+        Rule::ObservationWindow,
+        Rule::AllergyInterval,
+        Rule::OnsetPlausible,
+        Rule::OnsetInWindow,
+        Rule::TreenutAggregate,
+    ];
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Rule::ObservationWindow => "age_start_years <= age_end_years",
+            Rule::AllergyInterval => "*_alg_start <= *_alg_end",
+            Rule::OnsetPlausible => "onset age within plausible bounds",
+            Rule::OnsetInWindow => "onset at or before age_end_years",
+            Rule::TreenutAggregate => "treenut onset iff a specific tree nut onset",
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Rule::ObservationWindow => "observation_window",
+            Rule::AllergyInterval => "allergy_interval",
+            Rule::OnsetPlausible => "onset_plausible",
+            Rule::OnsetInWindow => "onset_in_window",
+            Rule::TreenutAggregate => "treenut_aggregate",
+        })
+    }
+}
+
+/// One rule broken by one record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub rule: Rule,
+    /// 1-based position of the record in the input.
+    pub row: usize,
+    pub subject_id: String,
+    pub detail: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ConsistencyReport {
+    pub rows: usize,
+    pub violations: Vec<Violation>,
+}
+
+pub fn check_consistency(records: &[Record]) -> ConsistencyReport {
+    let mut violations = Vec::new();
+    for (i, record) in records.iter().enumerate() {
+        let mut flag = |rule, detail: String| {
+            violations.push(Violation { rule, row: i + 1, subject_id: record.subject_id.clone(), detail });
+        };
+        if record.age_start_years > record.age_end_years {
+            flag(
+                Rule::ObservationWindow,
+                format!("age_start_years {} > age_end_years {}", record.age_start_years, record.age_end_years),
+            );
+        }
+        for allergy in record.allergies() {
+            let Some(onset) = record.get_allergy_start(allergy) else { continue };
+            if let Some(end) = record.get_allergy_end(allergy).filter(|&end| onset > end) {
+                flag(Rule::AllergyInterval, format!("{} onset {} > end {}", allergy, onset, end));
+            }
+            if !PLAUSIBLE_AGES.contains(&onset) {
+                flag(Rule::OnsetPlausible, format!("{} onset {} outside {:?}", allergy, onset, PLAUSIBLE_AGES));
+            }
+            if onset > record.age_end_years {
+                flag(
+                    Rule::OnsetInWindow,
+                    format!("{} onset {} > age_end_years {}", allergy, onset, record.age_end_years),
+                );
+            }
+        }
+        let specific: Vec<&str> =
+            TREE_NUTS.iter().copied().filter(|&nut| record.get_allergy_start(nut).is_some()).collect();
+        match (record.treenut_alg_start, specific.is_empty()) {
+            (Some(_), true) => flag(Rule::TreenutAggregate, "Treenut onset without a specific tree nut".to_string()),
+            (None, false) => {
+                flag(Rule::TreenutAggregate, format!("{} onset without a Treenut onset", specific.join(", ")))
+            }
+            _ => {}
+        }
+    }
+    ConsistencyReport { rows: records.len(), violations }
+}
+
+/// Fills in the `Treenut` interval of records that have a specific tree
+/// nut allergy but no aggregate one: the earliest specific onset, ending at
+/// the latest specific end unless one of them is still ongoing. Returns the
+/// number of records changed. The aggregate can't be split back into
+/// specific nuts, so records with only an aggregate onset are left alone.
+pub fn derive_treenut(records: &mut [Record]) -> usize {
+    let mut derived = 0;
+    for record in records.iter_mut().filter(|record| record.treenut_alg_start.is_none()) {
+        let intervals: Vec<(f64, Option<f64>)> = TREE_NUTS
+            .iter()
+            .filter_map(|&nut| record.get_allergy_start(nut).map(|start| (start, record.get_allergy_end(nut))))
+            .collect();
+        let Some(onset) = intervals.iter().map(|&(start, _)| start).reduce(f64::min) else { continue };
+        record.treenut_alg_start = Some(onset);
+        let ends: Option<Vec<f64>> = intervals.iter().map(|&(_, end)| end).collect();
+        record.treenut_alg_end = ends.and_then(|ends| ends.into_iter().reduce(f64::max));
+        derived += 1;
+    }
+    derived
+}
+
+impl ConsistencyReport {
+    /// Violations per rule, including rules with none.
+    pub fn counts(&self) -> BTreeMap<Rule, usize> {
+        let mut counts: BTreeMap<Rule, usize> = Rule::ALL.iter().map(|&rule| (rule, 0)).collect();
+        for violation in &self.violations {
+            *counts.entry(violation.rule).or_default() += 1;
+        }
+        counts
+    }
+
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "{:18}  {:>10}  check", "rule", "violations")?;
+        for (rule, count) in self.counts() {
+            writeln!(out, "{:18}  {:>10}  {}", rule.to_string(), count, rule.description())?;
+        }
+        writeln!(out, "{} rows checked", self.rows)?;
+        if !self.violations.is_empty() {
+            writeln!(out, "Offending rows:")?;
+        }
+        for violation in &self.violations {
+            writeln!(
+                out,
+                "  row {} (subject {}): {}: {}",
+                violation.row, violation.subject_id, violation.rule, violation.detail
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// How rows sharing a `subject_id` are resolved at ingest (`--dedup`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum DedupPolicy {
+    /// Keep every row.
+    #[default]
+    None,
+    /// Keep each subject's first row.
+    First,
+    /// Keep each subject's last row.
+    Last,
+    /// Drop subjects whose rows disagree on demographics, and keep the
+    /// first row of the others.
+    DropConflicting,
+    /// Merge each subject's rows into the first, as `merge_subjects` does:
+    /// every allergy recorded on any row, over the widest window.
+    UnionOfAllergies,
+    /// Fail if any subject has more than one row.
+    Error,
+}
+
+impl fmt::Display for DedupPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.to_possible_value().expect("no skipped variants").get_name())
+    }
+}
+
+fn demographics(record: &Record) -> [(&'static str, String); 6] {
+    [
+        ("birth_year", record.birth_year.to_string()),
+        ("gender_factor", record.gender_factor.clone()),
+        ("race_factor", record.race_factor.clone()),
+        ("ethnicity_factor", record.ethnicity_factor.clone()),
+        ("payer_factor", record.payer_factor.clone()),
+        ("atopic_march_cohort", record.atopic_march_cohort.to_string()),
+    ]
+}
+
+/// A subject with more than one row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuplicateGroup {
+    pub subject_id: String,
+    /// 1-based positions of the subject's rows in the input.
+    pub rows: Vec<usize>,
+    /// Rows identical to an earlier row of the subject.
+    pub exact: usize,
+    /// Demographic columns whose values differ between the rows.
+    pub conflicts: Vec<&'static str>,
+    /// Rows the policy keeps.
+    pub kept: Vec<usize>,
+}
+
+#[derive(Debug)]
+pub struct DuplicateReport {
+    pub policy: DedupPolicy,
+    pub groups: Vec<DuplicateGroup>,
+}
+
+/// Subjects with more than one row, in order of first appearance, and how
+/// `policy` resolves each.
+pub fn find_duplicates(records: &[Record], policy: DedupPolicy) -> DuplicateReport {
+    let mut order: Vec<&str> = Vec::new();
+    let mut rows: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, record) in records.iter().enumerate() {
+        let subject_rows = rows.entry(&record.subject_id).or_default();
+        if subject_rows.is_empty() {
+            order.push(&record.subject_id);
+        }
+        subject_rows.push(i);
+    }
+    let groups = order
+        .into_iter()
+        .filter_map(|id| {
+            let indices = &rows[id];
+            if indices.len() < 2 {
+                return None;
+            }
+            let exact = (1..indices.len())
+                .filter(|&j| indices[..j].iter().any(|&i| records[i] == records[indices[j]]))
+                .count();
+            let first = demographics(&records[indices[0]]);
+            let conflicts: Vec<&'static str> = (0..first.len())
+                .filter(|&f| indices[1..].iter().any(|&i| demographics(&records[i])[f].1 != first[f].1))
+                .map(|f| first[f].0)
+                .collect();
+            let rows: Vec<usize> = indices.iter().map(|i| i + 1).collect();
+            let kept = match policy {
+                DedupPolicy::None | DedupPolicy::Error => rows.clone(),
+                DedupPolicy::First | DedupPolicy::UnionOfAllergies => vec![rows[0]],
+                DedupPolicy::Last => vec![rows[rows.len() - 1]],
+                DedupPolicy::DropConflicting if conflicts.is_empty() => vec![rows[0]],
+                DedupPolicy::DropConflicting => Vec::new(),
+            };
+            Some(DuplicateGroup { subject_id: id.to_string(), rows, exact, conflicts, kept })
+        })
+        .collect();
+    DuplicateReport { policy, groups }
+}
+
+/// Removes the rows `policy` doesn't keep, or merges them into the kept
+/// row under `DedupPolicy::UnionOfAllergies`, returning the report of what
+/// was resolved.
+pub fn deduplicate(records: &mut Vec<Record>, policy: DedupPolicy) -> DuplicateReport {
+    let report = find_duplicates(records, policy);
+    if policy == DedupPolicy::UnionOfAllergies {
+        *records = merge_subjects(std::mem::take(records));
+        return report;
+    }
+    let dropped: HashSet<usize> = report
+        .groups
+        .iter()
+        .flat_map(|group| group.rows.iter().filter(|row| !group.kept.contains(row)))
+        .copied()
+        .collect();
+    let mut row = 0;
+    records.retain(|_| {
+        row += 1;
+        !dropped.contains(&row)
+    });
+    report
+}
+
+/// One record per subject, in order of first appearance, for
+/// subject-level analysis. Demographics and extra columns come from the
+/// subject's first row (extra columns it lacks are filled from later rows);
+/// the observation window and each allergy interval span all of the rows,
+/// so an allergy recorded on any row counts once with its earliest onset.
+pub fn merge_subjects(records: impl IntoIterator<Item = Record>) -> Vec<Record> {
+    let mut merged: Vec<Record> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for record in records {
+        let Some(&i) = positions.get(&record.subject_id) else {
+            positions.insert(record.subject_id.clone(), merged.len());
+            merged.push(record);
+            continue;
+        };
+        let subject = &mut merged[i];
+        subject.age_start_years = subject.age_start_years.min(record.age_start_years);
+        subject.age_end_years = subject.age_end_years.max(record.age_end_years);
+        for allergy in record.allergies() {
+            if let Some(start) = record.get_allergy_start(allergy) {
+                let earliest = subject.get_allergy_start(allergy).map_or(start, |first| first.min(start));
+                subject.set_allergy_start(allergy, earliest);
+            }
+            if let Some(end) = record.get_allergy_end(allergy) {
+                let latest = subject.get_allergy_end(allergy).map_or(end, |first| first.max(end));
+                subject.set_allergy_end(allergy, latest);
+            }
+        }
+        for (column, value) in record.extra {
+            subject.extra.entry(column).or_insert(value);
+        }
+    }
+    merged
+}
+
+impl DuplicateReport {
+    pub fn dropped_rows(&self) -> usize {
+        self.groups.iter().map(|group| group.rows.len() - group.kept.len()).sum()
+    }
+
+    /// The subjects with more than one row, as `--dedup error` reports
+    /// them.
+    pub fn summary(&self) -> String {
+        let subjects: Vec<&str> = self.groups.iter().take(5).map(|group| group.subject_id.as_str()).collect();
+        let more = if self.groups.len() > subjects.len() { ", ..." } else { "" };
+        format!("{} subject(s) with multiple rows: {}{}", self.groups.len(), subjects.join(", "), more)
+    }
+
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        let exact: usize = self.groups.iter().map(|group| group.exact).sum();
+        let conflicting = self.groups.iter().filter(|group| !group.conflicts.is_empty()).count();
+        writeln!(
+            out,
+            "{} subject(s) with multiple rows: {} exact duplicate row(s), {} subject(s) with conflicting demographics",
+            self.groups.len(),
+            exact,
+            conflicting
+        )?;
+        match self.policy {
+            DedupPolicy::UnionOfAllergies => {
+                writeln!(out, "Dedup policy {}: {} row(s) merged", self.policy, self.dropped_rows())?
+            }
+            DedupPolicy::Error if !self.groups.is_empty() => writeln!(out, "Dedup policy error: ingest fails")?,
+            _ => writeln!(out, "Dedup policy {}: {} row(s) dropped", self.policy, self.dropped_rows())?,
+        }
+        for group in &self.groups {
+            let join = |rows: &[usize]| rows.iter().map(usize::to_string).collect::<Vec<_>>().join(", ");
+            write!(out, "  subject {}: rows {}", group.subject_id, join(&group.rows))?;
+            if group.exact > 0 {
+                write!(out, "; {} exact duplicate(s)", group.exact)?;
+            }
+            if !group.conflicts.is_empty() {
+                write!(out, "; conflicting {}", group.conflicts.join(", "))?;
+            }
+            match group.kept.as_slice() {
+                [row] if self.policy == DedupPolicy::UnionOfAllergies => writeln!(out, "; merged into row {}", row)?,
+                _ if self.policy == DedupPolicy::Error => writeln!(out)?,
+                [] => writeln!(out, "; dropped all")?,
+                kept if kept.len() == group.rows.len() => writeln!(out, "; kept all")?,
+                kept => writeln!(out, "; kept row {}", join(kept))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_csv;
+
+    #[test]
+    fn test_check_consistency() {
+        let mut records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        // Subject 205650 has a cashew allergy but no Treenut onset
+        let violations = check_consistency(&records).violations;
+        assert_eq!(violations.len(), 1);
+        assert_eq!((violations[0].row, violations[0].rule), (1, Rule::TreenutAggregate));
+        assert_eq!(violations[0].detail, "Cashew onset without a Treenut onset");
+        records[0].treenut_alg_start = Some(2.0);
+        assert!(check_consistency(&records).violations.is_empty());
+
+        records[1].age_start_years = 7.0;
+        records[2].walnut_alg_end = Some(1.0);
+        records[3].cashew_alg_start = Some(150.0);
+        let report = check_consistency(&records);
+        let counts: Vec<usize> = report.counts().into_values().collect();
+        assert_eq!(counts, vec![1, 1, 1, 1, 1]);
+        assert_eq!(report.violations[0].detail, "age_start_years 7 > age_end_years 6");
+        assert_eq!((report.violations[1].row, report.violations[1].rule), (3, Rule::AllergyInterval));
+        let subjects: Vec<&str> = report.violations[2..].iter().map(|v| v.subject_id.as_str()).collect();
+        assert_eq!(subjects, vec!["205653", "205653", "205653"]);
+
+        let mut out = Vec::new();
+        report.write(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("  row 2 (subject 205651): observation_window: age_start_years 7 > age_end_years 6\n"));
+    }
+
+    #[test]
+    fn test_derive_treenut() {
+        let mut records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        records[0].cashew_alg_end = Some(5.0);
+        records[0].almond_alg_start = Some(1.5);
+        records[3].treenut_alg_start = Some(3.0);
+        let before = records.clone();
+        assert_eq!(derive_treenut(&mut records), 1);
+        // The almond allergy is ongoing, so the aggregate has no end
+        assert_eq!((records[0].treenut_alg_start, records[0].treenut_alg_end), (Some(1.5), None));
+        assert_eq!(&records[1..], &before[1..]);
+        let rules: Vec<(usize, Rule)> = check_consistency(&records).violations.iter().map(|v| (v.row, v.rule)).collect();
+        assert_eq!(rules, vec![(4, Rule::TreenutAggregate)]);
+
+        records[0].almond_alg_end = Some(4.0);
+        records[0].treenut_alg_start = None;
+        derive_treenut(&mut records);
+        assert_eq!(records[0].treenut_alg_end, Some(5.0));
+    }
+
+    #[test]
+    fn test_merge_subjects() {
+        let mut records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let mut later = records[0].clone();
+        later.age_end_years += 3.0;
+        later.peanut_alg_start = Some(0.5);
+        later.walnut_alg_start = Some(3.0);
+        later.cashew_alg_start = Some(2.5);
+        later.cashew_alg_end = Some(4.0);
+        later.gender_factor = "S1 - Female".to_string();
+        records.push(later);
+
+        let merged = merge_subjects(records.clone());
+        assert_eq!(merged.len(), 5);
+        let subject = &merged[0];
+        assert_eq!(subject.age_end_years, records[0].age_end_years + 3.0);
+        assert_eq!((subject.peanut_alg_start, subject.peanut_alg_end), (Some(0.5), Some(4.5)));
+        assert_eq!(subject.walnut_alg_start, Some(3.0));
+        assert_eq!((subject.cashew_alg_start, subject.cashew_alg_end), (Some(2.0), Some(4.0)));
+        assert_eq!(subject.gender_factor, records[0].gender_factor);
+        assert_eq!(&merged[1..], &records[1..5]);
+    }
+
+    #[test]
+    fn test_duplicates_under_each_policy() {
+        let mut records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        records.push(records[0].clone());
+        let mut conflicting = records[1].clone();
+        conflicting.payer_factor = "P0 - Non-Medicaid".to_string();
+        records.push(conflicting);
+
+        let report = find_duplicates(&records, DedupPolicy::None);
+        assert_eq!(report.groups.len(), 2);
+        assert_eq!((report.groups[0].rows.clone(), report.groups[0].exact), (vec![1, 6], 1));
+        assert!(report.groups[0].conflicts.is_empty());
+        assert_eq!(report.groups[1].conflicts, vec!["payer_factor"]);
+        assert_eq!(report.dropped_rows(), 0);
+
+        let mut union = records.clone();
+        union[5].walnut_alg_start = Some(3.0);
+        let report = deduplicate(&mut union, DedupPolicy::UnionOfAllergies);
+        assert_eq!((union.len(), union[0].walnut_alg_start), (5, Some(3.0)));
+        assert_eq!(union[1].payer_factor, "P1 - Medicaid");
+        let mut out = Vec::new();
+        report.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("union-of-allergies: 2 row(s) merged\n") && out.contains("; merged into row 2\n"));
+        let mut refused = records.clone();
+        let report = deduplicate(&mut refused, DedupPolicy::Error);
+        assert_eq!(refused.len(), 7);
+        assert_eq!(report.summary(), "2 subject(s) with multiple rows: 205650, 205651");
+
+        let mut last = records.clone();
+        assert_eq!(deduplicate(&mut last, DedupPolicy::Last).dropped_rows(), 2);
+        let ids: Vec<&str> = last.iter().map(|r| r.subject_id.as_str()).collect();
+        assert_eq!(ids, vec!["205652", "205653", "205654", "205650", "205651"]);
+        assert_eq!(last[4].payer_factor, "P0 - Non-Medicaid");
+
+        let report = deduplicate(&mut records, DedupPolicy::DropConflicting);
+        assert_eq!(records.len(), 4);
+        let mut out = Vec::new();
+        report.write(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "2 subject(s) with multiple rows: 1 exact duplicate row(s), 1 subject(s) with conflicting demographics\n\
+             Dedup policy drop-conflicting: 3 row(s) dropped\n\
+             \x20 subject 205650: rows 1, 6; 1 exact duplicate(s); kept row 1\n\
+             \x20 subject 205651: rows 2, 7; conflicting payer_factor; dropped all\n"
+        );
+    }
+}
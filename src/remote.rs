@@ -0,0 +1,166 @@
+//! `s3://` and `gs://` locations for inputs and report destinations.
+//!
+//! Object storage needs the `cloud` feature; credentials and region come
+//! from the usual `AWS_*` and `GOOGLE_*` environment variables. Any other
+//! path is read from or written to local disk.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+const SCHEMES: &[&str] = &["s3://", "gs://"];
+
+/// Bucket objects `copy` downloaded, kept for the next `read` of them.
+static FETCHED: Mutex<Option<HashMap<String, Vec<u8>>>> = Mutex::new(None);
+
+/// Whether `path` names an object in a bucket rather than a local file.
+pub fn is_remote(path: &Path) -> bool {
+    path.to_str().is_some_and(|p| SCHEMES.iter().any(|scheme| p.starts_with(scheme)))
+}
+
+/// Reads a local file or a bucket object.
+pub fn read(path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    if is_remote(path) {
+        let location = path.to_str().unwrap_or_default();
+        if let Some(contents) = FETCHED.lock().unwrap().as_mut().and_then(|fetched| fetched.remove(location)) {
+            return Ok(contents);
+        }
+        cloud::get(location)
+    } else {
+        Ok(fs::read(path)?)
+    }
+}
+
+/// Streams a local file or a bucket object into `out`, returning its size
+/// in bytes. A bucket object is kept for the next `read` of it, so it is
+/// downloaded once when its hash is recorded before it is read.
+pub fn copy(path: &Path, out: &mut impl Write) -> Result<u64, Box<dyn Error>> {
+    if !is_remote(path) {
+        return Ok(io::copy(&mut File::open(path)?, out)?);
+    }
+    let location = path.to_str().unwrap_or_default();
+    let contents = cloud::get(location)?;
+    out.write_all(&contents)?;
+    let size = contents.len() as u64;
+    FETCHED.lock().unwrap().get_or_insert_with(HashMap::new).insert(location.to_string(), contents);
+    Ok(size)
+}
+
+/// A report destination: a local file, or a buffer uploaded by `finish`.
+pub enum Destination {
+    File(File),
+    Remote { location: String, buffer: Vec<u8> },
+}
+
+impl Destination {
+    /// Opens `path` for writing, creating local parent directories.
+    pub fn create(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if is_remote(path) {
+            return Ok(Destination::Remote { location: path.to_string_lossy().into_owned(), buffer: Vec::new() });
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(Destination::File(File::create(path)?))
+    }
+
+    /// Flushes the file or uploads the buffered object.
+    pub fn finish(self) -> Result<(), Box<dyn Error>> {
+        match self {
+            Destination::File(mut file) => Ok(file.flush()?),
+            Destination::Remote { location, buffer } => cloud::put(&location, buffer),
+        }
+    }
+}
+
+impl Write for Destination {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Destination::File(file) => file.write(buf),
+            Destination::Remote { buffer, .. } => buffer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Destination::File(file) => file.flush(),
+            Destination::Remote { .. } => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "cloud")]
+mod cloud {
+    use std::error::Error;
+
+    use object_store::path::Path as ObjectPath;
+    use object_store::{parse_url_opts, ObjectStore, PutPayload};
+    use url::Url;
+
+    /// Store for `location` configured from `AWS_*`/`GOOGLE_*` variables.
+    fn store(location: &str) -> Result<(Box<dyn ObjectStore>, ObjectPath), Box<dyn Error>> {
+        let url = Url::parse(location)?;
+        let options = std::env::vars()
+            .map(|(key, value)| (key.to_ascii_lowercase(), value))
+            .filter(|(key, _)| key.starts_with("aws_") || key.starts_with("google_"));
+        Ok(parse_url_opts(&url, options)?)
+    }
+
+    fn runtime() -> std::io::Result<tokio::runtime::Runtime> {
+        tokio::runtime::Builder::new_current_thread().enable_all().build()
+    }
+
+    pub fn get(location: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let (store, path) = store(location)?;
+        let bytes = runtime()?.block_on(async { store.get(&path).await?.bytes().await })?;
+        Ok(bytes.to_vec())
+    }
+
+    pub fn put(location: &str, bytes: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        let (store, path) = store(location)?;
+        runtime()?.block_on(store.put(&path, PutPayload::from(bytes)))?;
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "cloud"))]
+mod cloud {
+    use std::error::Error;
+
+    fn unsupported(location: &str) -> Box<dyn Error> {
+        format!("{}: object storage paths need a build with the `cloud` feature", location).into()
+    }
+
+    pub fn get(location: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        Err(unsupported(location))
+    }
+
+    pub fn put(location: &str, _bytes: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        Err(unsupported(location))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remote_paths_and_local_destination() {
+        assert!(is_remote(Path::new("s3://extracts/cohort.csv")));
+        assert!(is_remote(Path::new("gs://extracts/cohort.csv")));
+        assert!(!is_remote(Path::new("data/s3://cohort.csv")));
+
+        let path = std::env::temp_dir().join(format!("allergy-remote-{}/report.txt", std::process::id()));
+        let mut destination = Destination::create(&path).unwrap();
+        writeln!(destination, "report").unwrap();
+        destination.finish().unwrap();
+        assert_eq!(read(&path).unwrap(), b"report\n");
+        let mut copied = Vec::new();
+        assert_eq!(copy(&path, &mut copied).unwrap(), 7);
+        assert_eq!(copied, b"report\n");
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+//! Strict schema validation (`--strict`): every expected column present, no
+//! allergen-looking column the schema would silently drop, and categorical
+//! values drawn from declared sets.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use csv::ReaderBuilder;
+use serde::Deserialize;
+
+use crate::error::AllergyNetError;
+use crate::remote;
+use crate::{ALLERGENS, RECORD_COLUMNS};
+
+/// Allowed values for categorical columns. Every column listed here is also
+/// required to be present.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(transparent)]
+pub struct AllowedValues {
+    pub columns: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Default for AllowedValues {
+    /// The coded values of the canonical demographic factors.
+    fn default() -> Self {
+        let sets: [(&str, &[&str]); 4] = [
+            ("gender_factor", &["S0 - Male", "S1 - Female"]),
+            (
+                "race_factor",
+                &["R0 - White", "R1 - Black", "R2 - Asian or Pacific Islander", "R3 - Other", "R4 - Unknown"],
+            ),
+            ("ethnicity_factor", &["E0 - Non-Hispanic", "E1 - Hispanic", "E2 - Unknown"]),
+            ("payer_factor", &["P0 - Non-Medicaid", "P1 - Medicaid"]),
+        ];
+        let columns = sets
+            .into_iter()
+            .map(|(column, values)| (column.to_string(), values.iter().map(|v| v.to_string()).collect()))
+            .collect();
+        AllowedValues { columns }
+    }
+}
+
+impl AllowedValues {
+    /// The defaults, with any column declared in the YAML file at `path`
+    /// (`column: [value, ...]`) added or replaced.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let declared: AllowedValues = serde_yaml::from_slice(&remote::read(path)?)
+            .map_err(|e| format!("invalid allowed values file {}: {}", path.display(), e))?;
+        let mut allowed = AllowedValues::default();
+        allowed.columns.extend(declared.columns);
+        Ok(allowed)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Violation {
+    MissingColumn(String),
+    /// An extra column that looks like allergy data but feeds no allergen.
+    UnmappedAllergenColumn(String),
+    /// A categorical value outside its allowed set, with the file lines it
+    /// appears on.
+    DisallowedValue { column: String, value: String, lines: Vec<u64> },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::MissingColumn(column) => write!(f, "missing column {}", column),
+            Violation::UnmappedAllergenColumn(column) => {
+                write!(f, "column {} looks like allergy data but is not part of the schema", column)
+            }
+            Violation::DisallowedValue { column, value, lines } => {
+                let lines: Vec<String> = lines.iter().map(u64::to_string).collect();
+                write!(f, "{} has disallowed value '{}' on line(s) {}", column, value, lines.join(", "))
+            }
+        }
+    }
+}
+
+/// Whether an unmapped header names an allergy, e.g. `macadamia_alg_start`,
+/// `coconut` or `sesame_allergy`.
+fn looks_like_allergen(header: &str) -> bool {
+    let header = header.to_lowercase();
+    header.contains("_alg_")
+        || header.contains("allerg")
+        || ALLERGENS.iter().any(|allergen| header.contains(&allergen.to_lowercase()))
+        || header.split(|c: char| !c.is_ascii_alphanumeric()).any(|word| word.ends_with("nut"))
+}
+
+/// Every violation in a CSV, in column order and then by value.
+pub fn validate(reader: impl io::Read, allowed: &AllowedValues) -> Result<Vec<Violation>, AllergyNetError> {
+    let mut rdr = ReaderBuilder::new().from_reader(reader);
+    let headers = rdr.headers()?.clone();
+    let has = |column: &str| headers.iter().any(|h| h == column);
+    let mut violations: Vec<Violation> = RECORD_COLUMNS
+        .iter()
+        .copied()
+        .chain(allowed.columns.keys().map(String::as_str).filter(|c| !RECORD_COLUMNS.contains(c)))
+        .filter(|column| !has(column))
+        .map(|column| Violation::MissingColumn(column.to_string()))
+        .collect();
+    violations.extend(
+        headers
+            .iter()
+            .filter(|h| !RECORD_COLUMNS.contains(h) && looks_like_allergen(h))
+            .map(|h| Violation::UnmappedAllergenColumn(h.to_string())),
+    );
+
+    let checked: Vec<(usize, &BTreeSet<String>)> = headers
+        .iter()
+        .enumerate()
+        .filter_map(|(i, h)| allowed.columns.get(h).map(|values| (i, values)))
+        .collect();
+    let mut disallowed: BTreeMap<(usize, String), Vec<u64>> = BTreeMap::new();
+    for row in rdr.records() {
+        let row = row?;
+        let line = row.position().map_or(0, |p| p.line());
+        for &(i, values) in &checked {
+            let value = row.get(i).unwrap_or_default();
+            if !values.contains(value) {
+                disallowed.entry((i, value.to_string())).or_default().push(line);
+            }
+        }
+    }
+    violations.extend(disallowed.into_iter().map(|((i, value), lines)| Violation::DisallowedValue {
+        column: headers[i].to_string(),
+        value,
+        lines,
+    }));
+    Ok(violations)
+}
+
+/// The full violation report used as the `--strict` failure message.
+pub fn report(violations: &[Violation]) -> String {
+    let mut report = format!("{} schema violation(s)", violations.len());
+    for violation in violations {
+        report.push_str("\n  ");
+        report.push_str(&violation.to_string());
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let fixture = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        assert_eq!(validate(fixture.as_slice(), &AllowedValues::default()).unwrap(), vec![]);
+
+        let mut allowed = AllowedValues::default();
+        allowed.columns.insert("clinic".to_string(), BTreeSet::from(["A".to_string()]));
+        let csv = "subject_id,gender_factor,race_factor,macadamia_alg_start,nutrition_score\n\
+                   1,S0 - Male,R0 - White,,\n\
+                   2,M,R9,,\n\
+                   3,M,R0 - White,,\n";
+        let violations = validate(csv.as_bytes(), &allowed).unwrap();
+        assert_eq!(violations.iter().filter(|v| matches!(v, Violation::MissingColumn(_))).count(), 25);
+        assert!(violations.contains(&Violation::MissingColumn("clinic".to_string())));
+        assert!(violations.contains(&Violation::UnmappedAllergenColumn("macadamia_alg_start".to_string())));
+        assert!(!violations.contains(&Violation::UnmappedAllergenColumn("nutrition_score".to_string())));
+        let disallowed: Vec<String> = violations[violations.len() - 2..].iter().map(Violation::to_string).collect();
+        assert_eq!(
+            disallowed,
+            vec![
+                "gender_factor has disallowed value 'M' on line(s) 3, 4",
+                "race_factor has disallowed value 'R9' on line(s) 3",
+            ]
+        );
+        assert!(report(&violations).starts_with("28 schema violation(s)\n  missing column birth_year\n"));
+    }
+}
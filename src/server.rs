@@ -0,0 +1,419 @@
+//! HTTP API for driving analyses from a dashboard (`serve` subcommand).
+//!
+//! - `POST /cohorts` with a CSV body builds a graph and returns its id
+//! - `GET /cohorts/{id}/metrics?name=degree&stratify_by=race,payer`
+//!   returns the same result objects as `--format ndjson`, as a JSON array,
+//!   with subject ids de-identified
+//! - `GET /cohorts/{id}/graph.json` returns the de-identified node-link
+//!   graph
+//! - `GET /metrics` exposes request counts, analysis durations, stored
+//!   graph sizes and cohort lookup hits in the Prometheus text format
+//!
+//! Cohorts are kept in memory up to `Limits::max_cohorts`, evicting the
+//! least recently used, and uploads are capped at `Limits::max_upload_bytes`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::{DefaultBodyLimit, MatchedPath, Path, Query, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use log::info;
+use petgraph::graph::DiGraph;
+use serde::Deserialize;
+
+use crate::privacy::node_link_export;
+use crate::strata::{apply_age_bins, Grouping, DEFAULT_DIMENSIONS};
+use crate::{
+    calculate_metric, check_grouping_columns, create_graph, read_csv_from_reader, EdgeWeight, Metric, NodeType,
+    Settings,
+};
+
+type ApiError = (StatusCode, String);
+
+/// Bounds on the memory the server holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Cohorts kept at once; storing one more evicts the least recently
+    /// used.
+    pub max_cohorts: usize,
+    /// Largest CSV body accepted by `POST /cohorts`; larger uploads get
+    /// `413 Payload Too Large`.
+    pub max_upload_bytes: usize,
+}
+
+struct AppState {
+    settings: Settings,
+    next_id: AtomicU64,
+    cohorts: Mutex<CohortStore>,
+    metrics: Metrics,
+}
+
+/// Uploaded cohorts, least recently used evicted first.
+struct CohortStore {
+    capacity: usize,
+    /// Each cohort with when it was last stored or looked up.
+    cohorts: HashMap<u64, (Arc<Cohort>, u64)>,
+    clock: u64,
+}
+
+impl CohortStore {
+    fn new(capacity: usize) -> Self {
+        CohortStore { capacity: capacity.max(1), cohorts: HashMap::new(), clock: 0 }
+    }
+
+    /// Stores `cohort` under `id`, returning the id evicted to make room.
+    fn insert(&mut self, id: u64, cohort: Arc<Cohort>) -> Option<u64> {
+        let evicted = if self.cohorts.len() >= self.capacity {
+            let (&oldest, _) = self.cohorts.iter().min_by_key(|(_, (_, used))| *used)?;
+            self.cohorts.remove(&oldest);
+            Some(oldest)
+        } else {
+            None
+        };
+        self.clock += 1;
+        self.cohorts.insert(id, (cohort, self.clock));
+        evicted
+    }
+
+    fn get(&mut self, id: u64) -> Option<Arc<Cohort>> {
+        self.clock += 1;
+        let (cohort, used) = self.cohorts.get_mut(&id)?;
+        *used = self.clock;
+        Some(cohort.clone())
+    }
+}
+
+/// Upper bounds, in seconds, of the analysis duration histogram buckets.
+const DURATION_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 30.0];
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations at or below each of `DURATION_BUCKETS`.
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        self.buckets.resize(DURATION_BUCKETS.len(), 0);
+        for (bucket, &bound) in self.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Writes the `HELP` and `TYPE` lines of a metric and returns its name.
+fn metric_header<'a>(out: &mut String, name: &'a str, kind: &str, help: &str) -> &'a str {
+    writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind).unwrap();
+    name
+}
+
+#[derive(Default)]
+struct Metrics {
+    /// Requests by method, route template and status code.
+    requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+    /// Durations by stage: `build` for uploads, `metrics` for queries.
+    analysis_seconds: Mutex<BTreeMap<&'static str, Histogram>>,
+    cohort_hits: AtomicU64,
+    cohort_misses: AtomicU64,
+    cohort_evictions: AtomicU64,
+}
+
+impl Metrics {
+    fn observe_analysis(&self, stage: &'static str, elapsed: Duration) {
+        self.analysis_seconds.lock().unwrap().entry(stage).or_default().observe(elapsed.as_secs_f64());
+    }
+
+    /// The Prometheus text exposition of these metrics and of the graphs
+    /// in `cohorts`.
+    fn render(&self, cohorts: &CohortStore) -> String {
+        let cohorts = &cohorts.cohorts;
+        let mut out = String::new();
+
+        let name = metric_header(&mut out, "allergynet_http_requests_total", "counter", "HTTP requests handled.");
+        for ((method, route, status), count) in self.requests.lock().unwrap().iter() {
+            writeln!(out, "{}{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}", name, method, route, status, count)
+                .unwrap();
+        }
+
+        let name = metric_header(
+            &mut out,
+            "allergynet_analysis_duration_seconds",
+            "histogram",
+            "Time spent building graphs and computing metrics.",
+        );
+        for (stage, histogram) in self.analysis_seconds.lock().unwrap().iter() {
+            for (bound, count) in DURATION_BUCKETS.iter().zip(&histogram.buckets) {
+                writeln!(out, "{}_bucket{{stage=\"{}\",le=\"{}\"}} {}", name, stage, bound, count).unwrap();
+            }
+            writeln!(out, "{}_bucket{{stage=\"{}\",le=\"+Inf\"}} {}", name, stage, histogram.count).unwrap();
+            writeln!(out, "{}_sum{{stage=\"{}\"}} {}", name, stage, histogram.sum).unwrap();
+            writeln!(out, "{}_count{{stage=\"{}\"}} {}", name, stage, histogram.count).unwrap();
+        }
+
+        let name = metric_header(&mut out, "allergynet_cohorts", "gauge", "Cohorts held in memory.");
+        writeln!(out, "{} {}", name, cohorts.len()).unwrap();
+        let mut ids: Vec<_> = cohorts.keys().collect();
+        ids.sort();
+        let name = metric_header(&mut out, "allergynet_graph_nodes", "gauge", "Nodes in each stored cohort graph.");
+        for id in &ids {
+            writeln!(out, "{}{{cohort=\"{}\"}} {}", name, id, cohorts[id].0.graph.node_count()).unwrap();
+        }
+        let name = metric_header(&mut out, "allergynet_graph_edges", "gauge", "Edges in each stored cohort graph.");
+        for id in &ids {
+            writeln!(out, "{}{{cohort=\"{}\"}} {}", name, id, cohorts[id].0.graph.edge_count()).unwrap();
+        }
+
+        let name = metric_header(
+            &mut out,
+            "allergynet_cohort_cache_hits_total",
+            "counter",
+            "Cohort lookups that found a stored graph.",
+        );
+        writeln!(out, "{} {}", name, self.cohort_hits.load(Ordering::Relaxed)).unwrap();
+        let name = metric_header(
+            &mut out,
+            "allergynet_cohort_cache_misses_total",
+            "counter",
+            "Cohort lookups for an unknown id.",
+        );
+        writeln!(out, "{} {}", name, self.cohort_misses.load(Ordering::Relaxed)).unwrap();
+        let name = metric_header(
+            &mut out,
+            "allergynet_cohort_evictions_total",
+            "counter",
+            "Cohorts evicted to stay within --max-cohorts.",
+        );
+        writeln!(out, "{} {}", name, self.cohort_evictions.load(Ordering::Relaxed)).unwrap();
+        out
+    }
+}
+
+struct Cohort {
+    graph: DiGraph<NodeType, EdgeWeight>,
+    /// Extra columns present in the upload, for validating `stratify_by`.
+    columns: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MetricsQuery {
+    name: String,
+    stratify_by: Option<String>,
+}
+
+fn bad_request(message: impl ToString) -> ApiError {
+    (StatusCode::BAD_REQUEST, message.to_string())
+}
+
+/// Runs CPU-bound `work` on tokio's blocking pool, so building graphs and
+/// computing metrics doesn't stall the requests sharing a worker thread.
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, ApiError> + Send + 'static,
+) -> Result<T, ApiError> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|error| (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?
+}
+
+/// Serves the API on `addr` until the process is stopped.
+pub fn serve(addr: &str, limits: Limits, settings: Settings) -> Result<(), Box<dyn Error>> {
+    let state = Arc::new(AppState {
+        settings,
+        next_id: AtomicU64::new(1),
+        cohorts: Mutex::new(CohortStore::new(limits.max_cohorts)),
+        metrics: Metrics::default(),
+    });
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        info!("Listening on {}", listener.local_addr()?);
+        axum::serve(listener, router(state, limits)).await?;
+        Ok(())
+    })
+}
+
+fn router(state: Arc<AppState>, limits: Limits) -> Router {
+    Router::new()
+        .route("/cohorts", post(upload_cohort).layer(DefaultBodyLimit::max(limits.max_upload_bytes)))
+        .route("/cohorts/{id}/metrics", get(cohort_metrics))
+        .route("/cohorts/{id}/graph.json", get(cohort_graph))
+        .route("/metrics", get(metrics))
+        .route_layer(middleware::from_fn_with_state(state.clone(), count_requests))
+        .with_state(state)
+}
+
+async fn count_requests(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = request.extensions().get::<MatchedPath>().map_or("", MatchedPath::as_str).to_string();
+    let response = next.run(request).await;
+    let key = (method, route, response.status().as_u16());
+    *state.metrics.requests.lock().unwrap().entry(key).or_default() += 1;
+    response
+}
+
+async fn metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let body = state.metrics.render(&state.cohorts.lock().unwrap());
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+async fn upload_cohort(
+    State(state): State<Arc<AppState>>,
+    body: String,
+) -> Result<(StatusCode, Json<serde_json::Value>), ApiError> {
+    let started = Instant::now();
+    let worker = state.clone();
+    let (graph, columns) = blocking(move || {
+        let mut records = read_csv_from_reader(body.as_bytes()).map_err(bad_request)?;
+        worker.settings.ingest.apply(&mut records).map_err(bad_request)?;
+        let columns: Vec<String> = records.first().map(|r| r.extra.keys().cloned().collect()).unwrap_or_default();
+        Ok((create_graph(records, &worker.settings.graph), columns))
+    })
+    .await?;
+    state.metrics.observe_analysis("build", started.elapsed());
+    let id = state.next_id.fetch_add(1, Ordering::Relaxed);
+    let response = serde_json::json!({
+        "id": id,
+        "nodes": graph.node_count(),
+        "edges": graph.edge_count(),
+    });
+    info!("Stored cohort {} ({} nodes)", id, graph.node_count());
+    if let Some(evicted) = state.cohorts.lock().unwrap().insert(id, Arc::new(Cohort { graph, columns })) {
+        state.metrics.cohort_evictions.fetch_add(1, Ordering::Relaxed);
+        info!("Evicted cohort {}", evicted);
+    }
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+fn find_cohort(state: &AppState, id: u64) -> Result<Arc<Cohort>, ApiError> {
+    let cohort = state.cohorts.lock().unwrap().get(id);
+    let counter = if cohort.is_some() { &state.metrics.cohort_hits } else { &state.metrics.cohort_misses };
+    counter.fetch_add(1, Ordering::Relaxed);
+    cohort.ok_or_else(|| (StatusCode::NOT_FOUND, format!("no cohort {}", id)))
+}
+
+async fn cohort_metrics(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+    Query(query): Query<MetricsQuery>,
+) -> Result<Json<Vec<serde_json::Value>>, ApiError> {
+    let cohort = find_cohort(&state, id)?;
+    if query.name != "degree" {
+        return Err(bad_request(format!("unknown metric '{}'", query.name)));
+    }
+    let mut groupings = query
+        .stratify_by
+        .as_deref()
+        .unwrap_or(DEFAULT_DIMENSIONS)
+        .split(',')
+        .map(str::parse)
+        .collect::<Result<Vec<Grouping>, _>>()
+        .map_err(bad_request)?;
+    apply_age_bins(&mut groupings, &state.settings.age_bins);
+    check_grouping_columns(&cohort.columns, &groupings).map_err(bad_request)?;
+    let started = Instant::now();
+    let worker = state.clone();
+    let results = blocking(move || {
+        let (report, export) = (&worker.settings.report, &worker.settings.export);
+        let mut results = calculate_metric(&cohort.graph, &groupings, Metric::Degree, report).map_err(bad_request)?;
+        let (ids, _) = export.ids(&cohort.graph).map_err(bad_request)?;
+        results.deidentify(&ids, export.ids);
+        Ok(results.json_rows(report))
+    })
+    .await?;
+    state.metrics.observe_analysis("metrics", started.elapsed());
+    Ok(Json(results))
+}
+
+async fn cohort_graph(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<u64>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let cohort = find_cohort(&state, id)?;
+    let json = blocking(move || {
+        node_link_export(&cohort.graph, state.settings.graph.unit, &state.settings.export).map_err(bad_request)
+    })
+    .await?;
+    Ok(Json(json))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{read_csv, GraphOptions};
+
+    #[test]
+    fn test_metrics_exposition() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let graph = create_graph(read_csv(path).unwrap(), &GraphOptions::default());
+        let mut cohorts = CohortStore::new(8);
+        cohorts.insert(1, Arc::new(Cohort { graph, columns: Vec::new() }));
+        let metrics = Metrics::default();
+        metrics.requests.lock().unwrap().insert(("GET".into(), "/cohorts/{id}/metrics".into(), 200), 3);
+        metrics.observe_analysis("metrics", Duration::from_millis(20));
+        metrics.cohort_hits.fetch_add(3, Ordering::Relaxed);
+
+        let text = metrics.render(&cohorts);
+        assert!(text.contains(
+            "allergynet_http_requests_total{method=\"GET\",route=\"/cohorts/{id}/metrics\",status=\"200\"} 3\n"
+        ));
+        assert!(text.contains("allergynet_analysis_duration_seconds_bucket{stage=\"metrics\",le=\"0.01\"} 0\n"));
+        assert!(text.contains("allergynet_analysis_duration_seconds_bucket{stage=\"metrics\",le=\"0.05\"} 1\n"));
+        assert!(text.contains("allergynet_analysis_duration_seconds_count{stage=\"metrics\"} 1\n"));
+        assert!(text.contains("allergynet_graph_edges{cohort=\"1\"} 10\n"));
+        assert!(text.contains("allergynet_cohort_cache_hits_total 3\n"));
+        assert!(text.contains("# TYPE allergynet_cohorts gauge\nallergynet_cohorts 1\n"));
+        assert!(text.contains("allergynet_cohort_evictions_total 0\n"));
+    }
+
+    #[test]
+    fn test_metrics_deidentify_subject_ids() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let state = Arc::new(AppState {
+            settings: Settings::default(),
+            next_id: AtomicU64::new(1),
+            cohorts: Mutex::new(CohortStore::new(8)),
+            metrics: Metrics::default(),
+        });
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let body = std::fs::read_to_string(path).unwrap();
+        let (status, Json(created)) = runtime.block_on(upload_cohort(State(state.clone()), body)).unwrap();
+        assert_eq!((status, created["id"].as_u64(), created["nodes"].as_u64()), (StatusCode::CREATED, Some(1), Some(14)));
+        let query = MetricsQuery { name: "degree".to_string(), stratify_by: Some("payer".to_string()) };
+        let Json(results) = runtime.block_on(cohort_metrics(State(state), Path(1), Query(query))).unwrap();
+        let nodes: Vec<&serde_json::Value> = results.iter().filter(|row| row["type"] == "node").collect();
+        assert_eq!(nodes.len(), 5);
+        for node in nodes {
+            assert!(["1", "2", "3", "4", "5"].contains(&node["id"].as_str().unwrap()), "{}", node);
+            assert!(node["node"].is_null());
+        }
+    }
+
+    #[test]
+    fn test_cohort_store_evicts_least_recently_used() {
+        let cohort = || Arc::new(Cohort { graph: DiGraph::new(), columns: Vec::new() });
+        let mut store = CohortStore::new(2);
+        assert_eq!(store.insert(1, cohort()), None);
+        assert_eq!(store.insert(2, cohort()), None);
+        // Looking 1 up makes 2 the least recently used
+        assert!(store.get(1).is_some());
+        assert_eq!(store.insert(3, cohort()), Some(2));
+        assert!(store.get(2).is_none());
+        assert_eq!(store.insert(4, cohort()), Some(1));
+        let mut ids: Vec<u64> = store.cohorts.keys().copied().collect();
+        ids.sort();
+        assert_eq!(ids, [3, 4]);
+    }
+}
@@ -0,0 +1,136 @@
+//! Descriptive statistics of the cohort, alongside the network metrics.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Write;
+
+use rand::rngs::StdRng;
+use rand::Rng;
+use serde::Serialize;
+
+use crate::disclosure::{suppress, Cell, Suppression};
+use crate::strata::Grouping;
+use crate::{Individual, ReportOptions};
+
+pub mod association;
+pub mod comparison;
+pub mod polysensitization;
+pub mod prevalence;
+pub mod progression;
+pub mod regression;
+pub mod resolution;
+pub mod survival;
+
+/// Grouping and group of the rows covering every individual.
+pub const OVERALL: &str = "overall";
+
+/// Standard normal quantile for a two-sided 95% interval.
+pub const Z_95: f64 = 1.959_963_984_540_054;
+
+/// Wilson score interval for `successes` out of `trials`, or `None` when
+/// there are no trials. Unlike the normal approximation it stays within
+/// `[0, 1]` and behaves at proportions near 0 or 1 and in small groups.
+pub fn wilson_interval(successes: usize, trials: usize, z: f64) -> Option<(f64, f64)> {
+    if trials == 0 {
+        return None;
+    }
+    let n = trials as f64;
+    let p = successes as f64 / n;
+    let z2 = z * z;
+    let centre = (p + z2 / (2.0 * n)) / (1.0 + z2 / n);
+    let half = z / (1.0 + z2 / n) * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt();
+    Some(((centre - half).max(0.0), (centre + half).min(1.0)))
+}
+
+/// The `q`th quantile (0 to 1) of ascending `sorted` values, linearly
+/// interpolated between the two nearest ranks, or `None` if there are none.
+pub fn quantile(sorted: &[f64], q: f64) -> Option<f64> {
+    let last = sorted.len().checked_sub(1)?;
+    let rank = q * last as f64;
+    let (below, above) = (rank.floor() as usize, rank.ceil() as usize);
+    Some(sorted[below] + (rank - below as f64) * (sorted[above] - sorted[below]))
+}
+
+/// 95% percentile bootstrap interval for the mean of `values`, from
+/// `resamples` resamples with replacement drawn from `rng`, or `None`
+/// without values or resamples.
+pub fn bootstrap_interval(values: &[f64], resamples: usize, rng: &mut StdRng) -> Option<(f64, f64)> {
+    if values.is_empty() || resamples == 0 {
+        return None;
+    }
+    let mut means: Vec<f64> = (0..resamples)
+        .map(|_| (0..values.len()).map(|_| values[rng.gen_range(0..values.len())]).sum::<f64>() / values.len() as f64)
+        .collect();
+    means.sort_by(f64::total_cmp);
+    Some((quantile(&means, 0.025)?, quantile(&means, 0.975)?))
+}
+
+/// `None` for the overall rows, then each of `groupings`.
+pub(crate) fn with_overall(groupings: &[Grouping]) -> impl Iterator<Item = Option<&Grouping>> {
+    std::iter::once(None).chain(groupings.iter().map(Some))
+}
+
+/// The individual's group in `grouping`, or `overall`.
+pub(crate) fn group_of(grouping: Option<&Grouping>, individual: &Individual) -> Option<String> {
+    grouping.map_or(Some(OVERALL.to_string()), |grouping| grouping.value_of(individual))
+}
+
+pub(crate) fn label(grouping: Option<&Grouping>) -> String {
+    grouping.map_or(OVERALL.to_string(), Grouping::label)
+}
+
+/// Cells for a `group -> (n, count)` table, with small cells judged on the
+/// count and flagged, masked or merged per `options`. Each cell totals the
+/// group's `n`, so merging pools both. The overall row has nothing to pool
+/// with, so is masked instead.
+pub(crate) fn count_cells(
+    groups: BTreeMap<String, (usize, usize)>,
+    grouping: Option<&Grouping>,
+    options: &ReportOptions,
+) -> Vec<Cell> {
+    let cells = groups.into_iter().map(|(group, (n, count))| Cell::new(group, n as f64, count)).collect();
+    let mode = match (grouping, options.suppression) {
+        (None, Suppression::Merge) => Suppression::Mask,
+        (_, mode) => mode,
+    };
+    suppress(cells, options.small_cell_threshold, mode)
+}
+
+/// Writes the rows as CSV with a header, leaving `None` values empty.
+pub fn write_csv<T: Serialize>(rows: &[T], out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(out);
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_wilson_interval() {
+        let (lower, upper) = wilson_interval(3, 5, Z_95).unwrap();
+        assert!((lower - 0.2307).abs() < 1e-4 && (upper - 0.8824).abs() < 1e-4);
+        let (lower, upper) = wilson_interval(0, 10, Z_95).unwrap();
+        assert_eq!(lower, 0.0);
+        assert!((upper - 0.2775).abs() < 1e-4);
+        assert_eq!(wilson_interval(0, 0, Z_95), None);
+    }
+
+    #[test]
+    fn test_bootstrap_interval() {
+        assert_eq!(quantile(&[0.0, 1.0, 2.0, 3.0, 4.0], 0.95), Some(3.8));
+        assert_eq!(quantile(&[], 0.5), None);
+        let values = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0];
+        let mut rng = StdRng::seed_from_u64(7);
+        let (lower, upper) = bootstrap_interval(&values, 500, &mut rng).unwrap();
+        assert!(lower < 4.5 && 4.5 < upper && lower >= 1.0 && upper <= 8.0, "{} {}", lower, upper);
+        assert_eq!(bootstrap_interval(&[3.0; 4], 100, &mut rng), Some((3.0, 3.0)));
+        assert_eq!(bootstrap_interval(&[], 100, &mut rng), None);
+        assert_eq!(bootstrap_interval(&values, 0, &mut rng), None);
+    }
+}
@@ -0,0 +1,345 @@
+//! Co-occurrence of pairs of allergies (`association` subcommand): for each
+//! ordered pair, the 2×2 table of the individuals who have either, with the
+//! odds ratio, the relative risk of the second allergy given the first, and
+//! chi-square and Fisher exact p-values for independence.
+//!
+//! When a cell of the table is 0 the odds ratio and relative risk are
+//! computed with 0.5 added to every cell (the Haldane–Anscombe correction),
+//! so they stay finite; the chi-square and Fisher tests use the table as
+//! it is.
+
+use std::error::Error;
+use std::io::Write;
+
+use clap::ValueEnum;
+use petgraph::graph::DiGraph;
+use petgraph::Direction;
+use serde::Serialize;
+
+use super::Z_95;
+use crate::disclosure::Suppression;
+use crate::{EdgeWeight, NodeType, ReportOptions};
+
+/// Individuals by whether they have each of two allergies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Table {
+    pub both: usize,
+    /// The first allergy but not the second.
+    pub first_only: usize,
+    /// The second allergy but not the first.
+    pub second_only: usize,
+    pub neither: usize,
+}
+
+impl Table {
+    fn n(&self) -> usize {
+        self.both + self.first_only + self.second_only + self.neither
+    }
+
+    /// The cells as `f64`, corrected if any is 0.
+    fn corrected(&self) -> [f64; 4] {
+        let cells = [self.both, self.first_only, self.second_only, self.neither];
+        let correction = if cells.contains(&0) { 0.5 } else { 0.0 };
+        cells.map(|cell| cell as f64 + correction)
+    }
+
+    /// Whether everyone, or no one, has one of the allergies, leaving
+    /// nothing to compare.
+    fn degenerate(&self) -> bool {
+        let (a, b, c, d) = (self.both, self.first_only, self.second_only, self.neither);
+        a + b == 0 || c + d == 0 || a + c == 0 || b + d == 0
+    }
+
+    /// Odds ratio with its 95% Woolf (log) interval.
+    pub fn odds_ratio(&self) -> Option<(f64, f64, f64)> {
+        if self.degenerate() {
+            return None;
+        }
+        let [a, b, c, d] = self.corrected();
+        let ratio = a * d / (b * c);
+        let half = Z_95 * (1.0 / a + 1.0 / b + 1.0 / c + 1.0 / d).sqrt();
+        Some((ratio, (ratio.ln() - half).exp(), (ratio.ln() + half).exp()))
+    }
+
+    /// Risk of the second allergy among those with the first over the risk
+    /// among those without it, with its 95% Katz (log) interval.
+    pub fn relative_risk(&self) -> Option<(f64, f64, f64)> {
+        if self.degenerate() {
+            return None;
+        }
+        let [a, b, c, d] = self.corrected();
+        let ratio = (a / (a + b)) / (c / (c + d));
+        let half = Z_95 * (1.0 / a - 1.0 / (a + b) + 1.0 / c - 1.0 / (c + d)).sqrt();
+        Some((ratio, (ratio.ln() - half).exp(), (ratio.ln() + half).exp()))
+    }
+
+    /// Pearson's chi-square statistic, without continuity correction, and
+    /// its p-value on 1 degree of freedom.
+    pub fn chi_square(&self) -> Option<(f64, f64)> {
+        if self.degenerate() {
+            return None;
+        }
+        let (a, b, c, d) = (self.both as f64, self.first_only as f64, self.second_only as f64, self.neither as f64);
+        let statistic = self.n() as f64 * (a * d - b * c).powi(2) / ((a + b) * (c + d) * (a + c) * (b + d));
+        Some((statistic, erfc((statistic / 2.0).sqrt())))
+    }
+
+    /// Two-sided Fisher exact p-value: the probability, given the margins,
+    /// of a table no more likely than this one.
+    pub fn fisher_p(&self) -> f64 {
+        let n = self.n();
+        let (first, second) = (self.both + self.first_only, self.both + self.second_only);
+        let ln_factorial: Vec<f64> = std::iter::once(0.0)
+            .chain((1..=n).scan(0.0, |sum, i| {
+                *sum += (i as f64).ln();
+                Some(*sum)
+            }))
+            .collect();
+        let ln_choose = |n: usize, k: usize| ln_factorial[n] - ln_factorial[k] - ln_factorial[n - k];
+        let probability = |both: usize| {
+            (ln_choose(second, both) + ln_choose(n - second, first - both) - ln_choose(n, first)).exp()
+        };
+        let observed = probability(self.both);
+        let range = (first + second).saturating_sub(n)..=first.min(second);
+        // Allow for rounding between tables as likely as the observed one
+        let p: f64 = range.map(probability).filter(|&p| p <= observed * (1.0 + 1e-7)).sum();
+        p.min(1.0)
+    }
+}
+
+/// Complementary error function, to within 1.2e-7 (Numerical Recipes'
+/// Chebyshev fit).
+pub(crate) fn erfc(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.5 * x.abs());
+    let coefficients = [
+        -1.26551223, 1.00002368, 0.37409196, 0.09678418, -0.18628806, 0.27886807, -1.13520398, 1.48851587, -0.82215223,
+    ];
+    let series = coefficients.iter().rev().fold(0.17087277, |sum, &coefficient| coefficient + t * sum);
+    let value = t * (-x * x + series).exp();
+    if x >= 0.0 {
+        value
+    } else {
+        2.0 - value
+    }
+}
+
+/// Association of one ordered pair of allergies. The estimates are `None`
+/// when the pair is suppressed or the table leaves nothing to compare.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Association {
+    pub allergy: String,
+    pub other: String,
+    /// Individuals with both; `None` when suppressed, like the rest of the
+    /// table.
+    pub both: Option<usize>,
+    pub allergy_only: Option<usize>,
+    pub other_only: Option<usize>,
+    pub neither: Option<usize>,
+    pub odds_ratio: Option<f64>,
+    pub or_ci_lower: Option<f64>,
+    pub or_ci_upper: Option<f64>,
+    /// Risk of `other` with `allergy` over its risk without.
+    pub relative_risk: Option<f64>,
+    pub rr_ci_lower: Option<f64>,
+    pub rr_ci_upper: Option<f64>,
+    pub chi_square: Option<f64>,
+    pub chi_square_p: Option<f64>,
+    pub fisher_p: Option<f64>,
+    /// Fewer individuals with both than the small-cell threshold.
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+/// Association of every ordered pair of distinct allergens in `graph`, in
+/// graph order. Small cells are judged on the individuals with both
+/// allergies; under `--suppress mask` or `merge` their whole table and
+/// estimates are hidden, since pairs can't be pooled. Refused under
+/// differential privacy; see [`ReportOptions::noise`].
+pub fn association(graph: &DiGraph<NodeType, EdgeWeight>, options: &ReportOptions) -> Result<Vec<Association>, String> {
+    if options.noise.is_some() {
+        return Err("association can't be released under differential privacy".to_string());
+    }
+    let allergens: Vec<_> = graph
+        .node_indices()
+        .filter_map(|node| match &graph[node] {
+            NodeType::AllergenStatus(name) => Some((node, name.clone())),
+            NodeType::Individual(_) | NodeType::Demographic { .. } => None,
+        })
+        .collect();
+    // Which allergens each individual has
+    let has: Vec<Vec<bool>> = graph
+        .node_indices()
+        .filter(|&node| matches!(graph[node], NodeType::Individual(_)))
+        .map(|node| {
+            let allergies: Vec<_> = graph.neighbors_directed(node, Direction::Outgoing).collect();
+            allergens.iter().map(|(allergen, _)| allergies.contains(allergen)).collect()
+        })
+        .collect();
+
+    let mut rows = Vec::new();
+    for (i, (_, allergy)) in allergens.iter().enumerate() {
+        for (j, (_, other)) in allergens.iter().enumerate().filter(|&(j, _)| j != i) {
+            let mut table = Table { both: 0, first_only: 0, second_only: 0, neither: 0 };
+            for individual in &has {
+                match (individual[i], individual[j]) {
+                    (true, true) => table.both += 1,
+                    (true, false) => table.first_only += 1,
+                    (false, true) => table.second_only += 1,
+                    (false, false) => table.neither += 1,
+                }
+            }
+            let small_cell = table.both < options.small_cell_threshold;
+            let suppressed = small_cell && options.suppression != Suppression::Flag;
+            let shown = |value: Option<(f64, f64, f64)>| value.filter(|_| !suppressed);
+            let (odds_ratio, relative_risk) = (shown(table.odds_ratio()), shown(table.relative_risk()));
+            let chi_square = table.chi_square().filter(|_| !suppressed);
+            let count = |count: usize| (!suppressed).then_some(count);
+            rows.push(Association {
+                allergy: allergy.clone(),
+                other: other.clone(),
+                both: count(table.both),
+                allergy_only: count(table.first_only),
+                other_only: count(table.second_only),
+                neither: count(table.neither),
+                odds_ratio: odds_ratio.map(|(ratio, _, _)| ratio),
+                or_ci_lower: odds_ratio.map(|(_, lower, _)| lower),
+                or_ci_upper: odds_ratio.map(|(_, _, upper)| upper),
+                relative_risk: relative_risk.map(|(ratio, _, _)| ratio),
+                rr_ci_lower: relative_risk.map(|(_, lower, _)| lower),
+                rr_ci_upper: relative_risk.map(|(_, _, upper)| upper),
+                chi_square: chi_square.map(|(statistic, _)| statistic),
+                chi_square_p: chi_square.map(|(_, p)| p),
+                fisher_p: (!suppressed && !table.degenerate()).then(|| table.fisher_p()),
+                small_cell,
+                suppressed,
+            });
+        }
+    }
+    Ok(rows)
+}
+
+/// Statistic laid out by `write_matrix`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Statistic {
+    /// Individuals with both allergies
+    Both,
+    OddsRatio,
+    RelativeRisk,
+    ChiSquareP,
+    FisherP,
+}
+
+impl Statistic {
+    fn of(self, row: &Association) -> Option<f64> {
+        match self {
+            Statistic::Both => row.both.map(|both| both as f64),
+            Statistic::OddsRatio => row.odds_ratio,
+            Statistic::RelativeRisk => row.relative_risk,
+            Statistic::ChiSquareP => row.chi_square_p,
+            Statistic::FisherP => row.fisher_p,
+        }
+    }
+}
+
+/// `statistic` of `rows` as an allergy × allergy matrix: the allergies in
+/// the order of `rows`, and `statistic(row, column)` for each row of
+/// allergies. The diagonal and missing values are `None`.
+pub fn matrix(rows: &[Association], statistic: Statistic) -> (Vec<&str>, Vec<Vec<Option<f64>>>) {
+    let mut allergies: Vec<&str> = Vec::new();
+    for row in rows {
+        for name in [&row.allergy, &row.other] {
+            if !allergies.contains(&name.as_str()) {
+                allergies.push(name);
+            }
+        }
+    }
+    let cells = allergies
+        .iter()
+        .map(|&allergy| {
+            allergies
+                .iter()
+                .map(|&other| {
+                    rows.iter()
+                        .find(|row| row.allergy == allergy && row.other == other)
+                        .and_then(|row| statistic.of(row))
+                })
+                .collect()
+        })
+        .collect();
+    (allergies, cells)
+}
+
+/// Writes `statistic` of `rows` as an allergy × allergy CSV matrix (see
+/// `matrix`), with the diagonal and missing values left empty.
+pub fn write_matrix(rows: &[Association], statistic: Statistic, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let (allergies, cells) = matrix(rows, statistic);
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(std::iter::once("allergy").chain(allergies.iter().copied()))?;
+    for (allergy, row) in allergies.iter().zip(cells) {
+        let row = row.into_iter().map(|value| value.map_or_else(String::new, |value| value.to_string()));
+        writer.write_record(std::iter::once(allergy.to_string()).chain(row))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    #[test]
+    fn test_table_statistics() {
+        let table = Table { both: 10, first_only: 20, second_only: 30, neither: 40 };
+        let (ratio, lower, upper) = table.odds_ratio().unwrap();
+        assert!((ratio - 0.6667).abs() < 1e-4 && (lower - 0.2725).abs() < 1e-4 && (upper - 1.6309).abs() < 1e-4);
+        let (ratio, _, _) = table.relative_risk().unwrap();
+        assert!((ratio - 0.7778).abs() < 1e-4);
+        let (statistic, p) = table.chi_square().unwrap();
+        assert!((statistic - 0.7937).abs() < 1e-4 && (p - 0.3730).abs() < 1e-4);
+        assert!((table.fisher_p() - 0.5045).abs() < 1e-4);
+        assert!((erfc(-1.0) - 1.8427).abs() < 1e-4);
+        assert_eq!(Table { both: 0, first_only: 0, second_only: 3, neither: 2 }.odds_ratio(), None);
+    }
+
+    #[test]
+    fn test_association() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let options = ReportOptions { small_cell_threshold: 2, ..Default::default() };
+        let rows = association(&graph, &options).unwrap();
+        assert_eq!(rows.len(), 9 * 8);
+        // Peanut: 205650, 205651, 205654; Cashew: 205650, 205654
+        let row = rows.iter().find(|row| row.allergy == "Peanut" && row.other == "Cashew").unwrap();
+        assert_eq!((row.both, row.allergy_only, row.other_only, row.neither), (Some(2), Some(1), Some(0), Some(2)));
+        // Corrected for the empty cell: 2.5 * 2.5 / (1.5 * 0.5), and
+        // (2.5 / 4) / (0.5 / 3)
+        assert!((row.odds_ratio.unwrap() - 8.3333).abs() < 1e-4);
+        assert!((row.relative_risk.unwrap() - 3.75).abs() < 1e-4);
+        assert!((row.chi_square.unwrap() - 2.2222).abs() < 1e-4 && (row.chi_square_p.unwrap() - 0.1360).abs() < 1e-4);
+        assert!((row.fisher_p.unwrap() - 0.4).abs() < 1e-9);
+        assert!(!row.small_cell);
+        // No one has Almond
+        let almond = rows.iter().find(|row| row.allergy == "Almond").unwrap();
+        assert!(almond.small_cell && almond.odds_ratio.is_none() && almond.fisher_p.is_none());
+
+        let mut out = Vec::new();
+        write_matrix(&rows, Statistic::FisherP, &mut out).unwrap();
+        let matrix = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = matrix.lines().collect();
+        assert_eq!(lines.len(), 10);
+        assert_eq!(lines[0], "allergy,Peanut,Treenut,Walnut,Pecan,Pistachio,Almond,Brazil,Hazelnut,Cashew");
+        // Peanut's row: its diagonal and the allergies no one has are empty
+        let peanut: Vec<&str> = lines[1].split(',').collect();
+        assert_eq!(&peanut[..2], ["Peanut", ""]);
+        assert_eq!(&peanut[6..9], ["", "", ""]);
+        assert!((peanut[9].parse::<f64>().unwrap() - 0.4).abs() < 1e-9);
+        let (allergies, both) = super::matrix(&rows, Statistic::Both);
+        assert_eq!((allergies[8], both[0][8], both[0][0]), ("Cashew", Some(2.0), None));
+
+        let masked = ReportOptions { suppression: Suppression::Mask, ..options };
+        let rows = association(&graph, &masked).unwrap();
+        let row = rows.iter().find(|row| row.allergy == "Peanut" && row.other == "Treenut").unwrap();
+        assert!(row.suppressed && row.both.is_none() && row.fisher_p.is_none());
+    }
+}
@@ -0,0 +1,244 @@
+//! Side-by-side comparison of two cohorts of the same graph (`compare`
+//! subcommand): each allergy's prevalence, each pair's co-occurrence and
+//! each metric's average in cohort A and cohort B, with their difference
+//! and its p-value.
+//!
+//! Prevalence and co-occurrence are compared with the two-sided Fisher
+//! exact test of cohort × having the allergy (or pair). Centrality averages
+//! are compared with a permutation test: the p-value is the share of random
+//! reassignments of the individuals to the cohorts, keeping their sizes,
+//! whose difference is at least as large. The tests assume the cohorts
+//! don't overlap.
+
+use petgraph::graph::DiGraph;
+use petgraph::Direction;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::Serialize;
+
+use super::association::Table;
+use crate::cohort::IndividualFilter;
+use crate::disclosure::Suppression;
+use crate::graph::subgraph;
+use crate::{calculate_metric, EdgeWeight, Metric, NodeType, ReportOptions};
+
+/// One quantity in both cohorts. The values are `None` when the row is
+/// suppressed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Difference {
+    /// `prevalence`, `co-occurrence` or `centrality`.
+    pub comparison: String,
+    /// The allergy, the pair (`Peanut + Cashew`) or the metric.
+    pub measure: String,
+    /// Individuals with the allergy or pair in each cohort; `None` for
+    /// centrality.
+    pub cases_a: Option<usize>,
+    pub cases_b: Option<usize>,
+    /// Proportion or average in each cohort.
+    pub a: Option<f64>,
+    pub b: Option<f64>,
+    /// `a - b`.
+    pub difference: Option<f64>,
+    /// `fisher` or `permutation`.
+    pub test: String,
+    pub p_value: Option<f64>,
+    /// Fewer cases in either cohort than the small-cell threshold, or for
+    /// centrality, fewer individuals.
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ComparisonReport {
+    /// The filter expressions of the two cohorts.
+    pub group_a: String,
+    pub group_b: String,
+    /// Individuals in each cohort.
+    pub n_a: usize,
+    pub n_b: usize,
+    /// Individuals in both cohorts.
+    pub overlap: usize,
+    /// Prevalence rows in graph order, then co-occurrence pairs, then
+    /// each metric.
+    pub differences: Vec<Difference>,
+}
+
+/// The allergen names of `graph` in graph order, and which of them each
+/// individual has, with the individual's subject id.
+fn allergies(graph: &DiGraph<NodeType, EdgeWeight>) -> (Vec<String>, Vec<(String, Vec<bool>)>) {
+    let allergens: Vec<_> = graph
+        .node_indices()
+        .filter_map(|node| match &graph[node] {
+            NodeType::AllergenStatus(name) => Some((node, name.clone())),
+            NodeType::Individual(_) | NodeType::Demographic { .. } => None,
+        })
+        .collect();
+    let individuals = graph
+        .node_indices()
+        .filter_map(|node| match &graph[node] {
+            NodeType::Individual(individual) => {
+                let has: Vec<_> = graph.neighbors_directed(node, Direction::Outgoing).collect();
+                Some((individual.id.clone(), allergens.iter().map(|(allergen, _)| has.contains(allergen)).collect()))
+            }
+            NodeType::AllergenStatus(_) | NodeType::Demographic { .. } => None,
+        })
+        .collect();
+    (allergens.into_iter().map(|(_, name)| name).collect(), individuals)
+}
+
+/// Share of `permutations` reassignments of `a` and `b`'s values whose
+/// difference of means is at least the observed one, counting the
+/// observed assignment itself.
+fn permutation_p(a: &[f64], b: &[f64], permutations: usize, rng: &mut StdRng) -> f64 {
+    let mean = |values: &[f64]| values.iter().sum::<f64>() / values.len() as f64;
+    let observed = (mean(a) - mean(b)).abs();
+    let mut pooled: Vec<f64> = a.iter().chain(b).copied().collect();
+    let mut extreme = 0;
+    for _ in 0..permutations {
+        pooled.shuffle(rng);
+        let (first, second) = pooled.split_at(a.len());
+        // Allow for rounding in sums of the same values in another order
+        if (mean(first) - mean(second)).abs() >= observed - 1e-12 {
+            extreme += 1;
+        }
+    }
+    (extreme + 1) as f64 / (permutations + 1) as f64
+}
+
+/// Compares the individuals of `graph` selected by `group_a` with those
+/// selected by `group_b`. Each cohort's `metrics` are computed on its own
+/// subgraph. Small cells are flagged, or under `--suppress mask` or
+/// `merge` hidden, since rows can't be pooled. Refused under differential
+/// privacy; see [`ReportOptions::noise`].
+pub fn compare(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    group_a: &IndividualFilter,
+    group_b: &IndividualFilter,
+    metrics: &[Metric],
+    permutations: usize,
+    options: &ReportOptions,
+) -> Result<ComparisonReport, String> {
+    if options.noise.is_some() {
+        return Err("comparisons can't be released under differential privacy".to_string());
+    }
+    let (graph_a, graph_b) = (subgraph(graph, group_a), subgraph(graph, group_b));
+    let ((allergens, a), (_, b)) = (allergies(&graph_a), allergies(&graph_b));
+    for (filter, cohort) in [(group_a, &a), (group_b, &b)] {
+        if cohort.is_empty() {
+            return Err(format!("no individuals where {}", filter));
+        }
+    }
+    let overlap = a.iter().filter(|(id, _)| b.iter().any(|(other, _)| other == id)).count();
+    let (n_a, n_b) = (a.len(), b.len());
+    let hidden = |small_cell: bool| small_cell && options.suppression != Suppression::Flag;
+
+    let mut differences = Vec::new();
+    let mut counted = |comparison: &str, measure: String, has: &dyn Fn(&[bool]) -> bool| {
+        let cases_a = a.iter().filter(|(_, allergies)| has(allergies)).count();
+        let cases_b = b.iter().filter(|(_, allergies)| has(allergies)).count();
+        let small_cell = cases_a.min(cases_b) < options.small_cell_threshold;
+        let suppressed = hidden(small_cell);
+        let table = Table { both: cases_a, first_only: n_a - cases_a, second_only: cases_b, neither: n_b - cases_b };
+        let (p_a, p_b) = (cases_a as f64 / n_a as f64, cases_b as f64 / n_b as f64);
+        let shown = |value: f64| (!suppressed).then_some(value);
+        differences.push(Difference {
+            comparison: comparison.to_string(),
+            measure,
+            cases_a: (!suppressed).then_some(cases_a),
+            cases_b: (!suppressed).then_some(cases_b),
+            a: shown(p_a),
+            b: shown(p_b),
+            difference: shown(p_a - p_b),
+            test: "fisher".to_string(),
+            p_value: shown(table.fisher_p()),
+            small_cell,
+            suppressed,
+        });
+    };
+    for (i, allergy) in allergens.iter().enumerate() {
+        counted("prevalence", allergy.clone(), &|allergies| allergies[i]);
+    }
+    for (i, allergy) in allergens.iter().enumerate() {
+        for (j, other) in allergens.iter().enumerate().skip(i + 1) {
+            counted("co-occurrence", format!("{} + {}", allergy, other), &|allergies| allergies[i] && allergies[j]);
+        }
+    }
+
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    for &metric in metrics {
+        let values = |graph| -> Result<Vec<f64>, String> {
+            Ok(calculate_metric(graph, &[], metric, options)?.nodes.into_iter().map(|node| node.value).collect())
+        };
+        let (values_a, values_b) = (values(&graph_a)?, values(&graph_b)?);
+        let small_cell = values_a.len().min(values_b.len()) < options.small_cell_threshold;
+        let suppressed = hidden(small_cell);
+        let testable = !suppressed && !values_a.is_empty() && !values_b.is_empty();
+        let mean = |values: &[f64]| testable.then(|| values.iter().sum::<f64>() / values.len() as f64);
+        let (mean_a, mean_b) = (mean(&values_a), mean(&values_b));
+        differences.push(Difference {
+            comparison: "centrality".to_string(),
+            measure: metric.to_string(),
+            cases_a: None,
+            cases_b: None,
+            a: mean_a,
+            b: mean_b,
+            difference: mean_a.zip(mean_b).map(|(a, b)| a - b),
+            test: "permutation".to_string(),
+            p_value: testable.then(|| permutation_p(&values_a, &values_b, permutations, &mut rng)),
+            small_cell,
+            suppressed,
+        });
+    }
+    Ok(ComparisonReport {
+        group_a: group_a.to_string(),
+        group_b: group_b.to_string(),
+        n_a,
+        n_b,
+        overlap,
+        differences,
+    })
+}
+
+impl ComparisonReport {
+    /// Rows reported unsuppressed despite being small cells.
+    pub fn small_cells(&self) -> usize {
+        self.differences.iter().filter(|row| row.small_cell && !row.suppressed).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    #[test]
+    fn test_compare() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let options = ReportOptions { small_cell_threshold: 1, suppression: Suppression::Flag, ..Default::default() };
+        let (male, female) = ("gender=Male".parse().unwrap(), "gender=Female".parse().unwrap());
+        let report = compare(&graph, &male, &female, &[Metric::Degree], 200, &options).unwrap();
+        assert_eq!((report.n_a, report.n_b, report.overlap), (3, 2, 0));
+        // Nine allergens, 36 pairs and one metric
+        assert_eq!(report.differences.len(), 9 + 36 + 1);
+        // Peanut: 205650 and 205654 of the men, 205651 of the women
+        let peanut = &report.differences[0];
+        assert_eq!((peanut.measure.as_str(), peanut.cases_a, peanut.cases_b), ("Peanut", Some(2), Some(1)));
+        assert!((peanut.difference.unwrap() - (2.0 / 3.0 - 0.5)).abs() < 1e-12);
+        assert!((peanut.p_value.unwrap() - 1.0).abs() < 1e-9);
+        let pair = report.differences.iter().find(|row| row.measure == "Peanut + Cashew").unwrap();
+        assert_eq!((pair.comparison.as_str(), pair.cases_a, pair.cases_b), ("co-occurrence", Some(2), Some(0)));
+        assert!(pair.small_cell && !pair.suppressed);
+        // Degrees 2, 0, 4 against 1, 3
+        let degree = report.differences.last().unwrap();
+        assert_eq!((degree.a, degree.b), (Some(2.0), Some(2.0)));
+        assert_eq!(degree.p_value, Some(1.0));
+
+        let masked = ReportOptions { small_cell_threshold: 2, suppression: Suppression::Mask, ..options };
+        let report = compare(&graph, &male, &female, &[Metric::Degree], 200, &masked).unwrap();
+        assert!(report.differences[0].suppressed && report.differences[0].p_value.is_none());
+        assert!(!report.differences.last().unwrap().suppressed);
+        assert!(compare(&graph, &male, &"race=Martian".parse().unwrap(), &[], 10, &masked).is_err());
+    }
+}
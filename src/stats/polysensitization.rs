@@ -0,0 +1,257 @@
+//! Polysensitized individuals, those with at least `k` allergies: who they
+//! are, how they are spread across demographic groups, and which
+//! combinations of allergies they have (`polysensitization` subcommand).
+
+use std::collections::BTreeMap;
+
+use petgraph::graph::{DiGraph, NodeIndex};
+use petgraph::Direction;
+use serde::Serialize;
+
+use super::{count_cells, group_of, label, wilson_interval, with_overall, Z_95};
+use crate::disclosure::{suppress, Cell, Suppression};
+use crate::strata::Grouping;
+use crate::{EdgeWeight, Individual, NodeType, ReportOptions};
+
+/// Polysensitized individuals in one group. The counts and estimates are
+/// `None` when the group is suppressed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Composition {
+    pub grouping: String,
+    pub group: String,
+    /// Individuals in the group with at least `min_allergies` allergies.
+    pub polysensitized: Option<usize>,
+    /// Individuals in the group.
+    pub n: Option<usize>,
+    pub proportion: Option<f64>,
+    /// Bounds of the 95% Wilson interval for `proportion`.
+    pub ci_lower: Option<f64>,
+    pub ci_upper: Option<f64>,
+    /// Share of all polysensitized individuals who are in the group.
+    pub share: Option<f64>,
+    /// Fewer polysensitized individuals than the small-cell threshold.
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+/// Polysensitized individuals with exactly one combination of allergies.
+/// `individuals` and `share` are `None` when the combination is suppressed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Combination {
+    /// The allergies in graph order, e.g. `Peanut + Cashew`.
+    pub allergies: String,
+    pub size: usize,
+    pub individuals: Option<usize>,
+    /// Share of all polysensitized individuals with the combination.
+    pub share: Option<f64>,
+    /// Fewer individuals than the small-cell threshold.
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+/// One polysensitized individual.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Member {
+    pub subject_id: String,
+    pub allergies: usize,
+    pub combination: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PolysensitizationReport {
+    pub min_allergies: usize,
+    pub composition: Vec<Composition>,
+    /// The most common combinations, most individuals first.
+    pub combinations: Vec<Combination>,
+}
+
+impl PolysensitizationReport {
+    /// Groups and combinations reported unsuppressed despite being small
+    /// cells.
+    pub fn small_cells(&self) -> usize {
+        let groups = self.composition.iter().filter(|row| row.small_cell && !row.suppressed).count();
+        groups + self.combinations.iter().filter(|row| row.small_cell && !row.suppressed).count()
+    }
+}
+
+/// Individuals with at least `min_allergies` edges to allergens, in graph
+/// order, each with its allergies in graph order.
+pub fn polysensitized(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    min_allergies: usize,
+) -> Vec<(&Individual, Vec<&str>)> {
+    let allergens: BTreeMap<NodeIndex, &str> = graph
+        .node_indices()
+        .filter_map(|node| match &graph[node] {
+            NodeType::AllergenStatus(name) => Some((node, name.as_str())),
+            _ => None,
+        })
+        .collect();
+    graph
+        .node_indices()
+        .filter_map(|node| {
+            let NodeType::Individual(individual) = &graph[node] else { return None };
+            let targets: Vec<NodeIndex> = graph.neighbors_directed(node, Direction::Outgoing).collect();
+            let allergies: Vec<&str> =
+                allergens.iter().filter(|(allergen, _)| targets.contains(allergen)).map(|(_, name)| *name).collect();
+            (allergies.len() >= min_allergies).then_some((individual, allergies))
+        })
+        .collect()
+}
+
+/// Refuses what `polysensitization` and `members` can't report.
+fn check(min_allergies: usize, options: &ReportOptions) -> Result<(), String> {
+    if options.noise.is_some() {
+        return Err("polysensitization can't be released under differential privacy".to_string());
+    }
+    if min_allergies == 0 {
+        return Err("--min-allergies must be at least 1".to_string());
+    }
+    Ok(())
+}
+
+/// The polysensitized individuals of `graph`, for `--members`. Their ids
+/// are as they appear in `graph`, so de-identify it first. Refused under
+/// differential privacy, as a list of individuals would undo it.
+pub fn members(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    min_allergies: usize,
+    options: &ReportOptions,
+) -> Result<Vec<Member>, String> {
+    check(min_allergies, options)?;
+    let members = polysensitized(graph, min_allergies)
+        .into_iter()
+        .map(|(individual, allergies)| Member {
+            subject_id: individual.id.clone(),
+            allergies: allergies.len(),
+            combination: allergies.join(" + "),
+        })
+        .collect();
+    Ok(members)
+}
+
+/// Individuals with at least `min_allergies` allergies: first over all of
+/// them, then per group of each of `groupings`, and the `top` most common
+/// combinations of allergies among them. Groups are judged on their
+/// polysensitized count and flagged, masked or merged per `options`;
+/// combinations are judged on their count, and masked under `mask` and
+/// `merge`. Refused under differential privacy; see
+/// [`ReportOptions::noise`].
+pub fn polysensitization(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    groupings: &[Grouping],
+    min_allergies: usize,
+    top: usize,
+    options: &ReportOptions,
+) -> Result<PolysensitizationReport, String> {
+    check(min_allergies, options)?;
+    let cohort = polysensitized(graph, min_allergies);
+    let total = cohort.len().max(1) as f64;
+    let mut composition = Vec::new();
+    for grouping in with_overall(groupings) {
+        // Group -> (individuals, polysensitized)
+        let mut groups: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+        for node in graph.node_weights() {
+            let NodeType::Individual(individual) = node else { continue };
+            let Some(group) = group_of(grouping, individual) else { continue };
+            groups.entry(group).or_default().0 += 1;
+        }
+        for (individual, _) in &cohort {
+            if let Some(group) = group_of(grouping, individual) {
+                groups.entry(group).or_default().1 += 1;
+            }
+        }
+        let label = label(grouping);
+        for Cell { group, total: n, count, suppressed } in count_cells(groups, grouping, options) {
+            let n = n as usize;
+            let interval = wilson_interval(count, n, Z_95).filter(|_| !suppressed);
+            composition.push(Composition {
+                grouping: label.clone(),
+                group,
+                polysensitized: (!suppressed).then_some(count),
+                n: (!suppressed).then_some(n),
+                proportion: (!suppressed).then(|| count as f64 / n.max(1) as f64),
+                ci_lower: interval.map(|(lower, _)| lower),
+                ci_upper: interval.map(|(_, upper)| upper),
+                share: (!suppressed).then(|| count as f64 / total),
+                small_cell: count < options.small_cell_threshold,
+                suppressed,
+            });
+        }
+    }
+
+    let mut counts: BTreeMap<Vec<&str>, usize> = BTreeMap::new();
+    for (_, allergies) in &cohort {
+        *counts.entry(allergies.clone()).or_default() += 1;
+    }
+    let mut counts: Vec<(Vec<&str>, usize)> = counts.into_iter().collect();
+    counts.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| a.cmp(b)));
+    let cells = counts.iter().map(|(allergies, count)| Cell::new(allergies.join(" + "), 0.0, *count)).collect();
+    let mode = match options.suppression {
+        Suppression::Flag => Suppression::Flag,
+        Suppression::Mask | Suppression::Merge => Suppression::Mask,
+    };
+    let combinations = suppress(cells, options.small_cell_threshold, mode)
+        .into_iter()
+        .zip(&counts)
+        .take(top)
+        .map(|(cell, (allergies, _))| Combination {
+            allergies: cell.group,
+            size: allergies.len(),
+            individuals: (!cell.suppressed).then_some(cell.count),
+            share: (!cell.suppressed).then(|| cell.count as f64 / total),
+            small_cell: cell.count < options.small_cell_threshold,
+            suppressed: cell.suppressed,
+        })
+        .collect();
+    Ok(PolysensitizationReport { min_allergies, composition, combinations })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::{write_csv, OVERALL};
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    #[test]
+    fn test_polysensitization() {
+        // Allergies: 205650 2, 205651 1, 205652 3, 205653 0, 205654 4
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let options = ReportOptions { small_cell_threshold: 2, suppression: Suppression::Flag, ..Default::default() };
+        let report = polysensitization(&graph, &["gender".parse().unwrap()], 2, 10, &options).unwrap();
+        let overall = &report.composition[0];
+        assert_eq!((overall.grouping.as_str(), overall.group.as_str()), (OVERALL, OVERALL));
+        assert_eq!((overall.polysensitized, overall.n, overall.share), (Some(3), Some(5), Some(1.0)));
+        assert!((overall.ci_lower.unwrap() - 0.2307).abs() < 1e-4);
+        let male = &report.composition[1];
+        assert_eq!((male.group.as_str(), male.polysensitized, male.n), ("S0 - Male", Some(2), Some(3)));
+        let female = &report.composition[2];
+        assert_eq!((female.polysensitized, female.small_cell), (Some(1), true));
+        assert!((female.share.unwrap() - 1.0 / 3.0).abs() < 1e-12);
+        // Each of the three has a combination of their own
+        assert_eq!(report.combinations.len(), 3);
+        assert!(report.combinations.iter().all(|row| row.individuals == Some(1) && row.small_cell));
+        assert_eq!(report.small_cells(), 1 + 3);
+
+        let masked = ReportOptions { suppression: Suppression::Mask, ..options.clone() };
+        let report = polysensitization(&graph, &["gender".parse().unwrap()], 2, 2, &masked).unwrap();
+        assert!(report.composition[1..].iter().all(|row| row.suppressed && row.share.is_none()));
+        assert_eq!(report.combinations.len(), 2);
+        assert!(report.combinations.iter().all(|row| row.suppressed && row.individuals.is_none()));
+
+        let report = polysensitization(&graph, &[], 4, 10, &options).unwrap();
+        assert_eq!(report.composition[0].polysensitized, Some(1));
+        assert_eq!(report.combinations[0].size, 4);
+        let members = members(&graph, 3, &options).unwrap();
+        let ids: Vec<&str> = members.iter().map(|member| member.subject_id.as_str()).collect();
+        assert_eq!(ids, ["205652", "205654"]);
+        assert_eq!(members[0].allergies, 3);
+        assert!(polysensitization(&graph, &[], 0, 10, &options).is_err());
+
+        let mut out = Vec::new();
+        write_csv(&report.combinations, &mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        assert!(csv.starts_with("allergies,size,individuals,share,small_cell,suppressed\n"), "{}", csv);
+    }
+}
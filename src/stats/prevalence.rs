@@ -0,0 +1,187 @@
+//! Prevalence of each allergy, overall and per demographic group, with
+//! Wilson confidence intervals (`prevalence` subcommand).
+
+use std::collections::BTreeMap;
+
+use petgraph::graph::DiGraph;
+use petgraph::Direction;
+use serde::Serialize;
+
+use super::{count_cells, group_of, label, wilson_interval, with_overall, Z_95};
+use crate::disclosure::{Budget, Cell};
+use crate::strata::Grouping;
+use crate::{EdgeWeight, NodeType, ReportOptions};
+
+/// Prevalence of one allergy in one group. The counts and estimates are
+/// `None` when the group is suppressed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Prevalence {
+    pub allergy: String,
+    pub grouping: String,
+    pub group: String,
+    /// Individuals in the group with the allergy.
+    pub cases: Option<usize>,
+    /// Individuals in the group.
+    pub n: Option<usize>,
+    pub proportion: Option<f64>,
+    /// Bounds of the 95% Wilson interval for `proportion`.
+    pub ci_lower: Option<f64>,
+    pub ci_upper: Option<f64>,
+    /// Fewer cases than the small-cell threshold.
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+/// Prevalence of every allergen in `graph`, in graph order: first over
+/// all individuals, then per group of each of `groupings`. Small cells
+/// are judged on the number of cases and flagged, masked or merged per
+/// `options`, the overall rows included.
+///
+/// Under differential privacy the cases and group sizes are released with
+/// noise, and small cells judged on the noisy cases. Each grouping, the
+/// overall one included, is charged as one query: one individual changes
+/// its group sizes by at most one and its cases by at most one per
+/// allergen. Intervals computed from noisy counts would understate the
+/// uncertainty, so none are released.
+pub fn prevalence(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    groupings: &[Grouping],
+    options: &ReportOptions,
+) -> Result<Vec<Prevalence>, String> {
+    let mut budget = options.noise.as_ref().map(|noise| Budget::new(noise, 1 + groupings.len())).transpose()?;
+    let allergens = graph.node_weights().filter(|node| matches!(node, NodeType::AllergenStatus(_))).count();
+    let sensitivity = 1.0 + allergens as f64;
+    // Per grouping: group -> individuals, noised once so every allergen
+    // reports the same group size
+    let mut sizes: Vec<BTreeMap<String, usize>> = Vec::new();
+    for grouping in with_overall(groupings) {
+        let mut groups: BTreeMap<String, usize> = BTreeMap::new();
+        for individual in graph.node_weights() {
+            let NodeType::Individual(individual) = individual else { continue };
+            if let Some(group) = group_of(grouping, individual) {
+                *groups.entry(group).or_default() += 1;
+            }
+        }
+        if let Some(budget) = &mut budget {
+            for n in groups.values_mut() {
+                *n = budget.noise(*n as f64, sensitivity).round().max(0.0) as usize;
+            }
+            budget.charge();
+        }
+        sizes.push(groups);
+    }
+    let mut rows = Vec::new();
+    for allergen in graph.node_indices() {
+        let NodeType::AllergenStatus(allergy) = &graph[allergen] else { continue };
+        for (grouping, sizes) in with_overall(groupings).zip(&sizes) {
+            // Group -> (individuals, cases)
+            let mut groups: BTreeMap<String, (usize, usize)> =
+                sizes.iter().map(|(group, &n)| (group.clone(), (n, 0))).collect();
+            for node in graph.node_indices() {
+                let NodeType::Individual(individual) = &graph[node] else { continue };
+                let Some(group) = group_of(grouping, individual) else { continue };
+                let case = graph.neighbors_directed(node, Direction::Outgoing).any(|target| target == allergen);
+                groups.get_mut(&group).expect("sized above").1 += usize::from(case);
+            }
+            if let Some(budget) = &mut budget {
+                for (n, cases) in groups.values_mut() {
+                    *cases = (budget.noise(*cases as f64, sensitivity).round().max(0.0) as usize).min(*n);
+                }
+            }
+            let label = label(grouping);
+            for Cell { group, total, count: cases, suppressed } in count_cells(groups, grouping, options) {
+                let n = total as usize;
+                let interval = wilson_interval(cases, n, Z_95).filter(|_| !suppressed && budget.is_none());
+                rows.push(Prevalence {
+                    allergy: allergy.clone(),
+                    grouping: label.clone(),
+                    group,
+                    cases: (!suppressed).then_some(cases),
+                    n: (!suppressed).then_some(n),
+                    proportion: (!suppressed).then(|| cases as f64 / n.max(1) as f64),
+                    ci_lower: interval.map(|(lower, _)| lower),
+                    ci_upper: interval.map(|(_, upper)| upper),
+                    small_cell: cases < options.small_cell_threshold,
+                    suppressed,
+                });
+            }
+        }
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disclosure::{Mechanism, NoiseOptions, Suppression};
+    use crate::stats::{write_csv, OVERALL};
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    #[test]
+    fn test_prevalence() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let options = ReportOptions { small_cell_threshold: 2, suppression: Suppression::Flag, ..Default::default() };
+        let rows = prevalence(&graph, &["gender".parse().unwrap()], &options).unwrap();
+        let peanut = &rows[0];
+        let labels = (peanut.allergy.as_str(), peanut.grouping.as_str(), peanut.group.as_str());
+        assert_eq!(labels, ("Peanut", OVERALL, OVERALL));
+        assert_eq!((peanut.cases, peanut.n, peanut.proportion), (Some(3), Some(5), Some(0.6)));
+        assert!((peanut.ci_lower.unwrap() - 0.2307).abs() < 1e-4);
+        let male = &rows[1];
+        assert_eq!((male.group.as_str(), male.cases, male.n, male.small_cell), ("S0 - Male", Some(2), Some(3), false));
+        let female = &rows[2];
+        assert_eq!((female.cases, female.small_cell), (Some(1), true));
+        // Overall and two genders for each of the nine allergens
+        assert_eq!(rows.len(), 27);
+
+        let masked = ReportOptions { suppression: Suppression::Mask, ..options };
+        let rows = prevalence(&graph, &["gender".parse().unwrap()], &masked).unwrap();
+        assert!(rows[2].suppressed && rows[2].ci_lower.is_none());
+        assert!(rows[1].suppressed, "the complementary group is masked too");
+        assert!(!rows[0].suppressed);
+        // Nobody has an almond allergy
+        let almond = rows.iter().find(|row| row.allergy == "Almond" && row.grouping == OVERALL).unwrap();
+        assert!(almond.suppressed && almond.cases.is_none());
+
+        let mut out = Vec::new();
+        write_csv(&rows[..1], &mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let header = "allergy,grouping,group,cases,n,proportion,ci_lower,ci_upper,small_cell,suppressed\n";
+        assert!(csv.starts_with(header));
+        assert!(csv.contains("\nPeanut,overall,overall,3,5,0.6,0.2307"));
+    }
+
+    #[test]
+    fn test_prevalence_with_noise() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let groupings = ["gender".parse().unwrap()];
+        let noise = |epsilon| ReportOptions {
+            noise: Some(NoiseOptions { epsilon, mechanism: Mechanism::Laplace, delta: 0.0 }),
+            ..Default::default()
+        };
+        // Negligible noise leaves the counts as they were, without intervals
+        let rows = prevalence(&graph, &groupings, &noise(1e9)).unwrap();
+        let exact = prevalence(&graph, &groupings, &ReportOptions::default()).unwrap();
+        for (row, exact) in rows.iter().zip(&exact) {
+            assert_eq!((row.cases, row.n), (exact.cases, exact.n));
+            assert!(row.ci_lower.is_none() && row.ci_upper.is_none());
+        }
+
+        // Every allergen reports the same noisy group sizes
+        let rows = prevalence(&graph, &groupings, &noise(0.5)).unwrap();
+        assert_eq!(rows.len(), 27);
+        for row in &rows {
+            let peanut = rows.iter().find(|other| other.allergy == "Peanut" && other.group == row.group).unwrap();
+            assert_eq!(row.n, peanut.n);
+            assert!(row.cases <= row.n);
+        }
+
+        let gaussian = ReportOptions {
+            noise: Some(NoiseOptions { epsilon: 2.0, mechanism: Mechanism::Gaussian, delta: 1e-6 }),
+            ..Default::default()
+        };
+        assert!(prevalence(&graph, &groupings, &gaussian).is_err(), "1 per query is too much for Gaussian noise");
+    }
+}
@@ -0,0 +1,220 @@
+//! Order and timing of allergy onsets in the atopic march cohort against
+//! everyone else (`progression` subcommand): the onset age of each allergy,
+//! and the sequences in which individuals with several allergies acquired
+//! them.
+
+use std::collections::BTreeMap;
+
+use petgraph::graph::DiGraph;
+use petgraph::visit::EdgeRef;
+use petgraph::Direction;
+use serde::Serialize;
+
+use super::quantile;
+use crate::disclosure::{suppress, Cell, Suppression};
+use crate::{EdgeWeight, NodeType, ReportOptions};
+
+/// Onset ages of one allergy in or out of the cohort. The counts and ages
+/// are `None` when the row is suppressed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OnsetAge {
+    pub allergy: String,
+    /// Whether the row covers the atopic march cohort or everyone else.
+    pub atopic_march: bool,
+    /// Individuals with an onset of the allergy.
+    pub cases: Option<usize>,
+    pub median_onset: Option<f64>,
+    /// Quartiles of the onset age.
+    pub q1_onset: Option<f64>,
+    pub q3_onset: Option<f64>,
+    /// Fewer cases than the small-cell threshold.
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+/// Individuals who acquired their allergies in one order, e.g.
+/// `Peanut > Treenut + Cashew` for peanut first, then tree nut and cashew
+/// at the same age. `individuals` and `median_span` are `None` when the
+/// sequence is suppressed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OnsetSequence {
+    pub atopic_march: bool,
+    pub sequence: String,
+    pub allergies: usize,
+    pub individuals: Option<usize>,
+    /// Median years from the first onset to the last.
+    pub median_span: Option<f64>,
+    /// Fewer individuals than the small-cell threshold.
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProgressionReport {
+    /// Each allergy in graph order, out of then in the cohort.
+    pub onsets: Vec<OnsetAge>,
+    /// The most common sequences of individuals with at least two
+    /// allergies, most individuals first, out of then in the cohort.
+    pub sequences: Vec<OnsetSequence>,
+}
+
+impl ProgressionReport {
+    /// Rows reported unsuppressed despite being small cells.
+    pub fn small_cells(&self) -> usize {
+        let onsets = self.onsets.iter().filter(|row| row.small_cell && !row.suppressed).count();
+        onsets + self.sequences.iter().filter(|row| row.small_cell && !row.suppressed).count()
+    }
+}
+
+/// The order of `onsets`, `(allergy, onset age)` pairs sorted by age:
+/// allergies with the same onset age share a step, joined by `+`, and
+/// steps are joined by `>`.
+fn sequence(onsets: &[(&str, f64)]) -> String {
+    let mut steps: Vec<Vec<&str>> = Vec::new();
+    let mut last = None;
+    for &(allergy, onset) in onsets {
+        match steps.last_mut() {
+            Some(step) if last == Some(onset) => step.push(allergy),
+            _ => steps.push(vec![allergy]),
+        }
+        last = Some(onset);
+    }
+    steps.iter().map(|step| step.join(" + ")).collect::<Vec<_>>().join(" > ")
+}
+
+/// Onset ages per allergy and the `top` most common onset sequences, for
+/// the atopic march cohort and everyone else. Each allergy's two rows are
+/// judged on their cases, and each sequence on its individuals; under
+/// `mask` and `merge` small cells are masked, since pooling would mix the
+/// two sides of the comparison. Refused under differential privacy; see
+/// [`ReportOptions::noise`].
+pub fn progression(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    top: usize,
+    options: &ReportOptions,
+) -> Result<ProgressionReport, String> {
+    if options.noise.is_some() {
+        return Err("onset progression can't be released under differential privacy".to_string());
+    }
+    let mode = match options.suppression {
+        Suppression::Flag => Suppression::Flag,
+        Suppression::Mask | Suppression::Merge => Suppression::Mask,
+    };
+    let allergens: Vec<(_, &str)> = graph
+        .node_indices()
+        .filter_map(|node| match &graph[node] {
+            NodeType::AllergenStatus(name) => Some((node, name.as_str())),
+            _ => None,
+        })
+        .collect();
+    // Per allergy and cohort: onset ages; per cohort and sequence: spans
+    let mut ages: Vec<[Vec<f64>; 2]> = vec![[Vec::new(), Vec::new()]; allergens.len()];
+    let mut spans: [BTreeMap<(String, usize), Vec<f64>>; 2] = [BTreeMap::new(), BTreeMap::new()];
+    for node in graph.node_indices() {
+        let NodeType::Individual(individual) = &graph[node] else { continue };
+        let cohort = usize::from(individual.atopic_march_cohort == Some(true));
+        let mut onsets: Vec<(usize, &str, f64)> = graph
+            .edges_directed(node, Direction::Outgoing)
+            .filter_map(|edge| {
+                let i = allergens.iter().position(|(allergen, _)| *allergen == edge.target())?;
+                Some((i, allergens[i].1, edge.weight().onset))
+            })
+            .collect();
+        for &(i, _, onset) in &onsets {
+            ages[i][cohort].push(onset);
+        }
+        if onsets.len() < 2 {
+            continue;
+        }
+        onsets.sort_by(|a, b| a.2.total_cmp(&b.2).then(a.0.cmp(&b.0)));
+        let ordered: Vec<(&str, f64)> = onsets.iter().map(|&(_, name, onset)| (name, onset)).collect();
+        let span = onsets[onsets.len() - 1].2 - onsets[0].2;
+        spans[cohort].entry((sequence(&ordered), onsets.len())).or_default().push(span);
+    }
+
+    let mut onsets = Vec::new();
+    for ((_, allergy), by_cohort) in allergens.iter().zip(&mut ages) {
+        let cells = [false, true].map(|cohort| Cell::new(cohort.to_string(), 0.0, by_cohort[usize::from(cohort)].len()));
+        for (cell, cohort) in suppress(cells.into(), options.small_cell_threshold, mode).into_iter().zip([false, true]) {
+            let ages = &mut by_cohort[usize::from(cohort)];
+            ages.sort_by(f64::total_cmp);
+            let shown = |q: f64| quantile(ages, q).filter(|_| !cell.suppressed);
+            onsets.push(OnsetAge {
+                allergy: allergy.to_string(),
+                atopic_march: cohort,
+                cases: (!cell.suppressed).then_some(cell.count),
+                median_onset: shown(0.5),
+                q1_onset: shown(0.25),
+                q3_onset: shown(0.75),
+                small_cell: cell.count < options.small_cell_threshold,
+                suppressed: cell.suppressed,
+            });
+        }
+    }
+
+    let mut sequences = Vec::new();
+    for (cohort, by_sequence) in [false, true].into_iter().zip(spans) {
+        let mut ranked: Vec<((String, usize), Vec<f64>)> = by_sequence.into_iter().collect();
+        ranked.sort_by(|(a, a_spans), (b, b_spans)| b_spans.len().cmp(&a_spans.len()).then_with(|| a.cmp(b)));
+        let cells = ranked.iter().map(|((sequence, _), spans)| Cell::new(sequence.clone(), 0.0, spans.len())).collect();
+        let cells = suppress(cells, options.small_cell_threshold, mode);
+        for (cell, ((_, allergies), mut spans)) in cells.into_iter().zip(ranked).take(top) {
+            spans.sort_by(f64::total_cmp);
+            sequences.push(OnsetSequence {
+                atopic_march: cohort,
+                sequence: cell.group,
+                allergies,
+                individuals: (!cell.suppressed).then_some(cell.count),
+                median_span: quantile(&spans, 0.5).filter(|_| !cell.suppressed),
+                small_cell: cell.count < options.small_cell_threshold,
+                suppressed: cell.suppressed,
+            });
+        }
+    }
+    Ok(ProgressionReport { onsets, sequences })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::write_csv;
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    #[test]
+    fn test_progression() {
+        // 205650 and 205652 are in the cohort; 205653 has no allergies
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let options = ReportOptions { small_cell_threshold: 2, suppression: Suppression::Flag, ..Default::default() };
+        let report = progression(&graph, 10, &options).unwrap();
+        // Two rows for each of the nine allergens
+        assert_eq!(report.onsets.len(), 18);
+        let peanut = &report.onsets[0];
+        assert_eq!((peanut.allergy.as_str(), peanut.atopic_march, peanut.cases), ("Peanut", false, Some(2)));
+        assert!((peanut.median_onset.unwrap() - 1.15).abs() < 1e-12);
+        assert!((peanut.q1_onset.unwrap() - 0.975).abs() < 1e-12 && !peanut.small_cell);
+        assert_eq!((report.onsets[1].cases, report.onsets[1].median_onset), (Some(1), Some(1.0)));
+
+        let sequences: Vec<(bool, &str)> =
+            report.sequences.iter().map(|row| (row.atopic_march, row.sequence.as_str())).collect();
+        let expected =
+            [(false, "Peanut > Treenut + Cashew > Pistachio"), (true, "Peanut > Cashew"), (true, "Treenut + Walnut > Pecan")];
+        assert_eq!(sequences, expected);
+        assert_eq!((report.sequences[0].allergies, report.sequences[0].median_span), (4, Some(0.5)));
+        // Every onset row but Peanut outside the cohort, and every sequence
+        assert_eq!(report.small_cells(), 17 + 3);
+
+        let masked = ReportOptions { suppression: Suppression::Mask, ..options };
+        let report = progression(&graph, 1, &masked).unwrap();
+        assert!(report.onsets[..2].iter().all(|row| row.suppressed && row.median_onset.is_none()));
+        assert_eq!(report.sequences.len(), 2);
+        assert!(report.sequences.iter().all(|row| row.suppressed && row.median_span.is_none()));
+
+        // Masked as the complement of the cohort's single case
+        let mut out = Vec::new();
+        write_csv(&report.onsets[..1], &mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let header = "allergy,atopic_march,cases,median_onset,q1_onset,q3_onset,small_cell,suppressed\n";
+        assert_eq!(csv, format!("{}Peanut,false,,,,,false,true\n", header));
+    }
+}
@@ -0,0 +1,306 @@
+//! Logistic regression of each allergy on demographics (`regression`
+//! subcommand): odds ratios for every level of the `--stratify-by`
+//! groupings against the most common one, and per year of birth, each
+//! adjusted for the others.
+//!
+//! Birth year isn't kept on the graph, so it comes from the records, as
+//! the observation windows do for `survival`.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use petgraph::graph::DiGraph;
+use petgraph::Direction;
+use serde::Serialize;
+
+use super::association::erfc;
+use super::Z_95;
+use crate::disclosure::Suppression;
+use crate::strata::Grouping;
+use crate::{EdgeWeight, NodeType, Record, ReportOptions};
+
+/// Newton–Raphson steps before a fit is given up on.
+pub const MAX_ITERATIONS: usize = 50;
+
+/// Inverse of the square `matrix` by Gauss–Jordan elimination, or `None`
+/// if it is singular.
+fn invert(matrix: &[Vec<f64>]) -> Option<Vec<Vec<f64>>> {
+    let size = matrix.len();
+    let scale = matrix.iter().flatten().fold(0.0_f64, |max, value| max.max(value.abs()));
+    let mut rows: Vec<Vec<f64>> = matrix
+        .iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let mut row = row.clone();
+            row.extend((0..size).map(|j| if i == j { 1.0 } else { 0.0 }));
+            row
+        })
+        .collect();
+    for column in 0..size {
+        let pivot = (column..size).max_by(|&a, &b| rows[a][column].abs().total_cmp(&rows[b][column].abs()))?;
+        if rows[pivot][column].abs() <= 1e-12 * scale {
+            return None;
+        }
+        rows.swap(column, pivot);
+        let divisor = rows[column][column];
+        rows[column].iter_mut().for_each(|value| *value /= divisor);
+        let pivot_row = rows[column].clone();
+        for (i, row) in rows.iter_mut().enumerate() {
+            let factor = row[column];
+            if i != column && factor != 0.0 {
+                row.iter_mut().zip(&pivot_row).for_each(|(value, pivot)| *value -= factor * pivot);
+            }
+        }
+    }
+    Some(rows.into_iter().map(|row| row[size..].to_vec()).collect())
+}
+
+/// Maximum-likelihood logistic regression of `outcomes` on `design`, one
+/// row of covariates per outcome starting with 1 for the intercept, by
+/// Newton–Raphson. Returns the coefficients and their standard errors, or
+/// `None` when the columns are collinear or the fit doesn't converge, as
+/// when a covariate separates those with the outcome from the rest.
+pub fn fit_logistic(design: &[Vec<f64>], outcomes: &[bool]) -> Option<(Vec<f64>, Vec<f64>)> {
+    let columns = design.first()?.len();
+    let mut coefficients = vec![0.0; columns];
+    for _ in 0..MAX_ITERATIONS {
+        let mut gradient = vec![0.0; columns];
+        let mut information = vec![vec![0.0; columns]; columns];
+        for (row, &outcome) in design.iter().zip(outcomes) {
+            let linear: f64 = row.iter().zip(&coefficients).map(|(x, b)| x * b).sum();
+            let p = 1.0 / (1.0 + (-linear).exp());
+            let weight = p * (1.0 - p);
+            for i in 0..columns {
+                gradient[i] += row[i] * (f64::from(u8::from(outcome)) - p);
+                for j in 0..columns {
+                    information[i][j] += weight * row[i] * row[j];
+                }
+            }
+        }
+        let covariance = invert(&information)?;
+        let step: Vec<f64> =
+            covariance.iter().map(|row| row.iter().zip(&gradient).map(|(c, g)| c * g).sum()).collect();
+        coefficients.iter_mut().zip(&step).for_each(|(b, s)| *b += s);
+        if step.iter().all(|s| s.abs() < 1e-8) {
+            let errors = (0..columns).map(|i| covariance[i][i].sqrt()).collect();
+            return Some((coefficients, errors));
+        }
+    }
+    None
+}
+
+/// Odds ratio of one allergy for one covariate. The counts and estimates
+/// are `None` when the row is suppressed, and the estimates also when the
+/// model doesn't converge.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OddsRatio {
+    pub allergy: String,
+    /// A grouping, or `birth year`.
+    pub covariate: String,
+    /// The group compared with `reference`; `None` for birth year, whose
+    /// odds ratio is per year.
+    pub level: Option<String>,
+    pub reference: Option<String>,
+    /// Individuals in `level`, or in the model for birth year.
+    pub individuals: Option<usize>,
+    /// Of them, those with the allergy.
+    pub cases: Option<usize>,
+    /// Log odds ratio.
+    pub coefficient: Option<f64>,
+    pub std_error: Option<f64>,
+    pub odds_ratio: Option<f64>,
+    /// Bounds of the 95% Wald interval for `odds_ratio`.
+    pub or_ci_lower: Option<f64>,
+    pub or_ci_upper: Option<f64>,
+    /// Two-sided Wald test of no association.
+    pub p_value: Option<f64>,
+    /// Whether the allergy's model converged.
+    pub converged: bool,
+    /// Fewer cases than the small-cell threshold.
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+/// One column of the design: a level of a grouping, or birth year.
+struct Term {
+    covariate: String,
+    /// The grouping's position and the level, for a level.
+    grouping: Option<usize>,
+    level: Option<String>,
+    reference: Option<String>,
+}
+
+/// Logistic regression of each allergy in `graph`, in graph order, on
+/// `groupings` and year of birth from `records`. Each grouping's most
+/// common group is its reference, and birth year is left out if everyone
+/// was born the same year. Individuals without a group or a record are
+/// left out. Small cells are judged on the cases in each level, and for
+/// birth year in the model; under `mask` and `merge` the row is left empty,
+/// since the levels are already in the model. Refused under differential
+/// privacy.
+pub fn regression(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    records: &[Record],
+    groupings: &[Grouping],
+    options: &ReportOptions,
+) -> Result<Vec<OddsRatio>, String> {
+    if options.noise.is_some() {
+        return Err("regression can't be released under differential privacy".to_string());
+    }
+    let mut birth_years = BTreeMap::new();
+    for record in records {
+        birth_years.entry(record.subject_id.as_str()).or_insert(f64::from(record.birth_year));
+    }
+    let allergens: Vec<_> = graph
+        .node_indices()
+        .filter_map(|node| match &graph[node] {
+            NodeType::AllergenStatus(name) => Some((node, name.clone())),
+            NodeType::Individual(_) | NodeType::Demographic { .. } => None,
+        })
+        .collect();
+    // Per individual: groups, birth year and which allergens they have
+    let mut individuals: Vec<(Vec<String>, f64, Vec<bool>)> = Vec::new();
+    for node in graph.node_indices() {
+        let NodeType::Individual(individual) = &graph[node] else { continue };
+        let groups: Option<Vec<String>> = groupings.iter().map(|grouping| grouping.value_of(individual)).collect();
+        let (Some(groups), Some(&birth_year)) = (groups, birth_years.get(individual.id.as_str())) else { continue };
+        let allergies: Vec<_> = graph.neighbors_directed(node, Direction::Outgoing).collect();
+        let has = allergens.iter().map(|(allergen, _)| allergies.contains(allergen)).collect();
+        individuals.push((groups, birth_year, has));
+    }
+
+    let mut terms = Vec::new();
+    for (i, grouping) in groupings.iter().enumerate() {
+        let mut sizes: BTreeMap<&str, usize> = BTreeMap::new();
+        for (groups, _, _) in &individuals {
+            *sizes.entry(groups[i].as_str()).or_insert(0) += 1;
+        }
+        // The first of the largest groups
+        let largest = sizes.iter().rev().max_by_key(|&(_, size)| size).map(|(group, _)| group.to_string());
+        let Some(reference) = largest else { continue };
+        for level in sizes.keys().filter(|&&level| level != reference) {
+            terms.push(Term {
+                covariate: grouping.label(),
+                grouping: Some(i),
+                level: Some(level.to_string()),
+                reference: Some(reference.clone()),
+            });
+        }
+    }
+    let years: BTreeSet<i64> = individuals.iter().map(|(_, year, _)| *year as i64).collect();
+    if years.len() > 1 {
+        terms.push(Term { covariate: "birth year".to_string(), grouping: None, level: None, reference: None });
+    }
+    // Birth year is centred, which keeps the intercept in range
+    let mean_year = individuals.iter().map(|(_, year, _)| year).sum::<f64>() / individuals.len().max(1) as f64;
+    let design: Vec<Vec<f64>> = individuals
+        .iter()
+        .map(|(groups, year, _)| {
+            let columns = terms.iter().map(|term| match (term.grouping, &term.level) {
+                (Some(i), Some(level)) => f64::from(u8::from(groups[i] == *level)),
+                _ => year - mean_year,
+            });
+            std::iter::once(1.0).chain(columns).collect()
+        })
+        .collect();
+
+    let mut rows = Vec::new();
+    for (a, (_, allergy)) in allergens.iter().enumerate() {
+        let outcomes: Vec<bool> = individuals.iter().map(|(_, _, has)| has[a]).collect();
+        let fit = fit_logistic(&design, &outcomes);
+        for (t, term) in terms.iter().enumerate() {
+            let (individuals, cases) = design.iter().zip(&outcomes).fold((0, 0), |(n, cases), (row, &outcome)| {
+                if term.level.is_none() || row[t + 1] == 1.0 {
+                    (n + 1, cases + usize::from(outcome))
+                } else {
+                    (n, cases)
+                }
+            });
+            let small_cell = cases < options.small_cell_threshold;
+            let suppressed = small_cell && options.suppression != Suppression::Flag;
+            let estimate =
+                fit.as_ref().filter(|_| !suppressed).map(|(coefficients, errors)| (coefficients[t + 1], errors[t + 1]));
+            rows.push(OddsRatio {
+                allergy: allergy.clone(),
+                covariate: term.covariate.clone(),
+                level: term.level.clone(),
+                reference: term.reference.clone(),
+                individuals: (!suppressed).then_some(individuals),
+                cases: (!suppressed).then_some(cases),
+                coefficient: estimate.map(|(b, _)| b),
+                std_error: estimate.map(|(_, se)| se),
+                odds_ratio: estimate.map(|(b, _)| b.exp()),
+                or_ci_lower: estimate.map(|(b, se)| (b - Z_95 * se).exp()),
+                or_ci_upper: estimate.map(|(b, se)| (b + Z_95 * se).exp()),
+                p_value: estimate.map(|(b, se)| erfc((b / se).abs() / std::f64::consts::SQRT_2)),
+                converged: fit.is_some(),
+                small_cell,
+                suppressed,
+            });
+        }
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::write_csv;
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    #[test]
+    fn test_fit_logistic() {
+        // One binary covariate: the odds ratio is the table's, 6/4 over 2/8
+        let mut design = Vec::new();
+        let mut outcomes = Vec::new();
+        for (exposed, outcome, count) in [(1.0, true, 6), (1.0, false, 4), (0.0, true, 2), (0.0, false, 8)] {
+            design.extend(std::iter::repeat_n(vec![1.0, exposed], count));
+            outcomes.extend(std::iter::repeat_n(outcome, count));
+        }
+        let (coefficients, errors) = fit_logistic(&design, &outcomes).unwrap();
+        assert!((coefficients[1].exp() - 6.0).abs() < 1e-9);
+        assert!((coefficients[0] - 0.25_f64.ln()).abs() < 1e-9);
+        // Woolf's standard error of the log odds ratio
+        let woolf = (1.0 / 6.0 + 1.0 / 4.0 + 1.0 / 2.0 + 1.0 / 8.0_f64).sqrt();
+        assert!((errors[1] - woolf).abs() < 1e-9);
+
+        // Everyone exposed has the outcome
+        let separated: Vec<bool> = design.iter().map(|row| row[1] == 1.0).collect();
+        assert_eq!(fit_logistic(&design, &separated), None);
+        assert_eq!(invert(&[vec![1.0, 2.0], vec![2.0, 4.0]]), None);
+    }
+
+    #[test]
+    fn test_regression() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records.clone(), &GraphOptions::default());
+        let options = ReportOptions { small_cell_threshold: 2, suppression: Suppression::Flag, ..Default::default() };
+        let rows = regression(&graph, &records, &["gender".parse().unwrap()], &options).unwrap();
+        // Female against Male, and birth year, for each of the nine allergens
+        assert_eq!(rows.len(), 18);
+        let female = &rows[0];
+        assert_eq!((female.allergy.as_str(), female.covariate.as_str()), ("Peanut", "gender"));
+        assert_eq!((female.level.as_deref(), female.reference.as_deref()), (Some("S1 - Female"), Some("S0 - Male")));
+        assert_eq!((female.individuals, female.cases, female.small_cell), (Some(2), Some(1), true));
+        // Everyone with Peanut but 205652 and 205653, so it converges
+        assert!(female.converged && female.odds_ratio.unwrap() > 0.0);
+        let (lower, upper) = (female.or_ci_lower.unwrap(), female.or_ci_upper.unwrap());
+        assert!(lower < female.odds_ratio.unwrap() && female.odds_ratio.unwrap() < upper);
+        let birth_year = &rows[1];
+        assert_eq!((birth_year.covariate.as_str(), birth_year.level.as_deref()), ("birth year", None));
+        assert_eq!((birth_year.individuals, birth_year.cases), (Some(5), Some(3)));
+        // Nobody has an almond allergy
+        let almond = rows.iter().find(|row| row.allergy == "Almond").unwrap();
+        assert!(!almond.converged && almond.odds_ratio.is_none());
+
+        let masked = ReportOptions { suppression: Suppression::Mask, ..options };
+        let rows = regression(&graph, &records, &["gender".parse().unwrap()], &masked).unwrap();
+        assert!(rows[0].suppressed && rows[0].cases.is_none() && rows[0].odds_ratio.is_none());
+        assert!(!rows[1].suppressed && rows[1].converged);
+        let mut out = Vec::new();
+        write_csv(&rows[..1], &mut out).unwrap();
+        let header = "allergy,covariate,level,reference,individuals,cases,coefficient,std_error,odds_ratio,\
+                      or_ci_lower,or_ci_upper,p_value,converged,small_cell,suppressed\n";
+        let row = "Peanut,gender,S1 - Female,S0 - Male,,,,,,,,,true,true,true\n";
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{}{}", header, row));
+    }
+}
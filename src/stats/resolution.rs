@@ -0,0 +1,227 @@
+//! Outgrowth of each allergy: the share of allergic individuals whose
+//! allergy resolved while they were observed, overall and per demographic
+//! group, and Kaplan–Meier curves of the time from onset to resolution
+//! (`resolution` subcommand).
+//!
+//! An allergy resolved when its edge has an `end`, which `EdgeWeight` only
+//! keeps when it is before the end of observation. Unresolved allergies
+//! are censored at the end of observation.
+
+use std::collections::BTreeMap;
+
+use petgraph::graph::DiGraph;
+use petgraph::visit::EdgeRef;
+use petgraph::Direction;
+use serde::Serialize;
+
+use super::{count_cells, group_of, label, wilson_interval, with_overall, Z_95};
+use crate::disclosure::Cell;
+use crate::strata::Grouping;
+use crate::{EdgeWeight, NodeType, ReportOptions};
+
+/// Outgrowth of one allergy in one group. The counts and estimates are
+/// `None` when the group is suppressed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Outgrowth {
+    pub allergy: String,
+    pub grouping: String,
+    pub group: String,
+    /// Individuals in the group with the allergy.
+    pub allergic: Option<usize>,
+    /// Those whose allergy resolved while observed.
+    pub resolved: Option<usize>,
+    pub rate: Option<f64>,
+    /// Bounds of the 95% Wilson interval for `rate`.
+    pub ci_lower: Option<f64>,
+    pub ci_upper: Option<f64>,
+    /// Years from onset by which half have outgrown the allergy, from the
+    /// Kaplan–Meier curve; `None` if the curve never gets there.
+    pub median_years: Option<f64>,
+    /// Fewer resolutions than the small-cell threshold.
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+/// One step of a Kaplan–Meier curve. Each curve starts with everyone
+/// still allergic at 0 years.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CurvePoint {
+    pub allergy: String,
+    pub grouping: String,
+    pub group: String,
+    /// Years since onset.
+    pub years: f64,
+    /// Individuals still allergic and observed just before `years`.
+    pub at_risk: usize,
+    /// Resolutions at `years`.
+    pub resolved: usize,
+    /// Allergies whose observation ended at `years` unresolved.
+    pub censored: usize,
+    /// Estimated share still allergic after `years`.
+    pub still_allergic: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResolutionReport {
+    pub rates: Vec<Outgrowth>,
+    /// The curve of every unsuppressed row of `rates` with an allergic
+    /// individual, in the same order.
+    pub curves: Vec<CurvePoint>,
+}
+
+/// Kaplan–Meier steps of `(years, resolved)` observations: one per
+/// distinct time, as `(years, at risk, resolved, censored, still allergic)`.
+pub fn kaplan_meier(mut observations: Vec<(f64, bool)>) -> Vec<(f64, usize, usize, usize, f64)> {
+    observations.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut steps = Vec::new();
+    let mut at_risk = observations.len();
+    let mut still_allergic = 1.0;
+    for chunk in observations.chunk_by(|a, b| a.0 == b.0) {
+        let resolved = chunk.iter().filter(|(_, resolved)| *resolved).count();
+        still_allergic *= 1.0 - resolved as f64 / at_risk as f64;
+        steps.push((chunk[0].0, at_risk, resolved, chunk.len() - resolved, still_allergic));
+        at_risk -= chunk.len();
+    }
+    steps
+}
+
+/// Outgrowth of every allergen in `graph`, in graph order, first over all
+/// allergic individuals and then per group of each of `groupings`, with
+/// the curve of each. Small cells are judged on the number of
+/// resolutions, as `prevalence` judges them on cases, and a suppressed
+/// group has no curve. Groups, and allergies, with no allergic individual
+/// have no rows. Refused under differential privacy.
+pub fn resolution(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    groupings: &[Grouping],
+    options: &ReportOptions,
+) -> Result<ResolutionReport, String> {
+    if options.noise.is_some() {
+        return Err("resolution can't be released under differential privacy".to_string());
+    }
+    let mut report = ResolutionReport { rates: Vec::new(), curves: Vec::new() };
+    for allergen in graph.node_indices() {
+        let NodeType::AllergenStatus(allergy) = &graph[allergen] else { continue };
+        for grouping in with_overall(groupings) {
+            // Group -> (years, resolved) of each allergic individual
+            let mut groups: BTreeMap<String, Vec<(f64, bool)>> = BTreeMap::new();
+            for edge in graph.edges_directed(allergen, Direction::Incoming) {
+                let NodeType::Individual(individual) = &graph[edge.source()] else { continue };
+                let Some(group) = group_of(grouping, individual) else { continue };
+                let weight = edge.weight();
+                groups.entry(group).or_default().push((weight.duration, weight.end.is_some()));
+            }
+            let counts = groups
+                .iter()
+                .map(|(group, observations)| {
+                    let resolved = observations.iter().filter(|(_, resolved)| *resolved).count();
+                    (group.clone(), (observations.len(), resolved))
+                })
+                .collect();
+            let label = label(grouping);
+            let cells = count_cells(counts, grouping, options);
+            for cell in &cells {
+                let Cell { group, total, count: resolved, suppressed } = cell.clone();
+                let allergic = total as usize;
+                // A merged group pools the groups that no longer have a cell
+                let observations: Vec<(f64, bool)> = match groups.get(&group) {
+                    _ if suppressed => Vec::new(),
+                    Some(observations) => observations.clone(),
+                    None => groups
+                        .iter()
+                        .filter(|&(name, _)| !cells.iter().any(|cell| &cell.group == name))
+                        .flat_map(|(_, observations)| observations.iter().copied())
+                        .collect(),
+                };
+                let steps = kaplan_meier(observations);
+                let interval = wilson_interval(resolved, allergic, Z_95).filter(|_| !suppressed);
+                report.rates.push(Outgrowth {
+                    allergy: allergy.clone(),
+                    grouping: label.clone(),
+                    group: group.clone(),
+                    allergic: (!suppressed).then_some(allergic),
+                    resolved: (!suppressed).then_some(resolved),
+                    rate: (!suppressed).then(|| resolved as f64 / allergic.max(1) as f64),
+                    ci_lower: interval.map(|(lower, _)| lower),
+                    ci_upper: interval.map(|(_, upper)| upper),
+                    median_years: steps.iter().find(|step| step.4 <= 0.5).map(|step| step.0),
+                    small_cell: resolved < options.small_cell_threshold,
+                    suppressed,
+                });
+                report.curves.extend(steps.into_iter().map(|(years, at_risk, resolved, censored, still_allergic)| {
+                    CurvePoint {
+                        allergy: allergy.clone(),
+                        grouping: label.clone(),
+                        group: group.clone(),
+                        years,
+                        at_risk,
+                        resolved,
+                        censored,
+                        still_allergic,
+                    }
+                }));
+            }
+        }
+    }
+    Ok(report)
+}
+
+impl ResolutionReport {
+    /// Rows reported unsuppressed despite being small cells.
+    pub fn small_cells(&self) -> usize {
+        self.rates.iter().filter(|row| row.small_cell && !row.suppressed).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disclosure::Suppression;
+    use crate::stats::{write_csv, OVERALL};
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    #[test]
+    fn test_kaplan_meier() {
+        let steps = kaplan_meier(vec![(3.0, true), (1.0, true), (2.0, false), (3.0, false), (5.0, true)]);
+        assert_eq!(steps[0], (1.0, 5, 1, 0, 0.8));
+        assert_eq!(steps[1], (2.0, 4, 0, 1, 0.8));
+        // Three at risk at 3 years, one resolving
+        assert_eq!((steps[2].1, steps[2].2, steps[2].3), (3, 1, 1));
+        assert!((steps[2].4 - 0.8 * 2.0 / 3.0).abs() < 1e-12);
+        assert_eq!((steps[3].0, steps[3].4), (5.0, 0.0));
+        assert!(kaplan_meier(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn test_resolution() {
+        // Subject 205650 outgrew Peanut 3.5 years after onset; 205651 and
+        // 205654 still had it when last seen, after 4.5 and 8.7 years
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let options = ReportOptions { small_cell_threshold: 1, suppression: Suppression::Flag, ..Default::default() };
+        let report = resolution(&graph, &["gender".parse().unwrap()], &options).unwrap();
+        let peanut = &report.rates[0];
+        assert_eq!((peanut.grouping.as_str(), peanut.allergic, peanut.resolved), (OVERALL, Some(3), Some(1)));
+        assert!((peanut.rate.unwrap() - 1.0 / 3.0).abs() < 1e-12);
+        assert_eq!((peanut.median_years, peanut.small_cell), (None, false));
+        let curve: Vec<(f64, usize, usize, usize)> = report.curves[..3]
+            .iter()
+            .map(|point| (point.years, point.at_risk, point.resolved, point.censored))
+            .collect();
+        assert_eq!(curve, [(3.5, 3, 1, 0), (4.5, 2, 0, 1), ((9.5f64 - 0.8), 1, 0, 1)]);
+        // No one resolved Cashew, so its overall row is a small cell
+        let cashew = report.rates.iter().find(|row| row.allergy == "Cashew" && row.grouping == OVERALL).unwrap();
+        assert_eq!((cashew.allergic, cashew.resolved, cashew.small_cell), (Some(2), Some(0), true));
+
+        let masked = ReportOptions { small_cell_threshold: 2, suppression: Suppression::Mask, ..options };
+        let report = resolution(&graph, &[], &masked).unwrap();
+        assert!(report.rates.iter().all(|row| row.suppressed && row.rate.is_none()));
+        assert!(report.curves.is_empty());
+
+        let mut out = Vec::new();
+        write_csv(&report.rates[..1], &mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        assert!(csv.starts_with("allergy,grouping,group,allergic,resolved,rate,ci_lower,ci_upper,median_years,"));
+        assert!(csv.contains("\nPeanut,overall,overall,,,,,,,true,true\n"));
+    }
+}
@@ -0,0 +1,294 @@
+//! Time to first allergy (`survival` subcommand): Kaplan–Meier curves of
+//! the age at the first allergy onset, overall and per demographic group.
+//!
+//! Individuals enter the risk set at `age_start_years` and are censored at
+//! `age_end_years`, so one first seen at 4 only counts from age 4 (left
+//! truncation). The graph doesn't keep those ages, so they come from the
+//! records (`observation_windows`). Individuals whose first allergy began
+//! before they were first seen were never at risk while observed, so are
+//! left out and counted separately.
+
+use std::collections::BTreeMap;
+
+use petgraph::graph::DiGraph;
+use petgraph::visit::EdgeRef;
+use petgraph::Direction;
+use serde::Serialize;
+
+use super::{count_cells, group_of, label, with_overall, Z_95};
+use crate::disclosure::Cell;
+use crate::strata::Grouping;
+use crate::{EdgeWeight, NodeType, Record, ReportOptions};
+
+/// Each subject's first and last observed ages. A subject with several
+/// records is observed from the earliest start to the latest end.
+pub fn observation_windows(records: &[Record]) -> BTreeMap<String, (f64, f64)> {
+    let mut windows: BTreeMap<String, (f64, f64)> = BTreeMap::new();
+    for record in records {
+        let window = windows.entry(record.subject_id.clone()).or_insert((record.age_start_years, record.age_end_years));
+        window.0 = window.0.min(record.age_start_years);
+        window.1 = window.1.max(record.age_end_years);
+    }
+    windows
+}
+
+/// Kaplan–Meier steps of `(entry, age, first allergy)` observations with
+/// delayed entry: one per distinct age with an allergy or a censoring, as
+/// `(age, at risk, allergies, censored, allergy-free, 95% interval)`. An
+/// individual is at risk at ages after their entry up to their own, so an
+/// observation ending at its entry age isn't counted. The interval is
+/// Greenwood's on the log-log scale, `None` where it is undefined.
+#[allow(clippy::type_complexity)]
+pub fn kaplan_meier_with_entry(
+    mut observations: Vec<(f64, f64, bool)>,
+) -> Vec<(f64, usize, usize, usize, f64, Option<(f64, f64)>)> {
+    observations.sort_by(|a, b| a.1.total_cmp(&b.1));
+    let mut entries: Vec<f64> = observations.iter().map(|&(entry, _, _)| entry).collect();
+    entries.sort_by(f64::total_cmp);
+    let mut steps = Vec::new();
+    let (mut entered, mut exited) = (0, 0);
+    let (mut allergy_free, mut greenwood) = (1.0, 0.0);
+    for chunk in observations.chunk_by(|a, b| a.1 == b.1) {
+        let age = chunk[0].1;
+        while entered < entries.len() && entries[entered] < age {
+            entered += 1;
+        }
+        let at_risk = entered - exited;
+        exited += chunk.len();
+        let observed: Vec<bool> = chunk.iter().filter(|(entry, ..)| *entry < age).map(|&(.., event)| event).collect();
+        if observed.is_empty() {
+            continue;
+        }
+        let events = observed.iter().filter(|&&event| event).count();
+        if at_risk > 0 && events > 0 {
+            allergy_free *= 1.0 - events as f64 / at_risk as f64;
+            greenwood += if events < at_risk {
+                events as f64 / (at_risk as f64 * (at_risk - events) as f64)
+            } else {
+                f64::INFINITY
+            };
+        }
+        let interval = (allergy_free > 0.0 && allergy_free < 1.0 && greenwood.is_finite()).then(|| {
+            let log = allergy_free.ln();
+            let half = Z_95 * greenwood.sqrt() / log.abs();
+            (allergy_free.powf(half.exp()), allergy_free.powf((-half).exp()))
+        });
+        steps.push((age, at_risk, events, observed.len() - events, allergy_free, interval));
+    }
+    steps
+}
+
+/// One step of a time-to-first-allergy curve.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SurvivalPoint {
+    pub grouping: String,
+    pub group: String,
+    /// Age in years.
+    pub age: f64,
+    /// Individuals observed and still without an allergy just before `age`.
+    pub at_risk: usize,
+    /// First allergies at `age`.
+    pub allergies: usize,
+    /// Individuals whose observation ended at `age` without one.
+    pub censored: usize,
+    /// Estimated share still without an allergy after `age`.
+    pub allergy_free: f64,
+    /// Bounds of the 95% interval for `allergy_free`.
+    pub ci_lower: Option<f64>,
+    pub ci_upper: Option<f64>,
+}
+
+/// Time to first allergy in one group. The counts and the median are
+/// `None` when the group is suppressed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SurvivalSummary {
+    pub grouping: String,
+    pub group: String,
+    /// Individuals at risk at some point while observed.
+    pub n: Option<usize>,
+    /// Of them, those whose first allergy began while observed.
+    pub allergies: Option<usize>,
+    /// Age by which half have an allergy; `None` if the curve never gets
+    /// there.
+    pub median_age: Option<f64>,
+    /// Fewer allergies than the small-cell threshold.
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SurvivalReport {
+    pub summaries: Vec<SurvivalSummary>,
+    /// The curve of every unsuppressed row of `summaries`, in the same
+    /// order.
+    pub curves: Vec<SurvivalPoint>,
+    /// Individuals left out for having an allergy before they were first
+    /// observed, or no observation window.
+    pub excluded: usize,
+}
+
+impl SurvivalReport {
+    /// Rows reported unsuppressed despite being small cells.
+    pub fn small_cells(&self) -> usize {
+        self.summaries.iter().filter(|row| row.small_cell && !row.suppressed).count()
+    }
+}
+
+/// Time to first allergy in `graph`, first over all individuals and then
+/// per group of each of `groupings`, with each individual observed over
+/// their `windows` entry. Small cells are judged on the number of first
+/// allergies, as `resolution` judges them on resolutions, and a
+/// suppressed group has no curve. Refused under differential privacy.
+pub fn survival(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    windows: &BTreeMap<String, (f64, f64)>,
+    groupings: &[Grouping],
+    options: &ReportOptions,
+) -> Result<SurvivalReport, String> {
+    if options.noise.is_some() {
+        return Err("survival curves can't be released under differential privacy".to_string());
+    }
+    let mut observed = Vec::new();
+    let mut excluded = 0;
+    for node in graph.node_indices() {
+        let NodeType::Individual(individual) = &graph[node] else { continue };
+        let first = graph
+            .edges_directed(node, Direction::Outgoing)
+            .filter(|edge| matches!(graph[edge.target()], NodeType::AllergenStatus(_)))
+            .map(|edge| edge.weight().onset)
+            .min_by(f64::total_cmp);
+        let Some(&(entry, exit)) = windows.get(&individual.id) else {
+            excluded += 1;
+            continue;
+        };
+        match first {
+            Some(onset) if onset < entry => excluded += 1,
+            Some(onset) if onset <= exit => observed.push((individual, (entry, onset, true))),
+            _ => observed.push((individual, (entry, exit, false))),
+        }
+    }
+
+    let mut report = SurvivalReport { summaries: Vec::new(), curves: Vec::new(), excluded };
+    for grouping in with_overall(groupings) {
+        let mut groups: BTreeMap<String, Vec<(f64, f64, bool)>> = BTreeMap::new();
+        for &(individual, observation) in &observed {
+            if let Some(group) = group_of(grouping, individual) {
+                groups.entry(group).or_default().push(observation);
+            }
+        }
+        let counts = groups
+            .iter()
+            .map(|(group, observations)| {
+                let events = observations.iter().filter(|(_, _, event)| *event).count();
+                (group.clone(), (observations.len(), events))
+            })
+            .collect();
+        let label = label(grouping);
+        let cells = count_cells(counts, grouping, options);
+        for Cell { group, total, count: events, suppressed } in &cells {
+            // A merged group pools the groups that no longer have a cell
+            let observations: Vec<(f64, f64, bool)> = match groups.get(group) {
+                _ if *suppressed => Vec::new(),
+                Some(observations) => observations.clone(),
+                None => groups
+                    .iter()
+                    .filter(|&(name, _)| !cells.iter().any(|cell| &cell.group == name))
+                    .flat_map(|(_, observations)| observations.iter().copied())
+                    .collect(),
+            };
+            let steps = kaplan_meier_with_entry(observations);
+            report.summaries.push(SurvivalSummary {
+                grouping: label.clone(),
+                group: group.clone(),
+                n: (!suppressed).then_some(*total as usize),
+                allergies: (!suppressed).then_some(*events),
+                median_age: steps.iter().find(|step| step.4 <= 0.5).map(|step| step.0),
+                small_cell: *events < options.small_cell_threshold,
+                suppressed: *suppressed,
+            });
+            report.curves.extend(steps.into_iter().map(|(age, at_risk, allergies, censored, allergy_free, interval)| {
+                SurvivalPoint {
+                    grouping: label.clone(),
+                    group: group.clone(),
+                    age,
+                    at_risk,
+                    allergies,
+                    censored,
+                    allergy_free,
+                    ci_lower: interval.map(|(lower, _)| lower),
+                    ci_upper: interval.map(|(_, upper)| upper),
+                }
+            }));
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disclosure::Suppression;
+    use crate::stats::{write_csv, OVERALL};
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    #[test]
+    fn test_kaplan_meier_with_entry() {
+        // The third enters at 2, after the first allergy
+        let observations = vec![(0.0, 1.0, true), (0.0, 3.0, false), (2.0, 4.0, true), (0.0, 5.0, true)];
+        let steps = kaplan_meier_with_entry(observations);
+        assert_eq!(steps.iter().map(|step| step.1).collect::<Vec<_>>(), [3, 3, 2, 1]);
+        assert_eq!((steps[0].0, steps[0].2), (1.0, 1));
+        assert!((steps[0].4 - 2.0 / 3.0).abs() < 1e-12 && steps[1].4 == steps[0].4);
+        assert_eq!((steps[1].2, steps[1].3), (0, 1));
+        assert!((steps[2].4 - 1.0 / 3.0).abs() < 1e-12);
+        let (lower, upper) = steps[2].5.unwrap();
+        assert!(lower < steps[2].4 && steps[2].4 < upper && lower > 0.0 && upper < 1.0);
+        // Everyone left at risk has an allergy at 5
+        assert_eq!((steps[3].4, steps[3].5), (0.0, None));
+        assert!(kaplan_meier_with_entry(Vec::new()).is_empty());
+        // An allergy at the entry age is before the individual is at risk
+        let steps = kaplan_meier_with_entry(vec![(0.0, 1.0, true), (1.0, 1.0, true)]);
+        assert_eq!(steps.iter().map(|step| (step.1, step.2, step.4)).collect::<Vec<_>>(), [(1, 1, 0.0)]);
+        let steps = kaplan_meier_with_entry(vec![(0.0, 1.0, true), (1.0, 1.0, true), (0.0, 2.0, false)]);
+        assert_eq!(steps.iter().map(|step| (step.1, step.2, step.4)).collect::<Vec<_>>(), [(2, 1, 0.5), (1, 0, 0.5)]);
+        assert!(kaplan_meier_with_entry(vec![(1.0, 1.0, true)]).is_empty());
+    }
+
+    #[test]
+    fn test_survival() {
+        // First allergies: 205650 at 1.0 (entry 0.5), 205651 1.5 (0.2),
+        // 205652 2.0 (1.0), 205654 0.8 (0.1); 205653 censored at 18
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let windows = observation_windows(&records);
+        assert_eq!(windows["205653"], (4.0, 18.0));
+        let graph = create_graph(records, &GraphOptions::default());
+        let options = ReportOptions { small_cell_threshold: 2, ..Default::default() };
+        let report = survival(&graph, &windows, &["gender".parse().unwrap()], &options).unwrap();
+        assert_eq!(report.excluded, 0);
+        let overall = &report.summaries[0];
+        assert_eq!((overall.grouping.as_str(), overall.n, overall.allergies), (OVERALL, Some(5), Some(4)));
+        assert_eq!(overall.median_age, Some(1.0));
+        let ages: Vec<f64> = report.curves.iter().filter(|point| point.grouping == OVERALL).map(|p| p.age).collect();
+        assert_eq!(ages, [0.8, 1.0, 1.5, 2.0, 18.0]);
+        // 205652 isn't at risk until after 1, nor 205653 until after 4
+        assert_eq!((report.curves[0].at_risk, report.curves[1].at_risk), (3, 2));
+        let female = &report.summaries[2];
+        assert_eq!((female.allergies, female.median_age, female.small_cell), (Some(2), Some(1.5), false));
+
+        let masked = ReportOptions { small_cell_threshold: 3, suppression: Suppression::Mask, ..options };
+        let report = survival(&graph, &windows, &["gender".parse().unwrap()], &masked).unwrap();
+        assert!(report.summaries[1..].iter().all(|row| row.suppressed && row.median_age.is_none()));
+        assert!(report.curves.iter().all(|point| point.grouping == OVERALL));
+
+        // An allergy before the first observed age is left out
+        let mut late = windows.clone();
+        late.insert("205654".to_string(), (1.0, 9.5));
+        let report = survival(&graph, &late, &[], &masked).unwrap();
+        assert_eq!((report.excluded, report.summaries[0].n), (1, Some(4)));
+
+        let mut out = Vec::new();
+        write_csv(&report.curves[..1], &mut out).unwrap();
+        let header = "grouping,group,age,at_risk,allergies,censored,allergy_free,ci_lower,ci_upper\n";
+        assert!(String::from_utf8(out).unwrap().starts_with(header));
+    }
+}
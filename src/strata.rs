@@ -0,0 +1,320 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::Individual;
+
+/// A column individuals can be grouped by when aggregating metrics.
+///
+/// The five demographic fields of the canonical schema have short names
+/// (`gender`, `race`, `ethnicity`, `payer`, `cohort`); anything else refers
+/// to an extra metadata column carried through from the input file.
+/// `age` groups individuals by their mid-observation age using the
+/// configured `AgeBins`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Dimension {
+    Gender,
+    Race,
+    Ethnicity,
+    Payer,
+    Cohort,
+    Age(AgeBins),
+    Column(String),
+}
+
+pub const DEFAULT_DIMENSIONS: &str = "gender,race,ethnicity,payer,cohort";
+
+impl Dimension {
+    /// Human-readable name used in report lines.
+    pub fn label(&self) -> &str {
+        match self {
+            Dimension::Gender => "gender",
+            Dimension::Race => "race",
+            Dimension::Ethnicity => "ethnicity",
+            Dimension::Payer => "payer factor",
+            Dimension::Cohort => "atopic march cohort",
+            Dimension::Age(_) => "age band",
+            Dimension::Column(name) => name,
+        }
+    }
+
+    pub fn with_age_bins(mut self, bins: &AgeBins) -> Self {
+        if let Dimension::Age(current) = &mut self {
+            *current = bins.clone();
+        }
+        self
+    }
+
+    /// The individual's value along this dimension, or `None` if the
+    /// metadata column is absent for them.
+    pub fn value_of(&self, individual: &Individual) -> Option<String> {
+        match self {
+            Dimension::Gender => Some(individual.gender.clone()),
+            Dimension::Race => Some(individual.race.clone()),
+            Dimension::Ethnicity => Some(individual.ethnicity.clone()),
+            Dimension::Payer => Some(individual.payer_factor.clone()),
+            Dimension::Cohort => individual.atopic_march_cohort.map(|cohort| cohort.to_string()),
+            Dimension::Age(bins) => Some(bins.label_for(individual.age)),
+            Dimension::Column(name) => individual.attributes.get(name).cloned(),
+        }
+    }
+}
+
+/// Points every `age` dimension at the configured bins.
+pub fn apply_age_bins(groupings: &mut [Grouping], bins: &AgeBins) {
+    for grouping in groupings.iter_mut() {
+        for dimension in grouping.0.iter_mut() {
+            if let Dimension::Age(current) = dimension {
+                *current = bins.clone();
+            }
+        }
+    }
+}
+
+/// One or more dimensions crossed together, written `race*payer` on the
+/// command line; each combination of values forms one group.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grouping(Vec<Dimension>);
+
+impl Grouping {
+    pub fn dimensions(&self) -> &[Dimension] {
+        &self.0
+    }
+
+    pub fn label(&self) -> String {
+        self.0.iter().map(Dimension::label).collect::<Vec<_>>().join(" × ")
+    }
+
+    /// The individual's cell, or `None` if any crossed value is missing.
+    pub fn value_of(&self, individual: &Individual) -> Option<String> {
+        let values = self
+            .0
+            .iter()
+            .map(|dimension| dimension.value_of(individual))
+            .collect::<Option<Vec<_>>>()?;
+        Some(values.join(" × "))
+    }
+}
+
+impl From<Dimension> for Grouping {
+    fn from(dimension: Dimension) -> Self {
+        Grouping(vec![dimension])
+    }
+}
+
+impl FromStr for Grouping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let dimensions = s.split('*').map(str::parse).collect::<Result<Vec<Dimension>, _>>()?;
+        Ok(Grouping(dimensions))
+    }
+}
+
+impl fmt::Display for Grouping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.label())
+    }
+}
+
+impl FromStr for Dimension {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim();
+        if name.is_empty() {
+            return Err("empty stratification dimension".to_string());
+        }
+        Ok(match name.to_ascii_lowercase().as_str() {
+            "gender" | "gender_factor" => Dimension::Gender,
+            "race" | "race_factor" => Dimension::Race,
+            "ethnicity" | "ethnicity_factor" => Dimension::Ethnicity,
+            "payer" | "payer_factor" => Dimension::Payer,
+            "cohort" | "atopic_march_cohort" => Dimension::Cohort,
+            "age" | "age_band" => Dimension::Age(AgeBins::default()),
+            _ => Dimension::Column(name.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for Dimension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+/// Ascending age cut points; an age falls in `[edges[i], edges[i + 1])`
+/// and anything at or past the last edge lands in an open-ended band.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AgeBins {
+    edges: Vec<f64>,
+    /// Band labels as written in `from_bands`, one per edge; derived from
+    /// the edges when empty.
+    labels: Vec<String>,
+}
+
+impl AgeBins {
+    pub fn new(edges: Vec<f64>) -> Result<Self, String> {
+        if edges.is_empty() {
+            return Err("age bins need at least one edge".to_string());
+        }
+        if edges.iter().any(|e| !e.is_finite()) {
+            return Err("age bin edges must be finite".to_string());
+        }
+        if edges.windows(2).any(|w| w[0] >= w[1]) {
+            return Err("age bin edges must be strictly increasing".to_string());
+        }
+        Ok(AgeBins { edges, labels: Vec::new() })
+    }
+
+    /// Bins from bands in whole years, as in `0-2,3-5,6-12,13-17,18+`:
+    /// `3-5` covers ages from 3 up to (not including) 6, and a last band
+    /// `18+` is open-ended. The bands must follow on from each other.
+    /// Without an open-ended band, ages past the last one are labelled
+    /// like `18+`.
+    pub fn from_bands(spec: &str) -> Result<Self, String> {
+        let invalid = |band: &str| format!("invalid age band '{}' (expected e.g. 0-2,3-5,6-12,13-17,18+)", band);
+        let mut edges = Vec::new();
+        let mut labels = Vec::new();
+        let mut next: Option<f64> = None;
+        let mut open = false;
+        for band in spec.split(',').map(str::trim) {
+            if open {
+                return Err(format!("age band '{}' follows the open-ended band", band));
+            }
+            let (start, end) = match band.strip_suffix('+') {
+                Some(start) => (start, None),
+                None => band.split_once('-').map(|(start, end)| (start, Some(end))).ok_or_else(|| invalid(band))?,
+            };
+            let start: f64 = start.trim().parse().map_err(|_| invalid(band))?;
+            let end: Option<f64> = end.map(|end| end.trim().parse()).transpose().map_err(|_| invalid(band))?;
+            if end.is_some_and(|end| end < start) {
+                return Err(invalid(band));
+            }
+            if let Some(expected) = next.filter(|&expected| expected != start) {
+                return Err(format!("age band '{}' should start at {}", band, expected));
+            }
+            edges.push(start);
+            labels.push(band.to_string());
+            next = end.map(|end| end + 1.0);
+            open = end.is_none();
+        }
+        if let Some(last) = next {
+            edges.push(last);
+            labels.push(format!("{}+", last));
+        }
+        AgeBins::new(edges).map(|bins| AgeBins { labels, ..bins })
+    }
+
+    pub fn preset(name: &str) -> Option<Self> {
+        let edges = match name {
+            "pediatric" => vec![0.0, 2.0, 5.0, 12.0, 18.0],
+            "infant" => vec![0.0, 1.0, 2.0, 3.0, 5.0],
+            "decades" => vec![0.0, 10.0, 20.0, 30.0, 40.0, 50.0, 60.0],
+            _ => return None,
+        };
+        Some(AgeBins { edges, labels: Vec::new() })
+    }
+
+    /// Band label for `age`, e.g. `2-5`, `18+` or `<0`.
+    pub fn label_for(&self, age: f64) -> String {
+        let first = self.edges[0];
+        if age < first {
+            return format!("<{}", first);
+        }
+        let band = self.edges.iter().rposition(|&edge| age >= edge).unwrap_or(0);
+        if let Some(label) = self.labels.get(band) {
+            return label.clone();
+        }
+        match self.edges.get(band + 1) {
+            Some(end) => format!("{}-{}", self.edges[band], end),
+            None => format!("{}+", self.edges[band]),
+        }
+    }
+}
+
+impl Default for AgeBins {
+    fn default() -> Self {
+        AgeBins::preset("pediatric").expect("built-in preset")
+    }
+}
+
+impl FromStr for AgeBins {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(bins) = AgeBins::preset(s) {
+            return Ok(bins);
+        }
+        // Bands are written `3-5` or `18+`, edges as plain numbers
+        let band = |part: &str| part.ends_with('+') || part.get(1..).is_some_and(|rest| rest.contains('-'));
+        if s.split(',').map(str::trim).any(band) {
+            return AgeBins::from_bands(s);
+        }
+        let edges = s
+            .split(',')
+            .map(|edge| {
+                edge.trim()
+                    .parse::<f64>()
+                    .map_err(|_| format!("invalid age bin edge '{}' (expected numbers or a preset: pediatric, infant, decades)", edge.trim()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        AgeBins::new(edges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_builtin_and_extra_dimensions() {
+        assert_eq!("race".parse::<Dimension>().unwrap(), Dimension::Race);
+        assert_eq!("PAYER_FACTOR".parse::<Dimension>().unwrap(), Dimension::Payer);
+        assert_eq!(
+            "site".parse::<Dimension>().unwrap(),
+            Dimension::Column("site".to_string())
+        );
+        assert!(" ".parse::<Dimension>().is_err());
+        assert_eq!("age".parse::<Dimension>().unwrap(), Dimension::Age(AgeBins::default()));
+    }
+
+    #[test]
+    fn test_parse_crossed_grouping() {
+        let grouping: Grouping = "race*payer".parse().unwrap();
+        assert_eq!(grouping.dimensions(), &[Dimension::Race, Dimension::Payer]);
+        assert_eq!(grouping.label(), "race × payer factor");
+        assert!("race*".parse::<Grouping>().is_err());
+    }
+
+    #[test]
+    fn test_age_bins_labels() {
+        let bins: AgeBins = "0,2,5,12,18".parse().unwrap();
+        assert_eq!(bins, AgeBins::preset("pediatric").unwrap());
+        assert_eq!(bins.label_for(0.0), "0-2");
+        assert_eq!(bins.label_for(4.99), "2-5");
+        assert_eq!(bins.label_for(5.0), "5-12");
+        assert_eq!(bins.label_for(30.0), "18+");
+        assert_eq!(bins.label_for(-1.0), "<0");
+        assert!("0,5,2".parse::<AgeBins>().is_err());
+        assert!("toddler".parse::<AgeBins>().is_err());
+    }
+
+    #[test]
+    fn test_age_bands() {
+        let bands: AgeBins = "0-2, 3-5, 6-12, 13-17, 18+".parse().unwrap();
+        assert_eq!(bands.label_for(0.0), "0-2");
+        assert_eq!(bands.label_for(2.5), "0-2");
+        assert_eq!(bands.label_for(3.0), "3-5");
+        assert_eq!(bands.label_for(17.9), "13-17");
+        assert_eq!(bands.label_for(40.0), "18+");
+        assert_eq!(bands.label_for(-1.0), "<0");
+        // Without an open-ended band, older ages get one
+        let bands = AgeBins::from_bands("0-2,3-5").unwrap();
+        assert_eq!((bands.label_for(5.5), bands.label_for(6.0)), ("3-5".to_string(), "6+".to_string()));
+        assert!(AgeBins::from_bands("0-2,4-5").unwrap_err().contains("should start at 3"));
+        assert!(AgeBins::from_bands("0-2,3+,6-8").is_err());
+        assert!(AgeBins::from_bands("5-2").is_err());
+        assert!("0-two".parse::<AgeBins>().is_err());
+    }
+}
@@ -0,0 +1,297 @@
+//! Incremental graph maintenance for streaming input and batch refreshes
+//! (`--merge-input`), and the Kafka consumer behind the `consume`
+//! subcommand (`kafka` feature).
+
+use std::collections::HashMap;
+
+use petgraph::graph::{DiGraph, NodeIndex};
+use petgraph::Direction;
+
+use crate::error::AllergyNetError;
+use crate::{EdgeWeight, GraphOptions, Individual, NodeType, Record};
+
+/// A graph that records can be added to one at a time. A record for a
+/// subject already in the graph replaces their attributes and allergies,
+/// so the unit of analysis is always the subject.
+pub struct IncrementalGraph {
+    graph: DiGraph<NodeType, EdgeWeight>,
+    options: GraphOptions,
+    individuals: HashMap<String, NodeIndex>,
+    allergens: HashMap<String, NodeIndex>,
+}
+
+impl IncrementalGraph {
+    /// An empty graph holding only the allergen nodes, in the same order
+    /// as `create_graph`.
+    pub fn new(options: GraphOptions) -> Self {
+        let mut graph = DiGraph::new();
+        let allergens = options
+            .allergens()
+            .into_iter()
+            .map(|allergy| (allergy.to_string(), graph.add_node(NodeType::AllergenStatus(allergy.to_string()))))
+            .collect();
+        IncrementalGraph { graph, options, individuals: HashMap::new(), allergens }
+    }
+
+    /// Takes over a graph built earlier, by `create_graph` or
+    /// `graph_from_node_link`, reusing its individual and allergen nodes so
+    /// new batches can be merged without a rebuild. Allergens of `options`
+    /// the graph lacks are added. A graph where a subject has several
+    /// nodes, or with demographic nodes, can't be updated this way.
+    pub fn from_graph(
+        mut graph: DiGraph<NodeType, EdgeWeight>,
+        options: GraphOptions,
+    ) -> Result<Self, AllergyNetError> {
+        let mut individuals = HashMap::new();
+        let mut allergens = HashMap::new();
+        for node in graph.node_indices() {
+            match &graph[node] {
+                NodeType::Individual(individual) => {
+                    if individuals.insert(individual.id.clone(), node).is_some() {
+                        return Err(AllergyNetError::Graph(format!(
+                            "subject {} has several nodes; only a graph of subjects can be updated",
+                            individual.id
+                        )));
+                    }
+                }
+                NodeType::AllergenStatus(name) => {
+                    allergens.insert(name.clone(), node);
+                }
+                NodeType::Demographic { .. } => {
+                    return Err(AllergyNetError::Graph("a tripartite graph can't be updated".to_string()));
+                }
+            }
+        }
+        for allergy in options.allergens() {
+            if !allergens.contains_key(allergy) {
+                allergens.insert(allergy.to_string(), graph.add_node(NodeType::AllergenStatus(allergy.to_string())));
+            }
+        }
+        Ok(IncrementalGraph { graph, options, individuals, allergens })
+    }
+
+    /// Upserts each record of a new batch, returning how many of them
+    /// added, updated and removed a subject.
+    pub fn merge_records<'a>(&mut self, records: impl IntoIterator<Item = &'a Record>) -> MergeSummary {
+        let mut summary = MergeSummary::default();
+        for record in records {
+            let before = self.individuals.contains_key(&record.subject_id);
+            self.upsert(record);
+            match (before, self.individuals.contains_key(&record.subject_id)) {
+                (false, true) => summary.added += 1,
+                (true, true) => summary.updated += 1,
+                (true, false) => summary.removed += 1,
+                (false, false) => {}
+            }
+        }
+        summary
+    }
+
+    /// Adds `record`, or replaces its subject's. A subject the options
+    /// no longer keep (e.g. not observed at the snapshot age) is removed.
+    pub fn upsert(&mut self, record: &Record) {
+        if !self.options.includes_record(record) {
+            self.remove(&record.subject_id);
+            return;
+        }
+        let individual = NodeType::Individual(Individual::from(record));
+        let node = match self.individuals.get(&record.subject_id) {
+            Some(&node) => {
+                self.graph[node] = individual;
+                while let Some(edge) = self.graph.first_edge(node, Direction::Outgoing) {
+                    self.graph.remove_edge(edge);
+                }
+                node
+            }
+            None => {
+                let node = self.graph.add_node(individual);
+                self.individuals.insert(record.subject_id.clone(), node);
+                node
+            }
+        };
+        for (allergen, allergies) in self.options.allergen_groups() {
+            let Some(weight) = EdgeWeight::merged(record, &allergies) else { continue };
+            if self.options.includes_edge(&weight) {
+                self.graph.add_edge(node, self.allergens[allergen], weight);
+            }
+        }
+    }
+
+    fn remove(&mut self, subject_id: &str) {
+        let Some(node) = self.individuals.remove(subject_id) else { return };
+        self.graph.remove_node(node);
+        // The last node took the removed one's index
+        if let Some(NodeType::Individual(moved)) = self.graph.node_weight(node) {
+            self.individuals.insert(moved.id.clone(), node);
+        }
+    }
+
+    pub fn graph(&self) -> &DiGraph<NodeType, EdgeWeight> {
+        &self.graph
+    }
+
+    pub fn individual_count(&self) -> usize {
+        self.individuals.len()
+    }
+
+    pub fn into_graph(self) -> DiGraph<NodeType, EdgeWeight> {
+        self.graph
+    }
+}
+
+/// What `IncrementalGraph::merge_records` did with a batch. A record the
+/// options don't keep, for a subject not in the graph, counts as none.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MergeSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+}
+
+#[cfg(feature = "kafka")]
+pub use self::kafka::consume;
+
+#[cfg(feature = "kafka")]
+mod kafka {
+    use std::error::Error;
+    use std::io::{self, Write};
+    use std::time::{Duration, Instant};
+
+    use kafka::consumer::{Consumer, FetchOffset};
+    use log::{info, warn};
+
+    use super::IncrementalGraph;
+    use crate::strata::Grouping;
+    use crate::{calculate_centrality, emit_json, OutputFormat, Record, ReportOptions, Settings, Unit};
+
+    /// Consumes JSON records (one per message, using the CSV column names
+    /// as keys) from `topic`, and writes a metric snapshot to stdout every
+    /// `snapshot_every`. The topic is always read from the beginning, since
+    /// the graph only lives in memory. Runs until the process is stopped.
+    pub fn consume(
+        brokers: Vec<String>,
+        topic: &str,
+        snapshot_every: Duration,
+        groupings: &[Grouping],
+        settings: &Settings,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut consumer = Consumer::from_hosts(brokers)
+            .with_topic(topic.to_string())
+            .with_fallback_offset(FetchOffset::Earliest)
+            .with_offset_storage(None)
+            .create()?;
+        let mut graph = IncrementalGraph::new(settings.graph.clone());
+        let report = ReportOptions { unit: Unit::Subject, ..settings.report.clone() };
+        let mut last_snapshot = Instant::now();
+        let mut snapshot = 0;
+        loop {
+            for set in consumer.poll()?.iter() {
+                for message in set.messages() {
+                    let record: Record = match serde_json::from_slice(message.value) {
+                        Ok(record) => record,
+                        Err(e) => {
+                            warn!("Skipping message at offset {}: {}", message.offset, e);
+                            continue;
+                        }
+                    };
+                    if !settings.ingest.exclude_ids.contains(&record.subject_id) {
+                        graph.upsert(&record);
+                    }
+                }
+            }
+            if last_snapshot.elapsed() >= snapshot_every {
+                snapshot += 1;
+                let out = &mut io::stdout().lock();
+                if settings.report.format == OutputFormat::Ndjson {
+                    emit_json(out, &serde_json::json!({
+                        "type": "snapshot",
+                        "snapshot": snapshot,
+                        "individuals": graph.individual_count(),
+                    }))?;
+                } else {
+                    writeln!(out, "== snapshot {}: {} individuals ==", snapshot, graph.individual_count())?;
+                }
+                let mut results = calculate_centrality(graph.graph(), groupings, &report)?;
+                results.deidentify(&settings.export.ids(graph.graph())?.0, settings.export.ids);
+                results.write(&report, out)?;
+                out.flush()?;
+                info!("Wrote snapshot {}", snapshot);
+                last_snapshot = Instant::now();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_graph, graph_from_node_link, node_link_json, read_csv};
+
+    #[test]
+    fn test_upsert_matches_batch_graph() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let mut records = read_csv(path).unwrap();
+        let mut incremental = IncrementalGraph::new(GraphOptions::default());
+        for record in &records {
+            incremental.upsert(record);
+        }
+        let batch = create_graph(read_csv(path).unwrap(), &GraphOptions::default());
+        assert_eq!(incremental.graph().node_count(), batch.node_count());
+        assert_eq!(incremental.graph().edge_count(), batch.edge_count());
+
+        // A corrected record replaces the subject's allergies
+        let record = &mut records[4];
+        record.peanut_alg_start = None;
+        record.treenut_alg_start = None;
+        record.walnut_alg_start = None;
+        record.pecan_alg_start = None;
+        record.pistach_alg_start = None;
+        record.almond_alg_start = None;
+        record.brazil_alg_start = None;
+        record.hazelnut_alg_start = None;
+        record.cashew_alg_start = None;
+        incremental.upsert(record);
+        assert_eq!(incremental.individual_count(), 5);
+        assert_eq!(incremental.graph().edge_count(), batch.edge_count() - 4);
+    }
+
+    #[test]
+    fn test_merge_records_into_saved_graph() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let records = read_csv(path).unwrap();
+        let saved = node_link_json(&create_graph(records[..3].to_vec(), &GraphOptions::default()));
+        let graph = graph_from_node_link(&saved).unwrap();
+        let mut incremental = IncrementalGraph::from_graph(graph, GraphOptions::default()).unwrap();
+        let merged = incremental.merge_records(&records[2..]);
+        assert_eq!(merged, MergeSummary { added: 2, updated: 1, removed: 0 });
+        let batch = create_graph(records.clone(), &GraphOptions::default());
+        let graph = incremental.into_graph();
+        assert_eq!((graph.node_count(), graph.edge_count()), (batch.node_count(), batch.edge_count()));
+
+        let twice = create_graph(vec![records[0].clone(), records[0].clone()], &GraphOptions::default());
+        assert!(IncrementalGraph::from_graph(twice, GraphOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_upsert_takes_snapshots() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv");
+        let options = GraphOptions { snapshot_age: Some(5.0), ..Default::default() };
+        let mut records = read_csv(path).unwrap();
+        let mut incremental = IncrementalGraph::new(options.clone());
+        for record in &records {
+            incremental.upsert(record);
+        }
+        let batch = create_graph(read_csv(path).unwrap(), &options);
+        assert_eq!(incremental.graph().node_count(), batch.node_count());
+        assert_eq!(incremental.graph().edge_count(), batch.edge_count());
+
+        // Subject 205650 is no longer observed at 5; 205654 keeps its node
+        records[0].age_end_years = 4.0;
+        incremental.upsert(&records[0]);
+        assert_eq!(incremental.individual_count(), 3);
+        records[4].peanut_alg_start = None;
+        incremental.upsert(&records[4]);
+        assert_eq!(incremental.individual_count(), 3);
+        assert_eq!(incremental.graph().edge_count(), batch.edge_count() - 2);
+    }
+}
@@ -0,0 +1,431 @@
+//! CSV outputs with versioned schemas (`--out-dir`): each metric per
+//! individual and per group, each allergy's prevalence and each pair's
+//! co-occurrence, as one file each, for scripts that read the results.
+//!
+//! Individuals in `centrality_by_node.csv` go by the id they are exported
+//! under (`--export-ids`), and unless ids are kept, with no node index and
+//! in order of that id, since node order is input order.
+//!
+//! Every file has the columns of `SCHEMAS` in that order, with a header
+//! even when it has no rows, and every row starts with `SCHEMA_VERSION`.
+//! Columns are only ever added at the end within a version; renaming,
+//! removing or reordering one bumps it. `schema.json` repeats the columns
+//! alongside the provenance.
+
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use petgraph::graph::DiGraph;
+use serde::Serialize;
+
+use crate::privacy::Deidentify;
+use crate::remote::Destination;
+use crate::stats::association::{association, Association};
+use crate::stats::prevalence::{prevalence, Prevalence};
+use crate::strata::Grouping;
+use crate::{calculate_metric, EdgeWeight, Metric, NodeType, ReportOptions};
+
+/// Version of the columns of `SCHEMAS`.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Each file written, with its columns.
+pub const SCHEMAS: [(&str, &[&str]); 4] = [
+    ("centrality_by_node.csv", &["schema_version", "metric", "node", "id", "value", "unit"]),
+    (
+        "centrality_by_group.csv",
+        &[
+            "schema_version",
+            "metric",
+            "grouping",
+            "group",
+            "mean",
+            "total",
+            "n",
+            "ci_lower",
+            "ci_upper",
+            "denominator",
+            "unit",
+            "small_cell",
+            "suppressed",
+        ],
+    ),
+    (
+        "prevalence.csv",
+        &[
+            "schema_version",
+            "allergy",
+            "grouping",
+            "group",
+            "cases",
+            "n",
+            "proportion",
+            "ci_lower",
+            "ci_upper",
+            "small_cell",
+            "suppressed",
+        ],
+    ),
+    (
+        "cooccurrence.csv",
+        &[
+            "schema_version",
+            "allergy",
+            "other",
+            "both",
+            "allergy_only",
+            "other_only",
+            "neither",
+            "odds_ratio",
+            "or_ci_lower",
+            "or_ci_upper",
+            "relative_risk",
+            "rr_ci_lower",
+            "rr_ci_upper",
+            "chi_square",
+            "chi_square_p",
+            "fisher_p",
+            "small_cell",
+            "suppressed",
+        ],
+    ),
+];
+
+/// The schemas and provenance, next to the CSV files.
+pub const SCHEMA_FILE: &str = "schema.json";
+
+/// A row of `centrality_by_node.csv`: one individual's score.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NodeRow {
+    pub schema_version: u32,
+    pub metric: String,
+    /// The individual's node index; `None` unless ids are kept.
+    pub node: Option<usize>,
+    /// The de-identified subject id.
+    pub id: String,
+    pub value: f64,
+    pub unit: String,
+}
+
+/// A row of `centrality_by_group.csv`: one group's average, as `analyze`
+/// reports it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GroupRow {
+    pub schema_version: u32,
+    pub metric: String,
+    pub grouping: String,
+    pub group: String,
+    pub mean: Option<f64>,
+    pub total: Option<f64>,
+    pub n: Option<usize>,
+    pub ci_lower: Option<f64>,
+    pub ci_upper: Option<f64>,
+    /// Individuals in the graph.
+    pub denominator: usize,
+    pub unit: String,
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+/// A row of `prevalence.csv`, as `prevalence` reports it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PrevalenceRow {
+    pub schema_version: u32,
+    pub allergy: String,
+    pub grouping: String,
+    pub group: String,
+    pub cases: Option<usize>,
+    pub n: Option<usize>,
+    pub proportion: Option<f64>,
+    pub ci_lower: Option<f64>,
+    pub ci_upper: Option<f64>,
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+/// A row of `cooccurrence.csv`: one ordered pair, as `association`
+/// reports it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CooccurrenceRow {
+    pub schema_version: u32,
+    pub allergy: String,
+    pub other: String,
+    pub both: Option<usize>,
+    pub allergy_only: Option<usize>,
+    pub other_only: Option<usize>,
+    pub neither: Option<usize>,
+    pub odds_ratio: Option<f64>,
+    pub or_ci_lower: Option<f64>,
+    pub or_ci_upper: Option<f64>,
+    pub relative_risk: Option<f64>,
+    pub rr_ci_lower: Option<f64>,
+    pub rr_ci_upper: Option<f64>,
+    pub chi_square: Option<f64>,
+    pub chi_square_p: Option<f64>,
+    pub fisher_p: Option<f64>,
+    pub small_cell: bool,
+    pub suppressed: bool,
+}
+
+impl From<Prevalence> for PrevalenceRow {
+    fn from(row: Prevalence) -> Self {
+        PrevalenceRow {
+            schema_version: SCHEMA_VERSION,
+            allergy: row.allergy,
+            grouping: row.grouping,
+            group: row.group,
+            cases: row.cases,
+            n: row.n,
+            proportion: row.proportion,
+            ci_lower: row.ci_lower,
+            ci_upper: row.ci_upper,
+            small_cell: row.small_cell,
+            suppressed: row.suppressed,
+        }
+    }
+}
+
+impl From<Association> for CooccurrenceRow {
+    fn from(row: Association) -> Self {
+        CooccurrenceRow {
+            schema_version: SCHEMA_VERSION,
+            allergy: row.allergy,
+            other: row.other,
+            both: row.both,
+            allergy_only: row.allergy_only,
+            other_only: row.other_only,
+            neither: row.neither,
+            odds_ratio: row.odds_ratio,
+            or_ci_lower: row.or_ci_lower,
+            or_ci_upper: row.or_ci_upper,
+            relative_risk: row.relative_risk,
+            rr_ci_lower: row.rr_ci_lower,
+            rr_ci_upper: row.rr_ci_upper,
+            chi_square: row.chi_square,
+            chi_square_p: row.chi_square_p,
+            fisher_p: row.fisher_p,
+            small_cell: row.small_cell,
+            suppressed: row.suppressed,
+        }
+    }
+}
+
+/// The rows of every file of `SCHEMAS`, in its order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tables {
+    pub by_node: Vec<NodeRow>,
+    pub by_group: Vec<GroupRow>,
+    pub prevalence: Vec<PrevalenceRow>,
+    pub cooccurrence: Vec<CooccurrenceRow>,
+    /// What de-identification applied to the ids, as for graph exports.
+    pub deidentification: Vec<String>,
+    /// Group rows of the metrics reported unsuppressed despite being small
+    /// cells.
+    centrality_small_cells: usize,
+}
+
+/// Computes every table from `graph`: `metrics` and prevalence broken down
+/// by `groupings`, as `analyze` and `prevalence` compute them, and every
+/// pair's co-occurrence, as `association` does. Subject ids are
+/// de-identified by `export`. Under differential privacy prevalence and
+/// the metrics split the epsilon, and co-occurrence, which `association`
+/// refuses, is left empty.
+pub fn tables(
+    graph: &DiGraph<NodeType, EdgeWeight>,
+    groupings: &[Grouping],
+    metrics: &[Metric],
+    options: &ReportOptions,
+    export: &Deidentify,
+) -> Result<Tables, String> {
+    let mut shares = options.per_metric(1 + metrics.len());
+    let prevalence = prevalence(graph, groupings, &shares.next().expect("one share per table"))?;
+    let cooccurrence = if options.noise.is_some() { Vec::new() } else { association(graph, options)? };
+    let (ids, deidentification) = export.ids(graph)?;
+    let (mut by_node, mut by_group, mut centrality_small_cells) = (Vec::new(), Vec::new(), 0);
+    for (&metric, options) in metrics.iter().zip(shares) {
+        let mut report = calculate_metric(graph, groupings, metric, &options)?;
+        report.deidentify(&ids, export.ids);
+        centrality_small_cells += report.small_cells();
+        let (name, unit) = (metric.to_string(), report.unit.to_string());
+        by_node.extend(report.nodes.into_iter().map(|node| NodeRow {
+            schema_version: SCHEMA_VERSION,
+            metric: name.clone(),
+            node: node.node,
+            id: node.id,
+            value: node.value,
+            unit: unit.clone(),
+        }));
+        by_group.extend(report.groups.into_iter().map(|group| GroupRow {
+            schema_version: SCHEMA_VERSION,
+            metric: name.clone(),
+            grouping: group.grouping,
+            group: group.group,
+            mean: group.mean,
+            total: group.total,
+            n: group.n,
+            ci_lower: group.ci_lower,
+            ci_upper: group.ci_upper,
+            denominator: report.denominator,
+            unit: unit.clone(),
+            small_cell: group.small_cell,
+            suppressed: group.suppressed,
+        }));
+    }
+    Ok(Tables {
+        by_node,
+        by_group,
+        prevalence: prevalence.into_iter().map(PrevalenceRow::from).collect(),
+        cooccurrence: cooccurrence.into_iter().map(CooccurrenceRow::from).collect(),
+        deidentification,
+        centrality_small_cells,
+    })
+}
+
+/// Writes `rows` under `columns`, with the header even if there are no
+/// rows.
+fn write_rows<T: Serialize>(columns: &[&str], rows: &[T], out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(out);
+    writer.write_record(columns)?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+impl Tables {
+    /// Rows reported unsuppressed despite being small cells.
+    pub fn small_cells(&self) -> usize {
+        let prevalence = self.prevalence.iter().filter(|row| row.small_cell && !row.suppressed).count();
+        let cooccurrence = self.cooccurrence.iter().filter(|row| row.small_cell && !row.suppressed).count();
+        self.centrality_small_cells + prevalence + cooccurrence
+    }
+
+    /// Writes each file of `SCHEMAS` and `schema.json`, with the provenance
+    /// of `options`, to `dir` (a local directory, created if need be, or a
+    /// cloud URL); returns their paths.
+    pub fn write(&self, dir: &Path, options: &ReportOptions) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let mut written = Vec::new();
+        for (name, columns) in SCHEMAS {
+            let path = dir.join(name);
+            let mut destination = Destination::create(&path)?;
+            match name {
+                "centrality_by_node.csv" => write_rows(columns, &self.by_node, &mut destination)?,
+                "centrality_by_group.csv" => write_rows(columns, &self.by_group, &mut destination)?,
+                "prevalence.csv" => write_rows(columns, &self.prevalence, &mut destination)?,
+                _ => write_rows(columns, &self.cooccurrence, &mut destination)?,
+            }
+            destination.finish()?;
+            written.push(path);
+        }
+        let files: serde_json::Map<_, _> =
+            SCHEMAS.iter().map(|(name, columns)| (name.to_string(), columns.to_vec().into())).collect();
+        let schema = serde_json::json!({
+            "schema_version": SCHEMA_VERSION,
+            "files": files,
+            "deidentification": self.deidentification,
+            "provenance": options.provenance,
+        });
+        let path = dir.join(SCHEMA_FILE);
+        let mut destination = Destination::create(&path)?;
+        serde_json::to_writer_pretty(&mut destination, &schema)?;
+        writeln!(destination)?;
+        destination.finish()?;
+        written.push(path);
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    use crate::disclosure::{Mechanism, NoiseOptions, Suppression};
+    use crate::privacy::ExportIds;
+    use crate::strata::Dimension;
+    use crate::{create_graph, read_csv, GraphOptions};
+
+    #[test]
+    fn test_tables_follow_their_schemas() {
+        let records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let graph = create_graph(records, &GraphOptions::default());
+        let options = ReportOptions { small_cell_threshold: 2, suppression: Suppression::Flag, ..Default::default() };
+        let groupings = [Dimension::Gender.into()];
+        let keep = Deidentify { ids: ExportIds::Keep, ..Default::default() };
+        let tables = tables(&graph, &groupings, &[Metric::Degree, Metric::Closeness], &options, &keep).unwrap();
+        // Five individuals and two genders, for each metric; nine
+        // allergies overall and per gender; 72 ordered pairs
+        assert_eq!((tables.by_node.len(), tables.by_group.len()), (10, 4));
+        assert_eq!((tables.prevalence.len(), tables.cooccurrence.len()), (27, 72));
+        assert!(tables.small_cells() > 0);
+
+        let dir = std::env::temp_dir().join(format!("allergy-tables-{}", std::process::id()));
+        let written = tables.write(&dir, &options).unwrap();
+        assert_eq!(written.len(), SCHEMAS.len() + 1);
+        for (name, columns) in SCHEMAS {
+            let contents = fs::read_to_string(dir.join(name)).unwrap();
+            let mut lines = contents.lines();
+            assert_eq!(lines.next(), Some(columns.join(",").as_str()), "{}", name);
+            // Each row has every column, starting with the version
+            for line in lines.filter(|line| !line.contains('"')) {
+                assert_eq!(line.split(',').count(), columns.len(), "{}: {}", name, line);
+                assert!(line.starts_with("1,"));
+            }
+        }
+        let node = fs::read_to_string(dir.join("centrality_by_node.csv")).unwrap();
+        assert!(node.contains("\n1,degree,9,205650,2.0,record\n"));
+        let schema = fs::read_to_string(dir.join(SCHEMA_FILE)).unwrap();
+        let schema: serde_json::Value = serde_json::from_str(&schema).unwrap();
+        assert_eq!(schema["schema_version"], 1);
+        assert_eq!(schema["files"]["prevalence.csv"][1], "allergy");
+        fs::remove_dir_all(&dir).unwrap();
+
+        // An empty table still has its header
+        let empty = Tables { by_node: Vec::new(), ..tables };
+        let dir = std::env::temp_dir().join(format!("allergy-tables-empty-{}", std::process::id()));
+        empty.write(&dir, &options).unwrap();
+        let node = fs::read_to_string(dir.join("centrality_by_node.csv")).unwrap();
+        assert_eq!(node, "schema_version,metric,node,id,value,unit\n");
+        fs::remove_dir_all(&dir).unwrap();
+
+        let noise = NoiseOptions { epsilon: 1.0, mechanism: Mechanism::Laplace, delta: 0.0 };
+        let noisy = ReportOptions { noise: Some(noise), ..options };
+        let noisy = super::tables(&graph, &groupings, &[Metric::Degree], &noisy, &keep).unwrap();
+        // Per-node values and co-occurrence aren't released with noise
+        assert!(noisy.by_node.is_empty() && noisy.cooccurrence.is_empty());
+        assert_eq!((noisy.by_group.len(), noisy.prevalence.len()), (2, 27));
+        assert!(noisy.prevalence.iter().all(|row| row.ci_lower.is_none()));
+    }
+
+    #[test]
+    fn test_node_ids_are_deidentified() {
+        let mut records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        for (record, id) in records.iter_mut().zip(["900017", "900003", "900042", "900008", "900011"]) {
+            record.subject_id = id.to_string();
+        }
+        let graph = create_graph(records, &GraphOptions::default());
+        let options = ReportOptions { suppression: Suppression::Flag, ..Default::default() };
+
+        let renumbered = tables(&graph, &[], &[Metric::Degree], &options, &Deidentify::default()).unwrap();
+        let ids: Vec<&str> = renumbered.by_node.iter().map(|row| row.id.as_str()).collect();
+        assert_eq!(ids, ["1", "2", "3", "4", "5"]);
+        assert!(renumbered.by_node.iter().all(|row| row.node.is_none()));
+        assert_eq!(renumbered.deidentification, ["subject ids renumbered in random order"]);
+        // Degrees 2, 1, 3, 0, 4 whatever the numbering
+        let mut degrees: Vec<f64> = renumbered.by_node.iter().map(|row| row.value).collect();
+        degrees.sort_by(f64::total_cmp);
+        assert_eq!(degrees, [0.0, 1.0, 2.0, 3.0, 4.0]);
+        let dir = std::env::temp_dir().join(format!("allergy-tables-ids-{}", std::process::id()));
+        renumbered.write(&dir, &options).unwrap();
+        let node = fs::read_to_string(dir.join("centrality_by_node.csv")).unwrap();
+        assert!(!node.contains("9000"), "{}", node);
+        assert!(node.contains("\n1,degree,,1,"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let hash = Deidentify { ids: ExportIds::Hash, salt: "s3cret".to_string(), ..Default::default() };
+        let hashed = tables(&graph, &[], &[Metric::Degree], &options, &hash).unwrap();
+        assert!(hashed.by_node.iter().any(|row| row.id == crate::privacy::salted_hash("s3cret", "900042")));
+        assert!(hashed.by_node.iter().all(|row| !row.id.starts_with("9000") && row.node.is_none()));
+    }
+}
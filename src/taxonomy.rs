@@ -0,0 +1,181 @@
+//! The allergen taxonomy: allergens as children of broader ones (walnut and
+//! pecan are tree nuts), so the graph can be built at a coarser level
+//! (`--taxonomy-level`), e.g. tree nut vs peanut.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::quality::TREE_NUTS;
+use crate::remote;
+
+/// Parent -> child links between allergens, as in the YAML file:
+///
+/// ```yaml
+/// Treenut: [Walnut, Pecan, Pistachio, Almond, Brazil, Hazelnut, Cashew]
+/// Nut: [Peanut, Treenut]
+/// ```
+///
+/// A parent needn't be an allergen itself (`Nut`); one that is (`Treenut`)
+/// keeps its own onsets too. Allergens not named are top-level. The default
+/// puts the seven specific tree nuts under `Treenut`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Taxonomy {
+    /// Child -> parent.
+    parents: BTreeMap<String, String>,
+}
+
+impl Default for Taxonomy {
+    fn default() -> Self {
+        let parents = TREE_NUTS.iter().map(|nut| (nut.to_string(), "Treenut".to_string())).collect();
+        Taxonomy { parents }
+    }
+}
+
+impl Taxonomy {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let declared: BTreeMap<String, Vec<String>> = serde_yaml::from_slice(&remote::read(path)?)
+            .map_err(|e| format!("invalid taxonomy file {}: {}", path.display(), e))?;
+        Taxonomy::new(declared).map_err(|e| format!("invalid taxonomy file {}: {}", path.display(), e).into())
+    }
+
+    /// From `parent -> children`; each allergen may have one parent, and
+    /// none may be its own ancestor.
+    pub fn new(declared: BTreeMap<String, Vec<String>>) -> Result<Self, String> {
+        let mut parents = BTreeMap::new();
+        for (parent, children) in declared {
+            for child in children {
+                match parents.insert(child.clone(), parent.clone()) {
+                    Some(other) if other != parent => {
+                        return Err(format!("{} is under both {} and {}", child, other, parent))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let taxonomy = Taxonomy { parents };
+        for child in taxonomy.parents.keys() {
+            if taxonomy.lineage(child).len() > taxonomy.parents.len() + 1 {
+                return Err(format!("{} is its own ancestor", child));
+            }
+        }
+        Ok(taxonomy)
+    }
+
+    /// `name`, its parent, and so on up to its top-level ancestor. Stops
+    /// after one repeat, so a cycle shows as a lineage longer than the
+    /// taxonomy.
+    fn lineage<'a>(&'a self, name: &'a str) -> Vec<&'a str> {
+        let mut lineage = vec![name];
+        while let Some(parent) = self.parents.get(*lineage.last().expect("never empty")) {
+            lineage.push(parent);
+            if lineage.len() > self.parents.len() + 1 {
+                break;
+            }
+        }
+        lineage
+    }
+
+    /// The node `allergy` is counted under at `level`: its ancestor at
+    /// that depth (top-level allergens are at depth 1), or itself if it is
+    /// no deeper.
+    pub fn node<'a>(&'a self, allergy: &'a str, level: TaxonomyLevel) -> &'a str {
+        let lineage = self.lineage(allergy);
+        match level {
+            TaxonomyLevel::Leaf => allergy,
+            TaxonomyLevel::Depth(depth) => lineage[lineage.len().saturating_sub(depth)],
+        }
+    }
+
+    /// Whether `ancestor` is above `name`.
+    pub fn is_ancestor(&self, ancestor: &str, name: &str) -> bool {
+        self.lineage(name)[1..].contains(&ancestor)
+    }
+
+    /// Names the taxonomy places with no children under them that aren't
+    /// among `allergens`, which would never have an edge.
+    pub fn unknown<'a>(&'a self, allergens: &[&str]) -> Vec<&'a str> {
+        let parents: Vec<&String> = self.parents.values().collect();
+        self.parents
+            .keys()
+            .filter(|child| !parents.contains(child) && !allergens.contains(&child.as_str()))
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+/// How far down the taxonomy the graph's allergen nodes go.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TaxonomyLevel {
+    /// Every allergen its own node.
+    #[default]
+    Leaf,
+    /// Allergens deeper than this merged into their ancestor at it; 1 is
+    /// the top level.
+    Depth(usize),
+}
+
+impl FromStr for TaxonomyLevel {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "leaf" => Ok(TaxonomyLevel::Leaf),
+            "top" => Ok(TaxonomyLevel::Depth(1)),
+            _ => match value.parse::<usize>() {
+                Ok(depth) if depth > 0 => Ok(TaxonomyLevel::Depth(depth)),
+                _ => Err(format!("expected leaf, top or a depth from 1, got '{}'", value)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for TaxonomyLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaxonomyLevel::Leaf => f.write_str("leaf"),
+            TaxonomyLevel::Depth(1) => f.write_str("top"),
+            TaxonomyLevel::Depth(depth) => write!(f, "{}", depth),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_taxonomy_levels() {
+        let default = Taxonomy::default();
+        assert_eq!(default.node("Walnut", TaxonomyLevel::Depth(1)), "Treenut");
+        assert_eq!(default.node("Walnut", TaxonomyLevel::Leaf), "Walnut");
+        assert_eq!(default.node("Peanut", TaxonomyLevel::Depth(1)), "Peanut");
+
+        let declared = BTreeMap::from([
+            ("Nut".to_string(), vec!["Peanut".to_string(), "Treenut".to_string()]),
+            ("Treenut".to_string(), vec!["Walnut".to_string(), "Pecan".to_string()]),
+        ]);
+        let taxonomy = Taxonomy::new(declared).unwrap();
+        assert_eq!(taxonomy.node("Walnut", "top".parse().unwrap()), "Nut");
+        assert_eq!(taxonomy.node("Walnut", "2".parse().unwrap()), "Treenut");
+        assert_eq!(taxonomy.node("Treenut", TaxonomyLevel::Depth(3)), "Treenut");
+        assert!(taxonomy.is_ancestor("Nut", "Walnut") && !taxonomy.is_ancestor("Walnut", "Walnut"));
+        assert_eq!(taxonomy.unknown(&["Peanut", "Walnut"]), ["Pecan"]);
+        assert!("0".parse::<TaxonomyLevel>().is_err());
+        assert_eq!(TaxonomyLevel::Depth(1).to_string(), "top");
+
+        let twice = BTreeMap::from([
+            ("Nut".to_string(), vec!["Walnut".to_string()]),
+            ("Treenut".to_string(), vec!["Walnut".to_string()]),
+        ]);
+        assert_eq!(Taxonomy::new(twice).unwrap_err(), "Walnut is under both Nut and Treenut");
+        let cycle = BTreeMap::from([
+            ("Nut".to_string(), vec!["Treenut".to_string()]),
+            ("Treenut".to_string(), vec!["Nut".to_string()]),
+        ]);
+        assert!(Taxonomy::new(cycle).unwrap_err().contains("its own ancestor"));
+    }
+}
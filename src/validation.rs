@@ -0,0 +1,280 @@
+//! Row-level validation at ingest (`--on-invalid`): records that can't be
+//! right, such as an observation window that ends before it starts, are
+//! skipped, fail the input or are only reported, and can be written to a
+//! rejects file with the reasons.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+use log::warn;
+
+use crate::error::AllergyNetError;
+use crate::schema::AllowedValues;
+use crate::{write_csv, Record, ALLERGENS, RECORD_COLUMNS};
+
+/// Extra columns of the rejects file: the 1-based position of the record
+/// in the input, and why it was rejected.
+pub const ROW_COLUMN: &str = "rejected_row";
+pub const REASONS_COLUMN: &str = "rejected_reasons";
+
+/// A coded column and its value in a record.
+type CodedColumn = (&'static str, fn(&Record) -> &str);
+
+/// Coded columns whose values are checked against the allowed codes.
+const CODED_COLUMNS: &[CodedColumn] =
+    &[("gender_factor", |record| &record.gender_factor), ("race_factor", |record| &record.race_factor)];
+
+/// What ingest does with an invalid record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OnInvalid {
+    /// Drop the record, logging how many were dropped.
+    Skip,
+    /// Refuse the input, giving the number of invalid rows per reason.
+    Fail,
+    /// Keep the record, logging each one as a warning.
+    Report,
+}
+
+impl fmt::Display for OnInvalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.to_possible_value().expect("no skipped variants").get_name())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RowValidation {
+    pub on_invalid: OnInvalid,
+    /// Allowed gender and race codes; other columns are ignored.
+    pub codes: AllowedValues,
+    /// CSV file the invalid records are written to, under any policy.
+    pub rejects: Option<PathBuf>,
+}
+
+/// One thing wrong with a record.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reason {
+    /// `age_end_years` before `age_start_years`.
+    WindowEndsBeforeStart { start: f64, end: f64 },
+    /// An allergy's end column before its onset column.
+    AllergyEndsBeforeOnset { column: &'static str, onset_column: &'static str, end: f64, onset: f64 },
+    NegativeAge { column: &'static str, age: f64 },
+    UnknownCode { column: &'static str, value: String },
+}
+
+impl Reason {
+    /// The reason without its values, for counting.
+    pub fn kind(&self) -> String {
+        match self {
+            Reason::WindowEndsBeforeStart { .. } => "observation window ending before it starts".to_string(),
+            Reason::AllergyEndsBeforeOnset { .. } => "allergy ending before onset".to_string(),
+            Reason::NegativeAge { .. } => "negative age".to_string(),
+            Reason::UnknownCode { column, .. } => format!("unknown {}", column),
+        }
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::WindowEndsBeforeStart { start, end } => {
+                write!(f, "age_end_years {} before age_start_years {}", end, start)
+            }
+            Reason::AllergyEndsBeforeOnset { column, onset_column, end, onset } => {
+                write!(f, "{} {} before {} {}", column, end, onset_column, onset)
+            }
+            Reason::NegativeAge { column, age } => write!(f, "negative {} {}", column, age),
+            Reason::UnknownCode { column, value } => write!(f, "unknown {} '{}'", column, value),
+        }
+    }
+}
+
+/// One invalid record and everything wrong with it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejected {
+    /// 1-based position of the record in the input.
+    pub row: usize,
+    pub record: Record,
+    pub reasons: Vec<Reason>,
+}
+
+impl Rejected {
+    /// The reasons, separated by `; `.
+    pub fn describe(&self) -> String {
+        self.reasons.iter().map(Reason::to_string).collect::<Vec<_>>().join("; ")
+    }
+}
+
+#[derive(Debug)]
+pub struct ValidationReport {
+    pub on_invalid: OnInvalid,
+    pub rows: usize,
+    pub rejected: Vec<Rejected>,
+}
+
+/// Why `record` can't be right, or nothing: an observation window or
+/// allergy interval that ends before it starts, a negative age, or a
+/// gender or race code outside `codes`.
+pub fn invalid_reasons(record: &Record, codes: &AllowedValues) -> Vec<Reason> {
+    let mut reasons = Vec::new();
+    if record.age_end_years < record.age_start_years {
+        reasons.push(Reason::WindowEndsBeforeStart { start: record.age_start_years, end: record.age_end_years });
+    }
+    let mut ages = vec![("age_start_years", record.age_start_years), ("age_end_years", record.age_end_years)];
+    // The allergy columns follow the demographics in allergen order
+    for (&allergy, columns) in ALLERGENS.iter().zip(RECORD_COLUMNS[9..].chunks(2)) {
+        let (onset, end) = (record.get_allergy_start(allergy), record.get_allergy_end(allergy));
+        if let (Some(onset), Some(end)) = (onset, end) {
+            if end < onset {
+                let (column, onset_column) = (columns[1], columns[0]);
+                reasons.push(Reason::AllergyEndsBeforeOnset { column, onset_column, end, onset });
+            }
+        }
+        ages.extend(onset.map(|onset| (columns[0], onset)));
+        ages.extend(end.map(|end| (columns[1], end)));
+    }
+    for (column, age) in ages {
+        if age < 0.0 {
+            reasons.push(Reason::NegativeAge { column, age });
+        }
+    }
+    for &(column, value) in CODED_COLUMNS {
+        let Some(allowed) = codes.columns.get(column) else { continue };
+        let value = value(record);
+        if !allowed.contains(value) {
+            reasons.push(Reason::UnknownCode { column, value: value.to_string() });
+        }
+    }
+    reasons
+}
+
+impl RowValidation {
+    /// Finds the invalid records and, under `Skip`, drops them. The records
+    /// are otherwise left unchanged.
+    pub fn enforce(&self, records: &mut Vec<Record>) -> ValidationReport {
+        let rejected: Vec<Rejected> = records
+            .iter()
+            .enumerate()
+            .filter_map(|(i, record)| {
+                let reasons = invalid_reasons(record, &self.codes);
+                (!reasons.is_empty()).then(|| Rejected { row: i + 1, record: record.clone(), reasons })
+            })
+            .collect();
+        let rows = records.len();
+        if self.on_invalid == OnInvalid::Skip {
+            let rows: HashSet<usize> = rejected.iter().map(|rejected| rejected.row).collect();
+            let mut row = 0;
+            records.retain(|_| {
+                row += 1;
+                !rows.contains(&row)
+            });
+        }
+        ValidationReport { on_invalid: self.on_invalid, rows, rejected }
+    }
+}
+
+impl ValidationReport {
+    /// e.g. `2 invalid row(s) of 5: 1 negative age, 1 unknown gender_factor`,
+    /// counting each kind of reason once per row.
+    pub fn summary(&self) -> String {
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for rejected in &self.rejected {
+            let kinds: BTreeSet<String> = rejected.reasons.iter().map(Reason::kind).collect();
+            for kind in kinds {
+                *counts.entry(kind).or_default() += 1;
+            }
+        }
+        let counts: Vec<String> = counts.iter().map(|(kind, count)| format!("{} {}", count, kind)).collect();
+        format!("{} invalid row(s) of {}: {}", self.rejected.len(), self.rows, counts.join(", "))
+    }
+
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "{}", self.summary())?;
+        for rejected in &self.rejected {
+            writeln!(
+                out,
+                "  row {} (subject {}): {}",
+                rejected.row,
+                rejected.record.subject_id,
+                rejected.describe()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes the invalid records as CSV, like `write_csv`, with their
+    /// input row and reasons in the `rejected_row` and `rejected_reasons`
+    /// columns.
+    pub fn write_rejects(&self, out: impl Write) -> Result<(), AllergyNetError> {
+        let records: Vec<Record> = self
+            .rejected
+            .iter()
+            .map(|rejected| {
+                let mut record = rejected.record.clone();
+                record.extra.insert(ROW_COLUMN.to_string(), rejected.row.to_string());
+                record.extra.insert(REASONS_COLUMN.to_string(), rejected.describe());
+                record
+            })
+            .collect();
+        write_csv(&records, out)
+    }
+
+    /// Logs the summary as a warning, and each invalid row too unless they
+    /// were skipped.
+    pub fn log(&self) {
+        if self.rejected.is_empty() {
+            return;
+        }
+        warn!("{} (--on-invalid {})", self.summary(), self.on_invalid);
+        if self.on_invalid != OnInvalid::Skip {
+            for rejected in &self.rejected {
+                warn!("  row {} (subject {}): {}", rejected.row, rejected.record.subject_id, rejected.describe());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_csv;
+
+    #[test]
+    fn test_row_validation() {
+        let mut records = read_csv(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/mock_records.csv")).unwrap();
+        let codes = AllowedValues::default();
+        let validation = RowValidation { on_invalid: OnInvalid::Report, codes, rejects: None };
+        assert!(validation.enforce(&mut records).rejected.is_empty());
+
+        records[1].age_end_years = 0.1;
+        records[2].walnut_alg_end = Some(1.5);
+        records[2].gender_factor = "Male".to_string();
+        records[4].peanut_alg_start = Some(-0.8);
+        let original = records.clone();
+        let report = validation.enforce(&mut records);
+        assert_eq!(records, original);
+        assert_eq!(report.rejected.iter().map(|rejected| rejected.row).collect::<Vec<_>>(), [2, 3, 5]);
+        assert_eq!(report.rejected[0].describe(), "age_end_years 0.1 before age_start_years 0.2");
+        assert_eq!(
+            report.rejected[1].describe(),
+            "walnut_alg_end 1.5 before walnut_alg_start 2; unknown gender_factor 'Male'"
+        );
+        assert_eq!(
+            report.summary(),
+            "3 invalid row(s) of 5: 1 allergy ending before onset, 1 negative age, \
+             1 observation window ending before it starts, 1 unknown gender_factor"
+        );
+
+        let mut out = Vec::new();
+        report.write_rejects(&mut out).unwrap();
+        let rejects = String::from_utf8(out).unwrap();
+        assert!(rejects.lines().next().unwrap().contains(",rejected_reasons,rejected_row"));
+        assert!(rejects.contains(",negative peanut_alg_start -0.8,5,"));
+
+        let skip = RowValidation { on_invalid: OnInvalid::Skip, ..validation };
+        skip.enforce(&mut records);
+        assert_eq!(records.iter().map(|record| record.subject_id.as_str()).collect::<Vec<_>>(), ["205650", "205653"]);
+    }
+}
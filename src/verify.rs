@@ -0,0 +1,148 @@
+//! Regression checks against a frozen report (`verify --baseline`): the
+//! analysis is recomputed and every result compared with the baseline's,
+//! numbers within a tolerance and everything else exactly.
+
+use std::fmt;
+
+use serde_json::Value;
+
+/// Numbers match when `|current - baseline| <= absolute + relative * |baseline|`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerance {
+    pub absolute: f64,
+    pub relative: f64,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Tolerance { absolute: 1e-9, relative: 0.0 }
+    }
+}
+
+impl Tolerance {
+    fn accepts(&self, baseline: f64, current: f64) -> bool {
+        (current - baseline).abs() <= self.absolute + self.relative * baseline.abs()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Drift {
+    /// A baseline result the recomputed report no longer has.
+    Missing(String),
+    /// A recomputed result the baseline doesn't have.
+    Unexpected(String),
+    Changed { key: String, field: String, baseline: Value, current: Value },
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Drift::Missing(key) => write!(f, "missing: {}", key),
+            Drift::Unexpected(key) => write!(f, "unexpected: {}", key),
+            Drift::Changed { key, field, baseline, current } => {
+                write!(f, "changed: {} {}: baseline {}, now {}", key, field, baseline, current)
+            }
+        }
+    }
+}
+
+/// Results from a saved report: `--format ndjson` output, or a JSON array
+/// of the same objects.
+pub fn parse_report(contents: &str) -> Result<Vec<Value>, serde_json::Error> {
+    if contents.trim_start().starts_with('[') {
+        return serde_json::from_str(contents);
+    }
+    contents.lines().filter(|line| !line.trim().is_empty()).map(serde_json::from_str).collect()
+}
+
+/// Identifies a result across runs, e.g. `group/degree/race/R1 - Black`.
+fn key(result: &Value) -> String {
+    ["type", "metric", "grouping", "group", "id"]
+        .iter()
+        .filter_map(|field| match &result[field] {
+            Value::Null => None,
+            Value::String(text) => Some(text.clone()),
+            other => Some(other.to_string()),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn same(baseline: &Value, current: &Value, tolerance: &Tolerance) -> bool {
+    match (baseline, current) {
+        (Value::Number(a), Value::Number(b)) => match (a.as_f64(), b.as_f64()) {
+            (Some(a), Some(b)) => tolerance.accepts(a, b),
+            _ => a == b,
+        },
+        (Value::Array(a), Value::Array(b)) => a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same(a, b, tolerance)),
+        _ => baseline == current,
+    }
+}
+
+/// Every difference between the two reports, in baseline order followed
+/// by the unexpected results. Provenance rows, which differ between any
+/// two runs, are skipped.
+pub fn compare(baseline: &[Value], current: &[Value], tolerance: &Tolerance) -> Vec<Drift> {
+    let results = |report: &[Value]| -> Vec<Value> {
+        report.iter().filter(|result| result["type"] != "provenance").cloned().collect()
+    };
+    let (baseline, current) = (results(baseline), results(current));
+    let mut drifts = Vec::new();
+    let mut matched = vec![false; current.len()];
+    for expected in &baseline {
+        let key = key(expected);
+        let Some(i) = (0..current.len()).find(|&i| !matched[i] && self::key(&current[i]) == key) else {
+            drifts.push(Drift::Missing(key));
+            continue;
+        };
+        matched[i] = true;
+        let (Some(expected), Some(actual)) = (expected.as_object(), current[i].as_object()) else { continue };
+        let fields = expected.keys().chain(actual.keys().filter(|field| !expected.contains_key(*field)));
+        for field in fields {
+            let before = expected.get(field).cloned().unwrap_or_default();
+            let now = actual.get(field).cloned().unwrap_or_default();
+            if !same(&before, &now, tolerance) {
+                drifts.push(Drift::Changed { key: key.clone(), field: field.clone(), baseline: before, current: now });
+            }
+        }
+    }
+    drifts.extend(current.iter().zip(matched).filter(|(_, matched)| !matched).map(|(result, _)| Drift::Unexpected(key(result))));
+    drifts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::{centrality_results, create_graph, GraphOptions, ReportOptions};
+
+    #[test]
+    fn test_compare_reports() {
+        let graph = create_graph(fixtures::cohort(30, 3), &GraphOptions::default());
+        let groupings = ["race".parse().unwrap(), "payer".parse().unwrap()];
+        let baseline = centrality_results(&graph, &groupings, &ReportOptions::default()).unwrap();
+        let ndjson: String = baseline.iter().map(|result| format!("{}\n", result)).collect();
+        let mut rerun = baseline.clone();
+        rerun.insert(0, serde_json::json!({"type": "provenance", "started_at": "2026-01-01T00:00:00Z"}));
+        assert_eq!(compare(&baseline, &rerun, &Tolerance::default()), vec![]);
+        assert_eq!(parse_report(&ndjson).unwrap(), baseline);
+        assert_eq!(parse_report(&serde_json::to_string(&baseline).unwrap()).unwrap(), baseline);
+        assert_eq!(compare(&baseline, &baseline, &Tolerance::default()), vec![]);
+
+        let mut current = baseline.clone();
+        let group = current.iter().position(|r| r["type"] == "group" && r["mean"].as_f64() > Some(0.5)).unwrap();
+        let mean = current[group]["mean"].as_f64().unwrap();
+        current[group]["mean"] = (mean + 1e-6).into();
+        let drifts = compare(&baseline, &current, &Tolerance::default());
+        assert_eq!(drifts.len(), 1);
+        assert!(drifts[0].to_string().starts_with("changed: group/degree/race/"));
+        assert!(compare(&baseline, &current, &Tolerance { absolute: 1e-5, relative: 0.0 }).is_empty());
+        assert!(compare(&baseline, &current, &Tolerance { absolute: 0.0, relative: 1e-5 }).is_empty());
+
+        let removed = current.remove(group);
+        current.push(serde_json::json!({"type": "group", "metric": "degree", "grouping": "race", "group": "R9"}));
+        let drifts = compare(&baseline, &current, &Tolerance::default());
+        assert_eq!(drifts[0], Drift::Missing(key(&removed)));
+        assert_eq!(drifts.last().unwrap(), &Drift::Unexpected("group/degree/race/R9".to_string()));
+    }
+}
@@ -0,0 +1,106 @@
+//! JavaScript API for running the analysis client-side, so patient data
+//! never leaves the browser. Build with
+//!
+//! ```sh
+//! cargo build --lib --release --target wasm32-unknown-unknown --features wasm
+//! wasm-bindgen --target web --out-dir pkg target/wasm32-unknown-unknown/release/project_name.wasm
+//! ```
+//!
+//! and call `analyze(csvText, JSON.stringify({ stratify_by: ["race"] }))`,
+//! which returns a JSON string. Subject ids in it are renumbered, as for
+//! the command line's exports.
+
+use serde::Deserialize;
+use wasm_bindgen::prelude::*;
+
+use crate::strata::{apply_age_bins, AgeBins, Grouping, DEFAULT_DIMENSIONS};
+use crate::privacy::Deidentify;
+use crate::{calculate_centrality, check_dimensions, create_graph, read_csv_from_reader};
+use crate::{GraphOptions, ReportOptions, Unit};
+
+/// Options accepted from JavaScript; mirrors the CLI flags of the same name.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AnalyzeOptions {
+    stratify_by: Vec<String>,
+    age_bins: Option<String>,
+    onset_before: Option<f64>,
+    onset_after: Option<f64>,
+    snapshot_age: Option<f64>,
+    small_cell_threshold: Option<usize>,
+    unit: Unit,
+}
+
+/// Parses `csv`, builds the allergy graph and returns
+/// `{"nodes", "edges", "results"}` where `results` holds the same objects
+/// as `--format ndjson`.
+#[wasm_bindgen]
+pub fn analyze(csv: &str, options: &str) -> Result<String, JsError> {
+    analyze_json(csv, options).map_err(|e| JsError::new(&e))
+}
+
+fn analyze_json(csv: &str, options: &str) -> Result<String, String> {
+    let options: AnalyzeOptions = if options.trim().is_empty() {
+        AnalyzeOptions::default()
+    } else {
+        serde_json::from_str(options).map_err(|e| format!("invalid options: {}", e))?
+    };
+    let age_bins: AgeBins = match &options.age_bins {
+        Some(bins) => bins.parse()?,
+        None => AgeBins::default(),
+    };
+    let mut groupings = if options.stratify_by.is_empty() {
+        DEFAULT_DIMENSIONS.split(',').map(str::parse).collect::<Result<Vec<Grouping>, _>>()?
+    } else {
+        options.stratify_by.iter().map(|g| g.parse()).collect::<Result<Vec<Grouping>, _>>()?
+    };
+    apply_age_bins(&mut groupings, &age_bins);
+
+    let records = read_csv_from_reader(csv.as_bytes()).map_err(|e| e.to_string())?;
+    check_dimensions(&records, &groupings).map_err(|e| e.to_string())?;
+    let graph_options = GraphOptions {
+        onset_before: options.onset_before,
+        onset_after: options.onset_after,
+        snapshot_age: options.snapshot_age,
+        unit: options.unit,
+        ..Default::default()
+    };
+    let graph = create_graph(records, &graph_options);
+    let report = ReportOptions {
+        small_cell_threshold: options.small_cell_threshold.unwrap_or(11),
+        filters: graph_options.describe().into_iter().collect(),
+        unit: options.unit,
+        ..Default::default()
+    };
+    let export = Deidentify::default();
+    let mut results = calculate_centrality(&graph, &groupings, &report)?;
+    results.deidentify(&export.ids(&graph)?.0, export.ids);
+    let results = results.json_rows(&report);
+    Ok(serde_json::json!({
+        "nodes": graph.node_count(),
+        "edges": graph.edge_count(),
+        "results": results,
+    })
+    .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_analyze_in_memory_csv() {
+        let csv = include_str!("../tests/fixtures/mock_records.csv");
+        let output = analyze_json(csv, r#"{"stratify_by": ["payer"]}"#).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(value["edges"], 10);
+        let groups: Vec<&serde_json::Value> = value["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|r| r["type"] == "group")
+            .collect();
+        assert_eq!(groups.len(), 2);
+        assert!(analyze_json(csv, r#"{"colour": "red"}"#).is_err());
+    }
+}